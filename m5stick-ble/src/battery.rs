@@ -0,0 +1,69 @@
+use esp_idf_hal::i2c::I2cDriver;
+use log::warn;
+
+/// I2C address of the AXP192 power IC of the M5StickC
+const AXP192: u8 = 0x34;
+/// Battery voltage, 12 bits split on two registers
+const BATTERY_VOLTAGE: u8 = 0x78;
+/// Power outputs switches, the LDO2 feeding the screen backlight
+const POWER_OUTPUT: u8 = 0x12;
+const LDO2_ENABLE: u8 = 0x04;
+/// Millivolts per step of the battery voltage ADC
+const VOLTAGE_STEP: f32 = 1.1;
+
+/// Voltages of an empty and a full LiPo cell, in millivolts
+const EMPTY_VOLTAGE: f32 = 3300.0;
+const FULL_VOLTAGE: f32 = 4150.0;
+
+/// Battery of the stick, read through the AXP192 on the internal I2C bus
+pub struct Battery {
+    i2c: I2cDriver<'static>,
+}
+
+impl Battery {
+    pub fn new(i2c: I2cDriver<'static>) -> Self {
+        Self { i2c }
+    }
+
+    /// Battery voltage, in millivolts
+    pub fn get_voltage(&mut self) -> Option<f32> {
+        let mut buffer = [0u8; 2];
+        self.i2c
+            .write_read(AXP192, &[BATTERY_VOLTAGE], &mut buffer, 50)
+            .ok()
+            .or_else(|| {
+                warn!("Unable to read the battery voltage");
+                None
+            })?;
+        let raw = ((buffer[0] as u16) << 4) | (buffer[1] as u16 & 0x0f);
+        Some(raw as f32 * VOLTAGE_STEP)
+    }
+
+    /// Turns the screen backlight on or off, the AXP192 powers it as well
+    pub fn set_backlight(&mut self, on: bool) {
+        let mut outputs = [0u8];
+        self.i2c
+            .write_read(AXP192, &[POWER_OUTPUT], &mut outputs, 50)
+            .and_then(|_| {
+                let outputs = if on {
+                    outputs[0] | LDO2_ENABLE
+                } else {
+                    outputs[0] & !LDO2_ENABLE
+                };
+                self.i2c.write(AXP192, &[POWER_OUTPUT, outputs], 50)
+            })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to switch the backlight");
+                None
+            });
+    }
+
+    /// Battery level in percent, estimated linearly from the voltage
+    pub fn get_level(&mut self) -> Option<u8> {
+        self.get_voltage().map(|voltage| {
+            let level = (voltage - EMPTY_VOLTAGE) * 100.0 / (FULL_VOLTAGE - EMPTY_VOLTAGE);
+            level.clamp(0.0, 100.0) as u8
+        })
+    }
+}
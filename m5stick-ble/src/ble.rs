@@ -0,0 +1,73 @@
+#[cfg(target_os = "espidf")]
+mod client;
+#[cfg(target_os = "espidf")]
+mod esp;
+
+#[cfg(target_os = "espidf")]
+pub use esp::EspBleStack;
+
+use shared::{AdvertStatus, Sensor, SensorKind};
+
+use crate::queues::Subscription;
+
+/// Characteristics the bridge notifies the clients on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Characteristic {
+    Tx,
+    BatteryLevel,
+    Log,
+    Position,
+}
+
+/// BLE stack of the stick, as seen by the bridge. The GATT handlers feed the
+/// `bridge::Shared` queues, the bridge drives the GAP through this trait.
+pub trait BleStack {
+    /// Advertises with the given interval, in units of 0.625 ms.
+    /// In whitelist mode, only the bonded phones may connect.
+    fn start_advertising(&mut self, interval: (u16, u16), whitelist: bool) -> anyhow::Result<()>;
+
+    fn stop_advertising(&mut self);
+
+    fn disconnect(&mut self, bda: [u8; 6]);
+
+    /// Sets the name advertised to the phones
+    fn set_name(&mut self, name: &str);
+
+    /// Sets the status advertised to the phones, readable without connecting
+    fn set_advert_status(&mut self, status: &AdvertStatus);
+
+    /// Advertises as an iBeacon with the given minor in place of the name and status,
+    /// until called with `None`
+    fn set_beacon(&mut self, minor: Option<u16>);
+
+    /// Sends a value fitting in a single ATT packet to a subscribed client
+    fn notify(&mut self, characteristic: Characteristic, subscription: Subscription, value: &[u8]);
+
+    /// Looks for the fitness sensors around for the given number of seconds, the GAP
+    /// handler reports them through `bridge::Shared`
+    fn start_scan(&mut self, seconds: u32) -> anyhow::Result<()>;
+
+    fn stop_scan(&mut self);
+
+    /// Connects to a paired sensor and subscribes to its measurements, dropping the
+    /// sensor of the same kind connected before
+    fn connect_sensor(&mut self, sensor: &Sensor);
+
+    /// Whether the sensor of that kind is connected or being connected to
+    fn is_sensor_connected(&self, kind: SensorKind) -> bool;
+
+    /// Reads the RSSI of the link to a central, the value is given to `callback`
+    fn read_rssi<F>(&mut self, bda: [u8; 6], callback: F) -> anyhow::Result<()>
+    where
+        F: Fn(i8) + Send + 'static;
+
+    /// Whether the controller failed, the stack has to be set up again
+    fn is_failed(&self) -> bool;
+
+    /// Tears the stack down and sets it up again, with the same GATT table and
+    /// advertisement. The centrals are gone and advertising has to be started again.
+    fn restart(&mut self) -> anyhow::Result<()>;
+
+    /// Periodic work of the stack, run on every turn of the main loop
+    fn poll(&mut self) {}
+}
@@ -0,0 +1,305 @@
+use std::{cell::RefCell, sync::Mutex};
+
+use esp_idf_sys::*;
+use log::{info, warn};
+use shared::{Commands, Sensor, SensorKind, SensorUuid};
+
+use crate::{bridge::Shared, sensors::MeasurementDecoder};
+
+/// Application of the GATT client, the server one being registered with 1
+const CLIENT_APP_ID: u16 = 2;
+
+/// Value written to the CCCD of a measurement to be notified of it
+const CCCD_NOTIFY: [u8; 2] = [0x01, 0x00];
+
+/// Connection to a paired sensor, from its opening to the subscription to its measurement
+struct SensorLink {
+    sensor: Sensor,
+    /// Set once the connection is open, the link is being opened until then
+    conn_id: Option<u16>,
+    /// Range of handles of the sensor service
+    service: Option<(u16, u16)>,
+    measurement: Option<u16>,
+    decoder: MeasurementDecoder,
+}
+
+struct Client {
+    gattc_if: Option<esp_gatt_if_t>,
+    shared: Shared,
+    /// One link per sensor kind
+    links: Vec<SensorLink>,
+}
+
+/// Bluedroid takes a single GATT client callback, which reaches its state through this
+static CLIENT: Mutex<RefCell<Option<Client>>> = Mutex::new(RefCell::new(None));
+
+fn with_client<R>(f: impl FnOnce(&mut Client) -> R) -> Option<R> {
+    CLIENT
+        .lock()
+        .ok()
+        .and_then(|client| client.borrow_mut().as_mut().map(f))
+}
+
+/// Registers the GATT client of the stick, the measurements of the sensors going to `shared`
+pub fn init(shared: &Shared) -> Result<(), EspError> {
+    CLIENT.lock().ok().and_then(|client| {
+        client.replace(Some(Client {
+            gattc_if: None,
+            shared: shared.clone(),
+            links: vec![],
+        }));
+        Some(())
+    });
+    esp!(unsafe { esp_ble_gattc_register_callback(Some(on_event)) })?;
+    esp!(unsafe { esp_ble_gattc_app_register(CLIENT_APP_ID) })
+}
+
+/// Opens a connection to a sensor, closing the one to the sensor of the same kind
+pub fn connect(sensor: &Sensor) {
+    with_client(|client| {
+        let gattc_if = match client.gattc_if {
+            Some(gattc_if) => gattc_if,
+            None => {
+                warn!("GATT client not registered");
+                return;
+            }
+        };
+        if let Some(conn_id) = client
+            .links
+            .iter()
+            .find(|link| link.sensor.kind == sensor.kind)
+            .and_then(|link| link.conn_id)
+        {
+            esp!(unsafe { esp_ble_gattc_close(gattc_if, conn_id) })
+                .ok()
+                .or_else(|| {
+                    warn!("Unable to close the sensor connection");
+                    None
+                });
+        }
+        client.links.retain(|link| link.sensor.kind != sensor.kind);
+
+        let address_type = if sensor.random_address {
+            esp_ble_addr_type_t_BLE_ADDR_TYPE_RANDOM
+        } else {
+            esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC
+        };
+        let mut address = sensor.address;
+        match esp!(unsafe {
+            esp_ble_gattc_open(gattc_if, address.as_mut_ptr(), address_type, true)
+        }) {
+            Ok(_) => client.links.push(SensorLink {
+                sensor: sensor.clone(),
+                conn_id: None,
+                service: None,
+                measurement: None,
+                decoder: MeasurementDecoder::new(sensor.kind),
+            }),
+            Err(_) => warn!("Unable to connect to the {:?} sensor", sensor.kind),
+        }
+    });
+}
+
+/// Whether the sensor of that kind is connected or being connected to
+pub fn is_connected(kind: SensorKind) -> bool {
+    with_client(|client| client.links.iter().any(|link| link.sensor.kind == kind)).unwrap_or(false)
+}
+
+/// Whether a connection is the one of a sensor, which the GATT server is told about as well.
+/// Another unit is a central of the server too, subscribing to the position of the bike.
+pub fn is_sensor(bda: [u8; 6]) -> bool {
+    with_client(|client| {
+        client
+            .links
+            .iter()
+            .any(|link| link.sensor.address == bda && link.sensor.kind != SensorKind::Unit)
+    })
+    .unwrap_or(false)
+}
+
+fn uuid16(uuid: u16) -> esp_bt_uuid_t {
+    esp_bt_uuid_t {
+        len: ESP_UUID_LEN_16 as u16,
+        uuid: esp_bt_uuid_t__bindgen_ty_1 { uuid16: uuid },
+    }
+}
+
+fn sensor_uuid(uuid: SensorUuid) -> esp_bt_uuid_t {
+    match uuid {
+        SensorUuid::Uuid16(uuid) => uuid16(uuid),
+        SensorUuid::Uuid128(uuid) => esp_bt_uuid_t {
+            len: ESP_UUID_LEN_128 as u16,
+            uuid: esp_bt_uuid_t__bindgen_ty_1 { uuid128: uuid },
+        },
+    }
+}
+
+/// The position of another unit is only served over an encrypted link, which writing its
+/// CCCD opens. The fitness sensors take plain writes.
+fn get_write_auth(kind: SensorKind) -> esp_gatt_auth_req_t {
+    match kind {
+        SensorKind::Unit => esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_NO_MITM,
+        _ => esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_NONE,
+    }
+}
+
+/// Connection, discovery of the measurement, subscription to it and its notifications
+unsafe extern "C" fn on_event(
+    event: esp_gattc_cb_event_t,
+    gattc_if: esp_gatt_if_t,
+    param: *mut esp_ble_gattc_cb_param_t,
+) {
+    let param = &*param;
+    with_client(|client| match event {
+        esp_gattc_cb_event_t_ESP_GATTC_REG_EVT => {
+            info!("GATT client registered");
+            client.gattc_if = Some(gattc_if);
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_OPEN_EVT => {
+            let open = param.open;
+            let link = client
+                .links
+                .iter_mut()
+                .find(|link| link.sensor.address == open.remote_bda);
+            match link {
+                Some(link) if open.status == esp_gatt_status_t_ESP_GATT_OK => {
+                    info!("{:?} sensor connected", link.sensor.kind);
+                    link.conn_id = Some(open.conn_id);
+                    let mut service = sensor_uuid(link.sensor.kind.get_service_uuid());
+                    esp!(esp_ble_gattc_search_service(
+                        gattc_if,
+                        open.conn_id,
+                        &mut service
+                    ))
+                    .ok()
+                    .or_else(|| {
+                        warn!("Unable to look for the sensor service");
+                        None
+                    });
+                }
+                _ => {
+                    // Tried again by the bridge later on
+                    warn!("Unable to open the sensor connection: {}", open.status);
+                    client
+                        .links
+                        .retain(|link| link.sensor.address != open.remote_bda);
+                }
+            }
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_SEARCH_RES_EVT => {
+            let result = param.search_res;
+            client
+                .links
+                .iter_mut()
+                .filter(|link| link.conn_id == Some(result.conn_id))
+                .for_each(|link| link.service = Some((result.start_handle, result.end_handle)));
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_SEARCH_CMPL_EVT => {
+            let conn_id = param.search_cmpl.conn_id;
+            let link = client
+                .links
+                .iter_mut()
+                .find(|link| link.conn_id == Some(conn_id));
+            if let Some(link) = link {
+                let (start, end) = link.service.unwrap_or_default();
+                let mut characteristic = esp_gattc_char_elem_t::default();
+                let mut count = 1u16;
+                let status = esp_ble_gattc_get_char_by_uuid(
+                    gattc_if,
+                    conn_id,
+                    start,
+                    end,
+                    sensor_uuid(link.sensor.kind.get_measurement_uuid()),
+                    &mut characteristic,
+                    &mut count,
+                );
+                if link.service.is_none() || status != esp_gatt_status_t_ESP_GATT_OK || count == 0 {
+                    warn!("No measurement on the {:?} sensor", link.sensor.kind);
+                    return;
+                }
+                link.measurement = Some(characteristic.char_handle);
+                esp!(esp_ble_gattc_register_for_notify(
+                    gattc_if,
+                    link.sensor.address.as_mut_ptr(),
+                    characteristic.char_handle
+                ))
+                .ok()
+                .or_else(|| {
+                    warn!("Unable to register for the measurement");
+                    None
+                });
+            }
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_REG_FOR_NOTIFY_EVT => {
+            let handle = param.reg_for_notify.handle;
+            let link = client
+                .links
+                .iter()
+                .find(|link| link.measurement == Some(handle));
+            if let Some((conn_id, kind)) =
+                link.and_then(|link| link.conn_id.map(|conn_id| (conn_id, link.sensor.kind)))
+            {
+                let mut descriptor = esp_gattc_descr_elem_t::default();
+                let mut count = 1u16;
+                let status = esp_ble_gattc_get_descr_by_char_handle(
+                    gattc_if,
+                    conn_id,
+                    handle,
+                    uuid16(ESP_GATT_UUID_CHAR_CLIENT_CONFIG as u16),
+                    &mut descriptor,
+                    &mut count,
+                );
+                if status != esp_gatt_status_t_ESP_GATT_OK || count == 0 {
+                    warn!("No CCCD on the {:?} measurement", kind);
+                    return;
+                }
+                let mut value = CCCD_NOTIFY;
+                esp!(esp_ble_gattc_write_char_descr(
+                    gattc_if,
+                    conn_id,
+                    descriptor.handle,
+                    value.len() as u16,
+                    value.as_mut_ptr(),
+                    esp_gatt_write_type_t_ESP_GATT_WRITE_TYPE_RSP,
+                    get_write_auth(kind),
+                ))
+                .map(|_| info!("Subscribed to the {:?} measurement", kind))
+                .ok()
+                .or_else(|| {
+                    warn!("Unable to subscribe to the measurement");
+                    None
+                });
+            }
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_NOTIFY_EVT => {
+            let notify = param.notify;
+            let data = std::slice::from_raw_parts(notify.value, notify.value_len as usize);
+            let commands = client
+                .links
+                .iter_mut()
+                .find(|link| {
+                    link.conn_id == Some(notify.conn_id) && link.measurement == Some(notify.handle)
+                })
+                .map(|link| link.decoder.decode(data))
+                .unwrap_or_default();
+            client.shared.push_telemetry(commands);
+        }
+        esp_gattc_cb_event_t_ESP_GATTC_DISCONNECT_EVT => {
+            let disconnect = param.disconnect;
+            let lost: Vec<Commands> = client
+                .links
+                .iter()
+                .filter(|link| link.conn_id == Some(disconnect.conn_id))
+                .flat_map(|link| {
+                    warn!("{:?} sensor lost", link.sensor.kind);
+                    link.decoder.get_lost()
+                })
+                .collect();
+            client
+                .links
+                .retain(|link| link.conn_id != Some(disconnect.conn_id));
+            client.shared.push_telemetry(lost);
+        }
+        _ => {}
+    });
+}
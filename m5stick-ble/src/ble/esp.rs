@@ -0,0 +1,1252 @@
+use std::{
+    cell::RefCell,
+    ffi::CString,
+    sync::{mpsc::sync_channel, Arc, Mutex},
+    time::Duration,
+};
+
+use esp_idf_ble::{
+    AdvertiseData, AttributeValue, AutoResponse, BtUuid, EspBle, GapEvent, GattCharacteristic,
+    GattDescriptor, GattService, GattServiceEvent,
+};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_sys::*;
+use log::{info, warn};
+use shared::{
+    beacon_manufacturer_data, BridgeConfig, Commands, Sensor, SensorKind, CONFIG_CHAR_UUID,
+    LOG_CHAR_UUID, POSITION_CHAR_UUID, RX_CHAR_UUID, SERVICE_UUID, STATS_CHAR_UUID,
+    STREAM_CHAR_UUID, TX_CHAR_UUID,
+};
+
+use super::{client, BleStack, Characteristic};
+use crate::{
+    bridge::Shared,
+    queues::{
+        assemble, execute, prepare, subscribe, PrepareError, Subscription, SubscriptionTable,
+        MAX_PREPARED_LEN,
+    },
+    receiver::FrameReceiver,
+};
+
+/// Longest advertising data of a legacy advertisement
+const ADV_MAX_LEN: usize = 31;
+
+/// Largest MTU offered to the clients
+const LOCAL_MTU: u16 = 517;
+
+/// Connection interval requested after connect, in units of 1.25 ms
+const CONN_MIN_INTERVAL: u16 = 0x10;
+const CONN_MAX_INTERVAL: u16 = 0x20;
+/// Connection events the phone may skip when it has nothing to send
+const CONN_LATENCY: u16 = 4;
+/// Supervision timeout, in units of 10 ms
+const CONN_TIMEOUT: u16 = 400;
+
+/// Sensor scans listen 30 ms every 50 ms, in units of 0.625 ms, leaving room for the
+/// connections to the phones
+const SCAN_INTERVAL: u16 = 0x50;
+const SCAN_WINDOW: u16 = 0x30;
+
+/// Advertising calls failing in a row past which the controller is deemed gone
+const MAX_FAILURES: u32 = 3;
+
+/// Longest wait for the stack to confirm a setup step, past which the setup fails
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest value of the Device Information Service
+const DEVICE_INFO_LEN: usize = 16;
+
+/// Characteristics of the Device Information Service
+const DEVICE_INFO: [(u16, &str); 4] = [
+    (ESP_GATT_UUID_MANU_NAME as u16, "Newintel"),
+    (ESP_GATT_UUID_MODEL_NUMBER_STR as u16, "Byke"),
+    (
+        ESP_GATT_UUID_FW_VERSION_STR as u16,
+        env!("CARGO_PKG_VERSION"),
+    ),
+    (ESP_GATT_UUID_HW_VERSION_STR as u16, "M5StickC"),
+];
+
+/// Bluedroid stack, serving the Byke service, the battery service and the
+/// Device Information Service
+pub struct EspBleStack {
+    ble: EspBle,
+    /// Kept to set the stack up again after a controller failure
+    nvs: Arc<EspDefaultNvsPartition>,
+    shared: Shared,
+    /// Advertising calls that failed in a row
+    failures: u32,
+    gatts_if: esp_gatt_if_t,
+    tx_handle: u16,
+    level_handle: u16,
+    log_handle: u16,
+    position_handle: u16,
+    receiver: Arc<Mutex<RefCell<FrameReceiver>>>,
+    /// Advertised name and status, the advertisement is built again when either changes
+    name: String,
+    manufacturer_data: [u8; 6],
+    /// Minor of the iBeacon advertised instead, in anti-theft mode
+    beacon: Option<u16>,
+}
+
+impl EspBleStack {
+    /// Starts the stack and registers the services and their handlers, which feed the
+    /// shared queues
+    pub fn new(
+        nvs: Arc<EspDefaultNvsPartition>,
+        name: String,
+        shared: &Shared,
+    ) -> anyhow::Result<Self> {
+        let mut ble = EspBle::new(name.clone(), Arc::clone(&nvs))?;
+        let (s, r) = sync_channel(1);
+
+        ble.register_gatt_service_application(1, move |gatts_if, reg| {
+            if let GattServiceEvent::Register(reg) = reg {
+                info!("Service registered with {:?}", reg);
+                s.send(gatts_if).ok();
+            } else {
+                warn!("What are you doing here??");
+            }
+        })?;
+
+        let svc_uuid = BtUuid::Uuid128(SERVICE_UUID);
+
+        let svc = GattService::new_primary(svc_uuid, 18, 1);
+
+        info!("GattService to be created: {:?}", svc);
+
+        let gatts_if = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let (s, r) = sync_channel(1);
+
+        let sh_connect = shared.clone();
+        ble.register_connect_handler(gatts_if, move |_gatts_if, connect| {
+            if let GattServiceEvent::Connect(connect) = connect {
+                info!("Connect event: {:?}", connect);
+                // The connections to the sensors are the GATT client ones
+                if client::is_sensor(connect.remote_bda) {
+                    return;
+                }
+                sh_connect.connect(connect.conn_id, connect.remote_bda);
+                update_conn_params(connect.remote_bda);
+            }
+        });
+
+        let sh_disconnect = shared.clone();
+        ble.register_disconnect_handler(gatts_if, move |_gatts_if, disconnect| {
+            if let GattServiceEvent::Disconnect(disconnect) = disconnect {
+                info!("Disconnect event: {:?}", disconnect);
+                if client::is_sensor(disconnect.remote_bda) {
+                    return;
+                }
+                sh_disconnect.disconnect(disconnect.conn_id);
+            }
+        });
+
+        init_security().ok().or_else(|| {
+            warn!("Unable to set the security parameters");
+            None
+        });
+
+        client::init(shared).ok().or_else(|| {
+            warn!("Unable to register the GATT client");
+            None
+        });
+
+        init_scan().ok().or_else(|| {
+            warn!("Unable to set the scan parameters");
+            None
+        });
+
+        let sh_gap = shared.clone();
+        ble.register_gap_handler(move |event| match event {
+            GapEvent::SecurityRequest(request) => {
+                esp!(unsafe { esp_ble_gap_security_rsp(request.bd_addr.as_ptr() as *mut _, true) })
+                    .ok()
+                    .or_else(|| {
+                        warn!("Unable to accept the security request");
+                        None
+                    });
+            }
+            GapEvent::PasskeyNotification(notification) => {
+                info!("Passkey: {:06}", notification.passkey);
+                sh_gap.push_to_m5go(Commands::Passkey(notification.passkey));
+            }
+            GapEvent::AuthenticationComplete(auth) => {
+                sh_gap.authenticate(auth.bd_addr, auth.success);
+                if auth.success {
+                    info!("Pairing succeeded");
+                } else {
+                    warn!("Pairing failed with reason {:#04x}", auth.fail_reason);
+                }
+            }
+            GapEvent::ScanResult(result) => {
+                if result.search_evt == esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_RES_EVT {
+                    // The scan response follows the advertising data
+                    let len = (result.adv_data_len + result.scan_rsp_len) as usize;
+                    let data = &result.ble_adv[..len.min(result.ble_adv.len())];
+                    let random = result.ble_addr_type == esp_ble_addr_type_t_BLE_ADDR_TYPE_RANDOM;
+                    if let Some(sensor) =
+                        Sensor::from_advert(result.bda, random, result.rssi as i8, data)
+                    {
+                        sh_gap.report_sensor(sensor);
+                    }
+                } else if result.search_evt == esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_CMPL_EVT {
+                    sh_gap.end_scan();
+                }
+            }
+            _ => {}
+        });
+
+        esp!(unsafe { esp_ble_gatt_set_local_mtu(LOCAL_MTU) })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to set the local MTU");
+                None
+            });
+
+        let sh_mtu = shared.clone();
+        ble.register_mtu_handler(gatts_if, move |_gatts_if, mtu| {
+            if let GattServiceEvent::Mtu(mtu) = mtu {
+                info!("MTU of connection {} set to {}", mtu.conn_id, mtu.mtu);
+                sh_mtu.set_mtu(mtu.conn_id, mtu.mtu);
+            }
+        });
+
+        ble.create_service(gatts_if, svc, move |gatts_if, create| {
+            if let GattServiceEvent::Create(create) = create {
+                info!(
+                    "Service created with {{ \tgatts_if: {}\tstatus: {}\n\thandle: {}\n}}",
+                    gatts_if, create.status, create.service_handle
+                );
+                s.send(create.service_handle).ok();
+            }
+        })?;
+
+        let svc_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        ble.start_service(svc_handle, |_, start| {
+            if let GattServiceEvent::StartComplete(start) = start {
+                info!("Service started for handle: {}", start.service_handle);
+            }
+        })?;
+
+        // Nordic UART style: the phone writes commands to RX and is notified on TX
+        let rx_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(RX_CHAR_UUID),
+            ESP_GATT_PERM_WRITE_ENCRYPTED as _,
+            (ESP_GATT_CHAR_PROP_BIT_WRITE | ESP_GATT_CHAR_PROP_BIT_WRITE_NR) as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, rx_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("RX attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let rx_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let tx_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(TX_CHAR_UUID),
+            ESP_GATT_PERM_READ as _,
+            (ESP_GATT_CHAR_PROP_BIT_NOTIFY | ESP_GATT_CHAR_PROP_BIT_INDICATE) as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, tx_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("TX attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let tx_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let cccd_handle = add_cccd(
+            &mut ble,
+            svc_handle,
+            (ESP_GATT_PERM_READ_ENCRYPTED | ESP_GATT_PERM_WRITE_ENCRYPTED) as _,
+        );
+        register_cccd_handler(&mut ble, cccd_handle, shared.subscriptions.clone());
+
+        // High-rate commands, written without response
+        let stream_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(STREAM_CHAR_UUID),
+            ESP_GATT_PERM_WRITE_ENCRYPTED as _,
+            ESP_GATT_CHAR_PROP_BIT_WRITE_NR as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, stream_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Stream attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let stream_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        // Log output of the stick, for debugging from the phone
+        let log_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(LOG_CHAR_UUID),
+            ESP_GATT_PERM_READ as _,
+            ESP_GATT_CHAR_PROP_BIT_NOTIFY as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, log_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Log attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let log_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let log_cccd_handle = add_cccd(
+            &mut ble,
+            svc_handle,
+            (ESP_GATT_PERM_READ_ENCRYPTED | ESP_GATT_PERM_WRITE_ENCRYPTED) as _,
+        );
+        register_cccd_handler(&mut ble, log_cccd_handle, shared.log_subscriptions.clone());
+
+        // Position of the bike, notified at the rate set by the phone
+        let position_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(POSITION_CHAR_UUID),
+            ESP_GATT_PERM_READ as _,
+            ESP_GATT_CHAR_PROP_BIT_NOTIFY as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, position_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Position attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let position_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let position_cccd_handle = add_cccd(
+            &mut ble,
+            svc_handle,
+            (ESP_GATT_PERM_READ_ENCRYPTED | ESP_GATT_PERM_WRITE_ENCRYPTED) as _,
+        )?;
+        register_cccd_handler(
+            &mut ble,
+            position_cccd_handle,
+            shared.position_subscriptions.clone(),
+        );
+
+        // Settings of the stick, read and written as a single blob
+        let config_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(CONFIG_CHAR_UUID),
+            (ESP_GATT_PERM_READ_ENCRYPTED | ESP_GATT_PERM_WRITE_ENCRYPTED) as _,
+            (ESP_GATT_CHAR_PROP_BIT_READ | ESP_GATT_CHAR_PROP_BIT_WRITE) as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, config_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Config attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let config_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let config_read = Arc::clone(&shared.config);
+        ble.register_read_handler(config_handle, move |gatts_if, read| {
+            if let GattServiceEvent::Read(read) = read {
+                let config = config_read
+                    .try_lock()
+                    .ok()
+                    .map(|config| config.borrow().to_bytes())
+                    .unwrap_or_default();
+                esp_idf_ble::send(
+                    gatts_if,
+                    config_handle,
+                    read.conn_id,
+                    read.trans_id,
+                    esp_gatt_status_t_ESP_GATT_OK,
+                    &config,
+                )
+                .expect("Unable to send read response");
+            }
+        });
+
+        let sh_config = shared.clone();
+        ble.register_write_handler(config_handle, move |gatts_if, write| {
+            if let GattServiceEvent::Write(write) = write {
+                let status = match get_written(&write) {
+                    None => esp_gatt_status_t_ESP_GATT_INVALID_ATTR_LEN,
+                    Some(_) if sh_config.is_authenticated(write.conn_id) == false => {
+                        warn!("Config from an unauthenticated client refused");
+                        esp_gatt_status_t_ESP_GATT_INSUF_AUTHENTICATION
+                    }
+                    Some(value) => match BridgeConfig::from_bytes(value) {
+                        Ok(config) => {
+                            sh_config.new_config.try_lock().ok().and_then(|new_config| {
+                                new_config.replace(Some(config));
+                                Some(())
+                            });
+                            sh_config.wake();
+                            esp_gatt_status_t_ESP_GATT_OK
+                        }
+                        Err(err) => {
+                            warn!("Config refused: {}", err);
+                            esp_gatt_status_t_ESP_GATT_OUT_OF_RANGE
+                        }
+                    },
+                };
+                send_status(gatts_if, config_handle, &write, status);
+            }
+        });
+
+        // Counters of the stick, for diagnosing the links from the phone
+        let stats_charac = GattCharacteristic::new(
+            BtUuid::Uuid128(STATS_CHAR_UUID),
+            ESP_GATT_PERM_READ_ENCRYPTED as _,
+            ESP_GATT_CHAR_PROP_BIT_READ as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(svc_handle, stats_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Stats attr added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let stats_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let sh_stats = shared.clone();
+        ble.register_read_handler(stats_handle, move |gatts_if, read| {
+            if let GattServiceEvent::Read(read) = read {
+                esp_idf_ble::send(
+                    gatts_if,
+                    stats_handle,
+                    read.conn_id,
+                    read.trans_id,
+                    esp_gatt_status_t_ESP_GATT_OK,
+                    &sh_stats.get_stats().to_bytes(),
+                )
+                .expect("Unable to send read response");
+            }
+        });
+
+        let receiver = Arc::new(Mutex::new(RefCell::new(FrameReceiver::new(shared))));
+        let receiver_write = Arc::clone(&receiver);
+        let receiver_exec = Arc::clone(&receiver);
+        let receiver_stream = Arc::clone(&receiver);
+
+        let sh_write = shared.clone();
+        ble.register_write_handler(rx_handle, move |gatts_if, write| {
+            if let GattServiceEvent::Write(write) = write {
+                info!("Write event: {:?}", write.len);
+                match get_written(&write) {
+                    None => send_status(
+                        gatts_if,
+                        rx_handle,
+                        &write,
+                        esp_gatt_status_t_ESP_GATT_INVALID_ATTR_LEN,
+                    ),
+                    Some(_) if sh_write.is_authenticated(write.conn_id) == false => {
+                        warn!("Write from an unauthenticated client refused");
+                        send_status(
+                            gatts_if,
+                            rx_handle,
+                            &write,
+                            esp_gatt_status_t_ESP_GATT_INSUF_AUTHENTICATION,
+                        );
+                    }
+                    Some(value) if write.is_prep => {
+                        // Fragments are kept until the client executes the write
+                        let status =
+                            match prepare(&sh_write.prepared, write.conn_id, write.offset, value) {
+                                Ok(_) => esp_gatt_status_t_ESP_GATT_OK,
+                                Err(PrepareError::InvalidOffset) => {
+                                    esp_gatt_status_t_ESP_GATT_INVALID_OFFSET
+                                }
+                                Err(PrepareError::QueueFull) => {
+                                    esp_gatt_status_t_ESP_GATT_PREPARE_Q_FULL
+                                }
+                                Err(PrepareError::Busy) => esp_gatt_status_t_ESP_GATT_BUSY,
+                            };
+
+                        if write.need_rsp {
+                            send_prepare_response(
+                                gatts_if,
+                                write.conn_id,
+                                write.trans_id,
+                                status,
+                                rx_handle,
+                                write.offset,
+                                value,
+                            );
+                        }
+                    }
+                    Some(value) => {
+                        let back = assemble(
+                            &sh_write.writes,
+                            &sh_write.mtus,
+                            write.conn_id,
+                            value,
+                            |frame| {
+                                receiver_write
+                                    .try_lock()
+                                    .ok()
+                                    .map(|receiver| receiver.borrow_mut().receive(frame))
+                            },
+                        )
+                        .flatten()
+                        .unwrap_or_default();
+                        sh_write.wake();
+
+                        if write.need_rsp {
+                            info!("need rsp");
+                            esp_idf_ble::send(
+                                gatts_if,
+                                rx_handle,
+                                write.conn_id,
+                                write.trans_id,
+                                esp_gatt_status_t_ESP_GATT_OK,
+                                back.get_stream().unwrap_or_default().as_slice(),
+                            )
+                            .expect("Unable to send response");
+                        }
+                    }
+                }
+            }
+        });
+
+        let sh_stream = shared.clone();
+        ble.register_write_handler(stream_handle, move |_gatts_if, write| {
+            if let GattServiceEvent::Write(write) = write {
+                // Written without response, the frame is dropped
+                if sh_stream.is_authenticated(write.conn_id) == false {
+                    warn!("Stream from an unauthenticated client dropped");
+                    return;
+                }
+                let value = match get_written(&write) {
+                    Some(value) => value,
+                    None => return,
+                };
+                assemble(
+                    &sh_stream.streams,
+                    &sh_stream.mtus,
+                    write.conn_id,
+                    value,
+                    |frame| {
+                        receiver_stream.try_lock().ok().and_then(|receiver| {
+                            receiver.borrow_mut().receive_stream(frame);
+                            sh_stream.wake();
+                            Some(())
+                        })
+                    },
+                );
+            }
+        });
+
+        let sh_exec = shared.clone();
+        ble.register_exec_write_handler(gatts_if, move |gatts_if, exec| {
+            if let GattServiceEvent::ExecWrite(exec) = exec {
+                let status = execute(&sh_exec.prepared, exec.conn_id, |data| {
+                    if exec.exec_write_flag != ESP_GATT_PREP_WRITE_EXEC as u8 {
+                        info!("Prepared write cancelled");
+                        esp_gatt_status_t_ESP_GATT_OK
+                    } else if sh_exec.is_authenticated(exec.conn_id) == false {
+                        warn!("Write from an unauthenticated client refused");
+                        esp_gatt_status_t_ESP_GATT_INSUF_AUTHENTICATION
+                    } else if Commands::frame_len(data).is_none_or(|len| data.len() < len) {
+                        warn!("Incomplete command dropped");
+                        esp_gatt_status_t_ESP_GATT_ERROR
+                    } else {
+                        let back = receiver_exec
+                            .try_lock()
+                            .ok()
+                            .map(|receiver| receiver.borrow_mut().receive(data))
+                            .unwrap_or_default();
+                        sh_exec.wake();
+                        match back {
+                            Commands::OK => esp_gatt_status_t_ESP_GATT_OK,
+                            Commands::Backpressure(true) => esp_gatt_status_t_ESP_GATT_BUSY,
+                            _ => esp_gatt_status_t_ESP_GATT_ERROR,
+                        }
+                    }
+                })
+                .unwrap_or(esp_gatt_status_t_ESP_GATT_BUSY);
+
+                esp!(unsafe {
+                    esp_ble_gatts_send_response(
+                        gatts_if,
+                        exec.conn_id,
+                        exec.trans_id,
+                        status,
+                        std::ptr::null_mut(),
+                    )
+                })
+                .ok()
+                .or_else(|| {
+                    warn!("Unable to answer the execute write");
+                    None
+                });
+            }
+        });
+
+        let (s, r) = sync_channel(1);
+
+        let battery_svc = GattService::new_primary(
+            BtUuid::Uuid16(ESP_GATT_UUID_BATTERY_SERVICE_SVC as u16),
+            4,
+            0,
+        );
+
+        ble.create_service(gatts_if, battery_svc, move |_, create| {
+            if let GattServiceEvent::Create(create) = create {
+                info!(
+                    "Battery service created with handle: {}",
+                    create.service_handle
+                );
+                s.send(create.service_handle).ok();
+            }
+        })?;
+
+        let battery_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        ble.start_service(battery_handle, |_, start| {
+            if let GattServiceEvent::StartComplete(start) = start {
+                info!("Service started for handle: {}", start.service_handle);
+            }
+        })?;
+
+        let level_charac = GattCharacteristic::new(
+            BtUuid::Uuid16(ESP_GATT_UUID_BATTERY_LEVEL as u16),
+            ESP_GATT_PERM_READ as _,
+            (ESP_GATT_CHAR_PROP_BIT_READ | ESP_GATT_CHAR_PROP_BIT_NOTIFY) as _,
+            AttributeValue::<0>::default(),
+            AutoResponse::ByApp,
+        );
+
+        let (s, r) = sync_channel(1);
+
+        ble.add_characteristic(battery_handle, level_charac, move |_, add_char| {
+            if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                info!("Battery level added with handle: {}", add_char.attr_handle);
+                s.send(add_char.attr_handle).ok();
+            }
+        })?;
+
+        let level_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        let level_cccd_handle = add_cccd(
+            &mut ble,
+            battery_handle,
+            (ESP_GATT_PERM_READ | ESP_GATT_PERM_WRITE) as _,
+        )?;
+
+        let level_read = Arc::clone(&shared.battery_level);
+        ble.register_read_handler(level_handle, move |gatts_if, read| {
+            if let GattServiceEvent::Read(read) = read {
+                let level = level_read
+                    .try_lock()
+                    .ok()
+                    .map(|level| *level.borrow())
+                    .unwrap_or_default();
+                esp_idf_ble::send(
+                    gatts_if,
+                    level_handle,
+                    read.conn_id,
+                    read.trans_id,
+                    esp_gatt_status_t_ESP_GATT_OK,
+                    &[level],
+                )
+                .expect("Unable to send read response");
+            }
+        });
+
+        register_cccd_handler(
+            &mut ble,
+            level_cccd_handle,
+            shared.battery_subscriptions.clone(),
+        );
+
+        let (s, r) = sync_channel(1);
+
+        let dis_svc = GattService::new_primary(
+            BtUuid::Uuid16(ESP_GATT_UUID_DEVICE_INFO_SVC as u16),
+            1 + 2 * DEVICE_INFO.len() as u16,
+            0,
+        );
+
+        ble.create_service(gatts_if, dis_svc, move |_, create| {
+            if let GattServiceEvent::Create(create) = create {
+                info!(
+                    "Device information service created with handle: {}",
+                    create.service_handle
+                );
+                s.send(create.service_handle).ok();
+            }
+        })?;
+
+        let dis_handle = r.recv_timeout(SETUP_TIMEOUT)?;
+
+        ble.start_service(dis_handle, |_, start| {
+            if let GattServiceEvent::StartComplete(start) = start {
+                info!("Service started for handle: {}", start.service_handle);
+            }
+        })?;
+
+        for (uuid, value) in DEVICE_INFO {
+            // Constant values, read by the stack without going through a handler
+            let charac = GattCharacteristic::new(
+                BtUuid::Uuid16(uuid),
+                ESP_GATT_PERM_READ as _,
+                ESP_GATT_CHAR_PROP_BIT_READ as _,
+                AttributeValue::<DEVICE_INFO_LEN>::new_with_value(value.as_bytes()),
+                AutoResponse::ByGatt,
+            );
+
+            let (s, r) = sync_channel(1);
+
+            ble.add_characteristic(dis_handle, charac, move |_, add_char| {
+                if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
+                    info!(
+                        "Device information added with handle: {}",
+                        add_char.attr_handle
+                    );
+                    s.send(add_char.attr_handle).ok();
+                }
+            })?;
+
+            r.recv_timeout(SETUP_TIMEOUT)?;
+        }
+
+        configure_scan_response(&mut ble)?;
+        let manufacturer_data = AdvertStatus::default().to_manufacturer_data();
+        configure_advertising(&name, &manufacturer_data);
+
+        // Bonds are restored from the NVS by Bluedroid
+        info!("{} bonded devices", unsafe {
+            esp_ble_get_bond_device_num()
+        });
+
+        Ok(Self {
+            ble,
+            nvs,
+            shared: shared.clone(),
+            failures: 0,
+            gatts_if,
+            tx_handle,
+            level_handle,
+            log_handle,
+            position_handle,
+            receiver,
+            name,
+            manufacturer_data,
+            beacon: None,
+        })
+    }
+}
+
+impl EspBleStack {
+    fn update_advertising(&self) {
+        match self.beacon {
+            Some(minor) => configure_beacon(minor),
+            None => configure_advertising(&self.name, &self.manufacturer_data),
+        }
+    }
+
+    /// Counts the calls failing in a row, a single success clearing them
+    fn record<T>(&mut self, result: Result<T, EspError>) -> Result<T, EspError> {
+        if result.is_ok() {
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+        }
+        result
+    }
+}
+
+impl BleStack for EspBleStack {
+    fn start_advertising(&mut self, interval: (u16, u16), whitelist: bool) -> anyhow::Result<()> {
+        let (min, max) = interval;
+        let adv_filter_policy = if whitelist {
+            update_whitelist();
+            esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_WLST
+        } else {
+            esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_ANY
+        };
+        let mut params = esp_ble_adv_params_t {
+            adv_int_min: min,
+            adv_int_max: max,
+            adv_type: esp_ble_adv_type_t_ADV_TYPE_IND,
+            own_addr_type: esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+            channel_map: esp_ble_adv_channel_t_ADV_CHNL_ALL,
+            adv_filter_policy,
+            ..Default::default()
+        };
+        let result = esp!(unsafe { esp_ble_gap_start_advertising(&mut params) });
+        self.record(result)?;
+        Ok(())
+    }
+
+    fn stop_advertising(&mut self) {
+        esp!(unsafe { esp_ble_gap_stop_advertising() })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to stop advertising");
+                None
+            });
+    }
+
+    fn disconnect(&mut self, mut bda: [u8; 6]) {
+        esp!(unsafe { esp_ble_gap_disconnect(bda.as_mut_ptr()) })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to disconnect the client");
+                None
+            });
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+        let name = CString::new(name).unwrap_or_default();
+        esp!(unsafe { esp_ble_gap_set_device_name(name.as_ptr()) })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to set the name");
+                None
+            });
+        self.update_advertising();
+    }
+
+    fn set_advert_status(&mut self, status: &AdvertStatus) {
+        self.manufacturer_data = status.to_manufacturer_data();
+        self.update_advertising();
+    }
+
+    fn set_beacon(&mut self, minor: Option<u16>) {
+        self.beacon = minor;
+        self.update_advertising();
+    }
+
+    fn notify(&mut self, characteristic: Characteristic, subscription: Subscription, value: &[u8]) {
+        let handle = match characteristic {
+            Characteristic::Tx => self.tx_handle,
+            Characteristic::BatteryLevel => self.level_handle,
+            Characteristic::Log => self.log_handle,
+            Characteristic::Position => self.position_handle,
+        };
+        let mut value = value.to_vec();
+        let result = esp!(unsafe {
+            esp_ble_gatts_send_indicate(
+                self.gatts_if,
+                subscription.conn_id,
+                handle,
+                value.len() as u16,
+                value.as_mut_ptr(),
+                subscription.indicate,
+            )
+        });
+        // A client going away fails its notifications, the controller is still there
+        result.ok().or_else(|| {
+            warn!("Unable to notify the client");
+            None
+        });
+    }
+
+    fn start_scan(&mut self, seconds: u32) -> anyhow::Result<()> {
+        esp!(unsafe { esp_ble_gap_start_scanning(seconds) })?;
+        Ok(())
+    }
+
+    fn stop_scan(&mut self) {
+        esp!(unsafe { esp_ble_gap_stop_scanning() })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to stop scanning");
+                None
+            });
+    }
+
+    fn connect_sensor(&mut self, sensor: &Sensor) {
+        client::connect(sensor);
+    }
+
+    fn is_sensor_connected(&self, kind: SensorKind) -> bool {
+        client::is_connected(kind)
+    }
+
+    fn read_rssi<F>(&mut self, bda: [u8; 6], callback: F) -> anyhow::Result<()>
+    where
+        F: Fn(i8) + Send + 'static,
+    {
+        self.ble.read_rssi(bda, callback)?;
+        Ok(())
+    }
+
+    fn is_failed(&self) -> bool {
+        let controller = unsafe { esp_bt_controller_get_status() };
+        let bluedroid = unsafe { esp_bluedroid_get_status() };
+        self.failures >= MAX_FAILURES
+            || controller != esp_bt_controller_status_t_ESP_BT_CONTROLLER_STATUS_ENABLED
+            || bluedroid != esp_bluedroid_status_t_ESP_BLUEDROID_STATUS_ENABLED
+    }
+
+    fn restart(&mut self) -> anyhow::Result<()> {
+        // A step fails when the failed stack did not get that far, the next ones still run
+        for (step, result) in [
+            (
+                "disable Bluedroid",
+                esp!(unsafe { esp_bluedroid_disable() }),
+            ),
+            ("deinit Bluedroid", esp!(unsafe { esp_bluedroid_deinit() })),
+            (
+                "disable the controller",
+                esp!(unsafe { esp_bt_controller_disable() }),
+            ),
+            (
+                "deinit the controller",
+                esp!(unsafe { esp_bt_controller_deinit() }),
+            ),
+        ] {
+            result.ok().or_else(|| {
+                warn!("Unable to {}", step);
+                None
+            });
+        }
+        let mut stack = EspBleStack::new(Arc::clone(&self.nvs), self.name.clone(), &self.shared)?;
+        stack.manufacturer_data = self.manufacturer_data;
+        stack.beacon = self.beacon;
+        stack.update_advertising();
+        *self = stack;
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        self.receiver.try_lock().ok().and_then(|receiver| {
+            receiver.borrow_mut().check_backpressure();
+            Some(())
+        });
+    }
+}
+
+/// Adds a Client Characteristic Configuration Descriptor to the last characteristic of the service
+fn add_cccd(
+    ble: &mut EspBle,
+    svc_handle: u16,
+    permissions: esp_gatt_perm_t,
+) -> anyhow::Result<u16> {
+    let cdesc = GattDescriptor::new(
+        BtUuid::Uuid16(ESP_GATT_UUID_CHAR_CLIENT_CONFIG as u16),
+        permissions,
+    );
+
+    let (s, r) = sync_channel(1);
+
+    ble.add_descriptor(svc_handle, cdesc, move |_, add_desc| {
+        if let GattServiceEvent::AddDescriptorComplete(add_desc) = add_desc {
+            info!("Descriptor added with handle: {}", add_desc.attr_handle);
+            s.send(add_desc.attr_handle).ok();
+        }
+    })?;
+
+    Ok(r.recv_timeout(SETUP_TIMEOUT)?)
+}
+
+/// Keeps the subscriptions written by the clients to a CCCD
+fn register_cccd_handler(ble: &mut EspBle, cccd_handle: u16, subscriptions: SubscriptionTable) {
+    ble.register_write_handler(cccd_handle, move |gatts_if, write| {
+        if let GattServiceEvent::Write(write) = write {
+            let value = match get_written(&write) {
+                Some(value) => value,
+                None => {
+                    send_status(
+                        gatts_if,
+                        cccd_handle,
+                        &write,
+                        esp_gatt_status_t_ESP_GATT_INVALID_ATTR_LEN,
+                    );
+                    return;
+                }
+            };
+            subscriptions.try_lock().ok().and_then(|subscriptions| {
+                subscribe(
+                    &mut subscriptions.borrow_mut(),
+                    Subscription::from_cccd(write.conn_id, value),
+                    write.conn_id,
+                );
+                Some(())
+            });
+
+            if write.need_rsp {
+                esp_idf_ble::send(
+                    gatts_if,
+                    cccd_handle,
+                    write.conn_id,
+                    write.trans_id,
+                    esp_gatt_status_t_ESP_GATT_OK,
+                    value,
+                )
+                .expect("Unable to send response");
+            }
+        }
+    });
+}
+
+/// Value of a write, checked before trusting the pointer handed over by the stack. The
+/// value lives as long as the event.
+fn get_written(write: &esp_ble_gatts_cb_param_t_gatts_write_evt_param) -> Option<&[u8]> {
+    let len = write.len as usize;
+    if len == 0 {
+        return Some(&[]);
+    }
+    if write.value.is_null() || len > MAX_PREPARED_LEN {
+        warn!("Invalid write of {} bytes dropped", len);
+        return None;
+    }
+    Some(unsafe { std::slice::from_raw_parts(write.value, len) })
+}
+
+/// Answers a write with its status only, when the client asked for a response
+fn send_status(
+    gatts_if: esp_gatt_if_t,
+    handle: u16,
+    write: &esp_ble_gatts_cb_param_t_gatts_write_evt_param,
+    status: esp_gatt_status_t,
+) {
+    if write.need_rsp {
+        esp_idf_ble::send(gatts_if, handle, write.conn_id, write.trans_id, status, &[])
+            .expect("Unable to send response");
+    }
+}
+
+/// Prepare write responses echo the received fragment, so that the client can check it
+fn send_prepare_response(
+    gatts_if: esp_gatt_if_t,
+    conn_id: u16,
+    trans_id: u32,
+    status: esp_gatt_status_t,
+    handle: u16,
+    offset: u16,
+    value: &[u8],
+) {
+    let mut rsp = esp_gatt_rsp_t::default();
+    unsafe {
+        rsp.attr_value.handle = handle;
+        rsp.attr_value.offset = offset;
+        rsp.attr_value.len = value.len() as u16;
+        rsp.attr_value.auth_req = ESP_GATT_AUTH_REQ_NONE as u8;
+        rsp.attr_value.value[..value.len()].copy_from_slice(value);
+    }
+    esp!(unsafe { esp_ble_gatts_send_response(gatts_if, conn_id, trans_id, status, &mut rsp) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to answer the prepare write");
+            None
+        });
+}
+
+/// Bonds with the phones, showing a passkey on the M5Go when the phone can type it
+/// and falling back to Just Works otherwise. The bonding keys are kept in the NVS by Bluedroid.
+fn init_security() -> Result<(), EspError> {
+    let mut auth_req = ESP_LE_AUTH_REQ_SC_MITM_BOND as esp_ble_auth_req_t;
+    let mut io_cap = ESP_IO_CAP_OUT as esp_ble_io_cap_t;
+    let mut key_size = 16u8;
+    let mut init_key = (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8;
+    let mut rsp_key = (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8;
+
+    let params: [(esp_ble_sm_param_t, *mut u8, usize); 5] = [
+        (
+            esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE,
+            &mut auth_req as *mut _ as *mut u8,
+            std::mem::size_of_val(&auth_req),
+        ),
+        (
+            esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE,
+            &mut io_cap as *mut _ as *mut u8,
+            std::mem::size_of_val(&io_cap),
+        ),
+        (esp_ble_sm_param_t_ESP_BLE_SM_MAX_KEY_SIZE, &mut key_size, 1),
+        (esp_ble_sm_param_t_ESP_BLE_SM_SET_INIT_KEY, &mut init_key, 1),
+        (esp_ble_sm_param_t_ESP_BLE_SM_SET_RSP_KEY, &mut rsp_key, 1),
+    ];
+
+    for (param, value, len) in params {
+        esp!(unsafe { esp_ble_gap_set_security_param(param, value as *mut _, len as u8) })?;
+    }
+    Ok(())
+}
+
+/// Active scans, so that the sensors send their scan response with their name
+fn init_scan() -> Result<(), EspError> {
+    let mut params = esp_ble_scan_params_t {
+        scan_type: esp_ble_scan_type_t_BLE_SCAN_TYPE_ACTIVE,
+        own_addr_type: esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+        scan_filter_policy: esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_ALL,
+        scan_interval: SCAN_INTERVAL,
+        scan_window: SCAN_WINDOW,
+        scan_duplicate: esp_ble_scan_duplicate_t_BLE_SCAN_DUPLICATE_ENABLE,
+    };
+    esp!(unsafe { esp_ble_gap_set_scan_params(&mut params) })
+}
+
+/// The scan response carries the service, the advertisement being full with the status
+fn configure_scan_response(ble: &mut EspBle) -> Result<(), EspError> {
+    let scan_rsp_data = AdvertiseData {
+        include_name: false,
+        include_txpower: true,
+        set_scan_rsp: true,
+        service_uuid: Some(BtUuid::Uuid128(SERVICE_UUID)),
+        ..Default::default()
+    };
+
+    ble.configure_advertising_data(scan_rsp_data, |_| {
+        info!("Advertising configured");
+    })
+}
+
+/// Flags, status of the unit as manufacturer data, then as much of the name as fits
+fn configure_advertising(name: &str, manufacturer_data: &[u8]) {
+    let mut adv = vec![
+        2,
+        esp_ble_adv_data_type_ESP_BLE_AD_TYPE_FLAG as u8,
+        (ESP_BLE_ADV_FLAG_GEN_DISC | ESP_BLE_ADV_FLAG_BREDR_NOT_SPT) as u8,
+        1 + manufacturer_data.len() as u8,
+        esp_ble_adv_data_type_ESP_BLE_AD_MANUFACTURER_SPECIFIC_TYPE as u8,
+    ];
+    adv.extend_from_slice(manufacturer_data);
+
+    let room = ADV_MAX_LEN - adv.len() - 2;
+    let (name, name_type) = if name.len() > room {
+        (
+            &name.as_bytes()[..room],
+            esp_ble_adv_data_type_ESP_BLE_AD_TYPE_NAME_SHORT,
+        )
+    } else {
+        (
+            name.as_bytes(),
+            esp_ble_adv_data_type_ESP_BLE_AD_TYPE_NAME_CMPL,
+        )
+    };
+    adv.push(1 + name.len() as u8);
+    adv.push(name_type as u8);
+    adv.extend_from_slice(name);
+
+    esp!(unsafe { esp_ble_gap_config_adv_data_raw(adv.as_mut_ptr(), adv.len() as u32) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to configure the advertising data");
+            None
+        });
+}
+
+/// Advertises an iBeacon, which the phones track in the background
+fn configure_beacon(minor: u16) {
+    let beacon = beacon_manufacturer_data(minor);
+    let mut adv = vec![
+        2,
+        esp_ble_adv_data_type_ESP_BLE_AD_TYPE_FLAG as u8,
+        (ESP_BLE_ADV_FLAG_GEN_DISC | ESP_BLE_ADV_FLAG_BREDR_NOT_SPT) as u8,
+        1 + beacon.len() as u8,
+        esp_ble_adv_data_type_ESP_BLE_AD_MANUFACTURER_SPECIFIC_TYPE as u8,
+    ];
+    adv.extend_from_slice(&beacon);
+
+    esp!(unsafe { esp_ble_gap_config_adv_data_raw(adv.as_mut_ptr(), adv.len() as u32) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to configure the beacon");
+            None
+        });
+}
+
+/// Puts the bonded phones in the whitelist. It is built again from the bond list each
+/// time the stick advertises, so that a phone unbonded since is left out and one bonded
+/// during the last connection is let in.
+fn update_whitelist() {
+    esp!(unsafe { esp_ble_gap_clear_whitelist() })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to clear the whitelist");
+            None
+        });
+    let mut count = unsafe { esp_ble_get_bond_device_num() }.max(0);
+    let mut devices = vec![esp_ble_bond_dev_t::default(); count as usize];
+    esp!(unsafe { esp_ble_get_bond_device_list(&mut count, devices.as_mut_ptr()) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to read the bonded devices");
+            None
+        });
+    for device in devices.iter().take(count as usize) {
+        let (mut addr, addr_type) = get_identity(device);
+        esp!(unsafe { esp_ble_gap_update_whitelist(true, addr.as_mut_ptr(), addr_type) })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to whitelist a bonded device");
+                None
+            });
+    }
+}
+
+/// Identity address of a bonded phone and its type. Phones with a resolvable private
+/// address gave it with their IRK, the others are known by their public address.
+fn get_identity(device: &esp_ble_bond_dev_t) -> ([u8; 6], esp_ble_wl_addr_type_t) {
+    let pid = &device.bond_key.pid_key;
+    if device.bond_key.key_mask as u32 & ESP_LE_KEY_PID == 0 {
+        return (
+            device.bd_addr,
+            esp_ble_wl_addr_type_t_BLE_WL_ADDR_TYPE_PUBLIC,
+        );
+    }
+    let random = pid.addr_type == esp_ble_addr_type_t_BLE_ADDR_TYPE_RANDOM
+        || pid.addr_type == esp_ble_addr_type_t_BLE_ADDR_TYPE_RPA_RANDOM;
+    let addr_type = if random {
+        esp_ble_wl_addr_type_t_BLE_WL_ADDR_TYPE_RANDOM
+    } else {
+        esp_ble_wl_addr_type_t_BLE_WL_ADDR_TYPE_PUBLIC
+    };
+    (pid.static_addr, addr_type)
+}
+
+/// Asks the phone for a connection suited to small and frequent command frames
+fn update_conn_params(bda: [u8; 6]) {
+    let mut params = esp_ble_conn_update_params_t {
+        bda,
+        min_int: CONN_MIN_INTERVAL,
+        max_int: CONN_MAX_INTERVAL,
+        latency: CONN_LATENCY,
+        timeout: CONN_TIMEOUT,
+    };
+    esp!(unsafe { esp_ble_gap_update_conn_params(&mut params) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to update the connection parameters");
+            None
+        });
+}
@@ -0,0 +1,1580 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    sync::{mpsc::SyncSender, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use shared::{
+    bulk_commands, registers::UNIT_ADDRESSES, AdvertStatus, BleState, BridgeConfig, BridgeStats,
+    Commands, Coordinates, Sensor, SensorKind, Transport, ROUTE_BULK_ID,
+};
+
+use crate::{
+    ble::{BleStack, Characteristic},
+    led::LedStatus,
+    queues::{
+        chunk_size, get_subscriptions, AuthTable, BleQueue, BufferPool, BufferTable, CommandQueue,
+        I2cQueue, LogQueue, MtuTable, Overflow, PeerTable, Priority, ScanTable, SubscriptionTable,
+    },
+};
+
+/// Centrals connected at once, the rider's phone and a diagnostic tool
+pub const MAX_CONNECTIONS: usize = 2;
+
+/// Log lines notified on each turn of the main loop, so that the logs do not
+/// take the link over the commands
+const LOG_LINES_PER_POLL: usize = 2;
+
+/// Frames waiting for the M5Go past which it is deemed to have stopped reading the stick
+const STALLED_FRAMES: usize = 10;
+
+/// Route data carried by each chunk sent to the M5Go, well within an I2C frame
+const ROUTE_CHUNK_LEN: usize = 256;
+/// Frames waiting for the M5Go past which the route is held back
+const ROUTE_WINDOW: usize = 4;
+
+/// Seconds a sensor scan lasts, unless the M5Go stops it before
+const SCAN_DURATION: u32 = 10;
+/// Delay between two restarts of a BLE stack that keeps failing
+const BLE_RESTART_RETRY: Duration = Duration::from_secs(10);
+/// Delay between two connection attempts to a paired sensor out of reach
+const SENSOR_RETRY: Duration = Duration::from_secs(10);
+
+/// Settings of the stick that outlive a restart
+pub trait Settings {
+    fn get_name(&self) -> String;
+    fn set_name(&mut self, name: &str);
+    /// Minimum and maximum advertising intervals
+    fn get_adv_interval(&self) -> (u16, u16);
+    fn set_adv_interval(&mut self, interval: (u16, u16));
+    /// Whether only the bonded phones may connect
+    fn get_whitelist(&self) -> bool;
+    fn set_whitelist(&mut self, enabled: bool);
+    /// Whether the stick advertises on boot, off once the M5Go stopped the BLE
+    fn get_advertising(&self) -> bool;
+    fn set_advertising(&mut self, enabled: bool);
+    /// Seconds between two position notifications, 0 when the position is not sent
+    fn get_position_rate(&self) -> u16;
+    fn set_position_rate(&mut self, seconds: u16);
+    /// Whether the stick advertises as an iBeacon, for the phone to notice the bike leaving
+    fn get_anti_theft(&self) -> bool;
+    fn set_anti_theft(&mut self, armed: bool);
+    /// Address of the stick on the I2C bus of the M5Go, applied on restart
+    fn get_i2c_address(&self) -> u8;
+    fn set_i2c_address(&mut self, address: u8);
+    /// Sensor of each kind paired from the M5Go, connected to whenever it is in reach
+    fn get_sensor(&self, kind: SensorKind) -> Option<Sensor>;
+    fn set_sensor(&mut self, sensor: &Sensor);
+    /// Whether the centrals must pair before writing commands
+    fn get_auth_required(&self) -> bool;
+    fn set_auth_required(&mut self, required: bool);
+    /// Whether the LED blinks the status of the stick
+    fn get_led_patterns(&self) -> bool;
+    fn set_led_patterns(&mut self, enabled: bool);
+    /// Seconds of advertising without a connection before stopping, 0 for never
+    fn get_adv_timeout(&self) -> u16;
+    fn set_adv_timeout(&mut self, seconds: u16);
+}
+
+/// Queues and tables shared between the GATT handlers and the main loop
+#[derive(Clone)]
+pub struct Shared {
+    /// A command the M5Go has no room for is refused, so that the phone knows
+    pub to_m5go: I2cQueue,
+    /// Stale commands are dropped when the phones do not keep up
+    pub to_phone: BleQueue,
+    pub state: Arc<Mutex<RefCell<BleState>>>,
+    /// Set when the link dropped on its own, so that advertising starts over
+    pub restart: Arc<Mutex<RefCell<bool>>>,
+    /// Set once a new firmware or I2C address has been saved, the stick restarts on it
+    pub reboot: Arc<Mutex<RefCell<bool>>>,
+    /// Set while a new firmware is being written
+    pub updating: Arc<Mutex<RefCell<bool>>>,
+    /// Route assembled from the phone writes, streamed to the M5Go by the main loop
+    pub route: Arc<Mutex<RefCell<Option<Vec<u8>>>>>,
+    /// Name sent by the phone, applied by the main loop
+    pub rename: Arc<Mutex<RefCell<Option<String>>>>,
+    /// Position rate sent by the phone, saved by the main loop
+    pub position_rate: Arc<Mutex<RefCell<Option<u16>>>>,
+    /// Anti-theft mode sent by the phone, applied by the main loop
+    pub anti_theft: Arc<Mutex<RefCell<Option<bool>>>>,
+    /// I2C address sent by the phone, saved by the main loop
+    pub i2c_address: Arc<Mutex<RefCell<Option<u8>>>>,
+    /// Settings served on the config characteristic, kept up to date by the main loop
+    pub config: Arc<Mutex<RefCell<BridgeConfig>>>,
+    /// Settings written on the config characteristic, applied by the main loop
+    pub new_config: Arc<Mutex<RefCell<Option<BridgeConfig>>>>,
+    /// Until the main loop reads the settings, the centrals must pair
+    pub auth_required: Arc<Mutex<RefCell<bool>>>,
+    pub scan: ScanTable,
+    /// Addresses of the connected centrals, needed to query the link RSSI
+    pub peers: PeerTable,
+    /// Only the authenticated centrals may write commands
+    pub authenticated: AuthTable,
+    pub subscriptions: SubscriptionTable,
+    pub battery_subscriptions: SubscriptionTable,
+    pub log_subscriptions: SubscriptionTable,
+    pub position_subscriptions: SubscriptionTable,
+    pub logs: LogQueue,
+    /// Last battery level read from the AXP192, served to the clients
+    pub battery_level: Arc<Mutex<RefCell<u8>>>,
+    /// Counters served to `GetStats` and on the stats characteristic
+    pub stats: Arc<Mutex<RefCell<BridgeStats>>>,
+    pub mtus: MtuTable,
+    pub prepared: BufferTable,
+    pub writes: BufferTable,
+    pub streams: BufferTable,
+    /// Wakes the main loop up when a handler left it something to do
+    wakeup: SyncSender<()>,
+}
+
+impl Shared {
+    /// The main loop waits on the receiving end of `wakeup` between two turns
+    pub fn new(wakeup: SyncSender<()>) -> Self {
+        Self {
+            to_m5go: Arc::new(Mutex::new(RefCell::new(CommandQueue::new(
+                Overflow::DropNewest,
+            )))),
+            to_phone: Arc::new(Mutex::new(RefCell::new(CommandQueue::new(
+                Overflow::DropOldest,
+            )))),
+            state: Default::default(),
+            restart: Default::default(),
+            reboot: Default::default(),
+            updating: Default::default(),
+            route: Default::default(),
+            rename: Default::default(),
+            position_rate: Default::default(),
+            anti_theft: Default::default(),
+            i2c_address: Default::default(),
+            config: Default::default(),
+            new_config: Default::default(),
+            auth_required: Arc::new(Mutex::new(RefCell::new(true))),
+            scan: Default::default(),
+            peers: Default::default(),
+            authenticated: Default::default(),
+            subscriptions: Default::default(),
+            battery_subscriptions: Default::default(),
+            log_subscriptions: Default::default(),
+            position_subscriptions: Default::default(),
+            logs: Default::default(),
+            battery_level: Default::default(),
+            stats: Default::default(),
+            mtus: Default::default(),
+            prepared: Arc::new(Mutex::new(RefCell::new(BufferPool::new(MAX_CONNECTIONS)))),
+            writes: Arc::new(Mutex::new(RefCell::new(BufferPool::new(MAX_CONNECTIONS)))),
+            streams: Arc::new(Mutex::new(RefCell::new(BufferPool::new(MAX_CONNECTIONS)))),
+            wakeup,
+        }
+    }
+
+    /// Ends the wait of the main loop, without blocking when a wake-up is already pending
+    pub fn wake(&self) {
+        self.wakeup.try_send(()).ok();
+    }
+
+    pub fn get_state(&self) -> BleState {
+        self.state
+            .try_lock()
+            .ok()
+            .map(|state| state.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_state(&self, new_state: BleState) {
+        self.state.try_lock().ok().and_then(|state| {
+            state.replace(new_state);
+            Some(())
+        });
+    }
+
+    /// Updates the counters of the stick
+    pub fn count(&self, update: impl FnOnce(&mut BridgeStats)) {
+        self.stats.try_lock().ok().and_then(|stats| {
+            update(&mut stats.borrow_mut());
+            Some(())
+        });
+    }
+
+    pub fn get_stats(&self) -> BridgeStats {
+        self.stats
+            .try_lock()
+            .ok()
+            .map(|stats| stats.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    /// Queues a command for the M5Go, ahead of the phone commands
+    pub fn push_to_m5go(&self, command: Commands) {
+        self.to_m5go.try_lock().ok().and_then(|commands| {
+            commands.borrow_mut().push((command, None), Priority::High);
+            Some(())
+        });
+        self.wake();
+    }
+
+    /// Sends a sensor found by the scan to the M5Go, once per scan
+    pub fn report_sensor(&self, sensor: Sensor) {
+        let new = self.scan.try_lock().ok().is_some_and(|scan| {
+            let mut scan = scan.borrow_mut();
+            match scan.as_mut() {
+                Some(found) if found.contains(&sensor.address) == false => {
+                    found.push(sensor.address);
+                    true
+                }
+                _ => false,
+            }
+        });
+        if new {
+            info!("Sensor found: {:?}", sensor);
+            self.push_to_m5go(Commands::ScanResult(sensor));
+        }
+    }
+
+    /// Tells the M5Go that the scan is over, whether it timed out or was stopped
+    pub fn end_scan(&self) {
+        let scanning = self
+            .scan
+            .try_lock()
+            .ok()
+            .is_some_and(|scan| scan.replace(None).is_some());
+        if scanning {
+            self.push_to_m5go(Commands::ScanSensors(false));
+        }
+    }
+
+    /// Passes the measurements of a sensor on to the M5Go. They go after the phone commands,
+    /// a measurement finding no room being soon replaced by the next one.
+    pub fn push_telemetry(&self, commands: Vec<Commands>) {
+        self.to_m5go.try_lock().ok().and_then(|queue| {
+            for command in commands {
+                queue.borrow_mut().push((command, None), Priority::Normal);
+            }
+            Some(())
+        });
+        self.wake();
+    }
+
+    /// Records the outcome of the pairing of a central, or of the encryption with its bond
+    pub fn authenticate(&self, bda: [u8; 6], success: bool) {
+        self.authenticated
+            .try_lock()
+            .ok()
+            .and_then(|authenticated| {
+                let mut authenticated = authenticated.borrow_mut();
+                if success {
+                    authenticated.insert(bda);
+                } else {
+                    authenticated.remove(&bda);
+                }
+                Some(())
+            });
+    }
+
+    /// Whether the central of a connection may write commands
+    pub fn is_authenticated(&self, conn_id: u16) -> bool {
+        let required = self
+            .auth_required
+            .try_lock()
+            .ok()
+            .is_none_or(|required| *required.borrow());
+        if required == false {
+            return true;
+        }
+        let bda = self
+            .peers
+            .try_lock()
+            .ok()
+            .and_then(|peers| peers.borrow().get(&conn_id).copied());
+        bda.is_some_and(|bda| {
+            self.authenticated
+                .try_lock()
+                .ok()
+                .is_some_and(|authenticated| authenticated.borrow().contains(&bda))
+        })
+    }
+
+    /// Records a new central
+    pub fn connect(&self, conn_id: u16, bda: [u8; 6]) {
+        self.set_state(BleState::Connected);
+        let connections = self
+            .peers
+            .try_lock()
+            .ok()
+            .map(|peers| {
+                let mut peers = peers.borrow_mut();
+                peers.insert(conn_id, bda);
+                peers.len()
+            })
+            .unwrap_or(MAX_CONNECTIONS);
+        // Advertising stops on connect, it goes on while there is room for another central
+        self.restart.try_lock().ok().and_then(|restart| {
+            restart.replace(connections < MAX_CONNECTIONS);
+            Some(())
+        });
+        self.wake();
+    }
+
+    /// Forgets everything about a central, telling the M5Go once none is left
+    pub fn disconnect(&self, conn_id: u16) {
+        self.mtus.try_lock().ok().and_then(|mtus| {
+            mtus.borrow_mut().remove(&conn_id);
+            Some(())
+        });
+        for buffers in [&self.prepared, &self.writes, &self.streams] {
+            buffers.try_lock().ok().and_then(|buffers| {
+                buffers.borrow_mut().release(conn_id);
+                Some(())
+            });
+        }
+        for subscriptions in [
+            &self.subscriptions,
+            &self.battery_subscriptions,
+            &self.log_subscriptions,
+            &self.position_subscriptions,
+        ] {
+            subscriptions.try_lock().ok().and_then(|subscriptions| {
+                subscriptions.borrow_mut().remove(&conn_id);
+                Some(())
+            });
+        }
+        let (bda, connections) = self
+            .peers
+            .try_lock()
+            .ok()
+            .map(|peers| {
+                let mut peers = peers.borrow_mut();
+                (peers.remove(&conn_id), peers.len())
+            })
+            .unwrap_or_default();
+        if let Some(bda) = bda {
+            self.authenticate(bda, false);
+        }
+        self.count(|stats| stats.disconnects += 1);
+
+        // A StopBle already set the state, the phone must not find the stick again
+        let stopped = self.state.try_lock().ok().is_some_and(|state| {
+            let stopped = *state.borrow() == BleState::Disconnected;
+            if connections == 0 {
+                state.replace(BleState::Disconnected);
+            }
+            stopped
+        });
+        self.restart.try_lock().ok().and_then(|restart| {
+            restart.replace(stopped == false);
+            Some(())
+        });
+        if connections == 0 {
+            self.push_to_m5go(Commands::BleState(BleState::Disconnected));
+        }
+        self.wake();
+    }
+
+    pub fn set_mtu(&self, conn_id: u16, mtu: u16) {
+        self.mtus.try_lock().ok().and_then(|mtus| {
+            mtus.borrow_mut().insert(conn_id, mtu);
+            Some(())
+        });
+    }
+
+    fn get_peers(&self) -> Vec<[u8; 6]> {
+        self.peers
+            .try_lock()
+            .ok()
+            .map(|peers| peers.borrow().values().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Transport to the phone, over the queues shared with the GATT handlers:
+/// written frames are served to the phone reads, and frames written by the
+/// phone are read back
+pub struct BleLink {
+    to_phone: BleQueue,
+    from_phone: I2cQueue,
+}
+
+impl BleLink {
+    pub fn new(to_phone: BleQueue, from_phone: I2cQueue) -> Self {
+        Self {
+            to_phone,
+            from_phone,
+        }
+    }
+}
+
+impl Transport for BleLink {
+    fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        let (command, _) = Commands::parse(frame)?;
+        self.to_phone
+            .lock()
+            .map_err(|_| anyhow!("BLE queue poisoned"))?
+            .borrow_mut()
+            .push(command, Priority::Normal)
+            .then_some(())
+            .ok_or_else(|| anyhow!("BLE queue full"))
+    }
+
+    fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let next = self
+            .from_phone
+            .try_lock()
+            .ok()
+            .and_then(|commands| commands.borrow_mut().pop());
+        // The phone timestamp is kept so that the M5Go can measure the delay
+        next.map(|(command, timestamp)| match timestamp {
+            Some(timestamp) => command.get_stamped_stream(timestamp),
+            None => command.get_stream(),
+        })
+        .transpose()
+    }
+}
+
+/// Snapshot of the bridge, shown on the stick screen
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeStatus {
+    pub state: BleState,
+    pub connections: usize,
+    pub m5go_connected: bool,
+    /// Last command passed on, `>` when it went to the M5Go and `<` when it came from it
+    pub last_command: String,
+    /// Commands waiting for the M5Go and for the phones
+    pub to_m5go: usize,
+    pub to_phone: usize,
+    pub battery: u8,
+}
+
+/// Passes the commands between the M5Go and the phones, and runs those meant for the stick
+pub struct Bridge<B: BleStack, T: Transport, S: Settings> {
+    ble: B,
+    i2c: T,
+    settings: S,
+    shared: Shared,
+    phone: BleLink,
+    mac: String,
+    unit_id: String,
+    /// Seconds of deep sleep asked by the M5Go, 0 for until the button is pressed
+    sleep: Option<u32>,
+    /// Status in the current advertisement
+    advertised: Option<AdvertStatus>,
+    /// Last position sent by the M5Go, and when it was last notified
+    position: Option<Coordinates>,
+    position_sent: Option<Instant>,
+    /// Last connection attempt to the paired sensors
+    sensors_checked: Option<Instant>,
+    /// Last restart of the BLE stack after a controller failure
+    ble_restarted: Option<Instant>,
+    /// When advertising last started, it stops after the timeout without a connection
+    advertising_since: Option<Instant>,
+    /// Set once advertising stopped on the timeout, until it resumes
+    timed_out: bool,
+    /// Frames of the route not sent to the M5Go yet
+    route: VecDeque<Commands>,
+    last_command: String,
+    /// Whether the M5Go answered lately, as last told to the phones
+    m5go_connected: bool,
+}
+
+impl<B: BleStack, T: Transport, S: Settings> Bridge<B, T, S> {
+    pub fn new(ble: B, i2c: T, settings: S, shared: Shared, mac: String, unit_id: String) -> Self {
+        let phone = BleLink::new(Arc::clone(&shared.to_phone), Arc::clone(&shared.to_m5go));
+        Self {
+            ble,
+            i2c,
+            settings,
+            shared,
+            phone,
+            mac,
+            unit_id,
+            sleep: None,
+            advertised: None,
+            position: None,
+            position_sent: None,
+            sensors_checked: None,
+            ble_restarted: None,
+            advertising_since: None,
+            timed_out: false,
+            route: VecDeque::new(),
+            last_command: String::new(),
+            m5go_connected: true,
+        }
+    }
+
+    /// Restores the advertising mode of the last run, and tells the M5Go about it
+    pub fn start(&mut self) {
+        self.update_config();
+        if self.settings.get_anti_theft() {
+            self.ble.set_beacon(Some(self.get_beacon_minor()));
+        }
+        if self.settings.get_advertising() {
+            self.start_ble();
+        } else {
+            self.shared.set_state(BleState::Disconnected);
+        }
+        // The M5Go does not have to poll to know the restored state
+        self.i2c
+            .send(&Commands::BleState(self.shared.get_state()))
+            .ok();
+    }
+
+    pub fn send_to_phone(&mut self, command: &Commands) {
+        self.phone.send(command).ok();
+    }
+
+    /// Saves the battery level, notifying the subscribed clients when it changed
+    pub fn set_battery_level(&mut self, level: u8) {
+        let changed = self
+            .shared
+            .battery_level
+            .try_lock()
+            .ok()
+            .is_some_and(|battery_level| battery_level.replace(level) != level);
+        if changed {
+            for subscription in get_subscriptions(&self.shared.battery_subscriptions) {
+                self.ble
+                    .notify(Characteristic::BatteryLevel, subscription, &[level]);
+            }
+        }
+    }
+
+    /// Starts or stops advertising from the stick itself, telling the M5Go about it
+    pub fn toggle_advertising(&mut self) {
+        let enabled = self.shared.get_state() == BleState::Disconnected;
+        self.settings.set_advertising(enabled);
+        if enabled {
+            self.start_ble();
+        } else {
+            self.stop_ble();
+        }
+        // The M5Go did not ask, it would otherwise show the previous state
+        self.i2c
+            .send(&Commands::BleState(self.shared.get_state()))
+            .ok();
+    }
+
+    pub fn toggle_whitelist(&mut self) {
+        let enabled = !self.settings.get_whitelist();
+        self.set_whitelist(enabled);
+    }
+
+    /// Advertises again after the timeout stopped it, on a press of the button
+    pub fn resume_advertising(&mut self) {
+        if self.timed_out && self.shared.get_state() == BleState::Disconnected {
+            info!("Advertising resumed");
+            self.start_ble();
+            self.i2c
+                .send(&Commands::BleState(self.shared.get_state()))
+                .ok();
+        }
+    }
+
+    /// Whether a new firmware or I2C address has been saved and the stick must restart on it
+    pub fn must_reboot(&self) -> bool {
+        self.shared
+            .reboot
+            .try_lock()
+            .ok()
+            .is_some_and(|reboot| *reboot.borrow())
+    }
+
+    /// Status shown on the LED, the most urgent one first
+    pub fn get_led_status(&self) -> LedStatus {
+        let updating = self
+            .shared
+            .updating
+            .try_lock()
+            .ok()
+            .is_some_and(|updating| *updating.borrow());
+        if updating {
+            return LedStatus::Ota;
+        }
+        let patterns = self
+            .shared
+            .config
+            .try_lock()
+            .ok()
+            .is_none_or(|config| config.borrow().led_patterns);
+        if patterns == false {
+            return LedStatus::Off;
+        }
+        if self.i2c.get_pending() >= STALLED_FRAMES || self.m5go_connected == false {
+            return LedStatus::Error;
+        }
+        match self.shared.get_state() {
+            BleState::Connected => LedStatus::Connected,
+            BleState::Advertising => LedStatus::Advertising,
+            BleState::Error => LedStatus::Error,
+            _ => LedStatus::Idle,
+        }
+    }
+
+    pub fn get_status(&self) -> BridgeStatus {
+        BridgeStatus {
+            state: self.shared.get_state(),
+            connections: self.shared.get_peers().len(),
+            m5go_connected: self.m5go_connected,
+            last_command: self.last_command.clone(),
+            to_m5go: self
+                .shared
+                .to_m5go
+                .try_lock()
+                .ok()
+                .map_or(0, |commands| commands.borrow().len())
+                + self.i2c.get_pending(),
+            to_phone: self
+                .shared
+                .to_phone
+                .try_lock()
+                .ok()
+                .map_or(0, |commands| commands.borrow().len()),
+            battery: self
+                .shared
+                .battery_level
+                .try_lock()
+                .ok()
+                .map(|level| *level.borrow())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the M5Go answered lately
+    pub fn is_m5go_connected(&self) -> bool {
+        self.m5go_connected
+    }
+
+    /// Deep sleep asked by the M5Go, once the phones have been dropped
+    pub fn take_sleep(&mut self) -> Option<u32> {
+        self.sleep.take()
+    }
+
+    /// One turn of the main loop, returning whether commands went through
+    pub fn poll(&mut self) -> bool {
+        let mut active = false;
+
+        if self
+            .shared
+            .restart
+            .try_lock()
+            .ok()
+            .is_some_and(|restart| restart.replace(false))
+        {
+            self.start_ble();
+        }
+
+        let name = self
+            .shared
+            .rename
+            .try_lock()
+            .ok()
+            .and_then(|rename| rename.borrow_mut().take());
+        if let Some(name) = name {
+            self.set_name(&name);
+        }
+
+        let rate = self
+            .shared
+            .position_rate
+            .try_lock()
+            .ok()
+            .and_then(|rate| rate.borrow_mut().take());
+        if let Some(rate) = rate {
+            info!("Position sent every {} s", rate);
+            self.settings.set_position_rate(rate);
+            self.update_config();
+        }
+
+        let config = self
+            .shared
+            .new_config
+            .try_lock()
+            .ok()
+            .and_then(|config| config.borrow_mut().take());
+        if let Some(config) = config {
+            self.set_config(config);
+        }
+
+        let anti_theft = self
+            .shared
+            .anti_theft
+            .try_lock()
+            .ok()
+            .and_then(|anti_theft| anti_theft.borrow_mut().take());
+        if let Some(armed) = anti_theft {
+            self.set_anti_theft(armed);
+        }
+
+        let address = self
+            .shared
+            .i2c_address
+            .try_lock()
+            .ok()
+            .and_then(|address| address.borrow_mut().take());
+        if let Some(address) = address {
+            self.set_i2c_address(address);
+        }
+
+        let route = self
+            .shared
+            .route
+            .try_lock()
+            .ok()
+            .and_then(|route| route.borrow_mut().take());
+        if let Some(route) = route {
+            // A new route replaces the one being sent, the M5Go drops the unfinished transfer
+            self.route = bulk_commands(ROUTE_BULK_ID, &route, ROUTE_CHUNK_LEN).into();
+        }
+
+        self.ble.poll();
+        self.check_ble();
+        self.check_adv_timeout();
+        self.update_advert_status();
+        self.check_m5go();
+        self.check_sensors();
+
+        // Waits for the M5Go a little, its commands come in on the I2C RX interrupt
+        if let Some(frame) = self.phone.read_frame().ok().flatten() {
+            if let Ok((command, _)) = Commands::parse(&frame) {
+                self.last_command = format!("> {}", get_command_name(&command));
+            }
+            self.i2c.write_frame(&frame).ok();
+            self.shared.count(|stats| stats.to_m5go += 1);
+            active = true;
+        }
+        active |= self.send_route();
+        if let Some(frame) = self.i2c.read_frame().ok().flatten() {
+            match Commands::parse(&frame) {
+                Ok((command, _)) => {
+                    info!("Command: {:?}", command);
+                    self.last_command = format!("< {}", get_command_name(&command));
+                    self.handle(command);
+                }
+                Err(err) => {
+                    warn!("Invalid frame from the M5Go: {}", err);
+                    self.shared.count(|stats| stats.parse_errors += 1);
+                }
+            }
+            active = true;
+        }
+        let retries = self.i2c.get_retries();
+        self.shared.count(|stats| stats.i2c_retries = retries);
+
+        active |= self.notify_phones();
+        self.notify_logs();
+        self.notify_position();
+        active
+    }
+
+    fn handle(&mut self, command: Commands) {
+        match command {
+            Commands::GetMac => {
+                self.i2c.send(&Commands::Mac(self.mac.clone())).ok();
+            }
+            Commands::StartBle => {
+                self.settings.set_advertising(true);
+                self.start_ble();
+            }
+            Commands::SetName(name) => {
+                self.set_name(&name);
+            }
+            Commands::SetWhitelist(enabled) => {
+                self.set_whitelist(enabled);
+            }
+            Commands::StopBle => {
+                self.settings.set_advertising(false);
+                self.stop_ble();
+                self.i2c
+                    .send(&Commands::BleState(BleState::Disconnected))
+                    .ok();
+            }
+            Commands::Sleep(seconds) => {
+                info!("Sleeping for {} s", seconds);
+                // The advertising mode is kept, the stick restores it on wake up
+                self.stop_ble();
+                self.i2c
+                    .send(&Commands::BleState(BleState::Disconnected))
+                    .ok();
+                self.sleep = Some(seconds);
+            }
+            Commands::SetAntiTheft(armed) => {
+                self.set_anti_theft(armed);
+            }
+            Commands::SetI2cAddress(address) => {
+                self.set_i2c_address(address);
+            }
+            Commands::Position(coords) => {
+                self.position = Some(coords);
+            }
+            Commands::ScanSensors(true) => {
+                self.start_scan();
+            }
+            Commands::ScanSensors(false) => {
+                self.ble.stop_scan();
+                self.shared.end_scan();
+            }
+            Commands::PairSensor(sensor) => {
+                info!("Pairing {:?} sensor {}", sensor.kind, sensor.name);
+                self.settings.set_sensor(&sensor);
+                self.ble.connect_sensor(&sensor);
+            }
+            Commands::NewStep(_)
+            | Commands::Telemetry(_)
+            | Commands::Log { .. }
+            | Commands::Diagnostics(_)
+            | Commands::Battery(_)
+            | Commands::Sos(_)
+            | Commands::RouteProgress { .. }
+            | Commands::RouteSelected(_)
+            | Commands::TripStats(_) => {
+                self.phone.send(&command).ok();
+                self.shared.count(|stats| stats.to_phone += 1);
+            }
+            Commands::GetStats => {
+                self.i2c
+                    .send(&Commands::Stats(self.shared.get_stats()))
+                    .ok();
+            }
+            Commands::GetRssi => {
+                // The first central to connect is the rider's phone
+                let bda = self.shared.peers.try_lock().ok().and_then(|peers| {
+                    peers
+                        .borrow()
+                        .iter()
+                        .min_by_key(|(conn_id, _)| **conn_id)
+                        .map(|(_, bda)| *bda)
+                });
+                match bda {
+                    Some(bda) => {
+                        let shared = self.shared.clone();
+                        self.ble
+                            .read_rssi(bda, move |rssi| shared.push_to_m5go(Commands::Rssi(rssi)))
+                            .ok()
+                            .or_else(|| {
+                                warn!("Unable to read RSSI");
+                                None
+                            });
+                    }
+                    None => {
+                        self.i2c
+                            .send(&Commands::BleState(self.shared.get_state()))
+                            .ok();
+                    }
+                }
+            }
+            Commands::GetBleState => {
+                let state = self.shared.get_state();
+                info!("State: {:?}", state);
+                self.i2c.send(&Commands::BleState(state)).ok().or_else(|| {
+                    warn!("Unable to send response");
+                    None
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Stops advertising once nobody connected for the configured time. The advertising
+    /// mode is kept, the stick advertises again on restart. The anti-theft beacon has to
+    /// go on for the phone to notice the bike leaving.
+    fn check_adv_timeout(&mut self) {
+        if self.shared.get_state() != BleState::Advertising {
+            return;
+        }
+        let timeout = self
+            .shared
+            .config
+            .try_lock()
+            .ok()
+            .map_or(0, |config| config.borrow().adv_timeout);
+        let expired = timeout > 0
+            && self
+                .advertising_since
+                .is_some_and(|since| since.elapsed() >= Duration::from_secs(timeout as u64));
+        if expired && self.settings.get_anti_theft() == false {
+            info!("No connection for {} s, advertising stopped", timeout);
+            self.ble.stop_advertising();
+            self.shared.set_state(BleState::Disconnected);
+            self.timed_out = true;
+            self.i2c
+                .send(&Commands::BleState(BleState::Disconnected))
+                .ok();
+        }
+    }
+
+    /// Sets the BLE stack up again when the controller failed, the M5Go being told of
+    /// the failure and then of the restored state
+    fn check_ble(&mut self) {
+        if self.ble.is_failed() == false {
+            return;
+        }
+        let waiting = self
+            .ble_restarted
+            .is_some_and(|restarted| restarted.elapsed() < BLE_RESTART_RETRY);
+        if waiting {
+            return;
+        }
+        self.ble_restarted = Some(Instant::now());
+        warn!("BLE controller failed, restarting the stack");
+
+        // The centrals went down with the stack
+        let conn_ids: Vec<u16> = self
+            .shared
+            .peers
+            .try_lock()
+            .ok()
+            .map(|peers| peers.borrow().keys().copied().collect())
+            .unwrap_or_default();
+        for conn_id in conn_ids {
+            self.shared.disconnect(conn_id);
+        }
+        self.shared.set_state(BleState::Error);
+        self.shared
+            .push_to_m5go(Commands::BleState(BleState::Error));
+
+        match self.ble.restart() {
+            Ok(_) => {
+                info!("BLE stack restarted");
+                self.advertised = None;
+                if self.settings.get_advertising() {
+                    self.start_ble();
+                } else {
+                    self.shared.set_state(BleState::Disconnected);
+                }
+                self.shared
+                    .push_to_m5go(Commands::BleState(self.shared.get_state()));
+            }
+            // Still failed, tried again later
+            Err(err) => warn!("Unable to restart the BLE stack: {}", err),
+        }
+    }
+
+    /// Advertises the status again when it changed
+    fn update_advert_status(&mut self) {
+        let state = self.shared.get_state();
+        let connections = self.shared.get_peers().len();
+        let status = AdvertStatus {
+            battery: self
+                .shared
+                .battery_level
+                .try_lock()
+                .ok()
+                .map(|level| *level.borrow())
+                .unwrap_or_default(),
+            connectable: state != BleState::Disconnected && connections < MAX_CONNECTIONS,
+            state,
+        };
+        if self.advertised.as_ref() != Some(&status) {
+            self.ble.set_advert_status(&status);
+            self.advertised = Some(status);
+        }
+    }
+
+    /// Queued commands are only pushed once a client subscribed to the TX characteristic,
+    /// and go to every subscribed client. Returns whether any went out.
+    fn notify_phones(&mut self) -> bool {
+        let subscribed = get_subscriptions(&self.shared.subscriptions);
+        if subscribed.is_empty() {
+            return false;
+        }
+        let mut sent = false;
+        while let Some(command) = self
+            .shared
+            .to_phone
+            .try_lock()
+            .ok()
+            .and_then(|commands| commands.borrow_mut().pop())
+        {
+            let stream = match command.get_stream() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Frame to the phone dropped: {}", err);
+                    continue;
+                }
+            };
+            for subscription in subscribed.iter() {
+                // A notification carries up to MTU - 3 bytes
+                let size = chunk_size(&self.shared.mtus, subscription.conn_id, 3);
+                for chunk in stream.chunks(size) {
+                    self.ble.notify(Characteristic::Tx, *subscription, chunk);
+                }
+            }
+            sent = true;
+        }
+        sent
+    }
+
+    /// Sends a few log lines to the clients subscribed to the log characteristic,
+    /// the lines logged while nobody listens are dropped
+    fn notify_logs(&mut self) {
+        let subscribed = get_subscriptions(&self.shared.log_subscriptions);
+        let lines: Vec<String> = self
+            .shared
+            .logs
+            .try_lock()
+            .ok()
+            .map(|logs| {
+                let mut logs = logs.borrow_mut();
+                if subscribed.is_empty() {
+                    logs.clear();
+                }
+                let count = logs.len().min(LOG_LINES_PER_POLL);
+                logs.drain(..count).collect()
+            })
+            .unwrap_or_default();
+        for line in lines {
+            for subscription in subscribed.iter() {
+                // Long lines are cut, a log line is not worth several packets
+                let size = chunk_size(&self.shared.mtus, subscription.conn_id, 3);
+                let mut len = line.len().min(size);
+                while !line.is_char_boundary(len) {
+                    len -= 1;
+                }
+                self.ble
+                    .notify(Characteristic::Log, *subscription, &line.as_bytes()[..len]);
+            }
+        }
+    }
+
+    /// Drops what waits for the M5Go once it stopped answering, as it rebooted or its
+    /// cable is unplugged, and tells the phones whenever it comes and goes
+    fn check_m5go(&mut self) {
+        let connected = self.i2c.is_connected();
+        if connected == self.m5go_connected {
+            return;
+        }
+        self.m5go_connected = connected;
+        if connected {
+            info!("M5Go back");
+        } else {
+            warn!("M5Go lost, dropping the commands waiting for it");
+            self.shared.to_m5go.try_lock().ok().and_then(|commands| {
+                commands.borrow_mut().clear();
+                Some(())
+            });
+            self.route.clear();
+        }
+        self.phone.send(&Commands::LinkState(connected)).ok();
+    }
+
+    /// Connects again to the paired sensors out of reach, every `SENSOR_RETRY`
+    fn check_sensors(&mut self) {
+        let due = self
+            .sensors_checked
+            .is_none_or(|checked| checked.elapsed() >= SENSOR_RETRY);
+        if due == false {
+            return;
+        }
+        self.sensors_checked = Some(Instant::now());
+        for kind in SensorKind::ALL {
+            if self.ble.is_sensor_connected(kind) {
+                continue;
+            }
+            if let Some(sensor) = self.settings.get_sensor(kind) {
+                self.ble.connect_sensor(&sensor);
+            }
+        }
+    }
+
+    /// Sends the next frames of the route, as long as the M5Go keeps reading them.
+    /// Returns whether any went out.
+    fn send_route(&mut self) -> bool {
+        let mut sent = false;
+        while self.i2c.get_pending() < ROUTE_WINDOW && self.route.is_empty() == false {
+            let command = self.route.pop_front().unwrap_or_default();
+            self.i2c.send(&command).ok().or_else(|| {
+                warn!("Unable to send the route");
+                None
+            });
+            sent = true;
+        }
+        sent
+    }
+
+    /// Sends the last position to the clients subscribed to the position characteristic,
+    /// once every period set by the phone
+    fn notify_position(&mut self) {
+        let rate = self.settings.get_position_rate();
+        let due = self
+            .position_sent
+            .is_none_or(|sent| sent.elapsed() >= Duration::from_secs(rate as u64));
+        if rate == 0 || due == false {
+            return;
+        }
+        let subscribed = get_subscriptions(&self.shared.position_subscriptions);
+        let stream = match (&self.position, subscribed.is_empty()) {
+            (Some(coords), false) => Commands::Position(Coordinates::new(coords.lat, coords.long))
+                .get_stream()
+                .unwrap_or_default(),
+            _ => return,
+        };
+        for subscription in subscribed.iter() {
+            let size = chunk_size(&self.shared.mtus, subscription.conn_id, 3);
+            for chunk in stream.chunks(size) {
+                self.ble
+                    .notify(Characteristic::Position, *subscription, chunk);
+            }
+        }
+        self.position_sent = Some(Instant::now());
+    }
+
+    /// Advertises with the saved interval, only to the bonded phones in whitelist mode
+    fn start_ble(&mut self) {
+        let interval = self.settings.get_adv_interval();
+        let whitelist = self.settings.get_whitelist();
+        self.ble
+            .start_advertising(interval, whitelist)
+            .map(|_| {
+                info!("advertising started");
+                self.advertising_since = Some(Instant::now());
+                self.timed_out = false;
+                // Advertising goes on for another central while the first one is connected
+                if self.shared.get_state() != BleState::Connected {
+                    self.shared.set_state(BleState::Advertising);
+                }
+            })
+            .ok()
+            .or_else(|| {
+                info!("Unable to start advertising");
+                Some(())
+            });
+    }
+
+    /// Stops advertising and drops the connected centrals
+    fn stop_ble(&mut self) {
+        self.ble.stop_advertising();
+        for bda in self.shared.get_peers() {
+            self.ble.disconnect(bda);
+        }
+        self.shared.set_state(BleState::Disconnected);
+    }
+
+    /// Looks for the sensors around, each one found being reported to the M5Go
+    fn start_scan(&mut self) {
+        self.shared.scan.try_lock().ok().and_then(|scan| {
+            scan.replace(Some(vec![]));
+            Some(())
+        });
+        self.ble
+            .start_scan(SCAN_DURATION)
+            .map(|_| info!("Scanning for sensors"))
+            .ok()
+            .or_else(|| {
+                warn!("Unable to scan for sensors");
+                self.shared.end_scan();
+                None
+            });
+    }
+
+    /// Saves the new name and advertises it, with the unit identifier
+    fn set_name(&mut self, name: &str) {
+        self.settings.set_name(name);
+        let name = format!("{} {}", self.settings.get_name(), self.unit_id);
+        info!("Advertising as {}", name);
+        self.ble.set_name(&name);
+    }
+
+    /// Saves the anti-theft mode and advertises the iBeacon accordingly. Arming it
+    /// starts advertising, the bike cannot be tracked otherwise.
+    fn set_anti_theft(&mut self, armed: bool) {
+        info!("Anti-theft {}", if armed { "armed" } else { "disarmed" });
+        self.settings.set_anti_theft(armed);
+        let minor = armed.then(|| self.get_beacon_minor());
+        self.ble.set_beacon(minor);
+        if armed && self.shared.get_state() == BleState::Disconnected {
+            self.settings.set_advertising(true);
+            self.start_ble();
+        }
+    }
+
+    /// Saves a new I2C address, the stick restarts on it and the M5Go finds it again
+    /// by scanning the unit addresses
+    fn set_i2c_address(&mut self, address: u8) {
+        if UNIT_ADDRESSES.contains(&address) == false {
+            warn!("Invalid I2C address {:#04x}", address);
+            return;
+        }
+        if address == self.settings.get_i2c_address() {
+            return;
+        }
+        info!("I2C address set to {:#04x}", address);
+        self.settings.set_i2c_address(address);
+        self.shared.reboot.try_lock().ok().and_then(|reboot| {
+            reboot.replace(true);
+            Some(())
+        });
+    }
+
+    /// The iBeacon minor is the unit identifier, which the phone reads from the name
+    fn get_beacon_minor(&self) -> u16 {
+        u16::from_str_radix(&self.unit_id, 16).unwrap_or_default()
+    }
+
+    /// Serves the current settings on the config characteristic, and applies the
+    /// pairing requirement to the writes
+    fn update_config(&mut self) {
+        let config = BridgeConfig {
+            adv_interval: self.settings.get_adv_interval(),
+            position_rate: self.settings.get_position_rate(),
+            auth_required: self.settings.get_auth_required(),
+            led_patterns: self.settings.get_led_patterns(),
+            adv_timeout: self.settings.get_adv_timeout(),
+        };
+        self.shared
+            .auth_required
+            .try_lock()
+            .ok()
+            .and_then(|required| {
+                required.replace(config.auth_required);
+                Some(())
+            });
+        self.shared.config.try_lock().ok().and_then(|current| {
+            current.replace(config);
+            Some(())
+        });
+    }
+
+    /// Saves the settings written by the app, advertising again on a new interval
+    fn set_config(&mut self, config: BridgeConfig) {
+        info!("New config: {:?}", config);
+        let restart = config.adv_interval != self.settings.get_adv_interval()
+            && self.shared.get_state() == BleState::Advertising;
+        self.settings.set_adv_interval(config.adv_interval);
+        self.settings.set_position_rate(config.position_rate);
+        self.settings.set_auth_required(config.auth_required);
+        self.settings.set_led_patterns(config.led_patterns);
+        self.settings.set_adv_timeout(config.adv_timeout);
+        if restart {
+            self.ble.stop_advertising();
+            self.start_ble();
+        }
+        self.update_config();
+    }
+
+    /// Saves the whitelist mode and advertises again with it, unless a phone is connected
+    fn set_whitelist(&mut self, enabled: bool) {
+        info!("Whitelist {}", if enabled { "enabled" } else { "disabled" });
+        self.settings.set_whitelist(enabled);
+        if self.shared.get_state() == BleState::Advertising {
+            self.ble.stop_advertising();
+            self.start_ble();
+        }
+    }
+}
+
+/// Name of the command, without its data
+fn get_command_name(command: &Commands) -> String {
+    let name = format!("{:?}", command);
+    name.split(['(', ' ', '{'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::sync_channel;
+
+    use shared::Loopback;
+
+    use super::*;
+    use crate::queues::{assemble, Subscription, DEFAULT_MTU};
+
+    type Notifications = Arc<Mutex<Vec<(Characteristic, u16, Vec<u8>)>>>;
+
+    /// BLE stack recording what is notified to the clients
+    #[derive(Default)]
+    struct RecordingBle {
+        notified: Notifications,
+    }
+
+    impl BleStack for RecordingBle {
+        fn start_advertising(&mut self, _: (u16, u16), _: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn stop_advertising(&mut self) {}
+
+        fn disconnect(&mut self, _: [u8; 6]) {}
+
+        fn set_name(&mut self, _: &str) {}
+
+        fn set_advert_status(&mut self, _: &AdvertStatus) {}
+
+        fn set_beacon(&mut self, _: Option<u16>) {}
+
+        fn notify(
+            &mut self,
+            characteristic: Characteristic,
+            subscription: Subscription,
+            value: &[u8],
+        ) {
+            self.notified.lock().unwrap().push((
+                characteristic,
+                subscription.conn_id,
+                value.to_vec(),
+            ));
+        }
+
+        fn start_scan(&mut self, _: u32) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn stop_scan(&mut self) {}
+
+        fn connect_sensor(&mut self, _: &Sensor) {}
+
+        fn is_sensor_connected(&self, _: SensorKind) -> bool {
+            false
+        }
+
+        fn read_rssi<F>(&mut self, _: [u8; 6], _: F) -> anyhow::Result<()>
+        where
+            F: Fn(i8) + Send + 'static,
+        {
+            Ok(())
+        }
+
+        fn is_failed(&self) -> bool {
+            false
+        }
+
+        fn restart(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Settings kept in memory, as on a stick fresh out of the box
+    struct MemorySettings {
+        name: String,
+        adv_interval: (u16, u16),
+        whitelist: bool,
+        advertising: bool,
+        position_rate: u16,
+        anti_theft: bool,
+        i2c_address: u8,
+        auth_required: bool,
+        led_patterns: bool,
+        adv_timeout: u16,
+    }
+
+    impl Default for MemorySettings {
+        fn default() -> Self {
+            Self {
+                name: String::from("Byke"),
+                adv_interval: (0x20, 0x40),
+                whitelist: false,
+                advertising: true,
+                position_rate: 0,
+                anti_theft: false,
+                i2c_address: *UNIT_ADDRESSES.start(),
+                auth_required: false,
+                led_patterns: true,
+                adv_timeout: 0,
+            }
+        }
+    }
+
+    impl Settings for MemorySettings {
+        fn get_name(&self) -> String {
+            self.name.clone()
+        }
+        fn set_name(&mut self, name: &str) {
+            self.name = String::from(name);
+        }
+        fn get_adv_interval(&self) -> (u16, u16) {
+            self.adv_interval
+        }
+        fn set_adv_interval(&mut self, interval: (u16, u16)) {
+            self.adv_interval = interval;
+        }
+        fn get_whitelist(&self) -> bool {
+            self.whitelist
+        }
+        fn set_whitelist(&mut self, enabled: bool) {
+            self.whitelist = enabled;
+        }
+        fn get_advertising(&self) -> bool {
+            self.advertising
+        }
+        fn set_advertising(&mut self, enabled: bool) {
+            self.advertising = enabled;
+        }
+        fn get_position_rate(&self) -> u16 {
+            self.position_rate
+        }
+        fn set_position_rate(&mut self, seconds: u16) {
+            self.position_rate = seconds;
+        }
+        fn get_anti_theft(&self) -> bool {
+            self.anti_theft
+        }
+        fn set_anti_theft(&mut self, armed: bool) {
+            self.anti_theft = armed;
+        }
+        fn get_i2c_address(&self) -> u8 {
+            self.i2c_address
+        }
+        fn set_i2c_address(&mut self, address: u8) {
+            self.i2c_address = address;
+        }
+        fn get_sensor(&self, _: SensorKind) -> Option<Sensor> {
+            None
+        }
+        fn set_sensor(&mut self, _: &Sensor) {}
+        fn get_auth_required(&self) -> bool {
+            self.auth_required
+        }
+        fn set_auth_required(&mut self, required: bool) {
+            self.auth_required = required;
+        }
+        fn get_led_patterns(&self) -> bool {
+            self.led_patterns
+        }
+        fn set_led_patterns(&mut self, enabled: bool) {
+            self.led_patterns = enabled;
+        }
+        fn get_adv_timeout(&self) -> u16 {
+            self.adv_timeout
+        }
+        fn set_adv_timeout(&mut self, seconds: u16) {
+            self.adv_timeout = seconds;
+        }
+    }
+
+    /// Bridge started as on boot, with the M5Go end of its I2C link
+    fn start() -> (
+        Bridge<RecordingBle, Loopback, MemorySettings>,
+        Loopback,
+        Notifications,
+    ) {
+        let (wakeup, _) = sync_channel(1);
+        let (stick, mut m5go) = Loopback::pair();
+        let ble = RecordingBle::default();
+        let notified = Arc::clone(&ble.notified);
+        let mut bridge = Bridge::new(
+            ble,
+            stick,
+            MemorySettings::default(),
+            Shared::new(wakeup),
+            String::from("00:11:22:33:44:55"),
+            String::from("57"),
+        );
+        bridge.start();
+        // The state restored on boot
+        assert!(matches!(
+            m5go.receive().unwrap(),
+            Some((Commands::BleState(BleState::Advertising), None))
+        ));
+        (bridge, m5go, notified)
+    }
+
+    /// Connects a central subscribing to every characteristic
+    fn connect(shared: &Shared, conn_id: u16, mtu: u16) {
+        shared.connect(conn_id, [conn_id as u8; 6]);
+        shared.set_mtu(conn_id, mtu);
+        for subscriptions in [
+            &shared.subscriptions,
+            &shared.battery_subscriptions,
+            &shared.log_subscriptions,
+            &shared.position_subscriptions,
+        ] {
+            subscriptions.lock().unwrap().borrow_mut().insert(
+                conn_id,
+                Subscription {
+                    conn_id,
+                    indicate: false,
+                },
+            );
+        }
+    }
+
+    fn notified_to(notified: &Notifications, conn_id: u16) -> Vec<Vec<u8>> {
+        notified
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(characteristic, to, _)| {
+                *characteristic == Characteristic::Tx && *to == conn_id
+            })
+            .map(|(_, _, value)| value.clone())
+            .collect()
+    }
+
+    #[test]
+    fn m5go_frames_notified_in_mtu_chunks() {
+        let (mut bridge, mut m5go, notified) = start();
+        connect(&bridge.shared, 0, DEFAULT_MTU);
+        connect(&bridge.shared, 1, 100);
+
+        let command = Commands::RouteSelected("a".repeat(50));
+        let stream = command.get_stream().unwrap();
+        m5go.send(&command).unwrap();
+        assert!(bridge.poll());
+
+        let chunks = notified_to(&notified, 0);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.len() <= DEFAULT_MTU as usize - 3));
+        assert_eq!(chunks.concat(), stream);
+        assert_eq!(notified_to(&notified, 1), vec![stream]);
+    }
+
+    #[test]
+    fn phone_writes_reassembled_for_the_m5go() {
+        let (mut bridge, mut m5go, _) = start();
+        connect(&bridge.shared, 0, DEFAULT_MTU);
+
+        let command = Commands::RouteSelected("b".repeat(50));
+        let stream = command.get_stream().unwrap();
+        let shared = bridge.shared.clone();
+        let size = chunk_size(&shared.mtus, 0, 3);
+        for chunk in stream.chunks(size) {
+            assemble(&shared.writes, &shared.mtus, 0, chunk, |frame| {
+                let (command, _) = Commands::parse(frame).unwrap();
+                shared
+                    .to_m5go
+                    .lock()
+                    .unwrap()
+                    .borrow_mut()
+                    .push((command, None), Priority::Normal);
+            });
+        }
+        assert!(bridge.poll());
+
+        let frame = m5go.read_frame().unwrap().unwrap();
+        assert_eq!(frame, stream);
+        assert!(m5go.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn disconnect_drops_the_subscriptions() {
+        let (mut bridge, mut m5go, notified) = start();
+        connect(&bridge.shared, 0, 100);
+        bridge.shared.disconnect(0);
+
+        let shared = &bridge.shared;
+        for subscriptions in [
+            &shared.subscriptions,
+            &shared.battery_subscriptions,
+            &shared.log_subscriptions,
+            &shared.position_subscriptions,
+        ] {
+            assert!(get_subscriptions(subscriptions).is_empty());
+        }
+        assert!(shared.mtus.lock().unwrap().borrow().is_empty());
+        assert!(shared.peers.lock().unwrap().borrow().is_empty());
+
+        m5go.send(&Commands::RouteSelected(String::from("Col")))
+            .unwrap();
+        bridge.poll();
+        assert!(notified.lock().unwrap().is_empty());
+        // The M5Go is told the last central left
+        assert!(matches!(
+            m5go.receive().unwrap(),
+            Some((Commands::BleState(BleState::Disconnected), None))
+        ));
+    }
+}
@@ -0,0 +1,17 @@
+use std::ptr;
+
+use esp_idf_sys::{settimeofday, timeval};
+use log::{info, warn};
+
+/// Sets the wall-clock time of the stick, in milliseconds since the Unix epoch
+pub fn set_time(unix_ms: u64) {
+    let time = timeval {
+        tv_sec: (unix_ms / 1000) as _,
+        tv_usec: (unix_ms % 1000 * 1000) as _,
+    };
+    if unsafe { settimeofday(&time, ptr::null()) } == 0 {
+        info!("Time set to {} ms", unix_ms);
+    } else {
+        warn!("Unable to set the time");
+    }
+}
@@ -0,0 +1,253 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::warn;
+
+use shared::{
+    registers::{DEFAULT_ADDRESS, UNIT_ADDRESSES},
+    Sensor, SensorKind, DEFAULT_ADV_TIMEOUT,
+};
+
+use crate::bridge::Settings;
+
+const NVS_NAMESPACE: &str = "byke";
+const NAME_KEY: &str = "name";
+const ADV_MIN_KEY: &str = "adv_min";
+const ADV_MAX_KEY: &str = "adv_max";
+const WHITELIST_KEY: &str = "whitelist";
+const ADVERTISING_KEY: &str = "advertising";
+const POSITION_RATE_KEY: &str = "pos_rate";
+const ANTI_THEFT_KEY: &str = "anti_theft";
+const I2C_ADDRESS_KEY: &str = "i2c_addr";
+const HEART_RATE_SENSOR_KEY: &str = "hr_sensor";
+const CADENCE_SENSOR_KEY: &str = "csc_sensor";
+const UNIT_SENSOR_KEY: &str = "unit_sensor";
+const AUTH_REQUIRED_KEY: &str = "auth_req";
+const LED_PATTERNS_KEY: &str = "led";
+const ADV_TIMEOUT_KEY: &str = "adv_timeout";
+
+/// Name advertised until the phone sets another one
+const DEFAULT_NAME: &str = "Byke";
+/// Longest name accepted, the advertising packet has little room left
+pub const MAX_NAME_LEN: usize = 12;
+
+/// Advertising interval, in units of 0.625 ms
+const DEFAULT_ADV_MIN: u16 = 0x20;
+const DEFAULT_ADV_MAX: u16 = 0x40;
+
+/// Seconds between two position notifications, until the phone sets another rate
+const DEFAULT_POSITION_RATE: u16 = 5;
+
+/// Longest sensor name kept, after the address type and the address
+const MAX_SENSOR_NAME_LEN: usize = 20;
+
+/// Settings of the stick, kept in the NVS
+pub struct Config {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Config {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    fn get_flag(&self, key: &str) -> Option<bool> {
+        let mut buffer = [0u8];
+        self.nvs
+            .get_raw(key, &mut buffer)
+            .ok()
+            .flatten()
+            .map(|value| value == [1])
+    }
+
+    fn set_flag(&mut self, key: &str, enabled: bool) {
+        self.nvs.set_raw(key, &[enabled as u8]).ok().or_else(|| {
+            warn!("Failed to save {}", key);
+            None
+        });
+    }
+
+    fn get_sensor_key(kind: SensorKind) -> &'static str {
+        match kind {
+            SensorKind::HeartRate => HEART_RATE_SENSOR_KEY,
+            SensorKind::Cadence => CADENCE_SENSOR_KEY,
+            SensorKind::Unit => UNIT_SENSOR_KEY,
+        }
+    }
+
+    fn set_u16(&mut self, key: &str, value: u16) {
+        self.nvs
+            .set_raw(key, &value.to_be_bytes())
+            .ok()
+            .or_else(|| {
+                warn!("Failed to save {}", key);
+                None
+            });
+    }
+
+    fn get_u16(&self, key: &str) -> Option<u16> {
+        let mut buffer = [0u8; 2];
+        self.nvs
+            .get_raw(key, &mut buffer)
+            .ok()
+            .flatten()
+            .and_then(|value| match value {
+                [high, low] => Some(u16::from_be_bytes([*high, *low])),
+                _ => None,
+            })
+    }
+}
+
+impl Settings for Config {
+    fn get_name(&self) -> String {
+        let mut buffer = [0u8; MAX_NAME_LEN];
+        self.nvs
+            .get_raw(NAME_KEY, &mut buffer)
+            .ok()
+            .flatten()
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .unwrap_or_else(|| String::from(DEFAULT_NAME))
+    }
+
+    fn set_name(&mut self, name: &str) {
+        // Cut on a character boundary, the name is read back as UTF-8
+        let mut len = name.len().min(MAX_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.nvs
+            .set_raw(NAME_KEY, &name.as_bytes()[..len])
+            .ok()
+            .or_else(|| {
+                warn!("Failed to save the name");
+                None
+            });
+    }
+
+    fn get_adv_interval(&self) -> (u16, u16) {
+        let min = self.get_u16(ADV_MIN_KEY).unwrap_or(DEFAULT_ADV_MIN);
+        let max = self.get_u16(ADV_MAX_KEY).unwrap_or(DEFAULT_ADV_MAX);
+        (min, max.max(min))
+    }
+
+    fn set_adv_interval(&mut self, (min, max): (u16, u16)) {
+        self.set_u16(ADV_MIN_KEY, min);
+        self.set_u16(ADV_MAX_KEY, max);
+    }
+
+    fn get_whitelist(&self) -> bool {
+        self.get_flag(WHITELIST_KEY).unwrap_or(false)
+    }
+
+    fn set_whitelist(&mut self, enabled: bool) {
+        self.set_flag(WHITELIST_KEY, enabled);
+    }
+
+    fn get_advertising(&self) -> bool {
+        self.get_flag(ADVERTISING_KEY).unwrap_or(true)
+    }
+
+    fn set_advertising(&mut self, enabled: bool) {
+        self.set_flag(ADVERTISING_KEY, enabled);
+    }
+    fn get_position_rate(&self) -> u16 {
+        self.get_u16(POSITION_RATE_KEY)
+            .unwrap_or(DEFAULT_POSITION_RATE)
+    }
+
+    fn set_position_rate(&mut self, seconds: u16) {
+        self.nvs
+            .set_raw(POSITION_RATE_KEY, &seconds.to_be_bytes())
+            .ok()
+            .or_else(|| {
+                warn!("Failed to save the position rate");
+                None
+            });
+    }
+    fn get_anti_theft(&self) -> bool {
+        self.get_flag(ANTI_THEFT_KEY).unwrap_or(false)
+    }
+
+    fn set_anti_theft(&mut self, armed: bool) {
+        self.set_flag(ANTI_THEFT_KEY, armed);
+    }
+    fn get_i2c_address(&self) -> u8 {
+        let mut buffer = [0u8];
+        self.nvs
+            .get_raw(I2C_ADDRESS_KEY, &mut buffer)
+            .ok()
+            .flatten()
+            .and_then(|value| value.first().copied())
+            .filter(|address| UNIT_ADDRESSES.contains(address))
+            .unwrap_or(DEFAULT_ADDRESS)
+    }
+
+    fn set_i2c_address(&mut self, address: u8) {
+        self.nvs
+            .set_raw(I2C_ADDRESS_KEY, &[address])
+            .ok()
+            .or_else(|| {
+                warn!("Failed to save the I2C address");
+                None
+            });
+    }
+
+    fn get_sensor(&self, kind: SensorKind) -> Option<Sensor> {
+        let mut buffer = [0u8; 7 + MAX_SENSOR_NAME_LEN];
+        self.nvs
+            .get_raw(Config::get_sensor_key(kind), &mut buffer)
+            .ok()
+            .flatten()
+            .and_then(|value| match value {
+                [random, a, b, c, d, e, f, name @ ..] => Some(Sensor {
+                    address: [*a, *b, *c, *d, *e, *f],
+                    random_address: *random != 0,
+                    kind,
+                    name: String::from_utf8_lossy(name).to_string(),
+                    rssi: 0,
+                }),
+                _ => None,
+            })
+    }
+
+    fn set_sensor(&mut self, sensor: &Sensor) {
+        let mut value = vec![sensor.random_address as u8];
+        value.extend_from_slice(&sensor.address);
+        let mut len = sensor.name.len().min(MAX_SENSOR_NAME_LEN);
+        while !sensor.name.is_char_boundary(len) {
+            len -= 1;
+        }
+        value.extend_from_slice(&sensor.name.as_bytes()[..len]);
+        self.nvs
+            .set_raw(Config::get_sensor_key(sensor.kind), &value)
+            .ok()
+            .or_else(|| {
+                warn!("Failed to save the sensor");
+                None
+            });
+    }
+
+    fn get_auth_required(&self) -> bool {
+        self.get_flag(AUTH_REQUIRED_KEY).unwrap_or(true)
+    }
+
+    fn set_auth_required(&mut self, required: bool) {
+        self.set_flag(AUTH_REQUIRED_KEY, required);
+    }
+
+    fn get_led_patterns(&self) -> bool {
+        self.get_flag(LED_PATTERNS_KEY).unwrap_or(true)
+    }
+
+    fn set_led_patterns(&mut self, enabled: bool) {
+        self.set_flag(LED_PATTERNS_KEY, enabled);
+    }
+
+    fn get_adv_timeout(&self) -> u16 {
+        self.get_u16(ADV_TIMEOUT_KEY).unwrap_or(DEFAULT_ADV_TIMEOUT)
+    }
+
+    fn set_adv_timeout(&mut self, seconds: u16) {
+        self.set_u16(ADV_TIMEOUT_KEY, seconds);
+    }
+}
@@ -0,0 +1,82 @@
+use std::{panic, ptr};
+
+use esp_idf_sys::{
+    esp, esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_TASK_WDT,
+    esp_reset_reason_t_ESP_RST_WDT, esp_task_wdt_add, esp_task_wdt_reset,
+};
+use log::warn;
+use shared::RESTART_REPORT;
+
+/// Marks a panic message written by the previous run
+const PANIC_MAGIC: u32 = 0xb1ce_dead;
+/// Longest panic message kept, the rest is cut
+const MAX_PANIC_LEN: usize = 96;
+
+/// Panic message of the previous run, kept in the RTC memory which is not
+/// cleared by the restart that follows a panic
+#[link_section = ".rtc_noinit"]
+static mut PANIC_MARK: u32 = 0;
+#[link_section = ".rtc_noinit"]
+static mut PANIC_LEN: usize = 0;
+#[link_section = ".rtc_noinit"]
+static mut PANIC_REASON: [u8; MAX_PANIC_LEN] = [0; MAX_PANIC_LEN];
+
+/// Keeps the panic message for the next boot, then panics as usual
+pub fn init_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let reason = info.to_string();
+        let len = reason.len().min(MAX_PANIC_LEN);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                reason.as_ptr(),
+                ptr::addr_of_mut!(PANIC_REASON) as *mut u8,
+                len,
+            );
+            PANIC_LEN = len;
+            PANIC_MARK = PANIC_MAGIC;
+        }
+        default_hook(info);
+    }));
+}
+
+/// Restarts the stick when the main loop does not feed the watchdog in time.
+/// The timeout is set by `CONFIG_ESP_TASK_WDT_TIMEOUT_S`.
+pub fn init_watchdog() {
+    esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to watch the main task");
+            None
+        });
+}
+
+pub fn feed_watchdog() {
+    unsafe { esp_task_wdt_reset() };
+}
+
+/// Why the previous run ended, when it did not end on a power-on or a requested restart
+pub fn take_report() -> Option<String> {
+    let panic_reason = unsafe {
+        let valid = PANIC_MARK == PANIC_MAGIC;
+        PANIC_MARK = 0;
+        valid.then(|| {
+            let reason = &*ptr::addr_of!(PANIC_REASON);
+            String::from_utf8_lossy(&reason[..PANIC_LEN.min(MAX_PANIC_LEN)]).to_string()
+        })
+    };
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_PANIC => Some(format!(
+            "{} after a crash: {}",
+            RESTART_REPORT,
+            panic_reason.unwrap_or_else(|| String::from("unknown"))
+        )),
+        esp_reset_reason_t_ESP_RST_TASK_WDT
+        | esp_reset_reason_t_ESP_RST_INT_WDT
+        | esp_reset_reason_t_ESP_RST_WDT => Some(format!("{} by the watchdog", RESTART_REPORT)),
+        esp_reset_reason_t_ESP_RST_BROWNOUT => Some(format!("{} on low battery", RESTART_REPORT)),
+        _ => None,
+    }
+}
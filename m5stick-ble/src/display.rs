@@ -0,0 +1,102 @@
+use anyhow::anyhow;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::Rgb565,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use esp_idf_hal::{
+    delay::Ets,
+    gpio::{Gpio18, Gpio23, Output, PinDriver},
+    spi::{SpiDeviceDriver, SpiDriver},
+};
+use log::warn;
+use st7735_lcd::{Orientation, ST7735};
+
+use crate::bridge::BridgeStatus;
+
+/// Size of the ST7735S of the M5StickC, held in landscape
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 80;
+/// Position of the visible area in the controller memory, in landscape
+const X_OFFSET: u16 = 1;
+const Y_OFFSET: u16 = 26;
+
+/// Height of a line of text
+const LINE_HEIGHT: i32 = 13;
+
+type Screen = ST7735<
+    SpiDeviceDriver<'static, SpiDriver<'static>>,
+    PinDriver<'static, Gpio23, Output>,
+    PinDriver<'static, Gpio18, Output>,
+>;
+
+/// Screen of the stick, showing the state of the bridge for debugging without the M5Go
+pub struct Display {
+    screen: Screen,
+    /// Status currently drawn, the screen is only drawn again when it changes
+    shown: Option<BridgeStatus>,
+}
+
+impl Display {
+    pub fn new(
+        spi: SpiDeviceDriver<'static, SpiDriver<'static>>,
+        dc: PinDriver<'static, Gpio23, Output>,
+        rst: PinDriver<'static, Gpio18, Output>,
+    ) -> anyhow::Result<Self> {
+        // The panel is BGR with inverted colors
+        let mut screen = ST7735::new(spi, dc, rst, false, true, WIDTH, HEIGHT);
+        screen
+            .init(&mut Ets)
+            .map_err(|_| anyhow!("Unable to init the screen"))?;
+        screen
+            .set_orientation(&Orientation::Landscape)
+            .map_err(|_| anyhow!("Unable to rotate the screen"))?;
+        screen.set_offset(X_OFFSET, Y_OFFSET);
+        screen
+            .clear(Rgb565::BLACK)
+            .map_err(|_| anyhow!("Unable to clear the screen"))?;
+        Ok(Self {
+            screen,
+            shown: None,
+        })
+    }
+
+    pub fn show(&mut self, status: &BridgeStatus) {
+        if self.shown.as_ref() == Some(status) {
+            return;
+        }
+        let lines = [
+            format!("BLE: {:?} ({})", status.state, status.connections),
+            format!("Last: {}", status.last_command),
+            format!(
+                "M5Go: {} ({})",
+                if status.m5go_connected { "ok" } else { "lost" },
+                status.to_m5go
+            ),
+            format!("To phone: {}", status.to_phone),
+            format!("Battery: {}%", status.battery),
+        ];
+        let style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+        let drawn = Rectangle::new(Point::zero(), Size::new(WIDTH, HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(&mut self.screen)
+            .and_then(|_| {
+                lines.iter().enumerate().try_for_each(|(i, line)| {
+                    Text::with_baseline(
+                        line,
+                        Point::new(2, 2 + i as i32 * LINE_HEIGHT),
+                        style,
+                        Baseline::Top,
+                    )
+                    .draw(&mut self.screen)
+                    .map(|_| ())
+                })
+            });
+        match drawn {
+            Ok(_) => self.shown = Some(status.clone()),
+            Err(_) => warn!("Unable to draw the status"),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::Instant;
+
+/// What the LED of the stick shows, from the most to the least urgent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LedStatus {
+    /// A new firmware is being written
+    Ota,
+    /// The M5Go stopped reading the stick
+    Error,
+    Connected,
+    Advertising,
+    /// The BLE is stopped
+    Idle,
+    /// The app turned the status patterns off
+    Off,
+}
+
+/// Blink pattern of each status, as alternating on and off durations in milliseconds,
+/// repeated. A single duration keeps the LED on, none keeps it off.
+const PATTERNS: [(LedStatus, &[u32]); 6] = [
+    (LedStatus::Ota, &[300, 100]),
+    (LedStatus::Error, &[100, 100]),
+    (LedStatus::Connected, &[1000]),
+    (LedStatus::Advertising, &[100, 900]),
+    (LedStatus::Idle, &[50, 2950]),
+    (LedStatus::Off, &[]),
+];
+
+fn get_pattern(status: LedStatus) -> &'static [u32] {
+    PATTERNS
+        .iter()
+        .find(|(pattern_status, _)| *pattern_status == status)
+        .map(|(_, pattern)| *pattern)
+        .unwrap_or_default()
+}
+
+/// Plays the pattern of the current status, from its start whenever the status changes
+pub struct StatusLed {
+    status: LedStatus,
+    since: Instant,
+}
+
+impl Default for StatusLed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusLed {
+    pub fn new() -> Self {
+        Self {
+            status: LedStatus::Idle,
+            since: Instant::now(),
+        }
+    }
+
+    /// Whether the LED must be lit now to show `status`
+    pub fn update(&mut self, status: LedStatus) -> bool {
+        if status != self.status {
+            self.status = status;
+            self.since = Instant::now();
+        }
+        let pattern = get_pattern(status);
+        let period: u32 = pattern.iter().sum();
+        if pattern.len() == 1 || period == 0 {
+            return pattern.is_empty() == false;
+        }
+        let mut elapsed = (self.since.elapsed().as_millis() % period as u128) as u32;
+        for (i, duration) in pattern.iter().enumerate() {
+            if elapsed < *duration {
+                return i % 2 == 0;
+            }
+            elapsed -= duration;
+        }
+        false
+    }
+}
@@ -0,0 +1,30 @@
+// The firmware checks booleans with `== false` and updates the shared tables with
+// `and_then(|table| { ..; Some(()) })`
+#![allow(clippy::bool_comparison, clippy::bind_instead_of_map)]
+
+pub mod ble;
+pub mod bridge;
+pub mod led;
+pub mod queues;
+pub mod sensors;
+
+#[cfg(target_os = "espidf")]
+pub mod battery;
+#[cfg(target_os = "espidf")]
+pub mod clock;
+#[cfg(target_os = "espidf")]
+pub mod config;
+#[cfg(target_os = "espidf")]
+pub mod crash;
+#[cfg(target_os = "espidf")]
+pub mod display;
+#[cfg(target_os = "espidf")]
+pub mod link;
+#[cfg(target_os = "espidf")]
+pub mod logger;
+#[cfg(target_os = "espidf")]
+pub mod ota;
+#[cfg(target_os = "espidf")]
+pub mod power;
+#[cfg(target_os = "espidf")]
+pub mod receiver;
@@ -0,0 +1,300 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{channel, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_hal::i2c::I2cSlaveDriver;
+use esp_idf_sys::{esp, i2c_port_t, i2c_reset_tx_fifo};
+use log::{info, warn};
+use shared::{
+    packet,
+    registers::{
+        MAX_LINK_FRAME_LEN, REG_INFO, REG_RX_FIFO, REG_STATUS, REG_TX_FIFO, STATUS_RX_ERROR,
+        STATUS_TX_PENDING, TX_REPEAT, UNIT_BLE,
+    },
+    unix_ms, Commands, Transport, PROTOCOL_VERSION,
+};
+
+/// Wait for the header of a write, in ticks. The task checks for one every tick, so that
+/// the reply is loaded right after the request.
+const HEADER_TIMEOUT: u32 = 1;
+/// Wait for the rest of a write once its header came, in ticks. A full frame takes
+/// about 50 ms at 100 kHz.
+const BODY_TIMEOUT: u32 = 10;
+const WRITE_TIMEOUT: u32 = 200;
+const TASK_STACK_SIZE: usize = 4096;
+
+/// Frames kept for the M5Go, the oldest ones are dropped past this
+const MAX_TX_FRAMES: usize = 20;
+
+/// Silence after which the M5Go is deemed gone, it polls the status several times a second
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Registers of the map, shared by the link and the task serving them
+struct Registers {
+    tx_fifo: VecDeque<Vec<u8>>,
+    /// Frame last served, until the M5Go asks for the next one
+    last_frame: Option<Vec<u8>>,
+    /// Set when a frame written by the M5Go came corrupted, until the status is read
+    rx_error: bool,
+    retries: u32,
+    last_exchange: Instant,
+}
+
+impl Registers {
+    fn is_connected(&self) -> bool {
+        self.last_exchange.elapsed() < LINK_TIMEOUT
+    }
+}
+
+/// Transport to the M5Go, as an I2C slave exposing the register map of
+/// `shared::registers`. The registers are served by a task of their own, the M5Go
+/// reading their reply right after its request.
+pub struct I2cSlaveLink {
+    registers: Arc<Mutex<Registers>>,
+    /// Frames written by the M5Go
+    frames: Receiver<Vec<u8>>,
+}
+
+impl I2cSlaveLink {
+    /// Starts serving the registers, `wakeup` being notified of each frame written
+    pub fn new(
+        driver: I2cSlaveDriver<'static>,
+        port: i2c_port_t,
+        wakeup: SyncSender<()>,
+    ) -> anyhow::Result<Self> {
+        let registers = Arc::new(Mutex::new(Registers {
+            tx_fifo: VecDeque::new(),
+            last_frame: None,
+            rx_error: false,
+            retries: 0,
+            last_exchange: Instant::now(),
+        }));
+        let (sender, frames) = channel();
+        let mut server = RegisterServer {
+            driver,
+            port,
+            registers: Arc::clone(&registers),
+            frames: sender,
+            wakeup,
+        };
+        thread::Builder::new()
+            .name(String::from("i2c_slave"))
+            .stack_size(TASK_STACK_SIZE)
+            .spawn(move || server.run())?;
+        Ok(Self { registers, frames })
+    }
+}
+
+impl Transport for I2cSlaveLink {
+    /// Queues the frame until the M5Go reads the TX FIFO register
+    fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        if frame.len() > MAX_LINK_FRAME_LEN {
+            return Err(anyhow!("Frame too long for the M5Go"));
+        }
+        let mut registers = self
+            .registers
+            .lock()
+            .map_err(|_| anyhow!("Registers poisoned"))?;
+        if registers.tx_fifo.len() >= MAX_TX_FRAMES {
+            warn!("M5Go not reading, frame dropped");
+            registers.tx_fifo.pop_front();
+        }
+        registers.tx_fifo.push_back(frame.to_vec());
+        Ok(())
+    }
+
+    fn get_pending(&self) -> usize {
+        self.registers
+            .lock()
+            .map_or(0, |registers| registers.tx_fifo.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.registers
+            .lock()
+            .is_ok_and(|registers| registers.is_connected())
+    }
+
+    fn get_retries(&self) -> u32 {
+        self.registers
+            .lock()
+            .map_or(0, |registers| registers.retries)
+    }
+
+    /// Next frame written by the M5Go
+    fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.frames.try_recv().ok())
+    }
+}
+
+/// Task answering the M5Go: each write starts with the register it is for
+struct RegisterServer {
+    driver: I2cSlaveDriver<'static>,
+    port: i2c_port_t,
+    registers: Arc<Mutex<Registers>>,
+    frames: Sender<Vec<u8>>,
+    wakeup: SyncSender<()>,
+}
+
+impl RegisterServer {
+    fn run(&mut self) {
+        let mut header = [0u8; 1];
+        loop {
+            match self.driver.read(&mut header, HEADER_TIMEOUT) {
+                Ok(1) => {}
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!("Reading the M5Go failed: {}", err);
+                    continue;
+                }
+            }
+            if let Err(err) = self.serve(header[0]) {
+                warn!("Write of the M5Go dropped: {}", err);
+                // What is left of the write would be taken for headers
+                self.drain();
+            }
+        }
+    }
+
+    fn serve(&mut self, register: u8) -> anyhow::Result<()> {
+        {
+            let mut registers = self
+                .registers
+                .lock()
+                .map_err(|_| anyhow!("Registers poisoned"))?;
+            // A M5Go coming back has restarted, the frames kept for it are stale
+            if registers.is_connected() == false {
+                info!(
+                    "M5Go back, {} stale frames dropped",
+                    registers.tx_fifo.len()
+                );
+                registers.tx_fifo.clear();
+                registers.last_frame = None;
+            }
+            registers.last_exchange = Instant::now();
+        }
+
+        match register {
+            REG_RX_FIFO => self.receive_frame(),
+            REG_STATUS | REG_TX_FIFO | REG_INFO => {
+                let mut sequence = [0u8; 1];
+                self.read_exact(&mut sequence)?;
+                let reply = match register {
+                    REG_STATUS => self.get_status()?,
+                    REG_TX_FIFO => {
+                        let mut flags = [0u8; 1];
+                        self.read_exact(&mut flags)?;
+                        self.next_frame(flags[0] & TX_REPEAT != 0)?
+                    }
+                    _ => vec![UNIT_BLE, PROTOCOL_VERSION],
+                };
+                self.reply(sequence[0], &reply)
+            }
+            _ => Err(anyhow!("Unknown register {:#04x}", register)),
+        }
+    }
+
+    /// Reads the packet following the header, up to its CRC
+    fn receive_frame(&mut self) -> anyhow::Result<()> {
+        let mut packet = vec![0u8; 2];
+        let received = self.read_exact(&mut packet).and_then(|_| {
+            let len = u16::from_be_bytes([packet[0], packet[1]]) as usize;
+            if len > MAX_LINK_FRAME_LEN {
+                return Err(anyhow!("Frame too long"));
+            }
+            packet.resize(len + packet::OVERHEAD, 0);
+            self.read_exact(&mut packet[2..])?;
+            Ok(packet::decode(&packet)?.to_vec())
+        });
+        let frame = match received {
+            Ok(frame) => frame,
+            Err(err) => {
+                let mut registers = self
+                    .registers
+                    .lock()
+                    .map_err(|_| anyhow!("Registers poisoned"))?;
+                registers.rx_error = true;
+                registers.retries = registers.retries.wrapping_add(1);
+                return Err(err);
+            }
+        };
+        if let Some(len) = Commands::frame_len(&frame).filter(|len| *len <= frame.len()) {
+            self.frames.send(frame[..len].to_vec())?;
+            self.wakeup.try_send(()).ok();
+        }
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut registers = self
+            .registers
+            .lock()
+            .map_err(|_| anyhow!("Registers poisoned"))?;
+        let pending = registers.tx_fifo.len().min(u8::MAX as usize) as u8;
+        let mut flags = if pending > 0 { STATUS_TX_PENDING } else { 0 };
+        if registers.rx_error {
+            flags |= STATUS_RX_ERROR;
+            registers.rx_error = false;
+        }
+        Ok(vec![flags, pending])
+    }
+
+    /// Next pending frame, or the previous one again when it came corrupted
+    fn next_frame(&mut self, repeat: bool) -> anyhow::Result<Vec<u8>> {
+        let mut registers = self
+            .registers
+            .lock()
+            .map_err(|_| anyhow!("Registers poisoned"))?;
+        let frame = if repeat {
+            registers.retries = registers.retries.wrapping_add(1);
+            registers.last_frame.clone()
+        } else {
+            let frame = registers.tx_fifo.pop_front().map(refresh_time);
+            registers.last_frame = frame.clone();
+            frame
+        };
+        Ok(frame.unwrap_or_else(|| Commands::NONE.get_stream().unwrap_or_default()))
+    }
+
+    /// Loads the reply of a register, in place of whatever the M5Go did not read. It
+    /// starts with the sequence of the request, for the M5Go to tell it from a stale one.
+    fn reply(&mut self, sequence: u8, reply: &[u8]) -> anyhow::Result<()> {
+        let mut payload = Vec::with_capacity(reply.len() + 1);
+        payload.push(sequence);
+        payload.extend_from_slice(reply);
+        esp!(unsafe { i2c_reset_tx_fifo(self.port) })?;
+        self.driver
+            .write(&packet::encode(&payload), WRITE_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
+        match self.driver.read(buffer, BODY_TIMEOUT)? {
+            len if len == buffer.len() => Ok(()),
+            _ => Err(anyhow!("Write cut short")),
+        }
+    }
+
+    /// Drops what is left of a write
+    fn drain(&mut self) {
+        let mut buffer = [0u8; 64];
+        while self.driver.read(&mut buffer, 0).is_ok_and(|len| len > 0) {}
+    }
+}
+
+/// A time sync carries the time at which the M5Go reads it, not the one at which it was
+/// queued. The stick clock has been set by the same command.
+fn refresh_time(frame: Vec<u8>) -> Vec<u8> {
+    if Commands::get_stream_code(&frame) == Some(Commands::SetTime(0).get_code()) {
+        Commands::SetTime(unix_ms()).get_stream().unwrap_or(frame)
+    } else {
+        frame
+    }
+}
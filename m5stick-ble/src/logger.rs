@@ -0,0 +1,90 @@
+use esp_idf_svc::log::EspLogger;
+use log::{Level, Log, Metadata, Record};
+use shared::{Commands, LogLevel};
+
+use crate::queues::{I2cQueue, LogQueue, Priority, MAX_LOG_LINES};
+
+/// Logs are not forwarded anymore once this many commands wait for the M5Go
+const MAX_PENDING_COMMANDS: usize = 10;
+
+/// Most verbose level sent on the log characteristic, the M5Go only gets `info!` and above
+const PHONE_LOG_LEVEL: Level = Level::Debug;
+
+/// Logger printing to the serial console like `EspLogger`, forwarding
+/// `info!`/`warn!`/`error!` messages to the M5Go over I2C and keeping
+/// the lines for the log characteristic
+pub struct BridgeLogger {
+    commands: I2cQueue,
+    logs: LogQueue,
+}
+
+impl BridgeLogger {
+    pub fn initialize(commands: I2cQueue, logs: LogQueue) {
+        let logger = Box::leak(Box::new(BridgeLogger { commands, logs }));
+        log::set_logger(logger)
+            .map(|_| log::set_max_level(PHONE_LOG_LEVEL.to_level_filter()))
+            .ok()
+            .or_else(|| {
+                println!("Logger already initialized");
+                None
+            });
+    }
+
+    fn keep_line(&self, record: &Record) {
+        if record.level() > PHONE_LOG_LEVEL {
+            return;
+        }
+        self.logs.try_lock().ok().and_then(|logs| {
+            let mut logs = logs.borrow_mut();
+            if logs.len() >= MAX_LOG_LINES {
+                logs.pop_front();
+            }
+            logs.push_back(format!(
+                "{} {}: {}",
+                &record.level().as_str()[..1],
+                record.target(),
+                record.args()
+            ));
+            Some(())
+        });
+    }
+}
+
+impl Log for BridgeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= PHONE_LOG_LEVEL || EspLogger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        EspLogger.log(record);
+        self.keep_line(record);
+
+        let level = match record.level() {
+            Level::Error => LogLevel::Error,
+            Level::Warn => LogLevel::Warn,
+            Level::Info => LogLevel::Info,
+            _ => return,
+        };
+
+        self.commands.try_lock().ok().and_then(|commands| {
+            let mut commands = commands.borrow_mut();
+            if commands.len() < MAX_PENDING_COMMANDS {
+                commands.push(
+                    (
+                        Commands::Log {
+                            level,
+                            text: record.args().to_string(),
+                        },
+                        None,
+                    ),
+                    Priority::Normal,
+                );
+            }
+            Some(())
+        });
+    }
+
+    fn flush(&self) {
+        EspLogger.flush();
+    }
+}
@@ -1,31 +1,59 @@
 use esp_idf_hal::{
     delay::FreeRtos,
-    gpio::PinDriver,
-    i2c::{I2cSlaveConfig, I2cSlaveDriver},
-    prelude::Peripherals,
+    gpio::{AnyIOPin, Pin, PinDriver},
+    i2c::{I2c, I2cConfig, I2cDriver, I2cSlaveConfig, I2cSlaveDriver, I2C1},
+    prelude::*,
+    spi::{config::Config as SpiConfig, Dma, SpiDeviceDriver},
 };
 use esp_idf_sys as _;
 
 use std::{
-    cell::RefCell,
-    sync::{mpsc::sync_channel, Arc, Mutex},
+    sync::{mpsc::sync_channel, Arc},
+    time::{Duration, Instant},
 };
 
-use esp_idf_ble::{
-    AdvertiseData, AttributeValue, AutoResponse, BtUuid, EspBle, GattCharacteristic,
-    GattDescriptor, GattService, GattServiceEvent, ServiceUuid,
-};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     netif::{EspNetif, NetifStack},
     nvs::EspDefaultNvsPartition,
 };
 
-use esp_idf_sys::*;
+use esp_idf_sys::esp_restart;
+
+use log::{error, info};
+
+use shared::{registers::LINK_BUFFER_LEN, Commands, Coordinates};
+
+use m5stick_ble::{
+    battery::Battery,
+    ble::EspBleStack,
+    bridge::{Bridge, Settings, Shared},
+    config::Config,
+    crash,
+    display::Display,
+    led::StatusLed,
+    link::I2cSlaveLink,
+    logger::BridgeLogger,
+    power::{self, PowerManager},
+};
+
+/// How long the button must be held to toggle advertising, and to toggle the whitelist
+const LONG_PRESS: Duration = Duration::from_secs(1);
+const VERY_LONG_PRESS: Duration = Duration::from_secs(3);
+
+/// Time between two battery readings
+const BATTERY_PERIOD: Duration = Duration::from_secs(60);
+
+/// Longest wait for an event between two turns of the main loop, while commands
+/// go through and once the stick is idle
+const BUSY_PERIOD: Duration = Duration::from_millis(50);
+const IDLE_PERIOD: Duration = Duration::from_millis(500);
 
-use log::{info, warn};
+/// How long the screen stays lit after boot and after a button press
+const DISPLAY_TIMEOUT: Duration = Duration::from_secs(30);
 
-use shared::{BleState, Commands, Coordinates};
+/// Time left to the M5Go before the stick goes to deep sleep
+const SLEEP_DELAY: Duration = Duration::from_millis(500);
 
 fn get_bluetooth_mac(mac: [u8; 6]) -> String {
     let mut mac_str = String::new();
@@ -39,374 +67,190 @@ fn get_bluetooth_mac(mac: [u8; 6]) -> String {
     mac_str
 }
 
+/// Short identifier of the unit, appended to the advertised name to tell bikes apart
+fn get_unit_id(mac: [u8; 6]) -> String {
+    format!("{:02X}{:02X}", mac[4], mac[5].wrapping_add(2))
+}
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_sys::link_patches();
     let netif_stack = Arc::new(EspNetif::new(NetifStack::Sta).expect("Unable to init Netif Stack"));
 
-    let mac = get_bluetooth_mac(netif_stack.get_mac().expect("Unable to get MAC address"));
+    let raw_mac = netif_stack.get_mac().expect("Unable to get MAC address");
+    let mac = get_bluetooth_mac(raw_mac);
+    let unit_id = get_unit_id(raw_mac);
     println!("MAC: {}", mac);
 
     let peripherals = Peripherals::take().unwrap();
 
     let mut led = PinDriver::output(peripherals.pins.gpio10)?;
+    let button_pin = peripherals.pins.gpio37.pin();
+    let button = PinDriver::input(peripherals.pins.gpio37)?;
+
+    #[allow(unused)]
+    let default_nvs = Arc::new(EspDefaultNvsPartition::take().unwrap());
+
+    FreeRtos::delay_us(100_u32);
+
+    // Read first, the I2C address is one of the settings
+    let settings = Config::new(default_nvs.as_ref().clone())?;
 
     // I2C
 
     let sda = peripherals.pins.gpio32;
+    let sda_pin = sda.pin();
     let scl = peripherals.pins.gpio33;
     let i2c = peripherals.i2c1;
 
     let config = I2cSlaveConfig::new()
-        .rx_buffer_length(256)
-        .tx_buffer_length(256);
-    let mut driver = I2cSlaveDriver::new(i2c, sda, scl, 0x16, &config)?;
+        .rx_buffer_length(LINK_BUFFER_LEN)
+        .tx_buffer_length(LINK_BUFFER_LEN);
+    let i2c = I2cSlaveDriver::new(i2c, sda, scl, settings.get_i2c_address(), &config)?;
+
+    // Internal bus of the power IC
+    let config = I2cConfig::new().baudrate(400.kHz().into());
+    let mut battery = Battery::new(I2cDriver::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio21,
+        peripherals.pins.gpio22,
+        &config,
+    )?);
+
+    // Screen
+    let spi = SpiDeviceDriver::new_single(
+        peripherals.spi2,
+        peripherals.pins.gpio13,
+        peripherals.pins.gpio15,
+        Option::<AnyIOPin>::None,
+        Dma::Disabled,
+        Some(peripherals.pins.gpio5),
+        &SpiConfig::new().baudrate(20.MHz().into()),
+    )?;
+    let mut display = Display::new(
+        spi,
+        PinDriver::output(peripherals.pins.gpio23)?,
+        PinDriver::output(peripherals.pins.gpio18)?,
+    )
+    .ok()
+    .or_else(|| {
+        println!("Unable to init the screen");
+        None
+    });
+    battery.set_backlight(display.is_some());
+    let mut display_until = Some(Instant::now() + DISPLAY_TIMEOUT);
 
     // BLE
-    esp_idf_svc::log::EspLogger::initialize_default();
-
-    let commands_ble = Arc::new(Mutex::new(RefCell::new(Vec::<Commands>::new())));
-    let com_ble = Arc::clone(&commands_ble);
-    let com_ble2 = Arc::clone(&commands_ble);
+    // Handlers wake the main loop up as soon as they queued something
+    let (wakeup, events) = sync_channel(1);
+    let i2c = I2cSlaveLink::new(i2c, I2C1::port(), wakeup.clone())?;
+    let shared = Shared::new(wakeup);
 
-    let commands_to_send_i2c = Arc::new(Mutex::new(RefCell::new(Vec::<Commands>::new())));
-    let cts_i2c = Arc::clone(&commands_to_send_i2c);
+    BridgeLogger::initialize(Arc::clone(&shared.to_m5go), Arc::clone(&shared.logs));
+    crash::init_panic_hook();
 
-    let state = Arc::new(Mutex::new(RefCell::new(BleState::NONE)));
-    let s_connect = Arc::clone(&state);
-    let s_disconnect = Arc::clone(&state);
+    // Forwarded to the M5Go by the logger, so that the rider knows the bridge restarted
+    if let Some(report) = crash::take_report() {
+        error!("{}", report);
+    }
 
     #[allow(unused)]
     let sys_loop_stack = Arc::new(EspSystemEventLoop::take().expect("Unable to init sys_loop"));
 
-    #[allow(unused)]
-    let default_nvs = Arc::new(EspDefaultNvsPartition::take().unwrap());
-
-    FreeRtos::delay_us(100_u32);
-
-    let mut ble = EspBle::new("ESP32".into(), default_nvs).unwrap();
-
-    let (s, r) = sync_channel(1);
-
-    ble.register_gatt_service_application(1, move |gatts_if, reg| {
-        if let GattServiceEvent::Register(reg) = reg {
-            info!("Service registered with {:?}", reg);
-            s.send(gatts_if).expect("Unable to send result");
-        } else {
-            warn!("What are you doing here??");
-        }
-    })
-    .expect("Unable to register service");
+    let name = format!("{} {}", settings.get_name(), unit_id);
+    let ble = EspBleStack::new(default_nvs, name, &shared)?;
 
-    let svc_uuid = BtUuid::Uuid16(ServiceUuid::Battery as u16);
+    let mut bridge = Bridge::new(ble, i2c, settings, shared, mac, unit_id);
+    bridge.start();
 
-    let svc = GattService::new_primary(svc_uuid, 4, 1);
+    let mut battery_read: Option<Instant> = None;
+    let mut pressed_since: Option<Instant> = None;
+    let mut status_led = StatusLed::new();
 
-    info!("GattService to be created: {:?}", svc);
+    bridge.send_to_phone(&Commands::NewStep(Coordinates::new(-5.6, 3.5)));
 
-    let gatts_if = r.recv().expect("Unable to receive value");
+    // Watched from here only, setting up the BLE takes longer than the timeout
+    crash::init_watchdog();
 
-    let (s, r) = sync_channel(1);
+    let mut power = PowerManager::new(sda_pin)?;
 
-    ble.register_connect_handler(gatts_if, move |_gatts_if, connect| {
-        if let GattServiceEvent::Connect(connect) = connect {
-            info!("Connect event: {:?}", connect);
-            s_connect.try_lock().ok().and_then(|state| {
-                state.replace(BleState::Connected);
-                Some(())
-            });
+    loop {
+        crash::feed_watchdog();
+
+        // Shown while awake or on a button press only, the LED would cost more than the
+        // sleep saves. The LED is lit on a low level.
+        let lit = status_led.update(bridge.get_led_status());
+        let shown = power.is_awake() || pressed_since.is_some();
+        led.set_level((shown && lit == false).into())?;
+
+        if battery_read.is_none_or(|read| read.elapsed() >= BATTERY_PERIOD) {
+            battery_read = Some(Instant::now());
+            if let Some(level) = battery.get_level() {
+                bridge.set_battery_level(level);
+            }
         }
-    });
 
-    ble.register_disconnect_handler(gatts_if, move |_gatts_if, disconnect| {
-        if let GattServiceEvent::Disconnect(disconnect) = disconnect {
-            info!("Disconnect event: {:?}", disconnect);
+        // Acts on release, once it is known how long the button was held
+        if button.is_low() {
+            pressed_since.get_or_insert_with(Instant::now);
+            if display.is_some() && display_until.is_none() {
+                battery.set_backlight(true);
+            }
+            display_until = Some(Instant::now() + DISPLAY_TIMEOUT);
+        } else if let Some(since) = pressed_since.take() {
+            let held = since.elapsed();
+            if held >= VERY_LONG_PRESS {
+                bridge.toggle_whitelist();
+            } else if held >= LONG_PRESS {
+                bridge.toggle_advertising();
+            } else {
+                bridge.resume_advertising();
+            }
         }
-        s_disconnect.try_lock().ok().and_then(|state| {
-            state.replace(BleState::Disconnected);
-            Some(())
-        });
-        com_ble2.try_lock().ok().and_then(|commands| {
-            commands.borrow_mut().insert(0, Commands::StartBle);
-            Some(())
-        });
-    });
 
-    ble.create_service(gatts_if, svc, move |gatts_if, create| {
-        if let GattServiceEvent::Create(create) = create {
-            info!(
-                "Service created with {{ \tgatts_if: {}\tstatus: {}\n\thandle: {}\n}}",
-                gatts_if, create.status, create.service_handle
-            );
-            s.send(create.service_handle).expect("Unable to send value");
+        if bridge.poll() {
+            power.keep_awake();
         }
-    })
-    .expect("Unable to create service");
-
-    let svc_handle = r.recv().expect("Unable to receive value");
+        power.set_m5go_connected(bridge.is_m5go_connected());
+        power.update();
 
-    ble.start_service(svc_handle, |_, start| {
-        if let GattServiceEvent::StartComplete(start) = start {
-            info!("Service started for handle: {}", start.service_handle);
-        }
-    })
-    .expect("Unable to start ble service");
-
-    let attr_value: AttributeValue<12> = AttributeValue::new_with_value(&[
-        0x48, 0x65, 0x6C, 0x6C, 0x6F, 0x20, 0x57, 0x6F, 0x72, 0x6C, 0x64,
-    ]);
-    let charac = GattCharacteristic::new(
-        BtUuid::Uuid16(0xff01),
-        (ESP_GATT_PERM_READ | ESP_GATT_PERM_WRITE) as _,
-        (ESP_GATT_CHAR_PROP_BIT_READ | ESP_GATT_CHAR_PROP_BIT_WRITE) as _,
-        attr_value,
-        AutoResponse::ByApp,
-    );
-
-    let (s, r) = sync_channel(1);
-
-    ble.add_characteristic(svc_handle, charac, move |_, add_char| {
-        if let GattServiceEvent::AddCharacteristicComplete(add_char) = add_char {
-            info!("Attr added with handle: {}", add_char.attr_handle);
-            s.send(add_char.attr_handle).expect("Unable to send value");
-        }
-    })
-    .expect("Unable to add characteristic");
-
-    let char_attr_handle = r.recv().expect("Unable to recv attr_handle");
-
-    let data = ble
-        .read_attribute_value(char_attr_handle)
-        .expect("Unable to read characteristic value");
-    info!("Characteristic values: {:?}", data);
-
-    let cdesc = GattDescriptor::new(
-        BtUuid::Uuid16(ESP_GATT_UUID_CHAR_CLIENT_CONFIG as u16),
-        ESP_GATT_PERM_READ as _,
-    );
-    ble.add_descriptor(svc_handle, cdesc, |_, add_desc| {
-        if let GattServiceEvent::AddDescriptorComplete(add_desc) = add_desc {
-            info!("Descriptor added with handle: {}", add_desc.attr_handle);
-        }
-    })
-    .expect("Unable to add characteristic");
-
-    let full_read_data = RefCell::new(Vec::<Vec<u8>>::new());
-    ble.register_read_handler(char_attr_handle, move |gatts_if, read| {
-        if let GattServiceEvent::Read(read) = read {
-            let mut data = full_read_data.borrow_mut();
-            if data.is_empty() {
-                let next_command = commands_ble
-                    .try_lock()
-                    .ok()
-                    .and_then(|commands| commands.borrow_mut().pop())
-                    .unwrap_or_default();
-                let slice = &next_command.get_stream();
-                for i in (0..slice.len()).step_by(20) {
-                    let end = std::cmp::min(i + 20, slice.len());
-                    data.insert(0, slice[i..end].to_vec());
+        if let Some(until) = display_until {
+            if Instant::now() < until {
+                if let Some(display) = display.as_mut() {
+                    display.show(&bridge.get_status());
                 }
-            };
-
-            esp_idf_ble::send(
-                gatts_if,
-                char_attr_handle,
-                read.conn_id,
-                read.trans_id,
-                esp_gatt_status_t_ESP_GATT_OK,
-                data.pop().unwrap().as_slice(),
-            )
-            .expect("Unable to send read response");
-        }
-    });
-
-    let full_write_data = RefCell::new(Vec::<u8>::new());
-
-    ble.register_write_handler(char_attr_handle, move |gatts_if, write| {
-        if let GattServiceEvent::Write(write) = write {
-            info!("Write event: {:?}", write.len);
-            if write.is_prep {
-                warn!("Unsupported write");
             } else {
-                let mut data = full_write_data.borrow_mut();
-                let mut value =
-                    unsafe { std::slice::from_raw_parts(write.value, write.len as usize) };
-
-                let mut d: Vec<u8> = vec![];
-                if data.is_empty() == false {
-                    data.extend_from_slice(value);
-                    if write.len == 20 && data.len() < *data.get(1).unwrap() as usize {
-                        return;
-                    }
-
-                    d.clone_from(&data);
-
-                    value = d.as_slice();
-                }
-
-                let back = Commands::parse(value)
-                    .ok()
-                    .and_then(|(command, len)| {
-                        if len > 20 && data.is_empty() {
-                            data.extend_from_slice(value);
-                            return None;
-                        }
-                        info!("Received Command: {:?}", command);
-                        commands_to_send_i2c.try_lock().ok().and_then(|commands| {
-                            commands.borrow_mut().insert(0, command);
-                            data.clear();
-                            Some(Commands::OK)
-                        })
-                    })
-                    .or_else(|| {
-                        if write.len != 20 {
-                            data.clear();
-                        }
-                        None
-                    })
-                    .unwrap_or_default();
-
-                if write.need_rsp {
-                    info!("need rsp");
-                    esp_idf_ble::send(
-                        gatts_if,
-                        char_attr_handle,
-                        write.conn_id,
-                        write.trans_id,
-                        esp_gatt_status_t_ESP_GATT_OK,
-                        back.get_stream().as_slice(),
-                    )
-                    .expect("Unable to send response");
-                }
+                display_until = None;
+                battery.set_backlight(false);
             }
         }
-    });
 
-    let adv_data = AdvertiseData {
-        include_name: true,
-        include_txpower: false,
-        min_interval: 6,
-        max_interval: 16,
-        service_uuid: Some(BtUuid::Uuid128([
-            0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0xFF, 0x00,
-            0x00, 0x00,
-        ])),
-        flag: (ESP_BLE_ADV_FLAG_GEN_DISC | ESP_BLE_ADV_FLAG_BREDR_NOT_SPT) as _,
-        ..Default::default()
-    };
-    ble.configure_advertising_data(adv_data, |_| {
-        info!("advertising configured");
-    })
-    .expect("Failed to configure advertising data");
-
-    let scan_rsp_data = AdvertiseData {
-        include_name: false,
-        include_txpower: true,
-        set_scan_rsp: true,
-        service_uuid: Some(BtUuid::Uuid128([
-            0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0xFF, 0x00,
-            0x00, 0x00,
-        ])),
-        ..Default::default()
-    };
-
-    ble.configure_advertising_data(scan_rsp_data, |_| {
-        info!("Advertising configured");
-    })
-    .expect("Failed to configure advertising data");
-
-    start_ble(&mut ble, Arc::clone(&state));
-
-    let mut t = 0;
-
-    com_ble.try_lock().ok().and_then(|commands| {
-        commands
-            .borrow_mut()
-            .insert(0, Commands::NewStep(Coordinates::new(-5.6, 3.5)));
-        Some(())
-    });
-
-    loop {
-        if t == 0 {
-            led.set_high()?;
-        } else if t == 2 {
-            led.set_low()?;
+        if bridge.must_reboot() {
+            info!("Restarting");
+            // Leaves time for the last notification to go out
+            FreeRtos::delay_ms(500);
+            unsafe { esp_restart() };
         }
-        t += 1;
-        t %= 4;
-
-        cts_i2c
-            .try_lock()
-            .ok()
-            .and_then(|commands| commands.borrow_mut().pop())
-            .and_then(|command| driver.write(command.get_stream().as_slice(), 200).ok());
-        let mut buffer = [0u8; 256];
-        if driver.read(&mut buffer, 50).is_ok() {
-            Commands::parse(&buffer)
-                .ok()
-                .and_then(|(command, size)| {
-                    info!("Command: {:?} - {size}", command);
-                    match command {
-                        Commands::GetMac => {
-                            driver
-                                .write(
-                                    Commands::Mac(String::from(&mac)).get_stream().as_slice(),
-                                    100,
-                                )
-                                .ok();
-                        }
-                        Commands::StartBle => {
-                            start_ble(&mut ble, Arc::clone(&state));
-                        }
-                        Commands::NewStep(_) => {
-                            com_ble.lock().ok().and_then(|commands| {
-                                commands.borrow_mut().insert(0, command);
-                                Some(())
-                            });
-                        }
-                        Commands::GetBleState => {
-                            state.try_lock().ok().and_then(|state| {
-                                println!("State: {:?}", state.borrow());
-                                driver
-                                    .write(
-                                        Commands::BleState(state.borrow().clone())
-                                            .get_stream()
-                                            .as_slice(),
-                                        100,
-                                    )
-                                    .ok()
-                                    .or_else(|| {
-                                        println!("Unable to send response");
-                                        Some(0)
-                                    })
-                            });
-                        }
-                        _ => {}
-                    }
-                    Some(())
-                })
-                .or_else(|| {
-                    println!("Unable to parse command");
-                    Some(())
-                });
+
+        if let Some(seconds) = bridge.take_sleep() {
+            // Keeps serving the M5Go a little, so that it reads the new BLE state
+            let until = Instant::now() + SLEEP_DELAY;
+            while Instant::now() < until {
+                crash::feed_watchdog();
+                bridge.poll();
+                // Leaves the CPU to the I2C slave task serving the M5Go
+                events.recv_timeout(BUSY_PERIOD).ok();
+            }
+            power::deep_sleep(button_pin, seconds);
         }
 
-        FreeRtos::delay_ms(50);
+        // Light sleep is entered by the idle task while waiting here
+        let period = if power.is_awake() || pressed_since.is_some() {
+            BUSY_PERIOD
+        } else {
+            IDLE_PERIOD
+        };
+        events.recv_timeout(period).ok();
     }
 }
-
-fn start_ble(ble: &mut EspBle, state: Arc<Mutex<RefCell<BleState>>>) {
-    ble.start_advertise(move |_| {
-        info!("advertising started");
-        state.try_lock().ok().and_then(|state| {
-            state.replace(BleState::Advertising);
-            Some(())
-        });
-    })
-    .ok()
-    .or_else(|| {
-        info!("Unable to start advertising");
-        Some(())
-    });
-}
@@ -0,0 +1,118 @@
+use std::ptr;
+
+use anyhow::anyhow;
+use esp_idf_sys::{
+    esp, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_handle_t, esp_ota_set_boot_partition, esp_ota_write,
+};
+use shared::{crc32_update, Commands, OTA_BULK_ID};
+
+struct Update {
+    handle: esp_ota_handle_t,
+    total_len: usize,
+    written: usize,
+    crc: u32,
+}
+
+/// Writes the firmware image sent as an `OTA_BULK_ID` bulk transfer to the inactive
+/// OTA partition, chunk by chunk, since the image does not fit in memory
+pub struct OtaWriter {
+    update: Option<Update>,
+}
+
+impl OtaWriter {
+    pub fn new() -> Self {
+        Self { update: None }
+    }
+
+    /// Whether the frame belongs to a firmware transfer
+    pub fn is_ota_frame(command: &Commands) -> bool {
+        matches!(
+            command,
+            Commands::BulkStart { id, .. }
+            | Commands::BulkChunk { id, .. }
+            | Commands::BulkEnd { id, .. } if *id == OTA_BULK_ID
+        )
+    }
+
+    pub fn abort(&mut self) {
+        if let Some(update) = self.update.take() {
+            unsafe { esp_ota_abort(update.handle) };
+        }
+    }
+
+    /// Feeds a frame of the firmware transfer, returning the progress in percent.
+    /// Once 100 is returned, the new image boots on the next restart.
+    pub fn push(&mut self, command: &Commands) -> anyhow::Result<Option<u8>> {
+        match command {
+            Commands::BulkStart { total_len, .. } => {
+                self.abort();
+                let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+                if partition.is_null() {
+                    return Err(anyhow!("No OTA partition"));
+                }
+                let mut handle: esp_ota_handle_t = 0;
+                esp!(unsafe { esp_ota_begin(partition, *total_len as usize, &mut handle) })?;
+                self.update = Some(Update {
+                    handle,
+                    total_len: *total_len as usize,
+                    written: 0,
+                    crc: 0,
+                });
+                Ok(Some(0))
+            }
+            Commands::BulkChunk { offset, data, .. } => {
+                let update = self
+                    .update
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("No firmware transfer"))?;
+                // Chunks must arrive in order, a repeated chunk is ignored. The offset
+                // comes off the wire and may overflow.
+                let offset = *offset as usize;
+                let end = match offset.checked_add(data.len()) {
+                    Some(end) => end,
+                    None => {
+                        self.abort();
+                        return Err(anyhow!("Firmware chunk out of bounds"));
+                    }
+                };
+                if end <= update.written {
+                    return Ok(None);
+                }
+                if offset != update.written || end > update.total_len {
+                    self.abort();
+                    return Err(anyhow!("Firmware chunk out of order"));
+                }
+                let previous = update.written * 100 / update.total_len;
+                if let Err(err) =
+                    esp!(unsafe { esp_ota_write(update.handle, data.as_ptr() as _, data.len()) })
+                {
+                    self.abort();
+                    return Err(err.into());
+                }
+                update.written += data.len();
+                update.crc = crc32_update(update.crc, data);
+                // Only whole percents are reported, the phone does not need more
+                let progress = update.written * 100 / update.total_len;
+                Ok((progress != previous && progress < 100).then_some(progress as u8))
+            }
+            Commands::BulkEnd { crc, .. } => {
+                let update = self
+                    .update
+                    .take()
+                    .ok_or_else(|| anyhow!("No firmware transfer"))?;
+                if update.written != update.total_len || update.crc != *crc {
+                    unsafe { esp_ota_abort(update.handle) };
+                    return Err(anyhow!("Firmware image corrupted"));
+                }
+                // Also validates the image
+                esp!(unsafe { esp_ota_end(update.handle) })?;
+                esp!(unsafe {
+                    esp_ota_set_boot_partition(esp_ota_get_next_update_partition(ptr::null()))
+                })?;
+                Ok(Some(100))
+            }
+            _ => Ok(None),
+        }
+    }
+}
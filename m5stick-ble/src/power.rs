@@ -0,0 +1,136 @@
+use std::{
+    ffi::CString,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::*;
+use log::warn;
+
+/// CPU frequencies between which the power management scales, in MHz
+const MAX_FREQ_MHZ: i32 = 80;
+const MIN_FREQ_MHZ: i32 = 40;
+
+/// The stick stays awake this long after the last command went through,
+/// the M5Go usually has more to say
+const AWAKE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Puts the stick in deep sleep until the button is pressed or, unless `seconds`
+/// is 0, the RTC timer expires. Waking up restarts the firmware.
+pub fn deep_sleep(button: i32, seconds: u32) -> ! {
+    // The button pulls its pin low
+    esp!(unsafe { esp_sleep_enable_ext0_wakeup(button, 0) })
+        .ok()
+        .or_else(|| {
+            warn!("Unable to wake up on the button");
+            None
+        });
+    if seconds > 0 {
+        esp!(unsafe { esp_sleep_enable_timer_wakeup(seconds as u64 * 1_000_000) })
+            .ok()
+            .or_else(|| {
+                warn!("Unable to wake up on the timer");
+                None
+            });
+    }
+    unsafe { esp_deep_sleep_start() }
+}
+
+/// Lets the stick enter light sleep whenever it waits for an event, unless
+/// commands went through lately or the M5Go is there. The M5Go wakes it up by
+/// pulling SDA low.
+pub struct PowerManager {
+    lock: esp_pm_lock_handle_t,
+    held: bool,
+    last_activity: Instant,
+    /// Held while the M5Go polls the stick, the I2C slave misses the transfers started
+    /// while it sleeps
+    link_lock: esp_pm_lock_handle_t,
+    link_held: bool,
+}
+
+fn create_lock(name: &str) -> anyhow::Result<esp_pm_lock_handle_t> {
+    let name = CString::new(name).unwrap_or_default();
+    let mut lock: esp_pm_lock_handle_t = ptr::null_mut();
+    esp!(unsafe {
+        esp_pm_lock_create(
+            esp_pm_lock_type_t_ESP_PM_NO_LIGHT_SLEEP,
+            0,
+            name.as_ptr(),
+            &mut lock,
+        )
+    })?;
+    Ok(lock)
+}
+
+impl PowerManager {
+    pub fn new(sda: i32) -> anyhow::Result<Self> {
+        let config = esp_pm_config_esp32_t {
+            max_freq_mhz: MAX_FREQ_MHZ,
+            min_freq_mhz: MIN_FREQ_MHZ,
+            light_sleep_enable: true,
+        };
+        esp!(unsafe { esp_pm_configure(&config as *const _ as *const _) })?;
+
+        let lock = create_lock("bridge")?;
+        let link_lock = create_lock("m5go")?;
+
+        // The start condition of the M5Go wakes the stick, the first transfer may be lost
+        esp!(unsafe { gpio_wakeup_enable(sda, gpio_int_type_t_GPIO_INTR_LOW_LEVEL) })?;
+        esp!(unsafe { esp_sleep_enable_gpio_wakeup() })?;
+
+        let mut power = Self {
+            lock,
+            held: false,
+            last_activity: Instant::now(),
+            link_lock,
+            link_held: false,
+        };
+        power.keep_awake();
+        Ok(power)
+    }
+
+    /// Keeps the stick awake for `AWAKE_WINDOW`
+    pub fn keep_awake(&mut self) {
+        self.last_activity = Instant::now();
+        if self.held == false {
+            self.held = esp!(unsafe { esp_pm_lock_acquire(self.lock) })
+                .ok()
+                .or_else(|| {
+                    warn!("Unable to keep the stick awake");
+                    None
+                })
+                .is_some();
+        }
+    }
+
+    pub fn is_awake(&self) -> bool {
+        self.held
+    }
+
+    /// Keeps the stick awake for as long as the link to the M5Go is up
+    pub fn set_m5go_connected(&mut self, connected: bool) {
+        if connected == self.link_held {
+            return;
+        }
+        let changed = if connected {
+            esp!(unsafe { esp_pm_lock_acquire(self.link_lock) })
+        } else {
+            esp!(unsafe { esp_pm_lock_release(self.link_lock) })
+        };
+        changed
+            .map(|_| self.link_held = connected)
+            .ok()
+            .or_else(|| {
+                warn!("Unable to change the sleep of the M5Go link");
+                None
+            });
+    }
+
+    /// Allows light sleep again once the stick has been idle long enough
+    pub fn update(&mut self) {
+        if self.held && self.last_activity.elapsed() >= AWAKE_WINDOW {
+            self.held = esp!(unsafe { esp_pm_lock_release(self.lock) }).is_err();
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use heapless::Deque;
+use log::{info, warn};
+use shared::{crc32, Commands};
+
+/// Commands kept for the M5Go, the phone is told when its command does not fit
+pub const I2C_QUEUE_LEN: usize = 32;
+/// Commands kept for the phones, the oldest ones are dropped past this
+pub const BLE_QUEUE_LEN: usize = 32;
+
+/// What a full queue does with a new command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Makes room by dropping the command waiting the longest
+    DropOldest,
+    /// Refuses the new command
+    DropNewest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    /// Goes out after the commands already waiting
+    Normal,
+    /// Goes out before the commands already waiting
+    High,
+}
+
+/// Bounded queue of commands, a high priority command always finds room
+/// at the expense of the newest normal one
+pub struct CommandQueue<T, const N: usize> {
+    items: Deque<T, N>,
+    overflow: Overflow,
+    dropped: usize,
+}
+
+impl<T, const N: usize> CommandQueue<T, N> {
+    pub fn new(overflow: Overflow) -> Self {
+        Self {
+            items: Deque::new(),
+            overflow,
+            dropped: 0,
+        }
+    }
+
+    /// Queues a command, returning whether it was kept
+    pub fn push(&mut self, item: T, priority: Priority) -> bool {
+        if self.items.is_full() {
+            let dropped = match (priority, self.overflow) {
+                (Priority::High, _) => self.items.pop_back(),
+                (Priority::Normal, Overflow::DropOldest) => self.items.pop_front(),
+                (Priority::Normal, Overflow::DropNewest) => None,
+            };
+            self.dropped += 1;
+            if dropped.is_none() {
+                return false;
+            }
+        }
+        match priority {
+            Priority::Normal => self.items.push_back(item).is_ok(),
+            Priority::High => self.items.push_front(item).is_ok(),
+        }
+    }
+
+    /// Queues a command ahead of the normal ones but behind the queued commands of its
+    /// kind, so that they keep their order. Past `max` of its kind, the oldest one
+    /// makes room.
+    pub fn push_urgent(&mut self, item: T, max: usize, is_kind: impl Fn(&T) -> bool) -> bool {
+        let mut items: Vec<T> = std::iter::from_fn(|| self.items.pop_front()).collect();
+        if items.iter().filter(|queued| is_kind(queued)).count() >= max {
+            if let Some(oldest) = items.iter().position(&is_kind) {
+                items.remove(oldest);
+                self.dropped += 1;
+            }
+        } else if items.len() >= N {
+            items.pop();
+            self.dropped += 1;
+        }
+        let at = items.iter().rposition(&is_kind).map_or(0, |last| last + 1);
+        items.insert(at, item);
+        for item in items {
+            self.items.push_back(item).ok();
+        }
+        true
+    }
+
+    /// Whether a queued command matches
+    pub fn any(&self, matches: impl Fn(&T) -> bool) -> bool {
+        self.items.iter().any(matches)
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Commands dropped or refused since the queue was created
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// A step received again within this delay is taken for a retry of the phone
+const RETRY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Recognizes the retries of the phone among the commands adding a step on the M5Go,
+/// which would create the same waypoint twice. The queries and the toggles always go
+/// through, the phone waiting for their answer.
+#[derive(Default)]
+pub struct RetryFilter {
+    /// CRC of the steps recently queued for the M5Go, with the time they came
+    recent: VecDeque<(u32, Instant)>,
+}
+
+impl RetryFilter {
+    /// CRC of the step, leaving the timestamp out as the phone stamps its retries again.
+    /// None for the other commands.
+    pub fn get_crc(command: &Commands) -> Option<u32> {
+        match command {
+            Commands::NewStep(_) | Commands::ClosestStep(_) => {
+                command.get_stream().ok().map(|stream| crc32(&stream))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the step of that CRC was queued within the window
+    pub fn is_retry(&mut self, crc: u32) -> bool {
+        let now = Instant::now();
+        while let Some((_, received)) = self.recent.front() {
+            if now.duration_since(*received) < RETRY_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        self.recent.iter().any(|(recent, _)| *recent == crc)
+    }
+
+    /// Remembers a step queued for the M5Go
+    pub fn record(&mut self, crc: u32) {
+        self.recent.push_back((crc, Instant::now()));
+    }
+}
+
+/// Commands waiting to be read by the M5Go, with the time they were sent by the phone
+pub type I2cQueue = Arc<Mutex<RefCell<CommandQueue<(Commands, Option<u32>), I2C_QUEUE_LEN>>>>;
+
+/// Commands waiting to be read by the phone
+pub type BleQueue = Arc<Mutex<RefCell<CommandQueue<Commands, BLE_QUEUE_LEN>>>>;
+
+/// Log lines waiting to be notified on the log characteristic
+pub type LogQueue = Arc<Mutex<RefCell<VecDeque<String>>>>;
+
+/// Log lines kept for the phone, the oldest ones are dropped past this
+pub const MAX_LOG_LINES: usize = 32;
+
+/// Notification or indication subscription of a client, set through the CCCD
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subscription {
+    pub conn_id: u16,
+    pub indicate: bool,
+}
+
+impl Subscription {
+    /// Reads the value written to a CCCD, 0x0001 asking for notifications and 0x0002 for indications
+    pub fn from_cccd(conn_id: u16, value: &[u8]) -> Option<Self> {
+        let flags = match value {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => 0,
+        };
+        info!("Client configuration: {:#06x}", flags);
+        match flags {
+            0x0001 => Some(Subscription {
+                conn_id,
+                indicate: false,
+            }),
+            0x0002 => Some(Subscription {
+                conn_id,
+                indicate: true,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Longest frame assembled from the writes of a connection
+pub const MAX_FRAME_LEN: usize = 1024;
+
+/// Buffers of the connections, allocated once so that the writes do not allocate.
+/// A connection takes a free buffer on its first write and gives it back on disconnect.
+pub struct BufferPool {
+    buffers: Vec<(Option<u16>, Vec<u8>)>,
+}
+
+impl BufferPool {
+    pub fn new(connections: usize) -> Self {
+        Self {
+            buffers: (0..connections)
+                .map(|_| (None, Vec::with_capacity(MAX_FRAME_LEN)))
+                .collect(),
+        }
+    }
+
+    /// Data received from the connection so far
+    pub fn get(&self, conn_id: u16) -> &[u8] {
+        self.buffers
+            .iter()
+            .find(|(owner, _)| *owner == Some(conn_id))
+            .map_or(&[], |(_, data)| data.as_slice())
+    }
+
+    /// Appends to the buffer of the connection, returning false when it does not fit
+    /// or all the buffers are taken
+    pub fn extend(&mut self, conn_id: u16, value: &[u8]) -> bool {
+        let index = self
+            .buffers
+            .iter()
+            .position(|(owner, _)| *owner == Some(conn_id))
+            .or_else(|| self.buffers.iter().position(|(owner, _)| owner.is_none()));
+        match index {
+            Some(index) if self.buffers[index].1.len() + value.len() <= MAX_FRAME_LEN => {
+                let (owner, data) = &mut self.buffers[index];
+                *owner = Some(conn_id);
+                data.extend_from_slice(value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Empties the buffer of the connection, which keeps it
+    pub fn clear(&mut self, conn_id: u16) {
+        self.buffers
+            .iter_mut()
+            .filter(|(owner, _)| *owner == Some(conn_id))
+            .for_each(|(_, data)| data.clear());
+    }
+
+    /// Gives the buffer of the connection back to the pool
+    pub fn release(&mut self, conn_id: u16) {
+        self.buffers
+            .iter_mut()
+            .filter(|(owner, _)| *owner == Some(conn_id))
+            .for_each(|(owner, data)| {
+                *owner = None;
+                data.clear();
+            });
+    }
+}
+
+/// Data received from each connection, waiting for the rest of its frame
+pub type BufferTable = Arc<Mutex<RefCell<BufferPool>>>;
+
+/// Address of each connected central
+pub type PeerTable = Arc<Mutex<RefCell<HashMap<u16, [u8; 6]>>>>;
+
+/// Addresses of the centrals that completed the pairing, or the encryption with their bond
+pub type AuthTable = Arc<Mutex<RefCell<HashSet<[u8; 6]>>>>;
+
+/// Addresses of the sensors reported during a scan, `None` while not scanning
+pub type ScanTable = Arc<Mutex<RefCell<Option<Vec<[u8; 6]>>>>>;
+
+/// Subscription of each connection to a characteristic
+pub type SubscriptionTable = Arc<Mutex<RefCell<HashMap<u16, Subscription>>>>;
+
+/// MTU negotiated by each connection
+pub type MtuTable = Arc<Mutex<RefCell<HashMap<u16, u16>>>>;
+
+/// MTU used until the client negotiates a larger one
+pub const DEFAULT_MTU: u16 = 23;
+
+/// Longest prepared write accepted, the largest attribute value
+pub const MAX_PREPARED_LEN: usize = 512;
+
+/// Reasons for refusing a fragment of a prepared write
+#[derive(Debug, PartialEq)]
+pub enum PrepareError {
+    /// The fragment does not follow the previous one
+    InvalidOffset,
+    /// The value would exceed `MAX_PREPARED_LEN`
+    QueueFull,
+    /// The table is in use by another handler
+    Busy,
+}
+
+/// Payload room left in an ATT packet of the connection, after `overhead` header bytes
+pub fn chunk_size(mtus: &MtuTable, conn_id: u16, overhead: u16) -> usize {
+    let mtu = mtus
+        .try_lock()
+        .ok()
+        .and_then(|mtus| mtus.borrow().get(&conn_id).copied())
+        .unwrap_or(DEFAULT_MTU);
+    (mtu - overhead) as usize
+}
+
+/// Appends a write to the buffer of the connection, handing the frame to `receive`
+/// once complete. A write filling the whole MTU announces that the frame continues.
+pub fn assemble<R>(
+    buffers: &BufferTable,
+    mtus: &MtuTable,
+    conn_id: u16,
+    value: &[u8],
+    receive: impl FnOnce(&[u8]) -> R,
+) -> Option<R> {
+    let size = chunk_size(mtus, conn_id, 3);
+    buffers.try_lock().ok().and_then(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        if buffers.extend(conn_id, value) == false {
+            warn!("Command too long, dropped");
+            buffers.clear(conn_id);
+            return None;
+        }
+        let data = buffers.get(conn_id);
+        match Commands::frame_len(data) {
+            Some(len) if data.len() >= len => {
+                let received = receive(data);
+                buffers.clear(conn_id);
+                Some(received)
+            }
+            _ if value.len() < size => {
+                warn!("Incomplete command dropped");
+                buffers.clear(conn_id);
+                None
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Keeps a fragment of a prepared write until the client executes it
+pub fn prepare(
+    prepared: &BufferTable,
+    conn_id: u16,
+    offset: u16,
+    value: &[u8],
+) -> Result<(), PrepareError> {
+    let prepared = prepared.try_lock().map_err(|_| PrepareError::Busy)?;
+    let mut prepared = prepared.borrow_mut();
+    let len = prepared.get(conn_id).len();
+    if offset as usize != len {
+        Err(PrepareError::InvalidOffset)
+    } else if len + value.len() > MAX_PREPARED_LEN || prepared.extend(conn_id, value) == false {
+        Err(PrepareError::QueueFull)
+    } else {
+        Ok(())
+    }
+}
+
+/// Hands the fragments of a prepared write to `receive` once the client executed or
+/// cancelled it, emptying the buffer
+pub fn execute<R>(
+    prepared: &BufferTable,
+    conn_id: u16,
+    receive: impl FnOnce(&[u8]) -> R,
+) -> Option<R> {
+    prepared.try_lock().ok().map(|prepared| {
+        let mut prepared = prepared.borrow_mut();
+        let received = receive(prepared.get(conn_id));
+        prepared.clear(conn_id);
+        received
+    })
+}
+
+/// Sets or clears the subscription of a connection
+pub fn subscribe(
+    subscriptions: &mut HashMap<u16, Subscription>,
+    subscription: Option<Subscription>,
+    conn_id: u16,
+) {
+    match subscription {
+        Some(subscription) => subscriptions.insert(conn_id, subscription),
+        None => subscriptions.remove(&conn_id),
+    };
+}
+
+pub fn get_subscriptions(subscriptions: &SubscriptionTable) -> Vec<Subscription> {
+    subscriptions
+        .try_lock()
+        .ok()
+        .map(|subscriptions| subscriptions.borrow().values().copied().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table<T: Default>() -> Arc<Mutex<RefCell<T>>> {
+        Default::default()
+    }
+
+    fn write_all(buffers: &BufferTable, mtus: &MtuTable, conn_id: u16, stream: &[u8]) -> usize {
+        let size = chunk_size(mtus, conn_id, 3);
+        let chunks: Vec<&[u8]> = stream.chunks(size).collect();
+        let (last, first) = chunks.split_last().unwrap();
+        for chunk in first {
+            assert!(assemble(buffers, mtus, conn_id, chunk, |_| ()).is_none());
+        }
+        assemble(buffers, mtus, conn_id, last, |frame| {
+            assert_eq!(frame, stream);
+        })
+        .unwrap();
+        chunks.len()
+    }
+
+    #[test]
+    fn chunk_size_follows_the_mtu() {
+        let mtus: MtuTable = table();
+        assert_eq!(chunk_size(&mtus, 0, 3), DEFAULT_MTU as usize - 3);
+        mtus.lock().unwrap().borrow_mut().insert(0, 185);
+        assert_eq!(chunk_size(&mtus, 0, 3), 182);
+        assert_eq!(chunk_size(&mtus, 1, 3), 20);
+    }
+
+    #[test]
+    fn frame_reassembled_from_mtu_writes() {
+        let buffers = Arc::new(Mutex::new(RefCell::new(BufferPool::new(2))));
+        let mtus: MtuTable = table();
+        let stream = Commands::RouteSelected("a".repeat(50))
+            .get_stream()
+            .unwrap();
+        assert_eq!(write_all(&buffers, &mtus, 0, &stream), 3);
+        // The buffer is empty again for the next frame
+        assert!(buffers.lock().unwrap().borrow().get(0).is_empty());
+
+        mtus.lock().unwrap().borrow_mut().insert(1, 100);
+        assert_eq!(write_all(&buffers, &mtus, 1, &stream), 1);
+    }
+
+    #[test]
+    fn short_write_ends_an_incomplete_frame() {
+        let buffers = Arc::new(Mutex::new(RefCell::new(BufferPool::new(2))));
+        let mtus: MtuTable = table();
+        let stream = Commands::RouteSelected("a".repeat(50))
+            .get_stream()
+            .unwrap();
+        assert!(assemble(&buffers, &mtus, 0, &stream[..10], |_| ()).is_none());
+        assert!(buffers.lock().unwrap().borrow().get(0).is_empty());
+    }
+
+    #[test]
+    fn buffers_released_on_disconnect() {
+        let mut pool = BufferPool::new(2);
+        assert!(pool.extend(1, &[1]));
+        assert!(pool.extend(2, &[2]));
+        assert!(pool.extend(3, &[3]) == false);
+        pool.release(1);
+        assert!(pool.get(1).is_empty());
+        assert!(pool.extend(3, &[3]));
+        assert_eq!(pool.get(3), &[3]);
+    }
+
+    /// Goes through the filter as `FrameReceiver::receive` does, returning whether the
+    /// command is queued
+    fn filter(retries: &mut RetryFilter, command: &Commands) -> bool {
+        let step = RetryFilter::get_crc(command);
+        if step.is_some_and(|crc| retries.is_retry(crc)) {
+            return false;
+        }
+        if let Some(crc) = step {
+            retries.record(crc);
+        }
+        true
+    }
+
+    #[test]
+    fn retried_step_dropped() {
+        let mut retries = RetryFilter::default();
+        let step = Commands::NewStep(shared::Coordinates::new(45.5, 5.25));
+        assert!(filter(&mut retries, &step));
+        assert!(filter(&mut retries, &step) == false);
+        // Another step goes through
+        let other = Commands::NewStep(shared::Coordinates::new(45.6, 5.25));
+        assert!(filter(&mut retries, &other));
+    }
+
+    #[test]
+    fn query_sent_twice_answered_twice() {
+        let mut retries = RetryFilter::default();
+        for command in [
+            Commands::GetBattery,
+            Commands::GetSensorData,
+            Commands::GetBleState,
+            Commands::StartBle,
+        ] {
+            assert!(filter(&mut retries, &command));
+            assert!(filter(&mut retries, &command));
+        }
+    }
+
+    #[test]
+    fn ble_queue_drops_the_oldest() {
+        let queue: BleQueue = Arc::new(Mutex::new(RefCell::new(CommandQueue::new(
+            Overflow::DropOldest,
+        ))));
+        let queue = queue.lock().unwrap();
+        let mut queue = queue.borrow_mut();
+        for level in 0..=BLE_QUEUE_LEN {
+            assert!(queue.push(Commands::SetBrightness(level as u8), Priority::Normal));
+        }
+        assert_eq!(queue.len(), BLE_QUEUE_LEN);
+        assert_eq!(queue.get_dropped(), 1);
+        assert!(matches!(queue.pop(), Some(Commands::SetBrightness(1))));
+    }
+
+    #[test]
+    fn i2c_queue_refuses_the_newest() {
+        let queue: I2cQueue = Arc::new(Mutex::new(RefCell::new(CommandQueue::new(
+            Overflow::DropNewest,
+        ))));
+        let queue = queue.lock().unwrap();
+        let mut queue = queue.borrow_mut();
+        for level in 0..I2C_QUEUE_LEN {
+            assert!(queue.push(
+                (Commands::SetBrightness(level as u8), None),
+                Priority::Normal
+            ));
+        }
+        assert!(queue.push((Commands::GetMac, None), Priority::Normal) == false);
+        // A high priority command takes the place of the newest one
+        assert!(queue.push((Commands::GetStats, None), Priority::High));
+        assert_eq!(queue.len(), I2C_QUEUE_LEN);
+        assert!(matches!(queue.pop(), Some((Commands::GetStats, None))));
+        assert!(queue.any(|(command, _)| matches!(command, Commands::SetBrightness(31))) == false);
+    }
+}
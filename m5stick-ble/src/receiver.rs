@@ -0,0 +1,311 @@
+use std::{
+    cell::RefCell,
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use shared::{
+    crc32, registers::MAX_LINK_FRAME_LEN, BridgeStats, BulkAssembler, Commands, ROUTE_BULK_ID,
+};
+
+use crate::{
+    bridge::Shared,
+    clock,
+    ota::OtaWriter,
+    queues::{BleQueue, I2cQueue, Priority, RetryFilter},
+};
+
+/// Largest bulk transfer accepted from the phone
+const MAX_BULK_LEN: usize = 16 * 1024;
+
+/// Commands waiting for the M5Go past which the phone commands are refused,
+/// until half of them went through
+const MAX_PENDING: usize = 20;
+
+/// Notifications waiting for the M5Go, the oldest one is dropped past this
+const MAX_NOTIFICATIONS: usize = 4;
+
+/// Decodes the frames written by the phone and dispatches their commands,
+/// whether they came in a single write, a prepared (long) write or the stream
+pub struct FrameReceiver {
+    bulk: BulkAssembler,
+    /// Routes are kept whole on the stick, so that none of their chunks is lost on the I2C
+    route: BulkAssembler,
+    ota: OtaWriter,
+    to_m5go: I2cQueue,
+    to_phone: BleQueue,
+    rename: Arc<Mutex<RefCell<Option<String>>>>,
+    position_rate: Arc<Mutex<RefCell<Option<u16>>>>,
+    anti_theft: Arc<Mutex<RefCell<Option<bool>>>>,
+    i2c_address: Arc<Mutex<RefCell<Option<u8>>>>,
+    reboot: Arc<Mutex<RefCell<bool>>>,
+    updating: Arc<Mutex<RefCell<bool>>>,
+    routes: Arc<Mutex<RefCell<Option<Vec<u8>>>>>,
+    stats: Arc<Mutex<RefCell<BridgeStats>>>,
+    retries: RetryFilter,
+    paused: bool,
+}
+
+impl FrameReceiver {
+    /// Feeds the queues and tables of `shared` that the main loop reads
+    pub fn new(shared: &Shared) -> Self {
+        Self {
+            bulk: BulkAssembler::new(MAX_BULK_LEN),
+            route: BulkAssembler::new(MAX_BULK_LEN),
+            ota: OtaWriter::new(),
+            to_m5go: Arc::clone(&shared.to_m5go),
+            to_phone: Arc::clone(&shared.to_phone),
+            rename: Arc::clone(&shared.rename),
+            position_rate: Arc::clone(&shared.position_rate),
+            anti_theft: Arc::clone(&shared.anti_theft),
+            i2c_address: Arc::clone(&shared.i2c_address),
+            reboot: Arc::clone(&shared.reboot),
+            updating: Arc::clone(&shared.updating),
+            routes: Arc::clone(&shared.route),
+            stats: Arc::clone(&shared.stats),
+            retries: RetryFilter::default(),
+            paused: false,
+        }
+    }
+
+    /// Handles a complete frame, returning `OK` once its command has been accepted,
+    /// or `Backpressure(true)` when the M5Go does not keep up
+    pub fn receive(&mut self, frame: &[u8]) -> Commands {
+        let timestamp = Commands::get_timestamp(frame);
+        Commands::parse(frame)
+            .ok()
+            .or_else(|| {
+                warn!("Invalid frame from the phone");
+                self.count_parse_error();
+                None
+            })
+            .and_then(|(command, _)| {
+                info!("Received Command: {:?}", command);
+                if let Commands::SetName(name) = command {
+                    return self.rename.try_lock().ok().and_then(|rename| {
+                        rename.replace(Some(name));
+                        Some(Commands::OK)
+                    });
+                }
+                if let Commands::SetPositionRate(seconds) = command {
+                    return self.position_rate.try_lock().ok().and_then(|rate| {
+                        rate.replace(Some(seconds));
+                        Some(Commands::OK)
+                    });
+                }
+                if let Commands::SetAntiTheft(armed) = command {
+                    return self.anti_theft.try_lock().ok().and_then(|anti_theft| {
+                        anti_theft.replace(Some(armed));
+                        Some(Commands::OK)
+                    });
+                }
+                if let Commands::SetI2cAddress(address) = command {
+                    return self.i2c_address.try_lock().ok().and_then(|i2c_address| {
+                        i2c_address.replace(Some(address));
+                        Some(Commands::OK)
+                    });
+                }
+                // Counted by the stick, the M5Go has nothing to add
+                if let Commands::GetStats = command {
+                    let stats = self.stats.try_lock().ok()?.borrow().clone();
+                    return self.to_phone.try_lock().ok().and_then(|commands| {
+                        commands
+                            .borrow_mut()
+                            .push(Commands::Stats(stats), Priority::Normal);
+                        Some(Commands::OK)
+                    });
+                }
+                // Forwarded as well, the I2C link gives it the time at which the M5Go reads it
+                if let Commands::SetTime(unix_ms) = command {
+                    clock::set_time(unix_ms);
+                }
+                // Firmware images are written by the stick itself
+                if OtaWriter::is_ota_frame(&command) {
+                    return self.push_ota(&command);
+                }
+                if let Commands::BulkStart { id, .. }
+                | Commands::BulkChunk { id, .. }
+                | Commands::BulkEnd { id, .. } = command
+                {
+                    if id == ROUTE_BULK_ID {
+                        return self.push_route(&command);
+                    }
+                }
+                // The I2C link would lose it after a few retries
+                if frame.len() > MAX_LINK_FRAME_LEN {
+                    warn!("Command too long for the M5Go, refused");
+                    return None;
+                }
+                // Call alerts are not held back by the route chunks or the backpressure
+                if let Commands::Notification { .. } = command {
+                    return self.push_notification(command);
+                }
+                // Already queued, the phone only missed the answer
+                let step = RetryFilter::get_crc(&command);
+                if step.is_some_and(|crc| self.retries.is_retry(crc)) {
+                    info!("Duplicate step dropped");
+                    return Some(Commands::OK);
+                }
+                if self.get_pending() >= MAX_PENDING {
+                    warn!("M5Go busy, command refused");
+                    self.pause();
+                    return Some(Commands::Backpressure(true));
+                }
+                // Bulk frames are checked on the way, so that the phone knows
+                // whether the whole transfer went through
+                if let Err(err) = self.bulk.push(&command) {
+                    warn!("Bulk transfer failed: {}", err);
+                    return None;
+                }
+                self.to_m5go
+                    .try_lock()
+                    .ok()
+                    .filter(|commands| {
+                        commands
+                            .borrow_mut()
+                            .push((command, timestamp), Priority::Normal)
+                    })
+                    .map(|_| {
+                        if let Some(crc) = step {
+                            self.retries.record(crc);
+                        }
+                        Commands::OK
+                    })
+            })
+            .unwrap_or_default()
+    }
+
+    /// Handles a frame written without response. When the M5Go does not keep up,
+    /// the frame is dropped and the phone only learns it from the notification.
+    pub fn receive_stream(&mut self, frame: &[u8]) {
+        self.receive(frame);
+    }
+
+    fn count_parse_error(&self) {
+        self.stats.try_lock().ok().and_then(|stats| {
+            stats.borrow_mut().parse_errors += 1;
+            Some(())
+        });
+    }
+
+    /// Asks the phone to hold its commands back, once
+    fn pause(&mut self) {
+        if self.paused == false {
+            self.paused = true;
+            self.signal(true);
+        }
+    }
+
+    /// Tells the phone to resume once the M5Go caught up
+    pub fn check_backpressure(&mut self) {
+        if self.paused && self.get_pending() < MAX_PENDING / 2 {
+            self.paused = false;
+            self.signal(false);
+        }
+    }
+
+    fn get_pending(&self) -> usize {
+        self.to_m5go
+            .try_lock()
+            .ok()
+            .map_or(MAX_PENDING, |commands| commands.borrow().len())
+    }
+
+    fn signal(&self, paused: bool) {
+        // Goes out before the other commands waiting for the phone
+        self.to_phone.try_lock().ok().and_then(|commands| {
+            commands
+                .borrow_mut()
+                .push(Commands::Backpressure(paused), Priority::High);
+            Some(())
+        });
+    }
+
+    /// Queues a notification for the M5Go ahead of the other commands, unless the
+    /// same one is already waiting
+    fn push_notification(&mut self, command: Commands) -> Option<Commands> {
+        self.to_m5go.try_lock().ok().and_then(|commands| {
+            let mut commands = commands.borrow_mut();
+            let duplicate = commands.any(|(queued, _)| match (queued, &command) {
+                (
+                    Commands::Notification { title, body },
+                    Commands::Notification {
+                        title: new_title,
+                        body: new_body,
+                    },
+                ) => title == new_title && body == new_body,
+                _ => false,
+            });
+            if duplicate {
+                info!("Notification already queued");
+            } else {
+                commands.push_urgent((command, None), MAX_NOTIFICATIONS, |(queued, _)| {
+                    matches!(queued, Commands::Notification { .. })
+                });
+            }
+            Some(Commands::OK)
+        })
+    }
+
+    /// Assembles a route, handing it to the main loop once complete. The `BulkEnd`
+    /// is sent back to the phone to acknowledge the whole route.
+    fn push_route(&mut self, command: &Commands) -> Option<Commands> {
+        match self.route.push(command) {
+            Ok(Some((id, data))) => {
+                info!("Route received ({} bytes)", data.len());
+                let crc = crc32(&data);
+                self.routes.try_lock().ok().and_then(|routes| {
+                    routes.replace(Some(data));
+                    Some(())
+                })?;
+                self.to_phone.try_lock().ok().and_then(|commands| {
+                    commands
+                        .borrow_mut()
+                        .push(Commands::BulkEnd { id, crc }, Priority::High);
+                    Some(())
+                });
+                Some(Commands::OK)
+            }
+            Ok(None) => Some(Commands::OK),
+            Err(err) => {
+                warn!("Route transfer failed: {}", err);
+                None
+            }
+        }
+    }
+
+    fn push_ota(&mut self, command: &Commands) -> Option<Commands> {
+        match self.ota.push(command) {
+            Ok(progress) => {
+                progress.and_then(|progress| {
+                    self.set_updating(progress < 100);
+                    if progress == 100 {
+                        self.reboot.try_lock().ok().and_then(|reboot| {
+                            reboot.replace(true);
+                            Some(())
+                        });
+                    }
+                    self.to_phone.try_lock().ok().and_then(|commands| {
+                        commands
+                            .borrow_mut()
+                            .push(Commands::OtaProgress(progress), Priority::Normal);
+                        Some(())
+                    })
+                });
+                Some(Commands::OK)
+            }
+            Err(err) => {
+                warn!("Firmware update failed: {}", err);
+                self.set_updating(false);
+                None
+            }
+        }
+    }
+
+    fn set_updating(&self, updating: bool) {
+        self.updating.try_lock().ok().and_then(|current| {
+            current.replace(updating);
+            Some(())
+        });
+    }
+}
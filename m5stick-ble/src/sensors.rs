@@ -0,0 +1,243 @@
+use shared::{Commands, Coordinates, CyclingData, SensorKind};
+
+use crate::queues::MAX_FRAME_LEN;
+
+/// Flag of the Heart Rate Measurement telling that the rate takes two bytes
+const HEART_RATE_U16: u8 = 0x01;
+
+/// Flags of the CSC Measurement telling which revolution data follow
+const CSC_WHEEL_DATA: u8 = 0x01;
+const CSC_CRANK_DATA: u8 = 0x02;
+
+/// Event times of the CSC Measurement are in 1/1024 s
+const CSC_TIME_UNIT: f32 = 1024.0;
+
+/// Circumference of a 700x25C wheel, in meters
+const WHEEL_CIRCUMFERENCE: f32 = 2.105;
+
+/// Beats per minute carried by a Heart Rate Measurement, capped to fit a byte
+pub fn parse_heart_rate(data: &[u8]) -> Option<u8> {
+    match data {
+        [flags, low, high, ..] if flags & HEART_RATE_U16 != 0 => {
+            Some(u16::from_le_bytes([*low, *high]).min(u8::MAX as u16) as u8)
+        }
+        [flags, bpm, ..] if flags & HEART_RATE_U16 == 0 => Some(*bpm),
+        _ => None,
+    }
+}
+
+/// Cumulative revolutions and time of the last one, as carried by a CSC Measurement
+#[derive(Clone, Copy)]
+struct Revolutions {
+    count: u32,
+    time: u16,
+}
+
+impl Revolutions {
+    /// Revolutions per second since `previous`, 0 when none happened. The counters wrap.
+    fn get_rate(&self, previous: &Revolutions, count_mask: u32) -> Option<f32> {
+        let count = self.count.wrapping_sub(previous.count) & count_mask;
+        if count == 0 {
+            return Some(0.0);
+        }
+        let time = self.time.wrapping_sub(previous.time);
+        if time == 0 {
+            return None;
+        }
+        Some(count as f32 * CSC_TIME_UNIT / time as f32)
+    }
+}
+
+/// Wheel and crank revolutions carried by a CSC Measurement
+fn parse_csc(data: &[u8]) -> Option<(Option<Revolutions>, Option<Revolutions>)> {
+    let (flags, mut data) = data.split_first()?;
+    let wheel = if flags & CSC_WHEEL_DATA != 0 {
+        let revolutions = data.get(..6)?;
+        data = &data[6..];
+        Some(Revolutions {
+            count: u32::from_le_bytes([
+                revolutions[0],
+                revolutions[1],
+                revolutions[2],
+                revolutions[3],
+            ]),
+            time: u16::from_le_bytes([revolutions[4], revolutions[5]]),
+        })
+    } else {
+        None
+    };
+    let crank = if flags & CSC_CRANK_DATA != 0 {
+        let revolutions = data.get(..4)?;
+        Some(Revolutions {
+            count: u16::from_le_bytes([revolutions[0], revolutions[1]]) as u32,
+            time: u16::from_le_bytes([revolutions[2], revolutions[3]]),
+        })
+    } else {
+        None
+    };
+    Some((wheel, crank))
+}
+
+/// Position sent to the M5Go when the other unit went out of reach, out of the valid range
+const LOST_POSITION: Coordinates = Coordinates {
+    lat: 90.0,
+    long: 180.0,
+};
+
+/// Turns the measurements notified by a sensor into commands for the M5Go. The cadence
+/// and speed come from the revolutions between two measurements, the position of another
+/// unit from the frame its chunks make up.
+pub struct MeasurementDecoder {
+    kind: SensorKind,
+    wheel: Option<Revolutions>,
+    crank: Option<Revolutions>,
+    frame: Vec<u8>,
+}
+
+impl MeasurementDecoder {
+    pub fn new(kind: SensorKind) -> Self {
+        Self {
+            kind,
+            wheel: None,
+            crank: None,
+            frame: vec![],
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Vec<Commands> {
+        match self.kind {
+            SensorKind::HeartRate => parse_heart_rate(data)
+                .map(|bpm| vec![Commands::HeartRate(bpm)])
+                .unwrap_or_default(),
+            SensorKind::Cadence => parse_csc(data)
+                .and_then(|(wheel, crank)| self.decode_csc(wheel, crank))
+                .map(|data| vec![Commands::CyclingData(data)])
+                .unwrap_or_default(),
+            SensorKind::Unit => self
+                .decode_position(data)
+                .map(|coords| vec![Commands::PeerPosition(coords)])
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Position carried by the frame, once its last chunk is notified
+    fn decode_position(&mut self, data: &[u8]) -> Option<Coordinates> {
+        self.frame.extend_from_slice(data);
+        let len = Commands::frame_len(&self.frame)?;
+        if self.frame.len() < len {
+            // A lost chunk would otherwise keep the buffer from ever completing
+            if self.frame.len() > MAX_FRAME_LEN {
+                self.frame.clear();
+            }
+            return None;
+        }
+        let frame = std::mem::take(&mut self.frame);
+        match Commands::parse(&frame[..len]) {
+            Ok((Commands::Position(coords), _)) => Some(coords),
+            _ => None,
+        }
+    }
+
+    fn decode_csc(
+        &mut self,
+        wheel: Option<Revolutions>,
+        crank: Option<Revolutions>,
+    ) -> Option<CyclingData> {
+        let wheel_speed = wheel
+            .zip(self.wheel)
+            .and_then(|(wheel, previous)| wheel.get_rate(&previous, u32::MAX))
+            .map(|rate| rate * WHEEL_CIRCUMFERENCE * 3.6);
+        let cadence = crank
+            .zip(self.crank)
+            .and_then(|(crank, previous)| crank.get_rate(&previous, u16::MAX as u32))
+            .map(|rate| (rate * 60.0).round() as u16);
+        self.wheel = wheel;
+        self.crank = crank;
+        // The first measurement only gives the counters to start from
+        if cadence.is_none() && wheel_speed.is_none() {
+            return None;
+        }
+        Some(CyclingData {
+            cadence,
+            wheel_speed,
+        })
+    }
+
+    /// Commands telling the M5Go that the sensor no longer gives its measurement
+    pub fn get_lost(&self) -> Vec<Commands> {
+        match self.kind {
+            SensorKind::HeartRate => vec![Commands::HeartRate(0)],
+            SensorKind::Cadence => vec![Commands::CyclingData(CyclingData::default())],
+            SensorKind::Unit => vec![Commands::PeerPosition(LOST_POSITION)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CSC Measurement with both the wheel and the crank revolutions
+    fn csc(wheel: u32, wheel_time: u16, crank: u16, crank_time: u16) -> Vec<u8> {
+        let mut data = vec![CSC_WHEEL_DATA | CSC_CRANK_DATA];
+        data.extend_from_slice(&wheel.to_le_bytes());
+        data.extend_from_slice(&wheel_time.to_le_bytes());
+        data.extend_from_slice(&crank.to_le_bytes());
+        data.extend_from_slice(&crank_time.to_le_bytes());
+        data
+    }
+
+    fn cycling_data(commands: Vec<Commands>) -> CyclingData {
+        match commands.as_slice() {
+            [Commands::CyclingData(data)] => data.clone(),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn heart_rate_formats() {
+        assert_eq!(parse_heart_rate(&[0x00, 72]), Some(72));
+        assert_eq!(parse_heart_rate(&[HEART_RATE_U16, 0x90, 0x00]), Some(144));
+        // Capped to fit the command
+        assert_eq!(parse_heart_rate(&[HEART_RATE_U16, 0x2c, 0x01]), Some(255));
+        assert_eq!(parse_heart_rate(&[HEART_RATE_U16, 0x90]), None);
+        assert_eq!(parse_heart_rate(&[0x00]), None);
+    }
+
+    #[test]
+    fn csc_rates() {
+        let mut decoder = MeasurementDecoder::new(SensorKind::Cadence);
+        // The first measurement only gives the counters
+        assert!(decoder.decode(&csc(100, 1024, 10, 1024)).is_empty());
+
+        let data = cycling_data(decoder.decode(&csc(104, 2048, 11, 2048)));
+        assert_eq!(data.cadence, Some(60));
+        let speed = data.wheel_speed.unwrap();
+        assert!((speed - 4.0 * WHEEL_CIRCUMFERENCE * 3.6).abs() < 0.01);
+
+        // No revolution since the last measurement
+        let data = cycling_data(decoder.decode(&csc(104, 2048, 11, 2048)));
+        assert_eq!(data.cadence, Some(0));
+        assert_eq!(data.wheel_speed, Some(0.0));
+    }
+
+    #[test]
+    fn csc_counters_wrap() {
+        let mut decoder = MeasurementDecoder::new(SensorKind::Cadence);
+        decoder.decode(&csc(u32::MAX, 64512, u16::MAX, 64512));
+        // One revolution of each a second later, the counters and event times wrapping
+        let data = cycling_data(decoder.decode(&csc(0, 0, 0, 0)));
+        assert_eq!(data.cadence, Some(60));
+        let speed = data.wheel_speed.unwrap();
+        assert!((speed - WHEEL_CIRCUMFERENCE * 3.6).abs() < 0.01);
+    }
+
+    #[test]
+    fn csc_truncated() {
+        let mut decoder = MeasurementDecoder::new(SensorKind::Cadence);
+        let data = csc(100, 1024, 10, 1024);
+        assert!(parse_csc(&data[..9]).is_none());
+        assert!(parse_csc(&[]).is_none());
+        assert!(decoder.decode(&data[..5]).is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::anyhow;
+
+use crate::{crc::crc32, Commands, Coordinates};
+
+/// Bulk transfer id reserved for the firmware images of the BLE unit
+pub const OTA_BULK_ID: u8 = 0xff;
+/// Bulk transfer id of the routes sent by the phone, as a JSON list of steps
+pub const ROUTE_BULK_ID: u8 = 0x01;
+
+/// Decodes the data of a `ROUTE_BULK_ID` transfer
+pub fn parse_route(data: &[u8]) -> anyhow::Result<Vec<Coordinates>> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+/// Splits `data` into the `BulkStart` / `BulkChunk` / `BulkEnd` frames of a transfer
+pub fn bulk_commands(id: u8, data: &[u8], chunk_size: usize) -> Vec<Commands> {
+    let mut commands = vec![Commands::BulkStart {
+        id,
+        total_len: data.len() as u32,
+    }];
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        commands.push(Commands::BulkChunk {
+            id,
+            offset: (i * chunk_size) as u32,
+            data: chunk.to_vec(),
+        });
+    }
+    commands.push(Commands::BulkEnd {
+        id,
+        crc: crc32(data),
+    });
+    commands
+}
+
+/// Reassembles a bulk transfer from its frames, one transfer at a time
+pub struct BulkAssembler {
+    max_len: usize,
+    id: Option<u8>,
+    total_len: usize,
+    data: Vec<u8>,
+}
+
+impl BulkAssembler {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            id: None,
+            total_len: 0,
+            data: vec![],
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.id.is_some()
+    }
+
+    /// Returns the transfer id with the received and expected byte counts
+    pub fn get_progress(&self) -> Option<(u8, usize, usize)> {
+        self.id.map(|id| (id, self.data.len(), self.total_len))
+    }
+
+    pub fn reset(&mut self) {
+        self.id = None;
+        self.total_len = 0;
+        self.data.clear();
+    }
+
+    /// Feeds a frame to the assembler, returning the transfer id and its data once
+    /// `BulkEnd` has been received with a matching CRC
+    pub fn push(&mut self, command: &Commands) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+        match command {
+            Commands::BulkStart { id, total_len } => {
+                let total_len = *total_len as usize;
+                if total_len > self.max_len {
+                    self.reset();
+                    return Err(anyhow!("Bulk transfer too large"));
+                }
+                self.id = Some(*id);
+                self.total_len = total_len;
+                self.data = Vec::with_capacity(total_len);
+                Ok(None)
+            }
+            Commands::BulkChunk { id, offset, data } => {
+                if self.id != Some(*id) {
+                    return Err(anyhow!("Unknown bulk transfer"));
+                }
+                // Chunks must arrive in order, a repeated chunk is ignored. The offset
+                // comes off the wire and may overflow on the 32-bit targets.
+                let offset = *offset as usize;
+                let end = match offset.checked_add(data.len()) {
+                    Some(end) => end,
+                    None => {
+                        self.reset();
+                        return Err(anyhow!("Bulk chunk out of bounds"));
+                    }
+                };
+                if end <= self.data.len() {
+                    return Ok(None);
+                }
+                if offset != self.data.len() || end > self.total_len {
+                    self.reset();
+                    return Err(anyhow!("Bulk chunk out of order"));
+                }
+                self.data.extend_from_slice(data);
+                Ok(None)
+            }
+            Commands::BulkEnd { id, crc } => {
+                if self.id != Some(*id) {
+                    return Err(anyhow!("Unknown bulk transfer"));
+                }
+                let complete = self.data.len() == self.total_len && crc32(&self.data) == *crc;
+                let data = std::mem::take(&mut self.data);
+                self.reset();
+                if complete {
+                    Ok(Some((*id, data)))
+                } else {
+                    Err(anyhow!("Bulk transfer corrupted"))
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        (0..100).collect()
+    }
+
+    fn push_all(
+        assembler: &mut BulkAssembler,
+        commands: &[Commands],
+    ) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+        let mut result = None;
+        for command in commands {
+            result = assembler.push(command)?;
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn transfer_reassembled() {
+        let mut assembler = BulkAssembler::new(1024);
+        let commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        assert_eq!(commands.len(), 6);
+        let (id, received) = push_all(&mut assembler, &commands).unwrap().unwrap();
+        assert_eq!(id, ROUTE_BULK_ID);
+        assert_eq!(received, data());
+        assert!(!assembler.is_busy());
+    }
+
+    #[test]
+    fn repeated_chunk_ignored() {
+        let mut assembler = BulkAssembler::new(1024);
+        let mut commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        let repeated = match &commands[1] {
+            Commands::BulkChunk { id, offset, data } => Commands::BulkChunk {
+                id: *id,
+                offset: *offset,
+                data: data.clone(),
+            },
+            other => panic!("Unexpected {:?}", other),
+        };
+        commands.insert(3, repeated);
+        let (_, received) = push_all(&mut assembler, &commands).unwrap().unwrap();
+        assert_eq!(received, data());
+    }
+
+    #[test]
+    fn chunk_out_of_order_refused() {
+        let mut assembler = BulkAssembler::new(1024);
+        let mut commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        commands.swap(1, 2);
+        assert!(push_all(&mut assembler, &commands).is_err());
+        assert!(!assembler.is_busy());
+    }
+
+    #[test]
+    fn chunk_offset_overflow_refused() {
+        let mut assembler = BulkAssembler::new(1024);
+        assembler
+            .push(&Commands::BulkStart {
+                id: ROUTE_BULK_ID,
+                total_len: 100,
+            })
+            .unwrap();
+        let chunk = Commands::BulkChunk {
+            id: ROUTE_BULK_ID,
+            offset: u32::MAX,
+            data: vec![0; 10],
+        };
+        assert!(assembler.push(&chunk).is_err());
+        assert!(!assembler.is_busy());
+    }
+
+    #[test]
+    fn chunk_past_the_announced_length_refused() {
+        let mut assembler = BulkAssembler::new(1024);
+        let mut commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        commands[0] = Commands::BulkStart {
+            id: ROUTE_BULK_ID,
+            total_len: 50,
+        };
+        assert!(push_all(&mut assembler, &commands).is_err());
+        assert!(!assembler.is_busy());
+    }
+
+    #[test]
+    fn transfer_too_large_refused() {
+        let mut assembler = BulkAssembler::new(64);
+        let commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        assert!(assembler.push(&commands[0]).is_err());
+        assert!(!assembler.is_busy());
+        // The chunks of the refused transfer are not taken
+        assert!(assembler.push(&commands[1]).is_err());
+    }
+
+    #[test]
+    fn crc_mismatch_refused() {
+        let mut assembler = BulkAssembler::new(1024);
+        let mut commands = bulk_commands(ROUTE_BULK_ID, &data(), 30);
+        let last = commands.len() - 1;
+        commands[last] = Commands::BulkEnd {
+            id: ROUTE_BULK_ID,
+            crc: crc32(&data()) ^ 1,
+        };
+        assert!(push_all(&mut assembler, &commands).is_err());
+        assert!(!assembler.is_busy());
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::anyhow;
+
+use crate::BleState;
+
+/// Byke UUIDs derive from the base `b7ce0000-5c1a-4e8b-9a2f-3d61a0c4e5f1`,
+/// the 16-bit id replacing the `0000` part
+const BASE_UUID: [u8; 16] = [
+    0xb7, 0xce, 0x00, 0x00, 0x5c, 0x1a, 0x4e, 0x8b, 0x9a, 0x2f, 0x3d, 0x61, 0xa0, 0xc4, 0xe5, 0xf1,
+];
+
+/// Byte order expected by the ESP-IDF, which is the reverse of the textual form
+const fn byke_uuid(id: u16) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        uuid[15 - i] = BASE_UUID[i];
+        i += 1;
+    }
+    let id = id.to_be_bytes();
+    uuid[13] = id[0];
+    uuid[12] = id[1];
+    uuid
+}
+
+/// Primary service of the BLE unit
+pub const SERVICE_UUID: [u8; 16] = byke_uuid(0x0001);
+/// Characteristic the phone writes its commands to
+pub const RX_CHAR_UUID: [u8; 16] = byke_uuid(0x0002);
+/// Characteristic notifying the phone of the commands sent by the unit
+pub const TX_CHAR_UUID: [u8; 16] = byke_uuid(0x0003);
+/// Characteristic the phone streams commands to, written without response
+pub const STREAM_CHAR_UUID: [u8; 16] = byke_uuid(0x0004);
+/// Characteristic notifying the log output of the unit, for debugging from the phone
+pub const LOG_CHAR_UUID: [u8; 16] = byke_uuid(0x0005);
+/// Characteristic notifying the position of the bike, at the rate set by the phone
+pub const POSITION_CHAR_UUID: [u8; 16] = byke_uuid(0x0006);
+/// Characteristic reading and writing the settings of the stick as a single `BridgeConfig`
+pub const CONFIG_CHAR_UUID: [u8; 16] = byke_uuid(0x0007);
+/// Characteristic serving the counters of the stick, as `BridgeStats::to_bytes`
+pub const STATS_CHAR_UUID: [u8; 16] = byke_uuid(0x0008);
+
+/// Version of the command protocol, advertised so that the app knows what it talks to
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Company identifier of the manufacturer data, the one reserved for tests
+pub const COMPANY_ID: u16 = 0xffff;
+
+/// Proximity UUID of the iBeacon advertised in anti-theft mode, in textual order
+pub const BEACON_UUID: [u8; 16] = {
+    let mut uuid = BASE_UUID;
+    uuid[3] = 0x07;
+    uuid
+};
+/// Major of the iBeacons of the Byke units, the minor being the unit identifier
+pub const BEACON_MAJOR: u16 = 0x0001;
+
+/// Company identifier of the iBeacon manufacturer data
+const APPLE_COMPANY_ID: u16 = 0x004c;
+/// iBeacon type and length of the data that follows it
+const BEACON_TYPE: [u8; 2] = [0x02, 0x15];
+/// Signal strength measured at 1 m, in dBm, for the phone to estimate its distance
+const BEACON_MEASURED_POWER: i8 = -59;
+
+/// Manufacturer specific data of an iBeacon: company identifier (little endian), type,
+/// proximity UUID, major and minor (big endian) and measured power
+pub fn beacon_manufacturer_data(minor: u16) -> [u8; 25] {
+    let mut data = [0u8; 25];
+    data[..2].copy_from_slice(&APPLE_COMPANY_ID.to_le_bytes());
+    data[2..4].copy_from_slice(&BEACON_TYPE);
+    data[4..20].copy_from_slice(&BEACON_UUID);
+    data[20..22].copy_from_slice(&BEACON_MAJOR.to_be_bytes());
+    data[22..24].copy_from_slice(&minor.to_be_bytes());
+    data[24] = BEACON_MEASURED_POWER as u8;
+    data
+}
+
+/// Version of the `BridgeConfig` layout, a blob of another version is refused
+/// except for the first one
+pub const CONFIG_VERSION: u8 = 2;
+/// Length of the `BridgeConfig` blob
+pub const CONFIG_LEN: usize = 10;
+
+/// First layout of the `BridgeConfig` blob, sent by older apps, without the
+/// advertising timeout
+const CONFIG_VERSION_1: u8 = 1;
+const CONFIG_V1_LEN: usize = 8;
+
+/// Seconds of advertising without a connection, until the app sets another timeout
+pub const DEFAULT_ADV_TIMEOUT: u16 = 600;
+
+/// Bounds of the advertising intervals allowed by the Bluetooth specification,
+/// in units of 0.625 ms
+const ADV_INTERVAL_MIN: u16 = 0x20;
+const ADV_INTERVAL_MAX: u16 = 0x4000;
+
+/// Flags of the `BridgeConfig` blob
+const CONFIG_AUTH_REQUIRED: u8 = 0x01;
+const CONFIG_LED_PATTERNS: u8 = 0x02;
+
+/// Settings of the stick the app reads and writes at once, without a command for each
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BridgeConfig {
+    /// Minimum and maximum advertising intervals, in units of 0.625 ms
+    pub adv_interval: (u16, u16),
+    /// Seconds between two position notifications, 0 when the position is not sent
+    pub position_rate: u16,
+    /// Whether the centrals must pair before writing commands
+    pub auth_required: bool,
+    /// Whether the LED blinks the status of the stick, off to stay unnoticed
+    pub led_patterns: bool,
+    /// Seconds of advertising without a connection before the stick stops, 0 for never
+    pub adv_timeout: u16,
+}
+
+impl BridgeConfig {
+    /// Blob of the characteristic: version, advertising intervals and position rate
+    /// (big endian), flags and advertising timeout (big endian)
+    pub fn to_bytes(&self) -> [u8; CONFIG_LEN] {
+        let (min, max) = self.adv_interval;
+        let mut flags = 0;
+        if self.auth_required {
+            flags |= CONFIG_AUTH_REQUIRED;
+        }
+        if self.led_patterns {
+            flags |= CONFIG_LED_PATTERNS;
+        }
+        let mut data = [0u8; CONFIG_LEN];
+        data[0] = CONFIG_VERSION;
+        data[1..3].copy_from_slice(&min.to_be_bytes());
+        data[3..5].copy_from_slice(&max.to_be_bytes());
+        data[5..7].copy_from_slice(&self.position_rate.to_be_bytes());
+        data[7] = flags;
+        data[8..10].copy_from_slice(&self.adv_timeout.to_be_bytes());
+        data
+    }
+
+    /// A blob of the first version leaves the advertising timeout to its default
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let adv_timeout = match data {
+            [CONFIG_VERSION, ..] if data.len() == CONFIG_LEN => {
+                u16::from_be_bytes([data[8], data[9]])
+            }
+            [CONFIG_VERSION_1, ..] if data.len() == CONFIG_V1_LEN => DEFAULT_ADV_TIMEOUT,
+            [CONFIG_VERSION | CONFIG_VERSION_1, ..] => {
+                return Err(anyhow!("Invalid config length"))
+            }
+            _ => return Err(anyhow!("Unknown config version")),
+        };
+        let min = u16::from_be_bytes([data[1], data[2]]);
+        let max = u16::from_be_bytes([data[3], data[4]]);
+        if min < ADV_INTERVAL_MIN || max > ADV_INTERVAL_MAX || min > max {
+            return Err(anyhow!("Invalid advertising interval"));
+        }
+        Ok(Self {
+            adv_interval: (min, max),
+            position_rate: u16::from_be_bytes([data[5], data[6]]),
+            auth_required: data[7] & CONFIG_AUTH_REQUIRED != 0,
+            led_patterns: data[7] & CONFIG_LED_PATTERNS != 0,
+            adv_timeout,
+        })
+    }
+}
+
+/// Room for another central in the advertised flags
+const FLAG_CONNECTABLE: u8 = 0x01;
+
+/// Status of the unit carried by its advertisement, so that the app can show it from a scan
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AdvertStatus {
+    pub battery: u8,
+    pub state: BleState,
+    pub connectable: bool,
+}
+
+impl AdvertStatus {
+    /// Manufacturer specific data: company identifier (little endian), protocol version,
+    /// battery level, BLE state and flags
+    pub fn to_manufacturer_data(&self) -> [u8; 6] {
+        let company = COMPANY_ID.to_le_bytes();
+        let flags = if self.connectable {
+            FLAG_CONNECTABLE
+        } else {
+            0
+        };
+        [
+            company[0],
+            company[1],
+            PROTOCOL_VERSION,
+            self.battery,
+            self.state.get_code(),
+            flags,
+        ]
+    }
+
+    pub fn from_manufacturer_data(data: &[u8]) -> Option<Self> {
+        match data {
+            [low, high, PROTOCOL_VERSION, battery, state, flags, ..]
+                if u16::from_le_bytes([*low, *high]) == COMPANY_ID =>
+            {
+                Some(Self {
+                    battery: *battery,
+                    state: BleState::from(*state),
+                    connectable: flags & FLAG_CONNECTABLE != 0,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trip() {
+        let config = BridgeConfig {
+            adv_interval: (0x20, 0x40),
+            position_rate: 5,
+            auth_required: true,
+            led_patterns: false,
+            adv_timeout: 120,
+        };
+        assert_eq!(
+            BridgeConfig::from_bytes(&config.to_bytes()).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn config_v1() {
+        let data = [CONFIG_VERSION_1, 0x00, 0x20, 0x00, 0x40, 0x00, 0x05, 0x03];
+        let config = BridgeConfig::from_bytes(&data).unwrap();
+        assert_eq!(config.adv_interval, (0x20, 0x40));
+        assert_eq!(config.position_rate, 5);
+        assert!(config.auth_required);
+        assert!(config.led_patterns);
+        assert_eq!(config.adv_timeout, DEFAULT_ADV_TIMEOUT);
+    }
+
+    #[test]
+    fn config_invalid() {
+        assert!(BridgeConfig::from_bytes(&[CONFIG_VERSION_1, 0x00, 0x20]).is_err());
+        assert!(
+            BridgeConfig::from_bytes(&[CONFIG_VERSION, 0x00, 0x20, 0x00, 0x40, 0, 5, 0]).is_err()
+        );
+        assert!(BridgeConfig::from_bytes(&[3; CONFIG_LEN]).is_err());
+    }
+}
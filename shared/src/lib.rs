@@ -1,4 +1,20 @@
-use std::str::from_utf8;
+mod bulk;
+mod crc;
+mod gatt;
+/// Length and CRC wrapping the I2C exchanges, so that a stale or partial buffer is never
+/// taken for a frame
+pub mod packet;
+/// Register map of the BLE unit on the I2C bus. The M5Go writes a register address,
+/// followed by a frame for `REG_RX_FIFO`, then reads the reply of the other registers
+/// once the unit had time to load it. Frames and replies travel as `packet`s.
+pub mod registers;
+mod sensor;
+mod transport;
+
+use std::{
+    str::from_utf8,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::anyhow;
 use embedded_graphics::mono_font::{
@@ -8,6 +24,20 @@ use embedded_graphics::mono_font::{
 use profont::PROFONT_24_POINT;
 use serde::{Deserialize, Serialize};
 
+pub use bulk::{bulk_commands, parse_route, BulkAssembler, OTA_BULK_ID, ROUTE_BULK_ID};
+pub use crc::{crc32, crc32_update};
+pub use gatt::{
+    beacon_manufacturer_data, AdvertStatus, BridgeConfig, BEACON_MAJOR, BEACON_UUID, COMPANY_ID,
+    CONFIG_CHAR_UUID, CONFIG_LEN, CONFIG_VERSION, DEFAULT_ADV_TIMEOUT, LOG_CHAR_UUID,
+    POSITION_CHAR_UUID, PROTOCOL_VERSION, RX_CHAR_UUID, SERVICE_UUID, STATS_CHAR_UUID,
+    STREAM_CHAR_UUID, TX_CHAR_UUID,
+};
+pub use sensor::{
+    CyclingData, Sensor, SensorKind, SensorUuid, CSC_MEASUREMENT_UUID,
+    CYCLING_SPEED_CADENCE_SERVICE_UUID, HEART_RATE_MEASUREMENT_UUID, HEART_RATE_SERVICE_UUID,
+};
+pub use transport::{Loopback, Transport};
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Coordinates {
     pub lat: f64,
@@ -16,6 +46,20 @@ pub struct Coordinates {
 
 const EARTH_RADIUS: f64 = 6371.0;
 
+/// Length byte announcing that the real payload length follows on two bytes
+const EXTENDED_LENGTH: u8 = 0xff;
+
+/// Bit set on the command code when a timestamp follows it
+const TIMESTAMP_FLAG: u8 = 0x80;
+
+/// Wall-clock time in milliseconds since the Unix epoch, as carried by `SetTime`
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 impl Coordinates {
     pub fn distance(&self, other: &Coordinates) -> f64 {
         let lat1 = self.lat.to_radians();
@@ -32,6 +76,18 @@ impl Coordinates {
         EARTH_RADIUS * c
     }
 
+    /// Direction of `other`, in degrees clockwise from the north
+    pub fn bearing(&self, other: &Coordinates) -> f64 {
+        let lat1 = self.lat.to_radians();
+        let lat2 = other.lat.to_radians();
+        let dlon = (other.long - self.long).to_radians();
+
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
+
     pub fn is_valid(&self) -> bool {
         self.lat.abs() < 90.0 && self.long.abs() < 180.0
     }
@@ -43,6 +99,129 @@ impl Coordinates {
     }
 }
 
+/// One-shot snapshot of the M5Go sensors
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Telemetry {
+    pub coords: Option<Coordinates>,
+    pub altitude: Option<f64>,
+    pub speed: Option<f64>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+}
+
+impl Telemetry {
+    /// JSON of the snapshot, as the phone reads it
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Broker the M5Go publishes its telemetry to, so that a ride can be followed live
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Tracking {
+    /// URL of the broker, as `mqtt://host:1883`
+    pub broker: String,
+    pub topic: String,
+    pub username: String,
+    pub password: String,
+    /// Seconds between two publications, 0 when the tracking is off
+    pub rate: u16,
+}
+
+/// Battery of the M5Go, answered to `GetBattery`
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Battery {
+    /// Percent left, by steps of 25 as the power IC tells it
+    pub level: u8,
+    /// Plugged in
+    pub charging: bool,
+}
+
+/// Summary of a recorded ride, sent to the phone from the summary screen
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct TripStats {
+    /// In kilometers
+    pub distance: f64,
+    /// Seconds spent moving, the average speed being the distance over it
+    pub moving_time: u32,
+    /// In km/h
+    pub max_speed: f64,
+    /// Meters climbed
+    pub climb: f64,
+    /// Rough estimate from the speed, the gradient and the weight of the rider
+    pub calories: f64,
+    /// Unknown without the climate sensor
+    pub min_temperature: Option<f32>,
+    pub max_temperature: Option<f32>,
+}
+
+/// Frame delivery measurements, answered to `GetDiagnostics`. The timestamps come from the
+/// clock of the phone, which the M5Go does not share, so only their order is looked at.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Diagnostics {
+    /// Frames received with a timestamp
+    pub stamped_frames: u32,
+    /// Step updates older than one received before them, dropped
+    pub stale_frames: u32,
+    #[serde(skip)]
+    last_step_timestamp: Option<u32>,
+}
+
+impl Diagnostics {
+    /// Counts a stamped frame, and returns false when it is an outdated step update that
+    /// must be dropped
+    pub fn check_frame(&mut self, command: &Commands, timestamp: u32) -> bool {
+        self.stamped_frames = self.stamped_frames.wrapping_add(1);
+
+        if let Commands::NewStep(_) | Commands::ClosestStep(_) = command {
+            let stale = self
+                .last_step_timestamp
+                .is_some_and(|last| (timestamp.wrapping_sub(last) as i32) < 0);
+            if stale {
+                self.stale_frames += 1;
+                return false;
+            }
+            self.last_step_timestamp = Some(timestamp);
+        }
+        true
+    }
+}
+
+/// Counters of the stick since it started, answered to `GetStats` and served on the
+/// stats characteristic
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct BridgeStats {
+    /// Frames passed from the phones to the M5Go
+    pub to_m5go: u32,
+    /// Frames passed from the M5Go to the phones
+    pub to_phone: u32,
+    /// Frames that could not be decoded, from either side
+    pub parse_errors: u32,
+    /// Frames the M5Go read again or had to write again
+    pub i2c_retries: u32,
+    pub disconnects: u32,
+}
+
+impl BridgeStats {
+    /// Value of the stats characteristic: the counters in order, big endian
+    pub fn to_bytes(&self) -> [u8; 20] {
+        let mut data = [0u8; 20];
+        for (i, counter) in [
+            self.to_m5go,
+            self.to_phone,
+            self.parse_errors,
+            self.i2c_retries,
+            self.disconnects,
+        ]
+        .iter()
+        .enumerate()
+        {
+            data[i * 4..i * 4 + 4].copy_from_slice(&counter.to_be_bytes());
+        }
+        data
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub enum BleState {
     #[default]
@@ -50,6 +229,8 @@ pub enum BleState {
     Advertising,
     Connected,
     Disconnected,
+    /// The BLE stack of the stick failed and is being set up again
+    Error,
 }
 
 impl From<u8> for BleState {
@@ -59,6 +240,7 @@ impl From<u8> for BleState {
             0x01 => BleState::Advertising,
             0x02 => BleState::Connected,
             0x03 => BleState::Disconnected,
+            0x04 => BleState::Error,
             _ => BleState::NONE,
         }
     }
@@ -71,6 +253,43 @@ impl BleState {
             BleState::Advertising => 0x01,
             BleState::Connected => 0x02,
             BleState::Disconnected => 0x03,
+            BleState::Error => 0x04,
+        }
+    }
+}
+
+/// Start of the log the stick sends on boot when its last run ended on a crash, the
+/// only one of its logs shown to the rider
+pub const RESTART_REPORT: &str = "Stick restarted";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl From<u8> for LogLevel {
+    fn from(num: u8) -> Self {
+        match num {
+            0x00 => LogLevel::Error,
+            0x01 => LogLevel::Warn,
+            0x02 => LogLevel::Info,
+            0x03 => LogLevel::Debug,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+impl LogLevel {
+    fn get_code(&self) -> u8 {
+        match self {
+            LogLevel::Error => 0x00,
+            LogLevel::Warn => 0x01,
+            LogLevel::Info => 0x02,
+            LogLevel::Debug => 0x03,
         }
     }
 }
@@ -89,6 +308,112 @@ pub enum Commands {
     StopBle,
     BleState(BleState),
     GetBleState,
+    Weather {
+        temp: f32,
+        condition: String,
+        wind: f32,
+    },
+    Notification {
+        title: String,
+        body: String,
+    },
+    GetSensorData,
+    Telemetry(Telemetry),
+    GetRssi,
+    Rssi(i8),
+    SetBrightness(u8),
+    Log {
+        level: LogLevel,
+        text: String,
+    },
+    BulkStart {
+        id: u8,
+        total_len: u32,
+    },
+    BulkChunk {
+        id: u8,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    BulkEnd {
+        id: u8,
+        crc: u32,
+    },
+    GetDiagnostics,
+    Diagnostics(Diagnostics),
+    Passkey(u32),
+    OtaProgress(u8),
+    SetName(String),
+    SetWhitelist(bool),
+    Backpressure(bool),
+    Sleep(u32),
+    Position(Coordinates),
+    SetPositionRate(u16),
+    SetAntiTheft(bool),
+    SetI2cAddress(u8),
+    LinkState(bool),
+    SetTime(u64),
+    ScanSensors(bool),
+    ScanResult(Sensor),
+    PairSensor(Sensor),
+    HeartRate(u8),
+    CyclingData(CyclingData),
+    GetStats,
+    Stats(BridgeStats),
+    PeerPosition(Coordinates),
+    /// Network the M5Go uploads the rides on, the one of home
+    SetWifi {
+        ssid: String,
+        password: String,
+    },
+    /// Endpoint the rides are posted to
+    SetUploadUrl(String),
+    /// Where the M5Go downloads its firmware from
+    SetFirmwareUrl(String),
+    /// Asks the M5Go to update its firmware when a new one is served
+    CheckFirmware,
+    /// Live tracking of the rides over MQTT, a rate of 0 turning it off
+    SetTracking(Tracking),
+    GetBattery,
+    Battery(Battery),
+    /// Sent by the M5Go when the rider did not cancel the countdown after a crash, with
+    /// the last fix of the GPS when there is one. Sent again until the phone sends it
+    /// back, with or without a position.
+    Sos(Option<Coordinates>),
+    /// Sent by the M5Go when the rider reaches a step of the route, `reached` of the
+    /// `total` steps being behind
+    RouteProgress {
+        reached: u16,
+        total: u16,
+    },
+    /// Distance to a step in meters under which it is told reached
+    SetStepRadius(u8),
+    /// Home of the rider, where the M5Go stops the ride and uploads it, a position out of
+    /// range forgetting it
+    SetHome(Coordinates),
+    /// Sent by the M5Go when the rider picks a route of its card, by name
+    RouteSelected(String),
+    /// Sent by the M5Go when the rider sends the summary of a ride just stopped
+    TripStats(TripStats),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WifiInfo {
+    ssid: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WeatherInfo {
+    temp: f32,
+    condition: String,
+    wind: f32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct NotificationInfo {
+    title: String,
+    body: String,
 }
 
 impl From<u8> for Commands {
@@ -105,6 +430,75 @@ impl From<u8> for Commands {
             0x08 => Commands::StopBle,
             0x09 => Commands::BleState(BleState::NONE),
             0x0a => Commands::GetBleState,
+            0x0b => Commands::Weather {
+                temp: 0.0,
+                condition: String::new(),
+                wind: 0.0,
+            },
+            0x0c => Commands::Notification {
+                title: String::new(),
+                body: String::new(),
+            },
+            0x0d => Commands::GetSensorData,
+            0x0e => Commands::Telemetry(Telemetry::default()),
+            0x0f => Commands::GetRssi,
+            0x10 => Commands::Rssi(0),
+            0x11 => Commands::SetBrightness(0),
+            0x12 => Commands::Log {
+                level: LogLevel::default(),
+                text: String::new(),
+            },
+            0x13 => Commands::BulkStart {
+                id: 0,
+                total_len: 0,
+            },
+            0x14 => Commands::BulkChunk {
+                id: 0,
+                offset: 0,
+                data: vec![],
+            },
+            0x15 => Commands::BulkEnd { id: 0, crc: 0 },
+            0x16 => Commands::GetDiagnostics,
+            0x17 => Commands::Diagnostics(Diagnostics::default()),
+            0x18 => Commands::Passkey(0),
+            0x19 => Commands::OtaProgress(0),
+            0x1a => Commands::SetName(String::new()),
+            0x1b => Commands::SetWhitelist(false),
+            0x1c => Commands::Backpressure(false),
+            0x1d => Commands::Sleep(0),
+            0x1e => Commands::Position(Coordinates::default()),
+            0x1f => Commands::SetPositionRate(0),
+            0x20 => Commands::SetAntiTheft(false),
+            0x21 => Commands::SetI2cAddress(0),
+            0x22 => Commands::LinkState(false),
+            0x23 => Commands::SetTime(0),
+            0x24 => Commands::ScanSensors(false),
+            0x25 => Commands::ScanResult(Sensor::default()),
+            0x26 => Commands::PairSensor(Sensor::default()),
+            0x27 => Commands::HeartRate(0),
+            0x28 => Commands::CyclingData(CyclingData::default()),
+            0x29 => Commands::GetStats,
+            0x2a => Commands::Stats(BridgeStats::default()),
+            0x2b => Commands::PeerPosition(Coordinates::default()),
+            0x2c => Commands::SetWifi {
+                ssid: String::new(),
+                password: String::new(),
+            },
+            0x2d => Commands::SetUploadUrl(String::new()),
+            0x2e => Commands::SetFirmwareUrl(String::new()),
+            0x2f => Commands::CheckFirmware,
+            0x30 => Commands::SetTracking(Tracking::default()),
+            0x31 => Commands::GetBattery,
+            0x32 => Commands::Battery(Battery::default()),
+            0x33 => Commands::Sos(None),
+            0x34 => Commands::RouteProgress {
+                reached: 0,
+                total: 0,
+            },
+            0x35 => Commands::SetStepRadius(0),
+            0x36 => Commands::SetHome(Coordinates::default()),
+            0x37 => Commands::RouteSelected(String::new()),
+            0x38 => Commands::TripStats(TripStats::default()),
             _ => Commands::NONE,
         }
     }
@@ -124,38 +518,243 @@ impl Commands {
             Commands::StopBle => 0x08,
             Commands::BleState(_) => 0x09,
             Commands::GetBleState => 0x0a,
+            Commands::Weather { .. } => 0x0b,
+            Commands::Notification { .. } => 0x0c,
+            Commands::GetSensorData => 0x0d,
+            Commands::Telemetry(_) => 0x0e,
+            Commands::GetRssi => 0x0f,
+            Commands::Rssi(_) => 0x10,
+            Commands::SetBrightness(_) => 0x11,
+            Commands::Log { .. } => 0x12,
+            Commands::BulkStart { .. } => 0x13,
+            Commands::BulkChunk { .. } => 0x14,
+            Commands::BulkEnd { .. } => 0x15,
+            Commands::GetDiagnostics => 0x16,
+            Commands::Diagnostics(_) => 0x17,
+            Commands::Passkey(_) => 0x18,
+            Commands::OtaProgress(_) => 0x19,
+            Commands::SetName(_) => 0x1a,
+            Commands::SetWhitelist(_) => 0x1b,
+            Commands::Backpressure(_) => 0x1c,
+            Commands::Sleep(_) => 0x1d,
+            Commands::Position(_) => 0x1e,
+            Commands::SetPositionRate(_) => 0x1f,
+            Commands::SetAntiTheft(_) => 0x20,
+            Commands::SetI2cAddress(_) => 0x21,
+            Commands::LinkState(_) => 0x22,
+            Commands::SetTime(_) => 0x23,
+            Commands::ScanSensors(_) => 0x24,
+            Commands::ScanResult(_) => 0x25,
+            Commands::PairSensor(_) => 0x26,
+            Commands::HeartRate(_) => 0x27,
+            Commands::CyclingData(_) => 0x28,
+            Commands::GetStats => 0x29,
+            Commands::Stats(_) => 0x2a,
+            Commands::PeerPosition(_) => 0x2b,
+            Commands::SetWifi { .. } => 0x2c,
+            Commands::SetUploadUrl(_) => 0x2d,
+            Commands::SetFirmwareUrl(_) => 0x2e,
+            Commands::CheckFirmware => 0x2f,
+            Commands::SetTracking(_) => 0x30,
+            Commands::GetBattery => 0x31,
+            Commands::Battery(_) => 0x32,
+            Commands::Sos(_) => 0x33,
+            Commands::RouteProgress { .. } => 0x34,
+            Commands::SetStepRadius(_) => 0x35,
+            Commands::SetHome(_) => 0x36,
+            Commands::RouteSelected(_) => 0x37,
+            Commands::TripStats(_) => 0x38,
         }
     }
 
     fn get_info(&self) -> Vec<u8> {
         match self {
-            Commands::NewStep(coords) | Commands::ClosestStep(coords) => {
+            Commands::NewStep(coords)
+            | Commands::ClosestStep(coords)
+            | Commands::Position(coords)
+            | Commands::PeerPosition(coords)
+            | Commands::SetHome(coords) => {
                 serde_json::to_string(&coords).unwrap().as_bytes().to_vec()
             }
             Commands::OK => "OK".as_bytes().to_vec(),
             Commands::Mac(mac) => mac.as_bytes().to_vec(),
             Commands::BleState(state) => vec![state.get_code()],
+            Commands::Weather {
+                temp,
+                condition,
+                wind,
+            } => serde_json::to_string(&WeatherInfo {
+                temp: *temp,
+                condition: condition.clone(),
+                wind: *wind,
+            })
+            .unwrap()
+            .as_bytes()
+            .to_vec(),
+            Commands::Notification { title, body } => serde_json::to_string(&NotificationInfo {
+                title: title.clone(),
+                body: body.clone(),
+            })
+            .unwrap()
+            .as_bytes()
+            .to_vec(),
+            Commands::Telemetry(telemetry) => serde_json::to_string(&telemetry)
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            Commands::Rssi(rssi) => vec![*rssi as u8],
+            Commands::SetBrightness(level) => vec![*level],
+            Commands::Log { level, text } => {
+                let mut info = vec![level.get_code()];
+                info.extend_from_slice(text.as_bytes());
+                info
+            }
+            Commands::BulkStart { id, total_len } => {
+                let mut info = vec![*id];
+                info.extend_from_slice(&total_len.to_be_bytes());
+                info
+            }
+            Commands::BulkChunk { id, offset, data } => {
+                let mut info = vec![*id];
+                info.extend_from_slice(&offset.to_be_bytes());
+                info.extend_from_slice(data);
+                info
+            }
+            Commands::BulkEnd { id, crc } => {
+                let mut info = vec![*id];
+                info.extend_from_slice(&crc.to_be_bytes());
+                info
+            }
+            Commands::Diagnostics(diagnostics) => serde_json::to_string(&diagnostics)
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            Commands::TripStats(stats) => {
+                serde_json::to_string(&stats).unwrap().as_bytes().to_vec()
+            }
+            Commands::Passkey(passkey) => passkey.to_be_bytes().to_vec(),
+            Commands::OtaProgress(progress) => vec![*progress],
+            Commands::SetName(name) => name.as_bytes().to_vec(),
+            Commands::SetWhitelist(enabled) => vec![*enabled as u8],
+            Commands::Backpressure(paused) => vec![*paused as u8],
+            Commands::Sleep(seconds) => seconds.to_be_bytes().to_vec(),
+            Commands::SetPositionRate(seconds) => seconds.to_be_bytes().to_vec(),
+            Commands::SetAntiTheft(armed) => vec![*armed as u8],
+            Commands::SetI2cAddress(address) => vec![*address],
+            Commands::LinkState(connected) => vec![*connected as u8],
+            Commands::SetTime(unix_ms) => unix_ms.to_be_bytes().to_vec(),
+            Commands::ScanSensors(scanning) => vec![*scanning as u8],
+            Commands::ScanResult(sensor) | Commands::PairSensor(sensor) => {
+                serde_json::to_string(&sensor).unwrap().as_bytes().to_vec()
+            }
+            Commands::HeartRate(bpm) => vec![*bpm],
+            Commands::CyclingData(data) => {
+                serde_json::to_string(&data).unwrap().as_bytes().to_vec()
+            }
+            Commands::Stats(stats) => serde_json::to_string(&stats).unwrap().as_bytes().to_vec(),
+            Commands::SetWifi { ssid, password } => serde_json::to_string(&WifiInfo {
+                ssid: ssid.clone(),
+                password: password.clone(),
+            })
+            .unwrap()
+            .as_bytes()
+            .to_vec(),
+            Commands::SetUploadUrl(url) => url.as_bytes().to_vec(),
+            Commands::SetFirmwareUrl(url) => url.as_bytes().to_vec(),
+            Commands::RouteSelected(name) => name.as_bytes().to_vec(),
+            Commands::SetTracking(tracking) => serde_json::to_string(&tracking)
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            Commands::Battery(battery) => vec![battery.level, battery.charging as u8],
+            Commands::Sos(coords) => serde_json::to_string(&coords).unwrap().as_bytes().to_vec(),
+            Commands::RouteProgress { reached, total } => {
+                let mut info = reached.to_be_bytes().to_vec();
+                info.extend_from_slice(&total.to_be_bytes());
+                info
+            }
+            Commands::SetStepRadius(meters) => vec![*meters],
             _ => "".as_bytes().to_vec(),
         }
     }
 
-    pub fn get_stream(&self) -> Vec<u8> {
+    /// Stream of the command, failing when its payload does not fit the extended length
+    pub fn get_stream(&self) -> anyhow::Result<Vec<u8>> {
+        self.get_frame(None)
+    }
+
+    /// Stream of the command preceded by the time it was emitted, in milliseconds
+    pub fn get_stamped_stream(&self, timestamp: u32) -> anyhow::Result<Vec<u8>> {
+        self.get_frame(Some(timestamp))
+    }
+
+    fn get_frame(&self, timestamp: Option<u32>) -> anyhow::Result<Vec<u8>> {
         let mut data = self.get_info();
-        let mut stream = vec![self.get_code(), data.len() as u8];
+        // Cut short, a JSON payload would not parse on the other end
+        if data.len() > u16::MAX as usize {
+            return Err(anyhow!("Payload of {} bytes too long", data.len()));
+        }
+        let mut stream = vec![];
+        match timestamp {
+            Some(timestamp) => {
+                stream.push(self.get_code() | TIMESTAMP_FLAG);
+                stream.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            None => stream.push(self.get_code()),
+        }
+        if data.len() < EXTENDED_LENGTH as usize {
+            stream.push(data.len() as u8);
+        } else {
+            stream.push(EXTENDED_LENGTH);
+            stream.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        }
         stream.append(&mut data);
-        stream
+        Ok(stream)
     }
 
-    pub fn parse(stream: &[u8]) -> anyhow::Result<(Self, usize)> {
-        if stream.len() < 2 {
-            return Err(anyhow!("Invalid command"));
+    /// Returns the size of the header and the length of the payload of a stream
+    fn get_header(stream: &[u8]) -> Option<(usize, usize)> {
+        let start = match stream.first() {
+            Some(code) if code & TIMESTAMP_FLAG != 0 => 5,
+            Some(_) => 1,
+            None => return None,
+        };
+        match stream.get(start) {
+            Some(&EXTENDED_LENGTH) => stream
+                .get(start + 1..start + 3)
+                .map(|len| (start + 3, u16::from_be_bytes([len[0], len[1]]) as usize)),
+            Some(&len) => Some((start + 1, len as usize)),
+            None => None,
+        }
+    }
+
+    /// Total length of the frame starting the stream, header included
+    pub fn frame_len(stream: &[u8]) -> Option<usize> {
+        Commands::get_header(stream).map(|(header, length)| header + length)
+    }
+
+    /// Code of the command starting the stream, whether it was sent with a timestamp or not
+    pub fn get_stream_code(stream: &[u8]) -> Option<u8> {
+        stream.first().map(|code| code & !TIMESTAMP_FLAG)
+    }
+
+    /// Timestamp of the frame starting the stream, if it was sent with one
+    pub fn get_timestamp(stream: &[u8]) -> Option<u32> {
+        match stream.first() {
+            Some(code) if code & TIMESTAMP_FLAG != 0 => stream
+                .get(1..5)
+                .map(|ts| u32::from_be_bytes([ts[0], ts[1], ts[2], ts[3]])),
+            _ => None,
         }
-        let code = stream[0];
+    }
+
+    pub fn parse(stream: &[u8]) -> anyhow::Result<(Self, usize)> {
+        let (header, length) = Commands::get_header(stream).ok_or(anyhow!("Invalid command"))?;
+        let code = stream[0] & !TIMESTAMP_FLAG;
         let command = Commands::from(code);
 
-        let length = stream[1] as usize;
-        let data = if length + 2 <= stream.len() {
-            Some(&stream[2..length + 2])
+        let data = if length + header <= stream.len() {
+            Some(&stream[header..length + header])
         } else {
             None
         };
@@ -188,6 +787,30 @@ impl Commands {
             return Ok((Commands::GetBleState, length));
         }
 
+        if code == Commands::GetSensorData.get_code() {
+            return Ok((Commands::GetSensorData, length));
+        }
+
+        if code == Commands::GetRssi.get_code() {
+            return Ok((Commands::GetRssi, length));
+        }
+
+        if code == Commands::GetDiagnostics.get_code() {
+            return Ok((Commands::GetDiagnostics, length));
+        }
+
+        if code == Commands::GetStats.get_code() {
+            return Ok((Commands::GetStats, length));
+        }
+
+        if code == Commands::CheckFirmware.get_code() {
+            return Ok((Commands::CheckFirmware, length));
+        }
+
+        if code == Commands::GetBattery.get_code() {
+            return Ok((Commands::GetBattery, length));
+        }
+
         if data.is_none() {
             return Ok((Commands::NONE, length));
         }
@@ -199,9 +822,261 @@ impl Commands {
             return Ok((Commands::Mac(mac.to_string()), length));
         }
 
-        if code == Commands::BleState(Default::default()).get_code() {
-            let state = BleState::from(data[0]);
-            return Ok((Commands::BleState(state), length));
+        if let (Commands::BleState(_), [state, ..]) = (&command, data) {
+            return Ok((Commands::BleState(BleState::from(*state)), length));
+        }
+
+        if let Commands::Weather { .. } = command {
+            if let Ok(weather) = serde_json::from_slice::<'_, WeatherInfo>(data) {
+                return Ok((
+                    Commands::Weather {
+                        temp: weather.temp,
+                        condition: weather.condition,
+                        wind: weather.wind,
+                    },
+                    length,
+                ));
+            }
+        }
+
+        if let Commands::Notification { .. } = command {
+            if let Ok(notification) = serde_json::from_slice::<'_, NotificationInfo>(data) {
+                return Ok((
+                    Commands::Notification {
+                        title: notification.title,
+                        body: notification.body,
+                    },
+                    length,
+                ));
+            }
+        }
+
+        if let Commands::Telemetry(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Telemetry>(data) {
+                return Ok((Commands::Telemetry(info), length));
+            }
+        }
+
+        if let (Commands::Rssi(_), [rssi, ..]) = (&command, data) {
+            return Ok((Commands::Rssi(*rssi as i8), length));
+        }
+
+        if let (Commands::SetBrightness(_), [level, ..]) = (&command, data) {
+            return Ok((Commands::SetBrightness(*level), length));
+        }
+
+        if let (Commands::OtaProgress(_), [percent, ..]) = (&command, data) {
+            return Ok((Commands::OtaProgress(*percent), length));
+        }
+
+        if let (Commands::SetWhitelist(_), [enabled, ..]) = (&command, data) {
+            return Ok((Commands::SetWhitelist(*enabled != 0), length));
+        }
+
+        if let (Commands::Backpressure(_), [paused, ..]) = (&command, data) {
+            return Ok((Commands::Backpressure(*paused != 0), length));
+        }
+
+        if let Commands::Log { .. } = command {
+            if let Some((level, text)) = data.split_first() {
+                return Ok((
+                    Commands::Log {
+                        level: LogLevel::from(*level),
+                        text: String::from_utf8_lossy(text).to_string(),
+                    },
+                    length,
+                ));
+            }
+        }
+
+        if data.len() >= 5 {
+            let id = data[0];
+            let value = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+            match command {
+                Commands::BulkStart { .. } => {
+                    return Ok((
+                        Commands::BulkStart {
+                            id,
+                            total_len: value,
+                        },
+                        length,
+                    ));
+                }
+                Commands::BulkChunk { .. } => {
+                    return Ok((
+                        Commands::BulkChunk {
+                            id,
+                            offset: value,
+                            data: data[5..].to_vec(),
+                        },
+                        length,
+                    ));
+                }
+                Commands::BulkEnd { .. } => {
+                    return Ok((Commands::BulkEnd { id, crc: value }, length));
+                }
+                _ => {}
+            }
+        }
+
+        if let Commands::Diagnostics(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Diagnostics>(data) {
+                return Ok((Commands::Diagnostics(info), length));
+            }
+        }
+
+        if let (Commands::Passkey(_), [a, b, c, d, ..]) = (&command, data) {
+            return Ok((
+                Commands::Passkey(u32::from_be_bytes([*a, *b, *c, *d])),
+                length,
+            ));
+        }
+
+        if let Commands::SetName(_) = command {
+            return Ok((
+                Commands::SetName(String::from_utf8_lossy(data).to_string()),
+                length,
+            ));
+        }
+
+        if let (Commands::Sleep(_), [a, b, c, d, ..]) = (&command, data) {
+            return Ok((
+                Commands::Sleep(u32::from_be_bytes([*a, *b, *c, *d])),
+                length,
+            ));
+        }
+
+        if let (Commands::SetPositionRate(_), [high, low, ..]) = (&command, data) {
+            return Ok((
+                Commands::SetPositionRate(u16::from_be_bytes([*high, *low])),
+                length,
+            ));
+        }
+
+        if let (Commands::SetAntiTheft(_), [armed, ..]) = (&command, data) {
+            return Ok((Commands::SetAntiTheft(*armed != 0), length));
+        }
+
+        if let (Commands::SetI2cAddress(_), [address, ..]) = (&command, data) {
+            return Ok((Commands::SetI2cAddress(*address), length));
+        }
+
+        if let (Commands::LinkState(_), [up, ..]) = (&command, data) {
+            return Ok((Commands::LinkState(*up != 0), length));
+        }
+
+        if let (Commands::SetTime(_), [a, b, c, d, e, f, g, h, ..]) = (&command, data) {
+            return Ok((
+                Commands::SetTime(u64::from_be_bytes([*a, *b, *c, *d, *e, *f, *g, *h])),
+                length,
+            ));
+        }
+
+        if let (Commands::ScanSensors(_), [scanning, ..]) = (&command, data) {
+            return Ok((Commands::ScanSensors(*scanning != 0), length));
+        }
+
+        if let Commands::ScanResult(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Sensor>(data) {
+                return Ok((Commands::ScanResult(info), length));
+            }
+        }
+
+        if let Commands::PairSensor(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Sensor>(data) {
+                return Ok((Commands::PairSensor(info), length));
+            }
+        }
+
+        if let (Commands::HeartRate(_), [bpm, ..]) = (&command, data) {
+            return Ok((Commands::HeartRate(*bpm), length));
+        }
+
+        if let Commands::CyclingData(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, CyclingData>(data) {
+                return Ok((Commands::CyclingData(info), length));
+            }
+        }
+
+        if let Commands::Stats(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, BridgeStats>(data) {
+                return Ok((Commands::Stats(info), length));
+            }
+        }
+
+        if let Commands::SetWifi { .. } = command {
+            if let Ok(wifi) = serde_json::from_slice::<'_, WifiInfo>(data) {
+                return Ok((
+                    Commands::SetWifi {
+                        ssid: wifi.ssid,
+                        password: wifi.password,
+                    },
+                    length,
+                ));
+            }
+        }
+
+        if let Commands::SetUploadUrl(_) = command {
+            return Ok((
+                Commands::SetUploadUrl(String::from_utf8_lossy(data).to_string()),
+                length,
+            ));
+        }
+
+        if let Commands::SetFirmwareUrl(_) = command {
+            return Ok((
+                Commands::SetFirmwareUrl(String::from_utf8_lossy(data).to_string()),
+                length,
+            ));
+        }
+
+        if let Commands::RouteSelected(_) = command {
+            return Ok((
+                Commands::RouteSelected(String::from_utf8_lossy(data).to_string()),
+                length,
+            ));
+        }
+
+        if let Commands::SetTracking(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Tracking>(data) {
+                return Ok((Commands::SetTracking(info), length));
+            }
+        }
+
+        if let (Commands::Battery(_), [level, charging, ..]) = (&command, data) {
+            return Ok((
+                Commands::Battery(Battery {
+                    level: *level,
+                    charging: *charging == 1,
+                }),
+                length,
+            ));
+        }
+
+        if let Commands::Sos(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, Option<Coordinates>>(data) {
+                return Ok((Commands::Sos(info), length));
+            }
+        }
+
+        if let (Commands::RouteProgress { .. }, [a, b, c, d, ..]) = (&command, data) {
+            return Ok((
+                Commands::RouteProgress {
+                    reached: u16::from_be_bytes([*a, *b]),
+                    total: u16::from_be_bytes([*c, *d]),
+                },
+                length,
+            ));
+        }
+
+        if let (Commands::SetStepRadius(_), [meters, ..]) = (&command, data) {
+            return Ok((Commands::SetStepRadius(*meters), length));
+        }
+
+        if let Commands::TripStats(_) = command {
+            if let Ok(info) = serde_json::from_slice::<'_, TripStats>(data) {
+                return Ok((Commands::TripStats(info), length));
+            }
         }
 
         serde_json::from_slice::<'_, Coordinates>(data)
@@ -211,17 +1086,17 @@ impl Commands {
                     Some((Commands::NewStep(coords), length))
                 } else if code == Commands::ClosestStep(Default::default()).get_code() {
                     Some((Commands::ClosestStep(coords), length))
+                } else if code == Commands::Position(Default::default()).get_code() {
+                    Some((Commands::Position(coords), length))
+                } else if code == Commands::PeerPosition(Default::default()).get_code() {
+                    Some((Commands::PeerPosition(coords), length))
+                } else if code == Commands::SetHome(Default::default()).get_code() {
+                    Some((Commands::SetHome(coords), length))
                 } else {
                     None
                 }
             })
-            .or_else(|| {
-                if length > 20 {
-                    Some((Commands::NONE, length))
-                } else {
-                    None
-                }
-            })
+            .or((length > 20).then_some((Commands::NONE, length)))
             .ok_or(anyhow!("Invalid command"))
     }
 }
@@ -0,0 +1,36 @@
+use anyhow::anyhow;
+
+use crate::crc::crc32;
+
+/// Bytes added around a payload: its length before it and its CRC after it
+pub const OVERHEAD: usize = 6;
+
+/// Wraps a payload as `[length (u16 BE), payload, CRC-32 of the payload (BE)]`
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(u16::MAX as usize)];
+    let mut packet = Vec::with_capacity(payload.len() + OVERHEAD);
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&crc32(payload).to_be_bytes());
+    packet
+}
+
+/// Returns the payload of a packet, which may be followed by padding. A stale or
+/// partial buffer does not pass the length and CRC checks.
+pub fn decode(packet: &[u8]) -> anyhow::Result<&[u8]> {
+    let len = match packet {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+        _ => return Err(anyhow!("Packet too short")),
+    };
+    let payload = packet
+        .get(2..2 + len)
+        .ok_or_else(|| anyhow!("Packet truncated"))?;
+    let crc = packet
+        .get(2 + len..2 + len + 4)
+        .map(|crc| u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]))
+        .ok_or_else(|| anyhow!("Packet truncated"))?;
+    if crc != crc32(payload) {
+        return Err(anyhow!("Packet corrupted"));
+    }
+    Ok(payload)
+}
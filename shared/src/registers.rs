@@ -0,0 +1,39 @@
+use std::ops::RangeInclusive;
+
+use crate::packet;
+
+// A register is read by writing `[register, sequence]`, the reply being the packet of
+// `[sequence, reply]`: the M5Go reads it again until the sequence is the one it wrote.
+
+/// Reply: `[status, pending frames]`
+pub const REG_STATUS: u8 = 0x01;
+/// Reply: the next pending frame, as produced by `Commands::get_stream`. The sequence is
+/// followed by flags, `TX_REPEAT` asking for the previous frame again when it came
+/// corrupted.
+pub const REG_TX_FIFO: u8 = 0x02;
+/// Written: `[REG_RX_FIFO, packet of a frame for the unit]`, without a sequence
+pub const REG_RX_FIFO: u8 = 0x03;
+/// Reply: `[unit kind, protocol version]`, for the M5Go to find its units on the bus
+pub const REG_INFO: u8 = 0x04;
+
+/// Status bit set while frames are pending for the M5Go
+pub const STATUS_TX_PENDING: u8 = 0x01;
+/// Status bit set when the last frame written by the M5Go came corrupted, it must be
+/// written again. Cleared once read.
+pub const STATUS_RX_ERROR: u8 = 0x02;
+
+/// Asks `REG_TX_FIFO` for the frame it last replied
+pub const TX_REPEAT: u8 = 0x01;
+
+/// Unit kind of the BLE bridge
+pub const UNIT_BLE: u8 = 0x01;
+
+/// Bytes of the FIFOs of the stick I2C slave, which hold a whole register write or reply
+pub const LINK_BUFFER_LEN: usize = 512;
+/// Longest frame the link carries, in a packet after the register or the sequence
+pub const MAX_LINK_FRAME_LEN: usize = LINK_BUFFER_LEN - packet::OVERHEAD - 1;
+
+/// Addresses the units may take, so that several of them share Port A
+pub const UNIT_ADDRESSES: RangeInclusive<u8> = 0x16..=0x1d;
+/// Address of the BLE unit until another one is set
+pub const DEFAULT_ADDRESS: u8 = 0x16;
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+use crate::gatt::{AdvertStatus, POSITION_CHAR_UUID, SERVICE_UUID};
+
+/// Standard services of the fitness sensors the stick pairs with
+pub const HEART_RATE_SERVICE_UUID: u16 = 0x180d;
+pub const CYCLING_SPEED_CADENCE_SERVICE_UUID: u16 = 0x1816;
+/// Characteristics notifying the measurements of the sensors
+pub const HEART_RATE_MEASUREMENT_UUID: u16 = 0x2a37;
+pub const CSC_MEASUREMENT_UUID: u16 = 0x2a5b;
+
+/// Types of the advertising data fields read from the sensors
+const AD_TYPE_UUID16_INCOMPLETE: u8 = 0x02;
+const AD_TYPE_UUID16_COMPLETE: u8 = 0x03;
+const AD_TYPE_NAME_SHORT: u8 = 0x08;
+const AD_TYPE_NAME_COMPLETE: u8 = 0x09;
+const AD_TYPE_MANUFACTURER_DATA: u8 = 0xff;
+
+/// UUID of a service or characteristic of a sensor, the fitness sensors using standard
+/// 16-bit ones and the Byke units their own 128-bit ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorUuid {
+    Uuid16(u16),
+    Uuid128([u8; 16]),
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorKind {
+    #[default]
+    HeartRate,
+    Cadence,
+    /// Stick of another Byke unit, notifying the position of its bike during group rides
+    Unit,
+}
+
+impl SensorKind {
+    pub const ALL: [SensorKind; 3] = [SensorKind::HeartRate, SensorKind::Cadence, SensorKind::Unit];
+
+    pub fn get_service_uuid(&self) -> SensorUuid {
+        match self {
+            SensorKind::HeartRate => SensorUuid::Uuid16(HEART_RATE_SERVICE_UUID),
+            SensorKind::Cadence => SensorUuid::Uuid16(CYCLING_SPEED_CADENCE_SERVICE_UUID),
+            SensorKind::Unit => SensorUuid::Uuid128(SERVICE_UUID),
+        }
+    }
+
+    pub fn get_measurement_uuid(&self) -> SensorUuid {
+        match self {
+            SensorKind::HeartRate => SensorUuid::Uuid16(HEART_RATE_MEASUREMENT_UUID),
+            SensorKind::Cadence => SensorUuid::Uuid16(CSC_MEASUREMENT_UUID),
+            SensorKind::Unit => SensorUuid::Uuid128(POSITION_CHAR_UUID),
+        }
+    }
+
+    fn from_service_uuid(uuid: u16) -> Option<Self> {
+        match uuid {
+            HEART_RATE_SERVICE_UUID => Some(SensorKind::HeartRate),
+            CYCLING_SPEED_CADENCE_SERVICE_UUID => Some(SensorKind::Cadence),
+            _ => None,
+        }
+    }
+}
+
+/// Measurement of the cycling speed and cadence sensor, a value being absent when the
+/// sensor does not measure it or went out of reach
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct CyclingData {
+    /// Crank revolutions per minute
+    pub cadence: Option<u16>,
+    /// Speed of the wheel, in km/h
+    pub wheel_speed: Option<f32>,
+}
+
+/// Fitness sensor found around the bike by the stick
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Sensor {
+    pub address: [u8; 6],
+    /// Most sensors use a random static address, which the connection must be told
+    pub random_address: bool,
+    pub kind: SensorKind,
+    pub name: String,
+    pub rssi: i8,
+}
+
+impl Sensor {
+    /// Reads an advertisement, followed by its scan response, and returns the sensor
+    /// it comes from when it advertises one of the supported services or is a Byke unit
+    pub fn from_advert(
+        address: [u8; 6],
+        random_address: bool,
+        rssi: i8,
+        data: &[u8],
+    ) -> Option<Self> {
+        let mut kind = None;
+        let mut name = String::new();
+        let mut fields = data;
+        while let [len, rest @ ..] = fields {
+            let len = *len as usize;
+            if len == 0 || len > rest.len() {
+                break;
+            }
+            let (field, next) = rest.split_at(len);
+            match field {
+                [AD_TYPE_UUID16_INCOMPLETE | AD_TYPE_UUID16_COMPLETE, uuids @ ..] => {
+                    kind = kind.or_else(|| {
+                        uuids.chunks_exact(2).find_map(|uuid| {
+                            SensorKind::from_service_uuid(u16::from_le_bytes([uuid[0], uuid[1]]))
+                        })
+                    });
+                }
+                [AD_TYPE_NAME_SHORT | AD_TYPE_NAME_COMPLETE, text @ ..] => {
+                    name = String::from_utf8_lossy(text).to_string();
+                }
+                // The other units tell their status in their manufacturer data
+                [AD_TYPE_MANUFACTURER_DATA, data @ ..] => {
+                    kind = kind.or_else(|| {
+                        AdvertStatus::from_manufacturer_data(data).map(|_| SensorKind::Unit)
+                    });
+                }
+                _ => {}
+            }
+            fields = next;
+        }
+        kind.map(|kind| Sensor {
+            address,
+            random_address,
+            kind,
+            name,
+            rssi,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heart_rate_advert() {
+        let data = [
+            2,
+            0x01,
+            0x06,
+            3,
+            AD_TYPE_UUID16_COMPLETE,
+            0x0d,
+            0x18,
+            5,
+            AD_TYPE_NAME_COMPLETE,
+            b'H',
+            b'R',
+            b'M',
+            b'1',
+        ];
+        let sensor = Sensor::from_advert([1; 6], true, -60, &data).unwrap();
+        assert_eq!(sensor.kind, SensorKind::HeartRate);
+        assert_eq!(sensor.name, "HRM1");
+        assert_eq!(sensor.rssi, -60);
+        assert!(sensor.random_address);
+    }
+
+    #[test]
+    fn unit_advert() {
+        let status = AdvertStatus::default().to_manufacturer_data();
+        let mut data = vec![1 + status.len() as u8, AD_TYPE_MANUFACTURER_DATA];
+        data.extend_from_slice(&status);
+        let sensor = Sensor::from_advert([2; 6], false, -50, &data).unwrap();
+        assert_eq!(sensor.kind, SensorKind::Unit);
+    }
+
+    #[test]
+    fn unsupported_advert() {
+        assert!(Sensor::from_advert([1; 6], true, -60, &[2, 0x01, 0x06]).is_none());
+        let data = [3, AD_TYPE_UUID16_COMPLETE, 0x0f, 0x18];
+        assert!(Sensor::from_advert([1; 6], true, -60, &data).is_none());
+    }
+
+    #[test]
+    fn truncated_advert() {
+        // The field claims more bytes than there are
+        let data = [5, AD_TYPE_UUID16_COMPLETE, 0x0d, 0x18];
+        assert!(Sensor::from_advert([1; 6], true, -60, &data).is_none());
+        // The fields before the truncated one are kept
+        let data = [
+            3,
+            AD_TYPE_UUID16_COMPLETE,
+            0x16,
+            0x18,
+            9,
+            AD_TYPE_NAME_COMPLETE,
+            b'C',
+        ];
+        let sensor = Sensor::from_advert([1; 6], true, -60, &data).unwrap();
+        assert_eq!(sensor.kind, SensorKind::Cadence);
+        assert_eq!(sensor.name, "");
+        assert!(Sensor::from_advert([1; 6], true, -60, &[]).is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+
+use crate::Commands;
+
+/// A link carrying command frames, as produced by `Commands::get_stream`
+pub trait Transport {
+    fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()>;
+
+    /// Returns the next complete frame, or `None` when nothing is pending
+    fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Frames written but not taken by the other end yet
+    fn get_pending(&self) -> usize {
+        0
+    }
+
+    /// Whether the other end was heard from lately, for the links that can tell
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Frames the other end had to read or write again, for the links that can tell
+    fn get_retries(&self) -> u32 {
+        0
+    }
+
+    fn send(&mut self, command: &Commands) -> anyhow::Result<()> {
+        self.write_frame(command.get_stream()?.as_slice())
+    }
+
+    /// Reads and decodes the next command, along with its timestamp if it has one
+    fn receive(&mut self) -> anyhow::Result<Option<(Commands, Option<u32>)>> {
+        match self.read_frame()? {
+            Some(frame) => {
+                let (command, _) = Commands::parse(&frame)?;
+                Ok(Some((command, Commands::get_timestamp(&frame))))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+type Frames = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// In-memory transport, each end reading what the other one writes
+pub struct Loopback {
+    inbox: Frames,
+    outbox: Frames,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let a: Frames = Arc::new(Mutex::new(VecDeque::new()));
+        let b: Frames = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Loopback {
+                inbox: Arc::clone(&a),
+                outbox: Arc::clone(&b),
+            },
+            Loopback {
+                inbox: b,
+                outbox: a,
+            },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        self.outbox
+            .lock()
+            .map_err(|_| anyhow!("Loopback poisoned"))?
+            .push_back(frame.to_vec());
+        Ok(())
+    }
+
+    fn get_pending(&self) -> usize {
+        self.outbox.lock().map_or(0, |outbox| outbox.len())
+    }
+
+    fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .inbox
+            .lock()
+            .map_err(|_| anyhow!("Loopback poisoned"))?
+            .pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Coordinates, EXTENDED_LENGTH, TIMESTAMP_FLAG};
+
+    #[test]
+    fn short_frame_round_trip() {
+        let (mut m5go, mut stick) = Loopback::pair();
+        let frame = Commands::SetBrightness(128).get_stream().unwrap();
+        assert_eq!(frame, vec![frame[0], 1, 128]);
+
+        m5go.write_frame(&frame).unwrap();
+        assert_eq!(m5go.get_pending(), 1);
+        match stick.receive().unwrap() {
+            Some((Commands::SetBrightness(128), None)) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(stick.receive().unwrap().is_none());
+    }
+
+    #[test]
+    fn extended_frame_round_trip() {
+        let body = "a".repeat(1000);
+        let command = Commands::Notification {
+            title: String::from("Appel"),
+            body: body.clone(),
+        };
+        let frame = command.get_stream().unwrap();
+        // Code, then 0xff and the length on two bytes
+        assert_eq!(frame[1], EXTENDED_LENGTH);
+        let length = u16::from_be_bytes([frame[2], frame[3]]) as usize;
+        assert_eq!(length + 4, frame.len());
+        assert_eq!(Commands::frame_len(&frame), Some(frame.len()));
+
+        let (mut phone, mut stick) = Loopback::pair();
+        phone.send(&command).unwrap();
+        match stick.receive().unwrap() {
+            Some((
+                Commands::Notification {
+                    title,
+                    body: received,
+                },
+                None,
+            )) => {
+                assert_eq!(title, "Appel");
+                assert_eq!(received, body);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stamped_frame_round_trip() {
+        let command = Commands::NewStep(Coordinates::new(45.5, 5.25));
+        let frame = command.get_stamped_stream(0x0102_0304).unwrap();
+        assert_eq!(frame[0], command.get_code() | TIMESTAMP_FLAG);
+        assert_eq!(Commands::get_stream_code(&frame), Some(command.get_code()));
+        assert_eq!(Commands::frame_len(&frame), Some(frame.len()));
+
+        let (mut phone, mut m5go) = Loopback::pair();
+        phone.write_frame(&frame).unwrap();
+        match m5go.receive().unwrap() {
+            Some((Commands::NewStep(coords), Some(0x0102_0304))) => {
+                assert_eq!((coords.lat, coords.long), (45.5, 5.25));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
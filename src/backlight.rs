@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use crate::{set_brightness, state::State};
+
+/// Brightness levels of the options screen
+pub const BRIGHTNESS_LEVELS: [u8; 4] = [64, 128, 192, 255];
+/// Seconds without a button pushed after which the screen is dimmed, 0 never dimming it
+pub const SCREEN_TIMEOUTS: [u8; 5] = [0, 15, 30, 60, 120];
+/// Part of the brightness kept once dimmed
+const DIM_DIVIDER: u8 = 4;
+/// Speed under which the bike is told stationary, the screen being turned off once idle
+const STATIONARY_SPEED_KMH: f64 = 2.0;
+
+/// Next value of the list after the current one, back to the first one past the last
+pub fn next_level<T: Copy + PartialOrd>(levels: &[T], current: T) -> T {
+    levels
+        .iter()
+        .copied()
+        .find(|level| *level > current)
+        .unwrap_or(levels[0])
+}
+
+/// Activity of the rider, seen by the UI
+pub struct BacklightState {
+    last_input: Instant,
+    /// Turned off, the next press only waking the screen up
+    off: bool,
+}
+
+impl BacklightState {
+    pub fn new() -> Self {
+        Self {
+            last_input: Instant::now(),
+            off: false,
+        }
+    }
+
+    /// Told on every button edge, returning whether the screen was off
+    pub fn wake(&mut self) -> bool {
+        self.last_input = Instant::now();
+        let off = self.off;
+        self.off = false;
+        off
+    }
+}
+
+/// Backlight of the screen, dimmed once no button was pushed for the timeout of the
+/// options, and turned off when the bike is stationary as well. It stays lit while a
+/// notification or the countdown of an SOS is shown.
+pub struct Backlight {
+    applied: Option<u8>,
+}
+
+impl Backlight {
+    pub fn new() -> Self {
+        Self { applied: None }
+    }
+
+    pub fn sync(&mut self, state: &mut State) {
+        let timeout = Duration::from_secs(state.options.screen_timeout as u64);
+        // A notification is shown at once, a call not waiting for the rider
+        let idle = timeout.is_zero() == false
+            && state.backlight.last_input.elapsed() >= timeout
+            && state.notification.is_visible() == false
+            && state.sos.is_counting() == false;
+        let stationary = state
+            .infos
+            .speed
+            .map_or(true, |speed| speed < STATIONARY_SPEED_KMH);
+        state.backlight.off = idle && stationary;
+
+        let level = if state.backlight.off {
+            0
+        } else if idle {
+            state.options.brightness / DIM_DIVIDER
+        } else {
+            state.options.brightness
+        };
+        if self.applied != Some(level) {
+            self.applied = Some(level);
+            set_brightness(level);
+        }
+    }
+}
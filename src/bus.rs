@@ -0,0 +1,105 @@
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc,
+};
+
+use heapless::mpmc::Q8;
+use shared::{Battery, Commands, LogLevel};
+
+use crate::{
+    gps::GpsEvent,
+    imu::MotionEvent,
+    ota::UpdateStatus,
+    screen::Button,
+    wifi::{UploadStatus, WifiRequest},
+};
+
+/// Devices of Port A
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Stick,
+    /// Temperature and humidity sensor
+    Climate,
+    /// Accelerometer and gyroscope, on the internal bus
+    Imu,
+}
+
+/// What the tasks tell the UI task, the only one updating and drawing the screens
+pub enum Event {
+    /// Command read from the stick, with the timestamp it was sent with
+    Command(Commands, Option<u32>),
+    /// Temperature and humidity
+    Climate(f32, f32),
+    Gps(GpsEvent),
+    /// Button pushed, or released
+    Button(Button, bool),
+    /// Device of Port A answering again, or no longer
+    Presence(Device, bool),
+    /// Port A was freed after failing too many times
+    BusRecovered,
+    /// Motion of the bike, told by the IMU
+    Motion(MotionEvent),
+    /// Level of the battery, sent when it changes
+    Battery(Battery),
+    /// Progress of the upload of the rides
+    Upload(UploadStatus),
+    /// Progress of the update of the firmware
+    Update(UpdateStatus),
+}
+
+/// Edges of the buttons, queued by their interrupts where a channel cannot be used. The
+/// UI task moves them to the bus.
+pub type ButtonQueue = Arc<Q8<(Button, bool)>>;
+
+/// Sending end of the bus, each task holding its own clone. The events go to the UI task,
+/// the commands to the bridge task which sends them to the stick, and the WiFi requests
+/// to the WiFi task.
+#[derive(Clone)]
+pub struct Bus {
+    events: Sender<Event>,
+    commands: Sender<Commands>,
+    wifi: Sender<WifiRequest>,
+}
+
+impl Bus {
+    pub fn new() -> (
+        Self,
+        Receiver<Event>,
+        Receiver<Commands>,
+        Receiver<WifiRequest>,
+    ) {
+        let (events, event_receiver) = mpsc::channel();
+        let (commands, command_receiver) = mpsc::channel();
+        let (wifi, wifi_receiver) = mpsc::channel();
+        (
+            Self {
+                events,
+                commands,
+                wifi,
+            },
+            event_receiver,
+            command_receiver,
+            wifi_receiver,
+        )
+    }
+
+    pub fn publish(&self, event: Event) -> Option<()> {
+        self.events.send(event).ok()
+    }
+
+    pub fn send_i2c(&self, command: Commands) -> Option<()> {
+        self.commands.send(command).ok()
+    }
+
+    pub fn send_wifi(&self, request: WifiRequest) -> Option<()> {
+        self.wifi.send(request).ok()
+    }
+
+    /// Sends a log line to the phone, through the stick
+    pub fn send_log(&self, level: LogLevel, text: &str) -> Option<()> {
+        self.send_i2c(Commands::Log {
+            level,
+            text: String::from(text),
+        })
+    }
+}
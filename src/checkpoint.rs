@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use shared::Commands;
+
+use crate::{bus::Bus, crash, route, routes, screen::ScreenId, state::State};
+
+/// Delay between two checkpoints of the ride, what was ridden since being lost on a
+/// power cut
+const CHECKPOINT_PERIOD: Duration = Duration::from_secs(60);
+/// Trip under which there is nothing worth resuming, in kilometers
+const MIN_RESUME_KM: f64 = 0.1;
+/// Bytes of the numbers, the name of the route coming after them
+const NUMBERS_LEN: usize = 5 * 8 + 4 + 2;
+/// Longest name of the route written, in bytes
+const MAX_ROUTE_NAME_LEN: usize = 64;
+/// Longest checkpoint read back from the NVS
+pub const MAX_CHECKPOINT_LEN: usize = NUMBERS_LEN + MAX_ROUTE_NAME_LEN;
+
+/// Trip computer and route progress written to the NVS during the ride, offered to the
+/// rider on the next boot
+#[derive(Clone, PartialEq)]
+pub struct Checkpoint {
+    /// In kilometers
+    pub distance: f64,
+    /// In seconds
+    pub moving_time: u32,
+    pub max_speed: f64,
+    pub climb: f64,
+    pub descent: f64,
+    pub calories: f64,
+    /// Route of the card being followed, and the steps of it behind the rider
+    pub route: Option<String>,
+    pub reached: u16,
+}
+
+impl Checkpoint {
+    fn new(state: &State) -> Self {
+        let trip = &state.trip;
+        Self {
+            distance: trip.distance,
+            moving_time: trip.moving_time.as_secs() as u32,
+            max_speed: trip.max_speed,
+            climb: trip.climb,
+            descent: trip.descent,
+            calories: trip.calories,
+            route: state.routes.active.clone(),
+            reached: state.infos.reached as u16,
+        }
+    }
+
+    /// Too short a trip to be offered, its checkpoint being removed
+    pub fn is_empty(&self) -> bool {
+        self.distance < MIN_RESUME_KM
+    }
+
+    /// A longer name of the route is cut, so that the checkpoint is read back
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut raw = vec![];
+        for number in [
+            self.distance,
+            self.max_speed,
+            self.climb,
+            self.descent,
+            self.calories,
+        ] {
+            raw.extend_from_slice(&number.to_be_bytes());
+        }
+        raw.extend_from_slice(&self.moving_time.to_be_bytes());
+        raw.extend_from_slice(&self.reached.to_be_bytes());
+        if let Some(route) = &self.route {
+            raw.extend_from_slice(crash::truncate(route, MAX_ROUTE_NAME_LEN).as_bytes());
+        }
+        raw
+    }
+
+    /// Checkpoint written by `to_raw`, none when it is not one
+    pub fn from_raw(raw: &[u8]) -> Option<Self> {
+        if raw.len() < NUMBERS_LEN {
+            return None;
+        }
+        let number =
+            |index: usize| f64::from_be_bytes(raw[index * 8..index * 8 + 8].try_into().unwrap());
+        let route = match std::str::from_utf8(&raw[NUMBERS_LEN..]).ok()? {
+            "" => None,
+            route => Some(String::from(route)),
+        };
+        Some(Self {
+            distance: number(0),
+            max_speed: number(1),
+            climb: number(2),
+            descent: number(3),
+            calories: number(4),
+            moving_time: u32::from_be_bytes(raw[40..44].try_into().unwrap()),
+            reached: u16::from_be_bytes(raw[44..46].try_into().unwrap()),
+            route,
+        })
+    }
+}
+
+pub struct CheckpointState {
+    /// Found in the NVS at boot, until the rider answers the resume screen
+    pub offered: Option<Checkpoint>,
+    saved_at: Option<Instant>,
+}
+
+impl CheckpointState {
+    pub fn new() -> Self {
+        Self {
+            offered: None,
+            saved_at: None,
+        }
+    }
+}
+
+/// Shows the resume screen at boot, a ride having been cut short
+pub fn offer(state: &mut State, checkpoint: Checkpoint) {
+    state.checkpoint.offered = Some(checkpoint);
+    state.current_screen = ScreenId::Resume;
+}
+
+/// Checkpoint to write to the NVS once a minute, none while the rider has not told
+/// whether to resume the one of the last boot
+pub fn sync(state: &mut State) -> Option<Checkpoint> {
+    let due = state
+        .checkpoint
+        .saved_at
+        .is_none_or(|at| at.elapsed() >= CHECKPOINT_PERIOD);
+    if state.checkpoint.offered.is_some() || due == false {
+        return None;
+    }
+    state.checkpoint.saved_at = Some(Instant::now());
+    Some(Checkpoint::new(state))
+}
+
+/// Puts the trip and the route of the checkpoint back, the profile and the laps of the
+/// ride being lost
+pub fn resume(bus: &Bus, state: &mut State) {
+    let checkpoint = match state.checkpoint.offered.take() {
+        Some(checkpoint) => checkpoint,
+        None => return,
+    };
+    let trip = &mut state.trip;
+    trip.distance = checkpoint.distance;
+    trip.moving_time = Duration::from_secs(checkpoint.moving_time as u64);
+    trip.max_speed = checkpoint.max_speed;
+    trip.climb = checkpoint.climb;
+    trip.descent = checkpoint.descent;
+    trip.calories = checkpoint.calories;
+    state.notification.show(
+        String::from("Sortie reprise"),
+        format!("{:.1} km", checkpoint.distance),
+    );
+
+    if let Some(name) = checkpoint.route {
+        match routes::load(&name) {
+            Ok(steps) => {
+                state.infos.set_route(steps);
+                state.infos.set_target(checkpoint.reached as usize);
+                bus.send_i2c(Commands::RouteSelected(name.clone()))
+                    .or_else(|| {
+                        println!("Error sending RouteSelected command");
+                        None
+                    });
+                route::send_progress(bus, state.infos.reached, state.infos.route.len());
+                state.routes.active = Some(name);
+            }
+            Err(err) => {
+                println!("Loading route {} failed: {}", name, err);
+                state
+                    .notification
+                    .show(String::from("Itineraire illisible"), name);
+            }
+        }
+    }
+    state.current_screen = ScreenId::Main;
+}
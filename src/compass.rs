@@ -0,0 +1,105 @@
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor},
+    primitives::Rectangle,
+};
+
+use crate::screen::Shape;
+
+/// Speed over which the GPS course is followed, the IMU turning the heading under it
+const COURSE_SPEED_KMH: f64 = 5.0;
+/// Pixels left around the dial
+const MARGIN: i32 = 8;
+/// Segments of the dial ring
+const RING_SEGMENTS: i32 = 36;
+const RING_COLOR: Rgb565 = Rgb565::WHITE;
+const NORTH_COLOR: Rgb565 = Rgb565::RED;
+const STEP_COLOR: Rgb565 = Rgb565::CYAN;
+/// Length of the tick of the next step, inside the ring
+const STEP_TICK: i32 = 18;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeadingSource {
+    Gps,
+    /// Turned by the gyroscope from the last GPS course, the bike being too slow for it
+    Imu,
+}
+
+/// Direction the bike faces, unknown until the GPS gave a course once
+pub struct HeadingState {
+    /// Degrees clockwise from the north
+    pub heading: Option<f32>,
+    pub source: HeadingSource,
+}
+
+impl HeadingState {
+    pub fn new() -> Self {
+        Self {
+            heading: None,
+            source: HeadingSource::Imu,
+        }
+    }
+
+    /// Course over ground of an RMC sentence, only trusted once the bike moves fast enough
+    pub fn set_course(&mut self, course: Option<f64>, speed: Option<f64>) {
+        match (course, speed) {
+            (Some(course), Some(speed)) if speed >= COURSE_SPEED_KMH => {
+                self.heading = Some(course as f32);
+                self.source = HeadingSource::Gps;
+            }
+            _ => self.source = HeadingSource::Imu,
+        }
+    }
+
+    /// Rotation told by the IMU, ignored while the GPS course is followed
+    pub fn turn(&mut self, degrees: f32) {
+        if self.source == HeadingSource::Imu {
+            self.heading = self
+                .heading
+                .map(|heading| (heading + degrees).rem_euclid(360.0));
+        }
+    }
+}
+
+/// Cardinal direction of the heading, in French
+pub fn get_cardinal(heading: f32) -> &'static str {
+    const CARDINALS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SO", "O", "NO"];
+    CARDINALS[((heading.rem_euclid(360.0) + 22.5) / 45.0) as usize % 8]
+}
+
+/// Dial turned so that the front of the bike is up, with the north needle and the tick of
+/// the next step
+pub fn get_shapes(heading: Option<f32>, step_bearing: Option<f32>, area: Rectangle) -> Vec<Shape> {
+    let heading = match heading {
+        Some(heading) => heading,
+        None => return vec![],
+    };
+    let radius = (area.size.width.min(area.size.height) as i32) / 2 - MARGIN;
+    let center = area.center();
+    // Angles clockwise from the top of the screen, the rows going down
+    let at = |degrees: f32, length: i32| {
+        let radians = degrees.to_radians();
+        center
+            + Point::new(
+                (radians.sin() * length as f32) as i32,
+                -(radians.cos() * length as f32) as i32,
+            )
+    };
+
+    let ring = (0..=RING_SEGMENTS)
+        .map(|i| at(i as f32 * 360.0 / RING_SEGMENTS as f32, radius))
+        .collect();
+    let mut shapes = vec![
+        Shape::Polyline(RING_COLOR, ring),
+        Shape::Polyline(NORTH_COLOR, vec![center, at(-heading, radius - MARGIN)]),
+        Shape::Marker(RING_COLOR, at(0.0, radius)),
+    ];
+    if let Some(bearing) = step_bearing {
+        let angle = bearing - heading;
+        shapes.push(Shape::Polyline(
+            STEP_COLOR,
+            vec![at(angle, radius - STEP_TICK), at(angle, radius)],
+        ));
+    }
+    shapes
+}
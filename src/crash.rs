@@ -0,0 +1,142 @@
+use std::{panic, ptr, sync::Mutex};
+
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::Rgb565,
+    prelude::{DrawTarget, Point, RgbColor},
+    text::Text,
+    Drawable,
+};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_sys::{
+    esp, esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_TASK_WDT,
+    esp_reset_reason_t_ESP_RST_WDT, esp_task_wdt_add, esp_task_wdt_reset,
+};
+use shared::TextSize;
+
+use crate::framebuffer::SharedScreen;
+
+pub const CRASH_NAMESPACE: &str = "crash";
+/// Panic message of the run that panicked, read by the next boot
+const PANIC_KEY: &str = "panic";
+/// Report of the last run that did not end well, kept until another one does not
+const REPORT_KEY: &str = "report";
+/// Longest panic message kept, the rest is cut
+const MAX_PANIC_LEN: usize = 96;
+const MAX_REPORT_LEN: usize = 160;
+/// Time the panic message stays on the screen before the restart
+const PANIC_SCREEN_MS: u32 = 3000;
+/// Characters on a line of the screen, in the small font
+const LINE_LEN: usize = 50;
+
+/// Start of the text, cut on a character
+pub fn truncate(text: &str, max_len: usize) -> &str {
+    let end = (0..=max_len.min(text.len()))
+        .rev()
+        .find(|end| text.is_char_boundary(*end))
+        .unwrap_or(0);
+    &text[..end]
+}
+
+/// Text cut into lines fitting the screen
+pub fn wrap(text: &str) -> String {
+    text.chars()
+        .collect::<Vec<char>>()
+        .chunks(LINE_LEN)
+        .map(|line| line.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Keeps the panic message for the next boot and shows it for a few seconds, then panics
+/// as usual. The screen is held meanwhile, so that the other tasks do not draw over it.
+pub fn init_panic_hook(nvs: EspNvs<NvsDefault>, screen: SharedScreen) {
+    let nvs = Mutex::new(nvs);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let reason = info.to_string();
+        let reason = truncate(&reason, MAX_PANIC_LEN);
+        nvs.try_lock()
+            .ok()
+            .and_then(|mut nvs| nvs.set_raw(PANIC_KEY, reason.as_bytes()).ok())
+            .or_else(|| {
+                println!("Failed to save the panic message");
+                None
+            });
+        // Not drawn when the panic comes while the screen is held
+        screen.try_lock().ok().and_then(|mut driver| {
+            driver.clear(Rgb565::RED).ok()?;
+            let text = format!("Erreur, redemarrage\n\n{}", wrap(reason));
+            let style = MonoTextStyle::new(TextSize::Small.get_font(), Rgb565::WHITE);
+            Text::new(&text, Point::new(5, 20), style)
+                .draw(&mut *driver)
+                .ok()?;
+            FreeRtos::delay_ms(PANIC_SCREEN_MS);
+            Some(())
+        });
+        default_hook(info);
+    }));
+}
+
+/// Restarts the M5Go when the calling task does not feed the watchdog in time, a stuck
+/// I2C transaction or display transfer freezing the UI otherwise. The timeout is set by
+/// `CONFIG_ESP_TASK_WDT_TIMEOUT_S`.
+pub fn watch_task() {
+    esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })
+        .ok()
+        .or_else(|| {
+            println!("Unable to watch the task");
+            None
+        });
+}
+
+pub fn feed_watchdog() {
+    unsafe { esp_task_wdt_reset() };
+}
+
+/// Why the previous run ended, when it did not end on a power-on or a requested restart.
+/// It is kept as the last report.
+pub fn take_report(nvs: &mut EspNvs<NvsDefault>) -> Option<String> {
+    let mut buffer = [0u8; MAX_PANIC_LEN];
+    let panic_reason = nvs
+        .get_raw(PANIC_KEY, &mut buffer)
+        .ok()
+        .flatten()
+        .map(|reason| String::from_utf8_lossy(reason).to_string());
+    if panic_reason.is_some() {
+        nvs.remove(PANIC_KEY).ok();
+    }
+
+    #[allow(non_upper_case_globals)]
+    let report = match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_PANIC => Some(format!(
+            "M5Go restarted after a crash: {}",
+            panic_reason.unwrap_or_else(|| String::from("unknown"))
+        )),
+        esp_reset_reason_t_ESP_RST_TASK_WDT
+        | esp_reset_reason_t_ESP_RST_INT_WDT
+        | esp_reset_reason_t_ESP_RST_WDT => Some(String::from("M5Go restarted by the watchdog")),
+        esp_reset_reason_t_ESP_RST_BROWNOUT => Some(String::from("M5Go restarted on low battery")),
+        _ => None,
+    };
+    if let Some(report) = &report {
+        nvs.set_raw(REPORT_KEY, truncate(report, MAX_REPORT_LEN).as_bytes())
+            .ok()
+            .or_else(|| {
+                println!("Failed to save the crash report");
+                None
+            });
+    }
+    report
+}
+
+/// Report of the last run that did not end well, this one or an older one
+pub fn last_report(nvs: &EspNvs<NvsDefault>) -> Option<String> {
+    let mut buffer = [0u8; MAX_REPORT_LEN];
+    nvs.get_raw(REPORT_KEY, &mut buffer)
+        .ok()
+        .flatten()
+        .map(|report| String::from_utf8_lossy(report).to_string())
+}
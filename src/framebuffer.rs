@@ -0,0 +1,135 @@
+use std::{
+    convert::Infallible,
+    mem,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Dimensions, DrawTarget, Point, RgbColor},
+    primitives::Rectangle,
+    Pixel,
+};
+use m5_go::M5GoScreenDriver;
+
+/// Pixels composed off-screen at once, enough for the popup and every box but the
+/// background of the screens
+pub const BUFFER_PIXELS: usize = 320 * 80;
+
+/// Screen shared by the UI task, drawing what does not fit in a buffer, and the display
+/// task sending the buffers
+pub type SharedScreen = Arc<Mutex<M5GoScreenDriver>>;
+
+/// Area composed, and its pixels
+pub type Frame = (Rectangle, Vec<Rgb565>);
+
+/// UI end of the display task. Two buffers go back and forth: one is composed while the
+/// other is sent to the panel.
+pub struct Display {
+    screen: SharedScreen,
+    frames: Sender<Frame>,
+    spares: Receiver<Vec<Rgb565>>,
+    /// Buffer given back by the display task, none while a frame is being sent
+    spare: Option<Vec<Rgb565>>,
+}
+
+impl Display {
+    pub fn new(screen: SharedScreen) -> (Self, Receiver<Frame>, Sender<Vec<Rgb565>>) {
+        let (frames, frame_receiver) = mpsc::channel();
+        let (spare_sender, spares) = mpsc::channel();
+        let display = Self {
+            screen,
+            frames,
+            spares,
+            spare: Some(Vec::with_capacity(BUFFER_PIXELS)),
+        };
+        (display, frame_receiver, spare_sender)
+    }
+
+    /// Waits for the frame being sent, if any
+    fn wait(&mut self) -> Option<()> {
+        if self.spare.is_none() {
+            self.spare = self.spares.recv().ok();
+        }
+        self.spare.as_ref().map(|_| ())
+    }
+
+    /// Draws on the screen directly, once the frame being sent is on it so that what is
+    /// drawn lands on top
+    pub fn draw_direct<T>(&mut self, f: impl FnOnce(&mut M5GoScreenDriver) -> T) -> Option<T> {
+        self.wait()?;
+        let mut driver = self.screen.lock().ok()?;
+        Some(f(&mut driver))
+    }
+}
+
+/// Pixels of an area of the panel, composed in RAM then sent in a single transfer so that
+/// the fill and the text of a box never show apart. There is no room for a whole frame
+/// without PSRAM, an area larger than the buffer is drawn on the panel directly.
+pub struct AreaBuffer {
+    area: Rectangle,
+    pixels: Vec<Rgb565>,
+    capacity: usize,
+}
+
+impl AreaBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            area: Rectangle::new(Point::zero(), Default::default()),
+            pixels: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Starts composing `area`, false when it does not fit in the buffer
+    pub fn start(&mut self, area: Rectangle) -> bool {
+        let len = area.size.width as usize * area.size.height as usize;
+        if len > self.capacity {
+            return false;
+        }
+        self.area = area;
+        self.pixels.clear();
+        self.pixels.resize(len, Rgb565::BLACK);
+        true
+    }
+
+    /// Hands the composed area to the display task, swapping for the buffer of the frame
+    /// sent before. Only waits while that frame is still being sent: the transfer itself
+    /// is a blocking write of the display task, the m5-go crate setting the SPI bus up.
+    pub fn hand_over(&mut self, display: &mut Display) -> Option<()> {
+        display.wait()?;
+        let spare = display.spare.take()?;
+        let pixels = mem::replace(&mut self.pixels, spare);
+        display.frames.send((self.area, pixels)).ok()
+    }
+}
+
+impl Dimensions for AreaBuffer {
+    fn bounding_box(&self) -> Rectangle {
+        self.area
+    }
+}
+
+impl DrawTarget for AreaBuffer {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let width = self.area.size.width as i32;
+        let height = self.area.size.height as i32;
+        for Pixel(point, color) in pixels {
+            // What falls out of the area is left as it is on the panel
+            let point = point - self.area.top_left;
+            if point.x >= 0 && point.y >= 0 && point.x < width && point.y < height {
+                self.pixels[(point.y * width + point.x) as usize] = color;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Duration, Instant};
+
+use crate::screen::Button;
+
+/// Time a button is held for a long press
+const LONG_PRESS: Duration = Duration::from_millis(800);
+/// Longest time between the release of a press and the next one for a double press
+const DOUBLE_PRESS: Duration = Duration::from_millis(300);
+/// Time a button is held before it repeats
+const REPEAT_DELAY: Duration = Duration::from_millis(400);
+/// Delay between the first repeats, shortened by `REPEAT_STEP` at each one down to
+/// `REPEAT_MIN`, the UI task polling every 100 ms when idle
+const REPEAT_START: Duration = Duration::from_millis(250);
+const REPEAT_STEP: Duration = Duration::from_millis(25);
+const REPEAT_MIN: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Short,
+    Long,
+    Double,
+    /// Held, again and again faster
+    Repeat,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Presses {
+    pushed_at: Option<Instant>,
+    /// Release of a first press, while a second one may come
+    released_at: Option<Instant>,
+    /// Pushed again soon after a first press
+    second: bool,
+    /// Held long enough for a long press, which was told
+    long: bool,
+    repeated_at: Option<Instant>,
+    repeats: u32,
+    /// The long press was handled by a screen, its release is not
+    consumed: bool,
+}
+
+/// Gestures of the buttons, made out of their edges. They are kept across the screens, a
+/// long press switching to another one not having its release seen there.
+#[derive(Default)]
+pub struct Gestures {
+    buttons: [Presses; 3],
+}
+
+impl Gestures {
+    fn get_mut(&mut self, button: Button) -> &mut Presses {
+        &mut self.buttons[button as usize - 1]
+    }
+
+    /// Feeds an edge of the button, returning the gesture it ends. A short press is held
+    /// back while a second one may come when `wait_double`.
+    pub fn push(&mut self, button: Button, pushed: bool, wait_double: bool) -> Option<Gesture> {
+        let presses = self.get_mut(button);
+        if pushed {
+            presses.second = presses
+                .released_at
+                .map_or(false, |at| at.elapsed() < DOUBLE_PRESS);
+            presses.released_at = None;
+            presses.pushed_at = Some(Instant::now());
+            presses.long = false;
+            presses.repeated_at = None;
+            presses.repeats = 0;
+            presses.consumed = false;
+            return None;
+        }
+
+        presses.pushed_at = None;
+        if presses.long || presses.repeats > 0 {
+            None
+        } else if presses.second {
+            presses.second = false;
+            Some(Gesture::Double)
+        } else if wait_double {
+            presses.released_at = Some(Instant::now());
+            None
+        } else {
+            Some(Gesture::Short)
+        }
+    }
+
+    /// Gestures ended by time passing: the buttons held long enough, the ones held
+    /// repeating, and the short presses no second one came after
+    pub fn poll(&mut self) -> Vec<(Button, Gesture)> {
+        let mut gestures = vec![];
+        for button in [Button::A, Button::B, Button::C] {
+            let presses = self.get_mut(button);
+            let held = presses
+                .pushed_at
+                .map_or(false, |at| at.elapsed() >= LONG_PRESS);
+            if held && presses.long == false {
+                presses.long = true;
+                presses.second = false;
+                gestures.push((button, Gesture::Long));
+            }
+            let repeat = match (presses.pushed_at, presses.repeated_at) {
+                (Some(_), Some(at)) => at.elapsed() >= repeat_interval(presses.repeats),
+                (Some(at), None) => at.elapsed() >= REPEAT_DELAY,
+                (None, _) => false,
+            };
+            if repeat {
+                presses.repeated_at = Some(Instant::now());
+                presses.repeats += 1;
+                presses.second = false;
+                gestures.push((button, Gesture::Repeat));
+            }
+            let alone = presses
+                .released_at
+                .map_or(false, |at| at.elapsed() >= DOUBLE_PRESS);
+            if alone {
+                presses.released_at = None;
+                gestures.push((button, Gesture::Short));
+            }
+        }
+        gestures
+    }
+
+    /// Keeps the release of the long press, or of the repeats, from the button callbacks
+    pub fn consume(&mut self, button: Button) {
+        self.get_mut(button).consumed = true;
+    }
+
+    pub fn is_consumed(&self, button: Button) -> bool {
+        self.buttons[button as usize - 1].consumed
+    }
+}
+
+fn repeat_interval(repeats: u32) -> Duration {
+    REPEAT_START
+        .saturating_sub(REPEAT_STEP.saturating_mul(repeats))
+        .max(REPEAT_MIN)
+}
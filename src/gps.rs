@@ -1,34 +1,145 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    fs::{self, File},
+    io::Write,
+    time::{Duration, Instant},
+};
 
-use critical_section::CriticalSection;
+use esp_idf_hal::uart::UartDriver;
+use heapless::Deque;
 use nmea_parser::{NmeaParser, ParsedMessage};
 
-use crate::UART;
+use crate::storage;
 
-pub fn read_gps_line(cs: CriticalSection) -> Option<ParsedMessage> {
-    // A line starts with '$' (code 36), and ends with '\n' (code 10)
-    let mut line: Vec<u8> = vec![];
-    let start = SystemTime::now();
+/// Bytes kept until they make up a line, a sentence being at most 82 of them
+const RING_SIZE: usize = 512;
+/// Longest wait for bytes in the UART, 100 ms at the default 100 Hz tick
+const READ_TIMEOUT_TICKS: u32 = 10;
 
-    UART.borrow_ref(cs).as_ref().and_then(|driver| loop {
-        if start.elapsed().unwrap() > Duration::from_secs(1) {
-            return None;
-        }
+/// File of the card the raw sentences are written to, moved to `NMEA_LOG_OLD` when it
+/// reaches `MAX_NMEA_LOG_LEN` so that the log takes twice that at most
+const NMEA_LOG: &str = "nmea.log";
+const NMEA_LOG_OLD: &str = "nmea.old";
+const MAX_NMEA_LOG_LEN: u64 = 4 * 1024 * 1024;
+/// Delay before the log is opened again after a write failed, the card being missing
+const NMEA_LOG_RETRY: Duration = Duration::from_secs(10);
+
+/// What the GPS task tells the UI
+pub enum GpsEvent {
+    Sentence(ParsedMessage),
+    /// No sentence could be read for a second
+    Silent,
+}
 
-        if driver.remaining_read().unwrap() > 0 {
-            let mut buf = [0_u8];
-            driver.read(&mut buf, 100).unwrap();
-            line.extend_from_slice(&buf);
+/// Lines of the GPS, the UART driver filling its own buffer from its interrupt while the
+/// bytes wait here until a line is complete
+pub struct GpsReader<'d> {
+    driver: UartDriver<'d>,
+    ring: Deque<u8, RING_SIZE>,
+    parser: NmeaParser,
+}
+
+impl<'d> GpsReader<'d> {
+    pub fn new(driver: UartDriver<'d>) -> Self {
+        Self {
+            driver,
+            ring: Deque::new(),
+            parser: NmeaParser::new(),
+        }
+    }
 
-            if line.starts_with("$".as_bytes()) == false {
-                line.clear();
+    /// Moves the bytes received by the UART to the ring, waiting a little for some to come.
+    /// The oldest bytes make room when the lines are not read fast enough.
+    pub fn fill(&mut self) {
+        let mut buffer = [0u8; 64];
+        let read = self
+            .driver
+            .read(&mut buffer, READ_TIMEOUT_TICKS)
+            .unwrap_or_default();
+        for byte in &buffer[..read] {
+            if self.ring.is_full() {
+                self.ring.pop_front();
             }
+            self.ring.push_back(*byte).ok();
+        }
+    }
 
-            if line.ends_with("\n".as_bytes()) {
-                let sentence = String::from_utf8(line).unwrap();
-                let mut parser = NmeaParser::new();
-                return parser.parse_sentence(sentence.as_str()).ok();
+    /// Next complete line of the ring, without waiting for one
+    pub fn next_line(&mut self) -> Option<String> {
+        // A line starts with '$' (code 36), and ends with '\n' (code 10)
+        loop {
+            let end = self.ring.iter().position(|byte| *byte == b'\n')?;
+            let line: Vec<u8> = (0..=end).filter_map(|_| self.ring.pop_front()).collect();
+            // What comes before the '$' is the end of a line partly dropped
+            let sentence = line
+                .iter()
+                .position(|byte| *byte == b'$')
+                .and_then(|start| String::from_utf8(line[start..].to_vec()).ok());
+            if sentence.is_some() {
+                return sentence;
             }
         }
-    })
+    }
+
+    pub fn parse(&mut self, line: &str) -> Option<ParsedMessage> {
+        self.parser.parse_sentence(line).ok()
+    }
+}
+
+/// Raw sentences written to the card, to look into the quality of the fixes
+pub struct NmeaLog {
+    file: Option<File>,
+    len: u64,
+    failed_at: Option<Instant>,
+}
+
+impl NmeaLog {
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            len: 0,
+            failed_at: None,
+        }
+    }
+
+    fn open(&mut self) -> std::io::Result<()> {
+        if self.len >= MAX_NMEA_LOG_LEN {
+            self.file = None;
+            // FatFS does not rename over an existing file
+            fs::remove_file(storage::get_path(NMEA_LOG_OLD)).ok();
+            fs::rename(storage::get_path(NMEA_LOG), storage::get_path(NMEA_LOG_OLD)).ok();
+        }
+        let file = storage::open_append(NMEA_LOG)?;
+        self.len = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Writes the line when the log is enabled, and closes the file when it is not
+    pub fn write(&mut self, line: &str, enabled: bool) {
+        if enabled == false {
+            self.file = None;
+            self.failed_at = None;
+            return;
+        }
+        if self
+            .failed_at
+            .map_or(false, |at| at.elapsed() < NMEA_LOG_RETRY)
+        {
+            return;
+        }
+
+        let mut written = Ok(());
+        if self.file.is_none() || self.len >= MAX_NMEA_LOG_LEN {
+            written = self.open();
+        }
+        if let (Ok(_), Some(file)) = (&written, self.file.as_mut()) {
+            written = file.write_all(line.as_bytes());
+            self.len += line.len() as u64;
+        }
+        if let Err(err) = written {
+            println!("Writing the NMEA log failed: {}", err);
+            self.file = None;
+            self.failed_at = Some(Instant::now());
+        }
+    }
 }
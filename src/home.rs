@@ -0,0 +1,53 @@
+use shared::{Commands, Coordinates};
+
+use crate::{bus::Bus, state::State};
+
+/// Distance from home under which the rider is told at home, in kilometers, the GPS
+/// wandering by tens of meters
+const HOME_RADIUS_KM: f64 = 0.1;
+
+/// Home of the rider, set by the phone and kept in the NVS
+pub struct HomeState {
+    pub home: Option<Coordinates>,
+    /// Unknown until a fix, so that booting at home is not told arriving
+    inside: Option<bool>,
+}
+
+impl HomeState {
+    pub fn new(home: Option<Coordinates>) -> Self {
+        Self { home, inside: None }
+    }
+
+    /// Position sent by the phone, an invalid one forgetting the home
+    pub fn set(&mut self, home: &Coordinates) {
+        self.home = Some(Coordinates::new(home.lat, home.long)).filter(|home| home.is_valid());
+        self.inside = None;
+    }
+}
+
+/// Once the rider arrives home, stops the recording, disarms the anti-theft of the stick
+/// and asks for the rides to be uploaded
+pub fn sync(bus: &Bus, state: &mut State) {
+    let inside = match (&state.home.home, &state.infos.coords) {
+        (Some(home), Some(coords)) if coords.is_valid() => coords.distance(home) <= HOME_RADIUS_KM,
+        _ => return,
+    };
+    let arrived = inside && state.home.inside == Some(false);
+    state.home.inside = Some(inside);
+    if arrived == false {
+        return;
+    }
+
+    println!("Arrived home");
+    state.recording.wanted = false;
+    bus.send_i2c(Commands::SetAntiTheft(false)).or_else(|| {
+        println!("Error sending SetAntiTheft command");
+        None
+    });
+    // Uploaded by the WiFi sync once the ride is stopped and its summary closed
+    state.upload.asked_at = None;
+    state.notification.show(
+        String::from("Arrivee a la maison"),
+        String::from("Trajet arrete, envoi des sorties"),
+    );
+}
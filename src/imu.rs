@@ -0,0 +1,199 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::{delay::FreeRtos, i2c::I2cDriver};
+
+/// MPU6886 accelerometer and gyroscope, on the internal bus wired to Port A
+const IMU: u8 = 0x68;
+const REG_SMPLRT_DIV: u8 = 0x19;
+const REG_CONFIG: u8 = 0x1a;
+const REG_GYRO_CONFIG: u8 = 0x1b;
+const REG_ACCEL_CONFIG: u8 = 0x1c;
+const REG_ACCEL_CONFIG2: u8 = 0x1d;
+/// First of the accelerometer, temperature and gyroscope registers, read at once
+const REG_ACCEL_XOUT_H: u8 = 0x3b;
+const REG_PWR_MGMT_1: u8 = 0x6b;
+const REG_WHO_AM_I: u8 = 0x75;
+const WHO_AM_I: u8 = 0x19;
+const TIMEOUT: u32 = 50;
+
+/// Raw values per g, in the ±8 g range
+const ACCEL_SCALE: f32 = 4096.0;
+/// Raw values per degree per second, in the ±2000 °/s range
+const GYRO_SCALE: f32 = 16.4;
+
+/// Acceleration beyond which a shock is told, a bump of the road staying under it
+const SHOCK_G: f32 = 3.0;
+/// Time after a shock before another one is told, a fall shaking the bike a while
+const SHOCK_REARM: Duration = Duration::from_secs(1);
+/// Rotation and acceleration off gravity beyond which the bike is told moving
+const MOTION_DPS: f32 = 15.0;
+const MOTION_G: f32 = 0.15;
+/// Time without any motion after which the bike is told still
+const STILL_DELAY: Duration = Duration::from_secs(5);
+/// Change of the tilt told again
+const TILT_STEP_DEG: f32 = 5.0;
+/// Rotation rate under which the gyroscope is told still, its drift not turning the heading
+const GYRO_DEADBAND_DPS: f32 = 1.5;
+/// Rotation around the vertical told once reached
+const TURN_STEP_DEG: f32 = 2.0;
+
+/// Measure of the IMU, in g and degrees per second
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MotionEvent {
+    /// The bike started moving, or stayed still for a while
+    Moving(bool),
+    /// Acceleration of a shock, in g
+    Shock(f32),
+    /// Angle in degrees from the position of the bike at boot, told once it changed enough
+    Tilt(f32),
+    /// Rotation around the vertical since the last one told, in degrees clockwise seen from
+    /// above
+    Turn(f32),
+}
+
+/// Wakes the IMU up and sets its ranges, telling whether it answered
+pub fn start(driver: &mut I2cDriver) -> bool {
+    let mut who_am_i = [0u8];
+    if driver
+        .write_read(IMU, &[REG_WHO_AM_I], &mut who_am_i, TIMEOUT)
+        .is_err()
+        || who_am_i[0] != WHO_AM_I
+    {
+        return false;
+    }
+    // Reset, then clocked by the gyroscope once it settled
+    let reset = driver.write(IMU, &[REG_PWR_MGMT_1, 0x80], TIMEOUT).is_ok();
+    FreeRtos::delay_ms(10);
+    reset
+        && [
+            [REG_PWR_MGMT_1, 0x01],
+            // ±8 g and ±2000 °/s, filtered at about 180 Hz
+            [REG_ACCEL_CONFIG, 0x10],
+            [REG_GYRO_CONFIG, 0x18],
+            [REG_CONFIG, 0x01],
+            [REG_SMPLRT_DIV, 0x05],
+            [REG_ACCEL_CONFIG2, 0x00],
+        ]
+        .iter()
+        .all(|write| driver.write(IMU, write, TIMEOUT).is_ok())
+}
+
+pub fn read(driver: &mut I2cDriver) -> Option<Sample> {
+    let mut buffer = [0u8; 14];
+    driver
+        .write_read(IMU, &[REG_ACCEL_XOUT_H], &mut buffer, TIMEOUT)
+        .ok()?;
+    let value = |i: usize| i16::from_be_bytes([buffer[i], buffer[i + 1]]) as f32;
+    // The temperature sits between the two, in bytes 6 and 7
+    Some(Sample {
+        accel: [value(0), value(2), value(4)].map(|raw| raw / ACCEL_SCALE),
+        gyro: [value(8), value(10), value(12)].map(|raw| raw / GYRO_SCALE),
+    })
+}
+
+fn norm(vector: &[f32; 3]) -> f32 {
+    vector.iter().map(|axis| axis * axis).sum::<f32>().sqrt()
+}
+
+/// Turns the samples into the motion events, each told once when it happens
+pub struct MotionDetector {
+    moving: bool,
+    moved_at: Instant,
+    shock_at: Option<Instant>,
+    /// Gravity at boot, the tilt being measured from it whatever the mount of the M5Go
+    reference: Option<[f32; 3]>,
+    tilt: Option<f32>,
+    /// Last acceleration close to gravity, pointing up
+    gravity: Option<[f32; 3]>,
+    sampled_at: Option<Instant>,
+    /// Rotation not told yet
+    turned: f32,
+}
+
+impl MotionDetector {
+    pub fn new() -> Self {
+        Self {
+            moving: false,
+            moved_at: Instant::now(),
+            shock_at: None,
+            reference: None,
+            tilt: None,
+            gravity: None,
+            sampled_at: None,
+            turned: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, sample: &Sample) -> Vec<MotionEvent> {
+        let mut events = vec![];
+        let accel = norm(&sample.accel);
+
+        if accel > SHOCK_G
+            && self
+                .shock_at
+                .map_or(true, |shock_at| shock_at.elapsed() > SHOCK_REARM)
+        {
+            self.shock_at = Some(Instant::now());
+            events.push(MotionEvent::Shock(accel));
+        }
+
+        if norm(&sample.gyro) > MOTION_DPS || (accel - 1.0).abs() > MOTION_G {
+            self.moved_at = Instant::now();
+        }
+        let moving = self.moved_at.elapsed() < STILL_DELAY;
+        if moving != self.moving {
+            self.moving = moving;
+            events.push(MotionEvent::Moving(moving));
+        }
+
+        // Only gravity is measured when the acceleration is close to it, the tilt being
+        // meaningless otherwise
+        if (accel - 1.0).abs() <= MOTION_G {
+            self.gravity = Some(sample.accel);
+            let reference = *self.reference.get_or_insert(sample.accel);
+            let cos = sample
+                .accel
+                .iter()
+                .zip(reference.iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / (accel * norm(&reference));
+            let tilt = cos.clamp(-1.0, 1.0).acos().to_degrees();
+            if self
+                .tilt
+                .map_or(true, |told| (tilt - told).abs() >= TILT_STEP_DEG)
+            {
+                self.tilt = Some(tilt);
+                events.push(MotionEvent::Tilt(tilt));
+            }
+        }
+
+        // The rotation around gravity, whatever the mount of the M5Go, the right hand rule
+        // turning counterclockwise around the up axis
+        let now = Instant::now();
+        if let (Some(sampled_at), Some(gravity)) = (self.sampled_at, self.gravity) {
+            let rate = sample
+                .gyro
+                .iter()
+                .zip(gravity.iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / norm(&gravity);
+            if rate.abs() > GYRO_DEADBAND_DPS {
+                self.turned -= rate * (now - sampled_at).as_secs_f32();
+            }
+            if self.turned.abs() >= TURN_STEP_DEG {
+                events.push(MotionEvent::Turn(self.turned));
+                self.turned = 0.0;
+            }
+        }
+        self.sampled_at = Some(now);
+        events
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_hal::{
+    delay::{Ets, FreeRtos},
+    i2c::I2cDriver,
+};
+use esp_idf_sys::{
+    esp, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD, gpio_set_direction, gpio_set_level,
+    i2c_mode_t_I2C_MODE_MASTER, i2c_reset_rx_fifo, i2c_reset_tx_fifo, i2c_set_pin,
+};
+use shared::{
+    packet,
+    registers::{
+        MAX_LINK_FRAME_LEN, REG_INFO, REG_RX_FIFO, REG_STATUS, REG_TX_FIFO, STATUS_RX_ERROR,
+        STATUS_TX_PENDING, TX_REPEAT, UNIT_ADDRESSES,
+    },
+    Commands, Transport,
+};
+
+const TIMEOUT: u32 = 50;
+/// Delay between two reads of a reply the unit has not loaded yet
+pub const REPLY_POLL_MS: u32 = 1;
+/// Time left to the unit to load the reply of a register, the stick checks for a request
+/// every tick
+const REPLY_TIMEOUT: Duration = Duration::from_millis(30);
+/// Attempts at an exchange before giving up on it
+const MAX_ATTEMPTS: usize = 3;
+
+/// Pins of Port A
+const SDA_PIN: i32 = 21;
+const SCL_PIN: i32 = 22;
+/// Half a clock period of the recovery, 100 kHz
+const RECOVERY_HALF_PERIOD_US: u32 = 5;
+
+/// Frees the bus held by a device stuck in the middle of a transfer: clocks it out of the
+/// byte it sends, then ends the transfer with a stop, before handing the pins back to the
+/// I2C controller
+pub fn recover_bus(driver: &mut I2cDriver) -> anyhow::Result<()> {
+    let port = driver.port();
+    unsafe {
+        esp!(gpio_set_direction(
+            SDA_PIN,
+            gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD
+        ))?;
+        esp!(gpio_set_direction(
+            SCL_PIN,
+            gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD
+        ))?;
+        gpio_set_level(SDA_PIN, 1);
+        for _ in 0..9 {
+            gpio_set_level(SCL_PIN, 0);
+            Ets::delay_us(RECOVERY_HALF_PERIOD_US);
+            gpio_set_level(SCL_PIN, 1);
+            Ets::delay_us(RECOVERY_HALF_PERIOD_US);
+        }
+        // Stop: SDA rising while SCL is high
+        gpio_set_level(SCL_PIN, 0);
+        gpio_set_level(SDA_PIN, 0);
+        Ets::delay_us(RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(SCL_PIN, 1);
+        Ets::delay_us(RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(SDA_PIN, 1);
+
+        esp!(i2c_set_pin(
+            port,
+            SDA_PIN,
+            SCL_PIN,
+            true,
+            true,
+            i2c_mode_t_I2C_MODE_MASTER
+        ))?;
+        esp!(i2c_reset_tx_fifo(port))?;
+        esp!(i2c_reset_rx_fifo(port))?;
+    }
+    Ok(())
+}
+
+/// Sequence of the last register read, told back by the unit in its reply
+static SEQUENCE: AtomicU8 = AtomicU8::new(0);
+
+/// Address of the first unit of the given kind found on Port A
+pub fn find_unit(driver: &mut I2cDriver, kind: u8) -> Option<u8> {
+    UNIT_ADDRESSES.into_iter().find(|address| {
+        I2cLink::new(driver, *address)
+            .get_info()
+            .map_or(false, |(unit_kind, _)| unit_kind == kind)
+    })
+}
+
+/// Transport to a device of the Port A I2C bus, through its register map
+pub struct I2cLink<'a, 'd> {
+    driver: &'a mut I2cDriver<'d>,
+    address: u8,
+}
+
+impl<'a, 'd> I2cLink<'a, 'd> {
+    pub fn new(driver: &'a mut I2cDriver<'d>, address: u8) -> Self {
+        Self { driver, address }
+    }
+
+    /// Reads the reply of a register, up to `len` bytes, asking for it again while it
+    /// comes corrupted
+    fn read_register(&mut self, register: u8, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut flags = 0;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            let mut request = vec![register, sequence];
+            if register == REG_TX_FIFO {
+                request.push(flags);
+            }
+            let written = self.driver.write(self.address, &request, TIMEOUT);
+            let requested = written.is_ok();
+            let reply = written
+                .map_err(anyhow::Error::from)
+                .and_then(|_| self.wait_reply(sequence, len));
+            match reply {
+                Ok(reply) => return Ok(reply),
+                Err(err) if attempts >= MAX_ATTEMPTS => return Err(err),
+                Err(_) => {
+                    // The frame has left the TX FIFO, the same one is asked for again
+                    if register == REG_TX_FIFO && requested {
+                        flags = TX_REPEAT;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the reply until it is the one of the request of `sequence`, what the unit
+    /// held before loading it being stale
+    fn wait_reply(&mut self, sequence: u8, len: usize) -> anyhow::Result<Vec<u8>> {
+        let until = Instant::now() + REPLY_TIMEOUT;
+        let mut buffer = vec![0u8; len + 1 + packet::OVERHEAD];
+        loop {
+            FreeRtos::delay_ms(REPLY_POLL_MS);
+            self.driver.read(self.address, &mut buffer, TIMEOUT)?;
+            match packet::decode(&buffer) {
+                Ok([replied, reply @ ..]) if *replied == sequence => return Ok(reply.to_vec()),
+                _ if Instant::now() >= until => return Err(anyhow!("No reply from the unit")),
+                _ => {}
+            }
+        }
+    }
+
+    /// Kind of the unit and version of its protocol
+    pub fn get_info(&mut self) -> anyhow::Result<(u8, u8)> {
+        match self.read_register(REG_INFO, 2)?.as_slice() {
+            [kind, version, ..] => Ok((*kind, *version)),
+            _ => Err(anyhow!("Invalid unit info")),
+        }
+    }
+
+    fn get_status(&mut self) -> anyhow::Result<(u8, u8)> {
+        match self.read_register(REG_STATUS, 2)?.as_slice() {
+            [flags, pending, ..] => Ok((*flags, *pending)),
+            _ => Err(anyhow!("Invalid status")),
+        }
+    }
+
+    /// Number of frames waiting to be read
+    pub fn get_pending(&mut self) -> anyhow::Result<u8> {
+        Ok(match self.get_status()? {
+            (flags, pending) if flags & STATUS_TX_PENDING != 0 => pending,
+            _ => 0,
+        })
+    }
+}
+
+impl Transport for I2cLink<'_, '_> {
+    /// Writes the frame again while the unit reports it came corrupted
+    fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        if frame.len() > MAX_LINK_FRAME_LEN {
+            return Err(anyhow!("Frame too long for the unit"));
+        }
+        let mut buffer = vec![REG_RX_FIFO];
+        buffer.extend_from_slice(&packet::encode(frame));
+        for _ in 0..MAX_ATTEMPTS {
+            self.driver.write(self.address, &buffer, TIMEOUT)?;
+            let (flags, _) = self.get_status()?;
+            if flags & STATUS_RX_ERROR == 0 {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("Frame corrupted on the way to the unit"))
+    }
+
+    /// Only reads the TX FIFO when the status announces a frame, so that stale
+    /// data is never taken for a command
+    fn read_frame(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.get_pending()? == 0 {
+            return Ok(None);
+        }
+        let frame = self.read_register(REG_TX_FIFO, MAX_LINK_FRAME_LEN)?;
+        Ok(Commands::frame_len(&frame)
+            .filter(|len| *len <= frame.len())
+            .map(|len| frame[..len].to_vec()))
+    }
+}
@@ -1,38 +1,75 @@
+mod backlight;
+mod bus;
+mod checkpoint;
+mod compass;
+mod crash;
+mod framebuffer;
+mod gesture;
 mod gps;
+mod home;
+mod imu;
+mod link;
+mod map;
+mod ota;
 mod qrcode;
+mod recorder;
+mod route;
+mod routes;
 mod screen;
+mod settings;
+mod sos;
+mod speaker;
+mod speed_alert;
 mod state;
-
-use std::cell::RefCell;
-
-// TODO: Implement an easier borrow for Mutex<RefCell<Option<T>>>
-use critical_section::{CriticalSection, Mutex};
-
-use esp_idf_hal::{delay::FreeRtos, gpio::InterruptType, prelude::Peripherals, uart::UartDriver};
-use esp_idf_sys as _;
-use heapless::Vec;
-use m5_go::{leds::Leds, ButtonAType, ButtonBType, ButtonCType, M5Go};
+mod storage;
+mod sunset;
+mod tasks;
+mod trail;
+mod wifi;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use esp_idf_hal::{gpio::InterruptType, modem::Modem, prelude::Peripherals};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    nvs::{EspDefaultNvsPartition, EspNvs},
+};
+use esp_idf_sys::{
+    esp, gpio_get_level, ledc_channel_config, ledc_channel_config_t, ledc_channel_t_LEDC_CHANNEL_0,
+    ledc_clk_cfg_t_LEDC_AUTO_CLK, ledc_mode_t_LEDC_HIGH_SPEED_MODE, ledc_set_duty,
+    ledc_timer_bit_t_LEDC_TIMER_8_BIT, ledc_timer_config, ledc_timer_config_t,
+    ledc_timer_config_t__bindgen_ty_1, ledc_timer_t_LEDC_TIMER_0, ledc_update_duty, settimeofday,
+    timeval,
+};
+use heapless::mpmc::Q8;
+use m5_go::M5Go;
 use screen::App;
-use shared::Commands;
-
-use crate::screen::Button;
-
-static BUTTON_A: Mutex<RefCell<Option<ButtonAType>>> = Mutex::new(RefCell::new(None));
-
-static BUTTON_B: Mutex<RefCell<Option<ButtonBType>>> = Mutex::new(RefCell::new(None));
-
-static BUTTON_C: Mutex<RefCell<Option<ButtonCType>>> = Mutex::new(RefCell::new(None));
-
-static CTS: Mutex<RefCell<Vec<Commands, 20>>> = Mutex::new(RefCell::new(Vec::new()));
-
-static APP: Mutex<RefCell<Option<App>>> = Mutex::new(RefCell::new(None));
-
-static UART: Mutex<RefCell<Option<UartDriver>>> = Mutex::new(RefCell::new(None));
-
-static LEDS: Mutex<RefCell<Option<Leds>>> = Mutex::new(RefCell::new(None));
-
-const STICK: u8 = 0x16;
-const SENSOR: u8 = 0x44;
+use shared::LogLevel;
+
+use crate::{
+    backlight::Backlight,
+    bus::{Bus, ButtonQueue, Event},
+    crash::CRASH_NAMESPACE,
+    framebuffer::Display,
+    recorder::Recorder,
+    screen::Button,
+    settings::SettingsStore,
+    speaker::Speaker,
+    storage::Storage,
+    wifi::Uploader,
+};
+
+/// Longest wait for an event before the screens are updated all the same, so that their
+/// timers run
+const FRAME_PERIOD_MS: u64 = 100;
+
+const BACKLIGHT_PIN: i32 = 32;
 
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -47,125 +84,170 @@ fn main() -> anyhow::Result<()> {
     m5.button_b.set_interrupt_type(InterruptType::AnyEdge)?;
     m5.button_c.set_interrupt_type(InterruptType::AnyEdge)?;
 
+    let buttons: ButtonQueue = Arc::new(Q8::new());
     unsafe {
-        m5.button_a.subscribe(on_push_a)?;
-        m5.button_b.subscribe(on_push_b)?;
-        m5.button_c.subscribe(on_push_c)?;
+        let (queue, pin) = (buttons.clone(), m5.button_a.pin());
+        m5.button_a
+            .subscribe(move || on_push(&queue, Button::A, pin))?;
+        let (queue, pin) = (buttons.clone(), m5.button_b.pin());
+        m5.button_b
+            .subscribe(move || on_push(&queue, Button::B, pin))?;
+        let (queue, pin) = (buttons.clone(), m5.button_c.pin());
+        m5.button_c
+            .subscribe(move || on_push(&queue, Button::C, pin))?;
     }
 
-    let mut screens = App::new();
-    screens.setup();
+    let (bus, events, commands, wifi_requests) = Bus::new();
 
-    // Activate temperature and humidity sensor
-    m5.port_a
-        .write(SENSOR, &[0x20, 0x32], 100)
-        .ok()
-        .or_else(|| {
-            println!("Write failed");
-            None
-        });
-
-    critical_section::with(|cs| {
-        BUTTON_A.replace(cs, Some(m5.button_a));
-        BUTTON_B.replace(cs, Some(m5.button_b));
-        BUTTON_C.replace(cs, Some(m5.button_c));
-        UART.replace(cs, Some(m5.port_c));
-        LEDS.replace(cs, Some(m5.leds));
+    let partition = EspDefaultNvsPartition::take()?;
+    let mut crash_nvs = EspNvs::new(partition.clone(), CRASH_NAMESPACE, true)?;
+    // Sent once the stick answers, so that the rider knows the M5Go restarted
+    if let Some(report) = crash::take_report(&mut crash_nvs) {
+        println!("{}", report);
+        bus.send_log(LogLevel::Error, &report);
+    }
 
-        APP.replace(cs, Some(screens));
-    });
+    // The m5-go crate takes the peripherals, and leaves the modem unused
+    let uploader = Uploader::new(
+        unsafe { Modem::new() },
+        EspSystemEventLoop::take()?,
+        partition.clone(),
+    )?;
+    let mut settings = SettingsStore::new(partition)?;
+    // Mounted by the UI task, as soon as it starts
+    let mut storage = Storage::new();
+    let mut recorder = Recorder::new();
+    let mut backlight = Backlight::new();
+    let mut speaker = Speaker::new()?;
+    let mut app = App::new(settings.get(), settings.get_odometer(), settings.get_home());
+    app.setup();
+    app.state.lock().unwrap().borrow_mut().last_crash = crash::last_report(&crash_nvs);
+    if let Some(checkpoint) = settings.get_checkpoint() {
+        checkpoint::offer(&mut app.state.lock().unwrap().borrow_mut(), checkpoint);
+    }
 
     m5.screen.turn_on();
 
+    // The backlight pin is driven by the LEDC once the screen has been turned on
+    init_backlight()?;
+    set_brightness(settings.get().brightness);
+
+    let screen = Arc::new(std::sync::Mutex::new(m5.screen.driver));
+    let (mut display, frames, spares) = Display::new(screen.clone());
+    crash::init_panic_hook(crash_nvs, screen.clone());
+    tasks::spawn_display(screen, frames, spares)?;
+
+    let port_a = Arc::new(std::sync::Mutex::new(m5.port_a));
+    let nmea_log = Arc::new(AtomicBool::new(settings.get().nmea_log));
+    tasks::spawn_gps(m5.port_c, bus.clone(), nmea_log.clone())?;
+    tasks::spawn_bridge(port_a.clone(), commands, bus.clone())?;
+    tasks::spawn_sensors(port_a.clone(), bus.clone())?;
+    tasks::spawn_imu(port_a, bus.clone())?;
+    tasks::spawn_wifi(uploader, wifi_requests, bus.clone())?;
+    // Every task started, the firmware is not rolled back
+    ota::mark_valid();
+
+    // UI task: the screens handle the button edges, then whatever the other tasks sent,
+    // and are drawn
+    crash::watch_task();
     loop {
-        let mut buffer = [0u8; 256];
-        let command = if m5.port_a.read(STICK, &mut buffer, 50).is_ok() {
-            let (command, _) = Commands::parse(&buffer).unwrap_or_default();
-            match command {
-                Commands::NONE => {}
-                _ => println!("received command : {:?}", command),
-            };
-            Some(command)
-        } else {
-            None
-        };
-
-        let mut sensor_buffer = [0u8; 6];
-        let c_h = if m5.port_a.read(SENSOR, &mut sensor_buffer, 50).is_ok() {
-            let data = sensor_buffer
-                .to_vec()
-                .iter_mut()
-                .map(|i| f32::from(*i))
-                .collect::<Vec<f32, 6>>();
-
-            let c = ((((data[0] * 256.0) + data[1]) * 175.) / 65535.0) - 45.;
-            let h = (((data[3] * 256.0) + data[4]) * 100.) / 65535.0;
-            Some((c, h))
-        } else {
-            None
-        };
-
-        critical_section::with(|cs| {
-            APP.borrow(cs).borrow_mut().as_mut().and_then(|app| {
-                let screen = app.get_screen();
-                screen.update(cs, command, c_h);
-                screen.draw(&mut m5.screen.driver);
-                Some(())
-            });
-            let mut commands = CTS.borrow_ref_mut(cs);
-            commands.pop().and_then(|command| {
-                println!("sending command: {:?}", command);
-                m5.port_a
-                    .write(STICK, command.get_stream().as_slice(), 50)
-                    .ok()
-                    .or_else(|| {
-                        println!("Failed to send command");
-                        commands.insert(0, command).ok().or_else(|| {
-                            println!("The command failed being re-sent");
-                            None
-                        });
-                        Some(())
-                    })
-            });
-        });
-        FreeRtos::delay_ms(100);
+        crash::feed_watchdog();
+        let mut pending: Vec<Event> = vec![];
+        let received = events.recv_timeout(Duration::from_millis(FRAME_PERIOD_MS));
+        while let Some((button, pushed)) = buttons.dequeue() {
+            pending.push(Event::Button(button, pushed));
+        }
+        pending.extend(received.into_iter().chain(events.try_iter()));
+
+        if pending.is_empty() {
+            app.get_screen().handle(&bus, None);
+        }
+        for event in pending {
+            app.get_screen().handle(&bus, Some(event));
+        }
+        app.draw(&mut display);
+        backlight.sync(&mut app.state.lock().unwrap().borrow_mut());
+        let options = app.state.lock().unwrap().borrow().options.get_settings();
+        settings.save(options);
+        settings.save_odometer(app.state.lock().unwrap().borrow().trip.odometer);
+        settings.save_home(app.state.lock().unwrap().borrow().home.home.as_ref());
+        let checkpoint = checkpoint::sync(&mut app.state.lock().unwrap().borrow_mut());
+        if let Some(checkpoint) = checkpoint {
+            settings.save_checkpoint(checkpoint);
+        }
+        nmea_log.store(options.nmea_log, Ordering::Relaxed);
+        storage.sync(&mut app.state.lock().unwrap().borrow_mut().storage);
+        recorder.sync(&mut app.state.lock().unwrap().borrow_mut());
+        wifi::sync(&bus, &mut app.state.lock().unwrap().borrow_mut());
+        sos::sync(&bus, &mut app.state.lock().unwrap().borrow_mut());
+        route::sync(&bus, &mut app.state.lock().unwrap().borrow_mut());
+        speed_alert::sync(&mut app.state.lock().unwrap().borrow_mut());
+        home::sync(&bus, &mut app.state.lock().unwrap().borrow_mut());
+        sunset::sync(&mut app.state.lock().unwrap().borrow_mut());
+        speaker.sync(&mut app.state.lock().unwrap().borrow_mut().sound);
     }
 }
 
-fn on_push_a() {
-    critical_section::with(|cs| {
-        BUTTON_A.borrow(cs).borrow().as_ref().and_then(|btn| {
-            APP.borrow(cs).borrow_mut().as_mut().and_then(|app| {
-                app.get_screen().call(cs, Button::A, btn.is_low());
-                Some(())
-            });
-            Some(())
-        });
-    });
+/// Interrupt of a button edge, the button being pushed while its pin is low. The edge is
+/// dropped when the UI task is too late to empty the queue.
+fn on_push(buttons: &ButtonQueue, button: Button, pin: i32) {
+    let pushed = unsafe { gpio_get_level(pin) } == 0;
+    buttons.enqueue((button, pushed)).ok();
 }
 
-fn on_push_b() {
-    critical_section::with(|cs| {
-        BUTTON_B.borrow(cs).borrow().as_ref().and_then(|btn| {
-            APP.borrow(cs).borrow_mut().as_mut().and_then(|app| {
-                app.get_screen().call(cs, Button::B, btn.is_low());
-                Some(())
-            })
-        });
-    });
+fn init_backlight() -> anyhow::Result<()> {
+    let timer = ledc_timer_config_t {
+        speed_mode: ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+        __bindgen_anon_1: ledc_timer_config_t__bindgen_ty_1 {
+            duty_resolution: ledc_timer_bit_t_LEDC_TIMER_8_BIT,
+        },
+        timer_num: ledc_timer_t_LEDC_TIMER_0,
+        freq_hz: 5000,
+        clk_cfg: ledc_clk_cfg_t_LEDC_AUTO_CLK,
+    };
+    let channel = ledc_channel_config_t {
+        gpio_num: BACKLIGHT_PIN,
+        speed_mode: ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+        channel: ledc_channel_t_LEDC_CHANNEL_0,
+        timer_sel: ledc_timer_t_LEDC_TIMER_0,
+        duty: u8::MAX as u32,
+        ..Default::default()
+    };
+
+    unsafe {
+        esp!(ledc_timer_config(&timer))?;
+        esp!(ledc_channel_config(&channel))?;
+    }
+    Ok(())
 }
 
-fn on_push_c() {
-    critical_section::with(|cs| {
-        BUTTON_C.borrow(cs).borrow().as_ref().and_then(|btn| {
-            APP.borrow(cs).borrow_mut().as_mut().and_then(|app| {
-                app.get_screen().call(cs, Button::C, btn.is_low());
-                Some(())
-            })
-        });
-    });
+fn set_time(unix_ms: u64) {
+    let time = timeval {
+        tv_sec: (unix_ms / 1000) as _,
+        tv_usec: (unix_ms % 1000 * 1000) as _,
+    };
+    if unsafe { settimeofday(&time, std::ptr::null()) } != 0 {
+        println!("Failed to set time");
+    }
 }
 
-fn send_i2c(cs: CriticalSection, command: Commands) -> Option<()> {
-    CTS.borrow_ref_mut(cs).insert(0, command).ok()
+fn set_brightness(level: u8) {
+    unsafe {
+        esp!(ledc_set_duty(
+            ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+            ledc_channel_t_LEDC_CHANNEL_0,
+            level as u32,
+        ))
+        .and_then(|_| {
+            esp!(ledc_update_duty(
+                ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+                ledc_channel_t_LEDC_CHANNEL_0,
+            ))
+        })
+        .ok()
+        .or_else(|| {
+            println!("Failed to set brightness");
+            None
+        });
+    }
 }
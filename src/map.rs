@@ -0,0 +1,141 @@
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor},
+    primitives::Rectangle,
+};
+use shared::Coordinates;
+
+use crate::{screen::Shape, state::InfoState};
+
+/// Pixels left around the drawing, the marker of the position staying whole
+const MARGIN: i32 = 6;
+/// Span under which the map is not zoomed in further, in degrees of latitude (about 200 m)
+const MIN_SPAN_DEG: f64 = 0.002;
+const ROUTE_DONE_COLOR: Rgb565 = Rgb565::new(8, 16, 8);
+const ROUTE_COLOR: Rgb565 = Rgb565::CYAN;
+const TRAIL_COLOR: Rgb565 = Rgb565::YELLOW;
+const POSITION_COLOR: Rgb565 = Rgb565::RED;
+const PROFILE_COLOR: Rgb565 = Rgb565::GREEN;
+/// Altitude span under which the profile is not stretched further, in meters
+const MIN_PROFILE_SPAN_M: f32 = 20.0;
+
+/// Projection of the coordinates in an area, the whole of the points fitting in it with
+/// the same scale on both axes
+struct Projection {
+    min_lat: f64,
+    min_long: f64,
+    /// Pixels per degree of latitude, the longitude ones shrinking with the latitude
+    scale: f64,
+    long_factor: f64,
+    origin: Point,
+    height: i32,
+}
+
+impl Projection {
+    fn new<'a>(points: impl Iterator<Item = &'a Coordinates>, area: Rectangle) -> Option<Self> {
+        let (mut min_lat, mut max_lat) = (f64::MAX, f64::MIN);
+        let (mut min_long, mut max_long) = (f64::MAX, f64::MIN);
+        for point in points.filter(|point| point.is_valid()) {
+            min_lat = min_lat.min(point.lat);
+            max_lat = max_lat.max(point.lat);
+            min_long = min_long.min(point.long);
+            max_long = max_long.max(point.long);
+        }
+        if min_lat > max_lat {
+            return None;
+        }
+        let long_factor = ((min_lat + max_lat) / 2.0).to_radians().cos();
+        let width = (area.size.width as i32 - 2 * MARGIN).max(1) as f64;
+        let height = (area.size.height as i32 - 2 * MARGIN).max(1) as f64;
+        let lat_span = (max_lat - min_lat).max(MIN_SPAN_DEG);
+        let long_span = ((max_long - min_long) * long_factor).max(MIN_SPAN_DEG);
+        let scale = (width / long_span).min(height / lat_span);
+        // Centered in the area, on the axis with room left
+        let used_width = (max_long - min_long) * long_factor * scale;
+        let used_height = (max_lat - min_lat) * scale;
+        Some(Self {
+            min_lat,
+            min_long,
+            scale,
+            long_factor,
+            origin: area.top_left
+                + Point::new(
+                    MARGIN + ((width - used_width) / 2.0) as i32,
+                    MARGIN + ((height - used_height) / 2.0) as i32,
+                ),
+            height: used_height as i32,
+        })
+    }
+
+    fn project(&self, point: &Coordinates) -> Point {
+        let x = (point.long - self.min_long) * self.long_factor * self.scale;
+        let y = (point.lat - self.min_lat) * self.scale;
+        // The north is up, the rows going down
+        self.origin + Point::new(x as i32, self.height - y as i32)
+    }
+
+    fn project_all(&self, points: &[Coordinates]) -> Vec<Point> {
+        let mut projected: Vec<Point> = vec![];
+        for point in points.iter().filter(|point| point.is_valid()) {
+            let point = self.project(point);
+            // The points falling on the same pixel only make the line longer to draw
+            if projected.last() != Some(&point) {
+                projected.push(point);
+            }
+        }
+        projected
+    }
+}
+
+/// Shapes of the trail, of the route, the part behind the rider dimmed, and of the
+/// position, scaled to the area
+pub fn get_shapes(infos: &InfoState, trail: &[Coordinates], area: Rectangle) -> Vec<Shape> {
+    let position = infos.coords.as_ref().filter(|coords| coords.is_valid());
+    let points = infos.route.iter().chain(trail.iter()).chain(position);
+    let projection = match Projection::new(points, area) {
+        Some(projection) => projection,
+        None => return vec![],
+    };
+
+    // The step reached last ends the part behind and starts the one ahead, so that they
+    // join
+    let reached = infos.reached.min(infos.route.len());
+    let mut shapes = vec![
+        Shape::Polyline(TRAIL_COLOR, projection.project_all(trail)),
+        Shape::Polyline(
+            ROUTE_DONE_COLOR,
+            projection.project_all(&infos.route[..reached]),
+        ),
+        Shape::Polyline(
+            ROUTE_COLOR,
+            projection.project_all(&infos.route[reached.saturating_sub(1)..]),
+        ),
+    ];
+    if let Some(position) = position {
+        shapes.push(Shape::Marker(POSITION_COLOR, projection.project(position)));
+    }
+    shapes
+}
+
+/// Elevation profile stretched to the area, the lowest altitude at its bottom
+pub fn get_profile_shapes(profile: &[f32], area: Rectangle) -> Vec<Shape> {
+    if profile.len() < 2 {
+        return vec![];
+    }
+    let min = profile.iter().copied().fold(f32::MAX, f32::min);
+    let max = profile.iter().copied().fold(f32::MIN, f32::max);
+    let span = (max - min).max(MIN_PROFILE_SPAN_M);
+    let width = (area.size.width as i32 - 2 * MARGIN).max(1) as f32;
+    let height = (area.size.height as i32 - 2 * MARGIN).max(1) as f32;
+    let bottom = area.top_left + Point::new(MARGIN, MARGIN + height as i32);
+    let points = profile
+        .iter()
+        .enumerate()
+        .map(|(i, altitude)| {
+            let x = i as f32 * width / (profile.len() - 1) as f32;
+            let y = (altitude - min) * height / span;
+            bottom + Point::new(x as i32, -(y as i32))
+        })
+        .collect();
+    vec![Shape::Polyline(PROFILE_COLOR, points)]
+}
@@ -0,0 +1,129 @@
+use std::{ptr, time::Duration};
+
+use anyhow::anyhow;
+use embedded_svc::{
+    http::{client::Client, Headers, Status},
+    io::Read,
+};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_sys::{
+    esp, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_handle_t, esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition,
+    esp_ota_write,
+};
+use shared::crc32_update;
+
+use crate::crash;
+
+/// Version of the image served, none being downloaded when it is the running one
+const VERSION_HEADER: &str = "X-Firmware-Version";
+/// CRC-32 of the image in hexadecimal, checked once it is written when it is given
+const CRC_HEADER: &str = "X-Firmware-Crc32";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of the image read and written at once
+const CHUNK_LEN: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateStatus {
+    Idle,
+    /// The phone gave no network or no firmware URL yet
+    NotConfigured,
+    Checking,
+    /// Home network out of reach
+    Away,
+    UpToDate,
+    /// Percent of the image written
+    Downloading(u8),
+    /// New image written and checked, booted in a moment
+    Restarting,
+    Failed(String),
+}
+
+impl UpdateStatus {
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self,
+            UpdateStatus::Checking | UpdateStatus::Downloading(_) | UpdateStatus::Restarting
+        )
+    }
+}
+
+/// Keeps the running image, the bootloader rolling back to the previous one otherwise
+/// when it restarts before this is called
+pub fn mark_valid() {
+    esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })
+        .ok()
+        .or_else(|| {
+            println!("Unable to mark the firmware valid");
+            None
+        });
+}
+
+/// Downloads the image served at the URL to the inactive OTA partition, telling the
+/// percent written as it goes. Once it returns true, the new image boots on the next
+/// restart; false is returned when the running one is served.
+pub fn download(url: &str, mut progress: impl FnMut(u8)) -> anyhow::Result<bool> {
+    let mut client = Client::wrap(EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(HTTP_TIMEOUT),
+        ..Default::default()
+    })?);
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    if (200..300).contains(&status) == false {
+        anyhow::bail!("Server answered {}", status);
+    }
+    if response.header(VERSION_HEADER) == Some(env!("CARGO_PKG_VERSION")) {
+        return Ok(false);
+    }
+    let total_len = response
+        .header("Content-Length")
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|len| *len > 0)
+        .ok_or_else(|| anyhow!("No firmware length"))?;
+    let expected_crc = response
+        .header(CRC_HEADER)
+        .and_then(|crc| u32::from_str_radix(crc.trim_start_matches("0x"), 16).ok());
+
+    let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    if partition.is_null() {
+        return Err(anyhow!("No OTA partition"));
+    }
+    let mut handle: esp_ota_handle_t = 0;
+    esp!(unsafe { esp_ota_begin(partition, total_len, &mut handle) })?;
+
+    let mut chunk = [0u8; CHUNK_LEN];
+    let mut written = 0;
+    let mut crc = 0;
+    let copied = loop {
+        crash::feed_watchdog();
+        let read = match response.read(&mut chunk) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(err) => break Err(anyhow::Error::from(err)),
+        };
+        if written + read > total_len {
+            break Err(anyhow!("Firmware longer than told"));
+        }
+        if let Err(err) = esp!(unsafe { esp_ota_write(handle, chunk.as_ptr() as _, read) }) {
+            break Err(err.into());
+        }
+        let previous = written * 100 / total_len;
+        written += read;
+        crc = crc32_update(crc, &chunk[..read]);
+        // Only whole percents are told, the screen does not need more
+        if written * 100 / total_len != previous {
+            progress((written * 100 / total_len) as u8);
+        }
+    };
+    let corrupted = written != total_len || expected_crc.map_or(false, |expected| expected != crc);
+    if copied.is_err() || corrupted {
+        unsafe { esp_ota_abort(handle) };
+        copied?;
+        return Err(anyhow!("Firmware image corrupted"));
+    }
+
+    // Also validates the image
+    esp!(unsafe { esp_ota_end(handle) })?;
+    esp!(unsafe { esp_ota_set_boot_partition(partition) })?;
+    Ok(true)
+}
@@ -0,0 +1,389 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use shared::{Coordinates, TripStats};
+
+use crate::{
+    screen::ScreenId,
+    state::{State, TripState},
+    storage,
+};
+
+/// Folder of the card holding a file per ride
+pub const RIDES_DIR: &str = "rides";
+/// Rides uploaded, kept with their number so that it is not given again
+pub const SENT_DIR: &str = "rides/sent";
+const MAX_RIDES: u32 = 9999;
+/// Delay between two positions looked at by the filter, most of them being left out
+const RECORD_PERIOD: Duration = Duration::from_secs(1);
+/// Distance in meters a position left out may be from the line between the points kept
+/// around it, the GPS wandering by about as much
+const TRACK_TOLERANCE_M: f64 = 5.0;
+/// Same for the altitude, interpolated along that line
+const ALTITUDE_TOLERANCE_M: f64 = 3.0;
+/// Positions left out in a row at most, so that a long straight still shows the speed
+const MAX_SKIPPED: usize = 30;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+/// Points after which the file is synced, what was not being lost on a power cut
+const SYNC_POINTS: u32 = 30;
+/// The lap column numbers the laps marked on the trip screen, so that they can be compared
+const CSV_HEADER: &str = "time,lat,long,speed_kmh,altitude_m,temperature_c,lap\n";
+
+/// Trip computer when the ride started, the summary being what it added since
+struct RideStart {
+    distance: f64,
+    moving_time: Duration,
+    climb: f64,
+    calories: f64,
+}
+
+/// Ride recording seen by the UI
+pub struct RecordingState {
+    /// Started, or stopped, from a long press of B on the infos screen
+    pub wanted: bool,
+    /// File of the ride being recorded
+    pub file: Option<String>,
+    pub points: u32,
+    /// Ride stopped last and its file, until the rider closes the summary screen
+    pub summary: Option<(TripStats, String)>,
+    start: Option<RideStart>,
+    /// Highest speed and temperatures of the points of the ride
+    stats: TripStats,
+}
+
+impl RecordingState {
+    pub fn new() -> Self {
+        Self {
+            wanted: false,
+            file: None,
+            points: 0,
+            summary: None,
+            start: None,
+            stats: TripStats::default(),
+        }
+    }
+
+    fn begin(&mut self, trip: &TripState) {
+        self.start = Some(RideStart {
+            distance: trip.distance,
+            moving_time: trip.moving_time,
+            climb: trip.climb,
+            calories: trip.calories,
+        });
+        self.stats = TripStats::default();
+    }
+
+    fn track(&mut self, speed: Option<f64>, temperature: Option<f32>) {
+        if let Some(speed) = speed {
+            self.stats.max_speed = self.stats.max_speed.max(speed);
+        }
+        if let Some(temperature) = temperature {
+            let min = self
+                .stats
+                .min_temperature
+                .map_or(temperature, |min| min.min(temperature));
+            let max = self
+                .stats
+                .max_temperature
+                .map_or(temperature, |max| max.max(temperature));
+            self.stats.min_temperature = Some(min);
+            self.stats.max_temperature = Some(max);
+        }
+    }
+
+    /// Summary of the ride, from the trip computer since it started. A reset of the trip
+    /// during the ride loses what came before it.
+    fn finish(&mut self, trip: &TripState) -> TripStats {
+        let start = self.start.take().unwrap_or(RideStart {
+            distance: 0.0,
+            moving_time: Duration::ZERO,
+            climb: 0.0,
+            calories: 0.0,
+        });
+        TripStats {
+            distance: (trip.distance - start.distance).max(0.0),
+            moving_time: trip.moving_time.saturating_sub(start.moving_time).as_secs() as u32,
+            climb: (trip.climb - start.climb).max(0.0),
+            calories: (trip.calories - start.calories).max(0.0),
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// Removes the file of the ride of the summary, closing it
+pub fn discard(state: &mut State) -> io::Result<()> {
+    match state.recording.summary.take() {
+        Some((_, name)) => fs::remove_file(storage::get_path(&name)),
+        None => Ok(()),
+    }
+}
+
+/// Position of the ride with its line of the file, written or not by the filter
+struct Sample {
+    coords: Coordinates,
+    altitude: Option<f64>,
+    lap: usize,
+    line: String,
+}
+
+impl Sample {
+    /// Meters east and north of the origin, flat over the few hundred meters between two
+    /// points kept
+    fn get_offset(&self, origin: &Sample) -> (f64, f64) {
+        let long_factor = origin.coords.lat.to_radians().cos();
+        (
+            (self.coords.long - origin.coords.long) * long_factor * METERS_PER_DEGREE,
+            (self.coords.lat - origin.coords.lat) * METERS_PER_DEGREE,
+        )
+    }
+
+    /// Whether the sample lies close enough to the line from `from` to `to`, on the map
+    /// and in altitude, to be left out
+    fn is_near(&self, from: &Sample, to: &Sample) -> bool {
+        let (x, y) = self.get_offset(from);
+        let (dx, dy) = to.get_offset(from);
+        let length = dx * dx + dy * dy;
+        // Fraction of the line the sample is abreast of, its ends beyond it
+        let along = if length > 0.0 {
+            ((x * dx + y * dy) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let off_track = (x - along * dx).hypot(y - along * dy);
+        let off_altitude = match (from.altitude, to.altitude, self.altitude) {
+            (Some(from), Some(to), Some(altitude)) => {
+                (altitude - (from + along * (to - from))).abs()
+            }
+            _ => 0.0,
+        };
+        off_track <= TRACK_TOLERANCE_M && off_altitude <= ALTITUDE_TOLERANCE_M
+    }
+}
+
+/// Online simplification of the ride in the manner of Douglas-Peucker: the positions
+/// since the last point written are held back as long as the line from it to the newest
+/// one passes close to all of them. Straights end up with few points and turns or hills
+/// with many.
+struct PointFilter {
+    kept: Option<Sample>,
+    skipped: Vec<Sample>,
+}
+
+impl PointFilter {
+    fn new() -> Self {
+        Self {
+            kept: None,
+            skipped: vec![],
+        }
+    }
+
+    /// Takes the new position, returning the line to write when one is needed to stay
+    /// within the tolerances
+    fn push(&mut self, sample: Sample) -> Option<String> {
+        let kept = match &self.kept {
+            Some(kept) => kept,
+            None => {
+                let line = sample.line.clone();
+                self.kept = Some(sample);
+                return Some(line);
+            }
+        };
+        let near = self.skipped.len() < MAX_SKIPPED
+            && self
+                .skipped
+                .last()
+                .map_or(true, |last| last.lap == sample.lap)
+            && self
+                .skipped
+                .iter()
+                .all(|skipped| skipped.is_near(kept, &sample));
+        if near {
+            self.skipped.push(sample);
+            return None;
+        }
+        // The last position held back ends the line that was still close to all of them
+        let last = self.skipped.pop();
+        self.skipped.clear();
+        self.skipped.push(sample);
+        last.map(|last| {
+            let line = last.line.clone();
+            self.kept = Some(last);
+            line
+        })
+    }
+
+    /// Last position held back, which ends the ride
+    fn flush(&mut self) -> Option<String> {
+        self.kept = None;
+        let last = self.skipped.pop();
+        self.skipped.clear();
+        last.map(|last| last.line)
+    }
+}
+
+/// Ride recorded on the TF card in a CSV file of its own, its positions being thinned by
+/// the filter
+pub struct Recorder {
+    file: Option<File>,
+    filter: PointFilter,
+    written_at: Option<Instant>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            filter: PointFilter::new(),
+            written_at: None,
+        }
+    }
+
+    /// Creates the file of the next ride, returning its name
+    fn start(&mut self) -> io::Result<String> {
+        fs::create_dir_all(storage::get_path(RIDES_DIR))?;
+        let exists = |dir: &str, file: &str| {
+            Path::new(&storage::get_path(&format!("{}/{}", dir, file))).exists()
+        };
+        let name = (1..=MAX_RIDES)
+            .map(|ride| format!("ride_{:04}.csv", ride))
+            .find(|file| exists(RIDES_DIR, file) == false && exists(SENT_DIR, file) == false)
+            .map(|file| format!("{}/{}", RIDES_DIR, file))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Too many rides"))?;
+        let mut file = storage::open_append(&name)?;
+        file.write_all(CSV_HEADER.as_bytes())?;
+        self.file = Some(file);
+        self.filter = PointFilter::new();
+        self.written_at = None;
+        Ok(name)
+    }
+
+    /// Current position and its line, none without a valid position
+    fn get_sample(state: &State) -> Option<Sample> {
+        let coords = state.infos.coords.as_ref().filter(|c| c.is_valid())?;
+        let time = state
+            .infos
+            .time
+            .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_default();
+        let speed = state.infos.speed.map(|speed| format!("{:.1}", speed));
+        let altitude = state
+            .infos
+            .altitude
+            .map(|altitude| format!("{:.1}", altitude));
+        let temperature = state
+            .infos
+            .temperature
+            .map(|temperature| format!("{:.1}", temperature));
+        let lap = state.trip.get_lap_number();
+        let line = format!(
+            "{},{:.6},{:.6},{},{},{},{}\n",
+            time,
+            coords.lat,
+            coords.long,
+            speed.unwrap_or_default(),
+            altitude.unwrap_or_default(),
+            temperature.unwrap_or_default(),
+            lap
+        );
+        Some(Sample {
+            coords: Coordinates::new(coords.lat, coords.long),
+            altitude: state.infos.altitude,
+            lap,
+            line,
+        })
+    }
+
+    fn write_point(&mut self, state: &mut State) -> io::Result<()> {
+        let sample = match Self::get_sample(state) {
+            Some(sample) => sample,
+            None => return Ok(()),
+        };
+        // The summary looks at every position, left out or not
+        state
+            .recording
+            .track(state.infos.speed, state.infos.temperature);
+        match self.filter.push(sample) {
+            Some(line) => self.write_line(state, &line),
+            None => Ok(()),
+        }
+    }
+
+    fn write_line(&mut self, state: &mut State, line: &str) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            state.recording.points += 1;
+            if state.recording.points % SYNC_POINTS == 0 {
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts or stops the ride as asked, and hands a position to the filter every second
+    pub fn sync(&mut self, state: &mut State) {
+        match (state.recording.wanted, self.file.is_some()) {
+            (true, false) => match self.start() {
+                Ok(name) => {
+                    state
+                        .notification
+                        .show(String::from("Enregistrement"), name.clone());
+                    state.recording.file = Some(name);
+                    state.recording.points = 0;
+                    state.recording.begin(&state.trip);
+                }
+                Err(err) => {
+                    println!("Starting the recording failed: {}", err);
+                    state.recording.wanted = false;
+                    state.notification.show(
+                        String::from("Enregistrement impossible"),
+                        String::from("Pas de carte SD"),
+                    );
+                }
+            },
+            (false, true) => {
+                if let Some(line) = self.filter.flush() {
+                    self.write_line(state, &line).ok().or_else(|| {
+                        println!("Writing the end of the ride failed");
+                        None
+                    });
+                }
+                if let Some(file) = self.file.take() {
+                    file.sync_all().ok();
+                }
+                let stats = state.recording.finish(&state.trip);
+                if let Some(name) = state.recording.file.take() {
+                    state.recording.summary = Some((stats, name));
+                    state.current_screen = ScreenId::Summary;
+                }
+                state.notification.show(
+                    String::from("Enregistrement arrete"),
+                    format!("{} points", state.recording.points),
+                );
+            }
+            _ => {}
+        }
+
+        let due = self
+            .written_at
+            .map_or(true, |at| at.elapsed() >= RECORD_PERIOD);
+        if self.file.is_none() || due == false {
+            return;
+        }
+        self.written_at = Some(Instant::now());
+        if let Err(err) = self.write_point(state) {
+            // The card was pulled out, or is full
+            println!("Recording failed: {}", err);
+            self.file = None;
+            state.recording.wanted = false;
+            state.recording.file = None;
+            state.notification.show(
+                String::from("Enregistrement interrompu"),
+                format!("{} points", state.recording.points),
+            );
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+use shared::{Commands, Coordinates};
+
+use crate::{
+    bus::Bus,
+    speaker::{ARRIVAL_BEEP, STEP_BEEP},
+    state::State,
+};
+
+/// Steps of the route told the same one under this distance, in kilometers
+const SAME_STEP_KM: f64 = 0.001;
+/// Delay between two searches of the closest step of the route
+const CLOSEST_PERIOD: Duration = Duration::from_secs(3);
+
+/// Index in the route of the step, when it is one of its steps
+pub fn find_step(route: &[Coordinates], step: &Coordinates) -> Option<usize> {
+    route
+        .iter()
+        .position(|route_step| route_step.distance(step) < SAME_STEP_KM)
+}
+
+/// Index of the step of the route closest to the position, among the ones not reached
+pub fn find_closest(route: &[Coordinates], reached: usize, coords: &Coordinates) -> Option<usize> {
+    route
+        .iter()
+        .enumerate()
+        .skip(reached)
+        .map(|(index, step)| (index, coords.distance(step)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+}
+
+/// Picks the closest step of the route every few seconds, the phone only telling it when
+/// there is no route
+fn update_closest(state: &mut State) {
+    let due = state
+        .infos
+        .closest_at
+        .map_or(true, |at| at.elapsed() >= CLOSEST_PERIOD);
+    let coords = match &state.infos.coords {
+        Some(coords) if due && coords.is_valid() => coords,
+        _ => return,
+    };
+    let closest = find_closest(&state.infos.route, state.infos.reached, coords);
+    state.infos.closest_at = Some(Instant::now());
+    if let Some(index) = closest {
+        let step = &state.infos.route[index];
+        state
+            .infos
+            .set_closest_step(Coordinates::new(step.lat, step.long));
+    }
+}
+
+/// Marks the closest step reached once the rider comes within the radius of the options,
+/// the next step of the route being the closest one then
+pub fn sync(bus: &Bus, state: &mut State) {
+    update_closest(state);
+    let radius_km = state.options.step_radius as f64 / 1000.0;
+    let reached = match (&state.infos.coords, &state.infos.closest_step) {
+        (Some(coords), Some(step)) => coords.distance(step) <= radius_km,
+        _ => false,
+    };
+    if reached == false {
+        return;
+    }
+
+    let total = state.infos.route.len();
+    let next = state.infos.step.map(|step| step + 1);
+    state.infos.closest_step = next
+        .and_then(|next| state.infos.route.get(next))
+        .map(|step| Coordinates::new(step.lat, step.long));
+    state.infos.step = next.filter(|next| *next < total);
+    state.infos.reached = next.unwrap_or(state.infos.reached);
+
+    match next {
+        Some(reached) if reached < total => {
+            state.notification.show(
+                String::from("Etape atteinte"),
+                format!("{} sur {}", reached, total),
+            );
+            state.sound.play(STEP_BEEP);
+        }
+        Some(_) => {
+            state.notification.show(
+                String::from("Arrivee"),
+                String::from("Derniere etape atteinte"),
+            );
+            state.sound.play(ARRIVAL_BEEP);
+        }
+        None => {
+            state
+                .notification
+                .show(String::from("Etape atteinte"), String::new());
+            state.sound.play(STEP_BEEP);
+        }
+    }
+    // Told only for the steps of the route, the phone knowing the others
+    if let Some(reached) = next {
+        send_progress(bus, reached, total);
+    }
+}
+
+/// Tells the phone through the stick how many steps of the route are behind the rider
+pub fn send_progress(bus: &Bus, reached: usize, total: usize) {
+    bus.send_i2c(Commands::RouteProgress {
+        reached: reached as u16,
+        total: total as u16,
+    })
+    .or_else(|| {
+        println!("Error sending RouteProgress command");
+        None
+    });
+}
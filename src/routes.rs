@@ -0,0 +1,65 @@
+use std::{fs, io, path::Path};
+
+use shared::{parse_route, Coordinates};
+
+use crate::{state::ScrollList, storage};
+
+/// Folder of the card holding the routes, sent by the phone or copied there
+pub const ROUTES_DIR: &str = "routes";
+const MAX_ROUTES: u32 = 9999;
+/// Routes are JSON lists of steps, as the phone sends them
+const ROUTE_EXTENSION: &str = ".json";
+
+/// Routes of the card seen by the routes screen
+pub struct RoutesState {
+    /// "Retour" comes before the routes
+    pub list: ScrollList,
+    /// Read when the screen is opened
+    pub names: Vec<String>,
+    /// Route being followed, when it is one of the card
+    pub active: Option<String>,
+}
+
+impl RoutesState {
+    pub fn new() -> Self {
+        Self {
+            list: ScrollList::new(),
+            names: vec![],
+            active: None,
+        }
+    }
+}
+
+fn get_path(name: &str) -> String {
+    storage::get_path(&format!("{}/{}{}", ROUTES_DIR, name, ROUTE_EXTENSION))
+}
+
+/// Writes a route sent by the phone to the card under the next free name, returning it
+pub fn save(data: &[u8]) -> io::Result<String> {
+    fs::create_dir_all(storage::get_path(ROUTES_DIR))?;
+    let name = (1..=MAX_ROUTES)
+        .map(|route| format!("route_{:04}", route))
+        .find(|name| Path::new(&get_path(name)).exists() == false)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Too many routes"))?;
+    fs::write(get_path(&name), data)?;
+    Ok(name)
+}
+
+/// Names of the routes of the card, sorted
+pub fn list() -> io::Result<Vec<String>> {
+    let mut names = fs::read_dir(storage::get_path(ROUTES_DIR))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map_or(false, |kind| kind.is_file()))
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_suffix(ROUTE_EXTENSION).map(String::from)
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+    Ok(names)
+}
+
+/// Steps of a route of the card
+pub fn load(name: &str) -> anyhow::Result<Vec<Coordinates>> {
+    parse_route(&fs::read(get_path(name))?)
+}
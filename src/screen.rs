@@ -1,26 +1,59 @@
 use std::{
     cell::RefCell,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use critical_section::CriticalSection;
 use embedded_graphics::{
     mono_font::MonoTextStyle,
     pixelcolor::Rgb565,
-    prelude::{Point, RgbColor, Size},
-    primitives::{Primitive, PrimitiveStyleBuilder, Rectangle},
+    prelude::{Dimensions, DrawTarget, Point, RgbColor, Size},
+    primitives::{Circle, Polyline, Primitive, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
     text::{Alignment, Text},
     Drawable,
 };
 
 use m5_go::M5GoScreenDriver;
 use nmea_parser::{chrono::NaiveTime, gnss::GgaQualityIndicator, ParsedMessage};
-use shared::{BleState, Commands, Coordinates, TextSize};
+use shared::{
+    parse_route, Battery, BleState, Commands, Coordinates, LogLevel, Sensor, SensorKind, TextSize,
+    RESTART_REPORT, ROUTE_BULK_ID,
+};
 
-use crate::{gps::read_gps_line, qrcode::draw_qrcode, send_i2c, state::State};
+use crate::{
+    backlight::{next_level, BRIGHTNESS_LEVELS, SCREEN_TIMEOUTS},
+    bus::{Bus, Event},
+    checkpoint,
+    compass::{self, HeadingSource},
+    crash::wrap,
+    framebuffer::{AreaBuffer, Display, BUFFER_PIXELS},
+    gesture::Gesture,
+    gps::GpsEvent,
+    imu::MotionEvent,
+    map,
+    ota::UpdateStatus,
+    qrcode::draw_qrcode,
+    recorder, route, routes,
+    settings::Settings,
+    sos,
+    speed_alert::SPEED_LIMITS,
+    state::{
+        ScrollList, State, WeatherState, LIST_ROWS, MAX_SENSORS, PASSKEY_DURATION, RIDER_WEIGHTS,
+        WAYPOINT_ACTIONS,
+    },
+    storage::CardStatus,
+    sunset::SUNSET_LEADS,
+    wifi::{self, UploadStatus, WifiRequest},
+};
 
 const WIDTH: u32 = 320;
 const HEIGHT: u32 = 240;
+/// Level of the battery the rider is warned at
+const LOW_BATTERY_LEVEL: u8 = 25;
+/// Speed under which no time to the next step is told, the rider not riding
+const MOVING_SPEED_KMH: f64 = 2.0;
+const SHAPE_STROKE: u32 = 2;
+const MARKER_DIAMETER: u32 = 9;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Button {
@@ -29,16 +62,50 @@ pub enum Button {
     C,
 }
 
+/// Part of a box to draw again
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Dirty {
+    Clean,
+    /// Area inside the border, its background being filled again before the text is drawn
+    Area(Rectangle),
+    /// Border, background and text
+    Whole,
+}
+
+/// Smallest rectangle holding both, an empty one adding nothing
+fn envelope(a: Rectangle, b: Rectangle) -> Rectangle {
+    match (a.bottom_right(), b.bottom_right()) {
+        (Some(a_end), Some(b_end)) => Rectangle::with_corners(
+            Point::new(
+                a.top_left.x.min(b.top_left.x),
+                a.top_left.y.min(b.top_left.y),
+            ),
+            Point::new(a_end.x.max(b_end.x), a_end.y.max(b_end.y)),
+        ),
+        (Some(_), None) => a,
+        (None, _) => b,
+    }
+}
+
+/// Drawing of a box, over its fill and under its text
+#[derive(Clone, PartialEq)]
+pub enum Shape {
+    Polyline(Rgb565, Vec<Point>),
+    /// Dot centered on the point
+    Marker(Rgb565, Point),
+}
+
 pub struct GraphicBox {
     style_builder: PrimitiveStyleBuilder<Rgb565>,
     drawable: Rectangle,
     color: Rgb565,
     filled: bool,
-    must_draw: bool,
+    dirty: Dirty,
     visible: bool,
     text: String,
     text_size: TextSize,
     qr_code: bool,
+    shapes: Vec<Shape>,
     id: BoxId,
 }
 
@@ -86,11 +153,12 @@ impl GraphicBox {
             drawable: Rectangle::new(position, size),
             color: Rgb565::BLACK,
             filled: false,
-            must_draw: true,
+            dirty: Dirty::Whole,
             visible: true,
             text: String::new(),
             text_size: TextSize::Small,
             qr_code: false,
+            shapes: vec![],
             id: BoxId::None,
         }
     }
@@ -135,20 +203,8 @@ impl GraphicBox {
         draw_qrcode(driver, text, size, coeff, self.drawable.top_left)
     }
 
-    pub fn draw(&mut self, driver: &mut M5GoScreenDriver) {
-        let color = if self.filled && self.visible {
-            self.color
-        } else {
-            Rgb565::BLACK
-        };
-
-        let border_color = if self.visible {
-            self.color
-        } else {
-            Rgb565::BLACK
-        };
-
-        let text_color = if self.visible {
+    fn get_text_color(&self) -> Rgb565 {
+        if self.visible {
             if self.color == Rgb565::BLACK {
                 Rgb565::WHITE
             } else if self.filled {
@@ -158,11 +214,13 @@ impl GraphicBox {
             }
         } else {
             Rgb565::BLACK
-        };
+        }
+    }
 
+    fn get_text(&self) -> Text<'_, MonoTextStyle<'static, Rgb565>> {
         let font = self.text_size.get_font();
 
-        let character_style = MonoTextStyle::new(&font, text_color);
+        let character_style = MonoTextStyle::new(font, self.get_text_color());
 
         let text_position = Point::new(
             self.drawable.top_left.x + self.drawable.size.width as i32 / 2,
@@ -171,53 +229,173 @@ impl GraphicBox {
                 + font.baseline as i32 / 2,
         );
 
-        let text_drawable = Text::with_alignment(
+        Text::with_alignment(
             self.text.as_str(),
             text_position,
             character_style,
             Alignment::Center,
-        );
+        )
+    }
 
-        self.drawable
-            .into_styled(
-                self.style_builder
-                    .fill_color(color)
-                    .stroke_color(border_color)
-                    .stroke_width(1)
-                    .build(),
-            )
-            .draw(driver)
-            .ok()
-            .or_else(|| {
-                println!("Draw rectangle failed");
-                None
-            });
+    /// Inside of the border
+    fn get_inner(&self) -> Rectangle {
+        Rectangle::new(
+            self.drawable.top_left + Point::new(1, 1),
+            Size::new(
+                self.drawable.size.width.saturating_sub(2),
+                self.drawable.size.height.saturating_sub(2),
+            ),
+        )
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty != Dirty::Clean
+    }
+
+    /// Marks the whole box to be drawn again
+    pub fn invalidate(&mut self) {
+        self.dirty = Dirty::Whole;
+    }
+
+    /// Marks the part of the box under `area` to be drawn again, the whole box when the
+    /// area reaches its border
+    pub fn invalidate_area(&mut self, area: Rectangle) {
+        let area = area.intersection(&self.drawable);
+        if area.bottom_right().is_none() {
+            return;
+        }
+        if self.get_inner().intersection(&area) != area {
+            self.dirty = Dirty::Whole;
+            return;
+        }
+        self.dirty = match self.dirty {
+            Dirty::Clean => Dirty::Area(area),
+            Dirty::Area(dirty) => Dirty::Area(envelope(dirty, area)),
+            Dirty::Whole => Dirty::Whole,
+        };
+    }
+
+    /// Text about to change: the area of the previous one is cleared as the new one is drawn
+    fn invalidate_text(&mut self, text: String) {
+        let previous = self.get_text().bounding_box();
+        self.text = text;
+        if self.visible {
+            let area = envelope(previous, self.get_text().bounding_box());
+            self.invalidate_area(area);
+        }
+    }
+
+    /// Area the next draw paints over, none when nothing changed
+    pub fn get_dirty_area(&self) -> Option<Rectangle> {
+        match self.dirty {
+            Dirty::Clean => None,
+            Dirty::Area(area) => Some(area),
+            Dirty::Whole => Some(self.drawable),
+        }
+    }
+
+    /// Draws what changed in the box, through the buffer when the area fits in it, and
+    /// returns the area painted over
+    pub fn draw(&mut self, display: &mut Display, buffer: &mut AreaBuffer) -> Option<Rectangle> {
+        let painted = self.get_dirty_area()?;
+        let flushed = if buffer.start(painted) {
+            self.paint(buffer, painted);
+            buffer.hand_over(display)
+        } else {
+            display.draw_direct(|driver| self.paint(driver, painted))
+        };
+        flushed.or_else(|| {
+            println!("Display task stopped");
+            None
+        });
+        self.dirty = Dirty::Clean;
+        Some(painted)
+    }
+
+    fn paint<D>(&self, target: &mut D, painted: Rectangle)
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let color = if self.filled && self.visible {
+            self.color
+        } else {
+            Rgb565::BLACK
+        };
+
+        let border_color = if self.visible {
+            self.color
+        } else {
+            Rgb565::BLACK
+        };
+
+        let style = if painted == self.drawable {
+            self.style_builder
+                .fill_color(color)
+                .stroke_color(border_color)
+                .stroke_width(1)
+                .build()
+        } else {
+            PrimitiveStyleBuilder::new().fill_color(color).build()
+        };
+
+        painted.into_styled(style).draw(target).ok().or_else(|| {
+            println!("Draw rectangle failed");
+            None
+        });
+
+        if self.visible {
+            for shape in self.shapes.iter() {
+                match shape {
+                    Shape::Polyline(color, points) => Polyline::new(points)
+                        .into_styled(PrimitiveStyle::with_stroke(*color, SHAPE_STROKE))
+                        .draw(target),
+                    Shape::Marker(color, center) => Circle::with_center(*center, MARKER_DIAMETER)
+                        .into_styled(PrimitiveStyle::with_fill(*color))
+                        .draw(target),
+                }
+                .ok()
+                .or_else(|| {
+                    println!("Draw shape failed");
+                    None
+                });
+            }
+        }
 
         if self.visible {
-            text_drawable.draw(driver).ok().or_else(|| {
+            self.get_text().draw(target).ok().or_else(|| {
                 println!("Draw text failed");
                 None
             });
         }
-        self.must_draw = false;
     }
 
     pub fn set_filled(&mut self, filled: bool) {
-        self.must_draw = self.filled != filled;
+        if self.filled != filled {
+            self.dirty = Dirty::Whole;
+        }
         self.filled = filled;
     }
 
     pub fn set_visible(&mut self, visible: bool) {
-        self.must_draw = self.visible != visible;
+        if self.visible != visible {
+            self.dirty = Dirty::Whole;
+        }
         self.visible = visible;
     }
 
+    /// Shapes drawn in the box, the whole of it being drawn again when they changed
+    pub fn set_shapes(&mut self, shapes: Vec<Shape>) {
+        if self.shapes != shapes {
+            self.shapes = shapes;
+            self.dirty = Dirty::Whole;
+        }
+    }
+
     pub fn set_text(&mut self, text: &str) {
         if self.text == text {
             return;
         }
-        self.text = String::from(text);
-        self.must_draw = true;
+        self.invalidate_text(String::from(text));
     }
 
     pub fn replace_text(&mut self, f: impl FnOnce(&str) -> String) {
@@ -225,14 +403,16 @@ impl GraphicBox {
         if self.text == text {
             return;
         }
-        self.text = text;
-        self.must_draw = true;
+        self.invalidate_text(text);
     }
 }
 
 pub struct Screen {
     callbacks: Callbacks,
     boxes: Vec<GraphicBox>,
+    /// Status bar in the top right corner, over the boxes of every screen
+    status: GraphicBox,
+    popup: GraphicBox,
     pub state: Arc<Mutex<RefCell<State>>>,
 }
 
@@ -246,9 +426,9 @@ impl GetBoxId for Vec<GraphicBox> {
     }
 }
 
-type Callback =
-    dyn Fn(CriticalSection, bool, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static;
-type UpdateCallback = dyn Fn(CriticalSection, Commands, &mut Vec<GraphicBox>, &mut State, Option<(f32, f32)>)
+type Callback = dyn Fn(&Bus, bool, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static;
+type GestureCallback = dyn Fn(&Bus, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static;
+type UpdateCallback = dyn Fn(&Bus, Commands, &mut Vec<GraphicBox>, &mut State, Option<(f32, f32)>, Option<GpsEvent>)
     + Send
     + Sync
     + 'static;
@@ -258,6 +438,9 @@ pub struct Callbacks {
     pub a: Option<Box<Callback>>,
     pub b: Option<Box<Callback>>,
     pub c: Option<Box<Callback>>,
+    pub gestures: Vec<(Button, Gesture, Box<GestureCallback>)>,
+    /// Buttons whose callback is called again while they are held
+    pub repeat: Vec<Button>,
     pub update: Option<Box<UpdateCallback>>,
 }
 
@@ -270,6 +453,48 @@ impl Callbacks {
         }
     }
 
+    pub fn get_gesture_callback(
+        &self,
+        button: Button,
+        gesture: Gesture,
+    ) -> Option<&Box<GestureCallback>> {
+        self.gestures
+            .iter()
+            .find(|(b, g, _)| *b == button && *g == gesture)
+            .map(|(_, _, f)| f)
+    }
+
+    /// Calls what the screen binds to the gesture, returning false when nothing is
+    fn call_gesture(
+        &self,
+        bus: &Bus,
+        button: Button,
+        gesture: Gesture,
+        boxes: &mut Vec<GraphicBox>,
+        state: &mut State,
+    ) -> bool {
+        self.get_gesture_callback(button, gesture)
+            .map(|f| f(bus, boxes, state))
+            .is_some()
+    }
+
+    /// Calls the callback of the held button as if it was released, when the screen
+    /// repeats it
+    fn call_repeat(
+        &self,
+        bus: &Bus,
+        button: Button,
+        boxes: &mut Vec<GraphicBox>,
+        state: &mut State,
+    ) -> bool {
+        if self.repeat.contains(&button) == false {
+            return false;
+        }
+        self.get_callback(button)
+            .map(|f| f(bus, false, boxes, state))
+            .is_some()
+    }
+
     pub fn get_update_callback(&self) -> Option<&Box<UpdateCallback>> {
         self.update.as_ref()
     }
@@ -277,9 +502,16 @@ impl Callbacks {
 
 impl Screen {
     fn new_internal(state: Arc<Mutex<RefCell<State>>>) -> Self {
+        let mut popup = GraphicBox::new(Point::new(10, 70), Size::new(WIDTH - 20, 80))
+            .with_color(Rgb565::YELLOW)
+            .with_filled(true);
+        popup.set_visible(false);
+
         Self {
             callbacks: Callbacks::default(),
             boxes: vec![],
+            status: GraphicBox::new(Point::new(WIDTH as i32 - 50, 0), Size::new(50, 18)),
+            popup,
             state,
         }
     }
@@ -318,7 +550,7 @@ impl Screen {
 
     pub fn on<F>(mut self, button: Button, f: F) -> Self
     where
-        F: Fn(CriticalSection, bool, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static,
+        F: Fn(&Bus, bool, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static,
     {
         match button {
             Button::A => self.callbacks.a = Some(Box::new(f)),
@@ -328,10 +560,31 @@ impl Screen {
         self
     }
 
+    /// Calls the callback of the button again and again while it is held, to scroll
+    pub fn with_repeat(mut self, button: Button) -> Self {
+        self.callbacks.repeat.push(button);
+        self
+    }
+
+    /// Binds a gesture of the button, on top of its edges
+    pub fn on_gesture<F>(mut self, button: Button, gesture: Gesture, f: F) -> Self
+    where
+        F: Fn(&Bus, &mut Vec<GraphicBox>, &mut State) + Send + Sync + 'static,
+    {
+        self.callbacks.gestures.push((button, gesture, Box::new(f)));
+        self
+    }
+
     pub fn on_update<F>(mut self, f: F) -> Self
     where
-        F: Fn(CriticalSection, Commands, &mut Vec<GraphicBox>, &mut State, Option<(f32, f32)>)
-            + Send
+        F: Fn(
+                &Bus,
+                Commands,
+                &mut Vec<GraphicBox>,
+                &mut State,
+                Option<(f32, f32)>,
+                Option<GpsEvent>,
+            ) + Send
             + Sync
             + 'static,
     {
@@ -339,7 +592,98 @@ impl Screen {
         self
     }
 
-    pub fn call(&mut self, cs: CriticalSection, button: Button, pushed: bool) {
+    /// Hands an event of the bus to the screen, which is updated all the same without one
+    /// so that its timers run
+    pub fn handle(&mut self, bus: &Bus, event: Option<Event>) {
+        self.poll_gestures(bus);
+        match event {
+            Some(Event::Button(button, pushed)) => self.call(bus, button, pushed),
+            Some(Event::Command(command, timestamp)) => {
+                self.update(bus, Some(command), timestamp, None, None)
+            }
+            Some(Event::Climate(c, h)) => self.update(bus, None, None, Some((c, h)), None),
+            Some(Event::Gps(gps)) => self.update(bus, None, None, None, Some(gps)),
+            Some(Event::Presence(device, present)) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    state.get_mut().devices.set(device, present);
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            Some(Event::Motion(motion)) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    let state = state.get_mut();
+                    if let MotionEvent::Turn(degrees) = motion {
+                        state.heading.turn(degrees);
+                    }
+                    state.motion.set(motion);
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            Some(Event::BusRecovered) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    state.get_mut().devices.recoveries += 1;
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            Some(Event::Upload(status)) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    let state = state.get_mut();
+                    match &status {
+                        UploadStatus::Done(rides) if *rides > 0 => state.notification.show(
+                            String::from("Sorties envoyees"),
+                            format!("{} sorties", rides),
+                        ),
+                        UploadStatus::Failed(reason) => state
+                            .notification
+                            .show(String::from("Envoi impossible"), reason.clone()),
+                        _ => {}
+                    }
+                    state.upload.status = status;
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            Some(Event::Battery(battery)) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    let state = state.get_mut();
+                    let was_low = state.battery.map_or(false, is_battery_low);
+                    if is_battery_low(battery) && was_low == false {
+                        state.notification.show(
+                            String::from("Batterie faible"),
+                            format!("{}% restants", battery.level),
+                        );
+                    }
+                    state.battery = Some(battery);
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            Some(Event::Update(status)) => {
+                self.state.try_lock().ok().and_then(|mut state| {
+                    let state = state.get_mut();
+                    match &status {
+                        UpdateStatus::Restarting => state.notification.show(
+                            String::from("Mise a jour installee"),
+                            String::from("Redemarrage"),
+                        ),
+                        UpdateStatus::Failed(reason) => state
+                            .notification
+                            .show(String::from("Mise a jour impossible"), reason.clone()),
+                        _ => {}
+                    }
+                    state.firmware = status;
+                    Some(())
+                });
+                self.update(bus, None, None, None, None)
+            }
+            None => self.update(bus, None, None, None, None),
+        }
+    }
+
+    pub fn call(&mut self, bus: &Bus, button: Button, pushed: bool) {
         self.state.try_lock().ok().and_then(|mut state| {
             let state = state.get_mut();
             self.boxes
@@ -347,27 +691,244 @@ impl Screen {
                 .unwrap()
                 .set_filled(state.options.fill_on_click && pushed);
 
+            let wait_double = self
+                .callbacks
+                .get_gesture_callback(button, Gesture::Double)
+                .is_some();
+            let waking = state.backlight.wake() && pushed;
+            let consumed = pushed == false && state.gestures.is_consumed(button);
+            let gesture = state.gestures.push(button, pushed, wait_double);
+            // The press turning the screen back on does nothing else, nor its release
+            if waking {
+                state.gestures.consume(button);
+                return Some(());
+            }
+
             if let Some(f) = self.callbacks.get_callback(button) {
-                f(cs, pushed, &mut self.boxes, state);
+                if consumed == false {
+                    f(bus, pushed, &mut self.boxes, state);
+                }
             }
+            if let Some(gesture) = gesture {
+                self.callbacks
+                    .call_gesture(bus, button, gesture, &mut self.boxes, state);
+            }
+
+            Some(())
+        });
+    }
 
+    /// Calls the gestures ended by time passing, a long press or a repeat handled here
+    /// keeping the release from the button callbacks
+    fn poll_gestures(&mut self, bus: &Bus) {
+        self.state.try_lock().ok().and_then(|mut state| {
+            let state = state.get_mut();
+            for (button, gesture) in state.gestures.poll() {
+                let handled = match gesture {
+                    Gesture::Repeat => {
+                        self.callbacks
+                            .call_repeat(bus, button, &mut self.boxes, state)
+                    }
+                    _ => self
+                        .callbacks
+                        .call_gesture(bus, button, gesture, &mut self.boxes, state),
+                };
+                if handled && matches!(gesture, Gesture::Long | Gesture::Repeat) {
+                    state.gestures.consume(button);
+                }
+            }
             Some(())
         });
     }
 
     pub fn update(
         &mut self,
-        cs: CriticalSection,
+        bus: &Bus,
         command: Option<Commands>,
+        timestamp: Option<u32>,
         c_h: Option<(f32, f32)>,
+        gps: Option<GpsEvent>,
     ) {
         self.state.try_lock().ok().and_then(|mut state| {
             let state = state.get_mut();
-            if let Some(Commands::BleState(s)) = &command {
-                state.connection.ble = s.clone();
+            let command = match (command, timestamp) {
+                (Some(command), Some(timestamp)) => {
+                    if state.diagnostics.check_frame(&command, timestamp) {
+                        Some(command)
+                    } else {
+                        println!("Dropping outdated command: {:?}", command);
+                        None
+                    }
+                }
+                (command, _) => command,
+            };
+            if let Some((temperature, humidity)) = c_h {
+                state.infos.temperature = Some(temperature);
+                state.infos.humidity = Some(humidity);
+            }
+            // Updated whatever the screen, the recording, the route and the trail
+            // following the rider
+            if let Some(GpsEvent::Sentence(message)) = &gps {
+                update_position(bus, state, message);
+            }
+            match &command {
+                Some(Commands::GetSensorData) => {
+                    bus.send_i2c(Commands::Telemetry(state.infos.get_telemetry()));
+                }
+                Some(Commands::GetBattery) => {
+                    if let Some(battery) = state.battery {
+                        bus.send_i2c(Commands::Battery(battery));
+                    }
+                }
+                Some(Commands::GetDiagnostics) => {
+                    bus.send_i2c(Commands::Diagnostics(state.diagnostics.clone()));
+                }
+                Some(Commands::BleState(s)) => {
+                    state.connection.ble = s.clone();
+                    if state.connection.ble != BleState::Connected {
+                        state.connection.rssi = None;
+                    }
+                }
+                Some(Commands::SetBrightness(level)) => {
+                    state.options.brightness = *level;
+                }
+                Some(Commands::SetStepRadius(meters)) => {
+                    state.options.step_radius = *meters;
+                }
+                Some(Commands::SetHome(home)) => {
+                    state.home.set(home);
+                }
+                Some(Commands::SetWifi { ssid, password }) => {
+                    bus.send_wifi(WifiRequest::Network {
+                        ssid: ssid.clone(),
+                        password: password.clone(),
+                    });
+                }
+                Some(Commands::SetUploadUrl(url)) => {
+                    bus.send_wifi(WifiRequest::Url(url.clone()));
+                }
+                Some(Commands::SetTracking(tracking)) => {
+                    bus.send_wifi(WifiRequest::Tracking(tracking.clone()));
+                }
+                Some(Commands::SetFirmwareUrl(url)) => {
+                    bus.send_wifi(WifiRequest::FirmwareUrl(url.clone()));
+                }
+                Some(Commands::CheckFirmware) => {
+                    // Not while a ride is recorded, the M5Go restarting once updated
+                    if state.recording.wanted == false && state.firmware.is_busy() == false {
+                        wifi::request_update(bus, state);
+                    }
+                }
+                Some(Commands::Rssi(rssi)) => {
+                    state.connection.rssi = Some(*rssi);
+                }
+                Some(Commands::Sos(_)) => {
+                    sos::acknowledged(state);
+                }
+                Some(Commands::Weather {
+                    temp,
+                    condition,
+                    wind,
+                }) => {
+                    state.infos.weather = Some(WeatherState {
+                        temp: *temp,
+                        condition: condition.clone(),
+                        wind: *wind,
+                    });
+                }
+                Some(Commands::Notification { title, body }) => {
+                    state.notification.show(title.clone(), body.clone());
+                }
+                Some(Commands::ScanResult(sensor)) => {
+                    state.sensors.add(sensor.clone());
+                }
+                Some(Commands::ScanSensors(false)) => {
+                    state.sensors.scanning = false;
+                }
+                Some(Commands::HeartRate(bpm)) => {
+                    // 0 when the sensor went out of reach
+                    state.infos.heart_rate = Some(*bpm).filter(|bpm| *bpm > 0);
+                }
+                Some(Commands::CyclingData(data)) => {
+                    state.infos.cadence = data.cadence;
+                    state.infos.wheel_speed = data.wheel_speed;
+                }
+                Some(Commands::PeerPosition(coords)) => {
+                    // Out of range when the other unit went out of reach
+                    state.infos.peer =
+                        Some(Coordinates::new(coords.lat, coords.long)).filter(|c| c.is_valid());
+                }
+                Some(Commands::Passkey(passkey)) => {
+                    state.notification.show_for(
+                        String::from("Code d'appairage"),
+                        format!("{:06}", passkey),
+                        PASSKEY_DURATION,
+                    );
+                }
+                Some(Commands::Log { level, text }) => {
+                    // The other logs would cover the ride screens, they are kept for the
+                    // diagnostics screen
+                    if *level == LogLevel::Error && text.starts_with(RESTART_REPORT) {
+                        state
+                            .notification
+                            .show(String::from("Stick redemarre"), text.clone());
+                    }
+                    state.logs.push(*level, text.clone());
+                }
+                Some(
+                    command @ (Commands::BulkStart { .. }
+                    | Commands::BulkChunk { .. }
+                    | Commands::BulkEnd { .. }),
+                ) => match state.bulk.push(command) {
+                    Ok(Some((ROUTE_BULK_ID, data))) => match parse_route(&data) {
+                        Ok(route) => {
+                            // Kept on the card to be picked again later, when there is one
+                            state.routes.active = routes::save(&data)
+                                .map_err(|err| println!("Saving the route failed: {}", err))
+                                .ok();
+                            state.notification.show(
+                                String::from("Itineraire recu"),
+                                format!("{} etapes", route.len()),
+                            );
+                            state.infos.set_route(route);
+                        }
+                        Err(err) => println!("Invalid route: {}", err),
+                    },
+                    Ok(Some((id, data))) => {
+                        println!("Bulk transfer {} received ({} bytes)", id, data.len());
+                    }
+                    Ok(None) => {}
+                    Err(err) => println!("Bulk transfer failed: {}", err),
+                },
+                _ => {}
             }
             if let Some(f) = self.callbacks.get_update_callback() {
-                f(cs, command.unwrap_or_default(), &mut self.boxes, state, c_h);
+                f(
+                    bus,
+                    command.unwrap_or_default(),
+                    &mut self.boxes,
+                    state,
+                    c_h,
+                    gps,
+                );
+            }
+
+            self.status.set_text(&get_battery_text(state.battery));
+
+            let popup_visible = state.notification.is_visible();
+            if popup_visible {
+                self.popup.set_text(state.notification.get_text().as_str());
+            }
+            if self.popup.visible != popup_visible {
+                self.popup.set_visible(popup_visible);
+                if popup_visible == false {
+                    // Repaint what was hidden behind the popup
+                    let area = self.popup.drawable;
+                    self.boxes
+                        .iter_mut()
+                        .for_each(|box_| box_.invalidate_area(area));
+                    state.qr.qr_code_drawn = false;
+                }
             }
             Some(())
         });
@@ -384,16 +945,33 @@ impl Screen {
         self
     }
 
-    pub fn draw(&mut self, driver: &mut M5GoScreenDriver) {
+    /// Marks every box to be drawn again, when the screen is switched to
+    pub fn repaint(&mut self) {
+        self.boxes.iter_mut().for_each(GraphicBox::invalidate);
+        self.status.invalidate();
+        self.popup.invalidate();
+        self.state.try_lock().ok().and_then(|state| {
+            state.borrow_mut().qr.qr_code_drawn = false;
+            Some(())
+        });
+    }
+
+    /// Draws the boxes that changed. What a box paints over is drawn again in the boxes on
+    /// top of it, so that only the changed areas reach the panel.
+    pub fn draw(&mut self, display: &mut Display, buffer: &mut AreaBuffer) {
+        let mut painted: Vec<Rectangle> = vec![];
         for box_ in self.boxes.iter_mut() {
-            if box_.must_draw {
-                box_.draw(driver);
+            painted.iter().for_each(|area| box_.invalidate_area(*area));
+            if let Some(area) = box_.draw(display, buffer) {
+                painted.push(area);
                 if box_.qr_code {
                     self.state.try_lock().ok().and_then(|state| {
                         let mut state = state.borrow_mut();
                         let mac = String::from(state.qr.get_mac());
                         if mac.is_empty() == false && state.qr.qr_code_drawn == false {
-                            box_.draw_qr_code(driver, mac.as_str(), 200, 2);
+                            display.draw_direct(|driver| {
+                                box_.draw_qr_code(driver, mac.as_str(), 200, 2)
+                            });
                             state.qr.qr_code_drawn = true
                         }
                         Some(())
@@ -401,6 +979,20 @@ impl Screen {
                 }
             }
         }
+
+        painted
+            .iter()
+            .for_each(|area| self.status.invalidate_area(*area));
+        if let Some(area) = self.status.draw(display, buffer) {
+            painted.push(area);
+        }
+
+        if self.popup.visible {
+            painted
+                .iter()
+                .for_each(|area| self.popup.invalidate_area(*area));
+            self.popup.draw(display, buffer);
+        }
     }
 }
 
@@ -408,6 +1000,7 @@ pub struct App {
     screens: Vec<Screen>,
     pub state: Arc<Mutex<RefCell<State>>>,
     pub on_screen: ScreenId,
+    buffer: AreaBuffer,
 }
 
 #[derive(Default, Copy, Clone, PartialEq, Eq)]
@@ -417,6 +1010,25 @@ pub enum ScreenId {
     QrCode,
     Infos,
     Options,
+    Sensors,
+    Diagnostics,
+    Upload,
+    /// Countdown after a crash, over whatever screen was shown
+    Sos,
+    /// Route and position
+    Map,
+    /// Trip computer
+    Trip,
+    /// Heading and direction of the next step
+    Compass,
+    /// Steps of the route, picked or removed by the rider
+    Waypoints,
+    /// Routes of the card, one of them being picked as the route
+    Routes,
+    /// Ride just stopped, kept, sent or discarded by the rider
+    Summary,
+    /// Ride cut short by a power cut, resumed or not at boot
+    Resume,
 }
 
 impl From<usize> for ScreenId {
@@ -426,6 +1038,17 @@ impl From<usize> for ScreenId {
             1 => Self::QrCode,
             2 => Self::Infos,
             3 => Self::Options,
+            4 => Self::Sensors,
+            5 => Self::Diagnostics,
+            6 => Self::Upload,
+            7 => Self::Sos,
+            8 => Self::Map,
+            9 => Self::Trip,
+            10 => Self::Compass,
+            11 => Self::Waypoints,
+            12 => Self::Routes,
+            13 => Self::Summary,
+            14 => Self::Resume,
             _ => Self::default(),
         }
     }
@@ -438,17 +1061,31 @@ impl Into<usize> for ScreenId {
             Self::QrCode => 1,
             Self::Infos => 2,
             Self::Options => 3,
+            Self::Sensors => 4,
+            Self::Diagnostics => 5,
+            Self::Upload => 6,
+            Self::Sos => 7,
+            Self::Map => 8,
+            Self::Trip => 9,
+            Self::Compass => 10,
+            Self::Waypoints => 11,
+            Self::Routes => 12,
+            Self::Summary => 13,
+            Self::Resume => 14,
         }
     }
 }
 
 impl App {
-    pub fn new() -> Self {
-        let state = Arc::new(Mutex::new(RefCell::new(State::new())));
+    pub fn new(settings: Settings, odometer: f64, home: Option<Coordinates>) -> Self {
+        let state = Arc::new(Mutex::new(RefCell::new(State::new(
+            settings, odometer, home,
+        ))));
         Self {
             screens: vec![],
             state,
             on_screen: ScreenId::Main,
+            buffer: AreaBuffer::new(BUFFER_PIXELS),
         }
     }
 
@@ -457,6 +1094,8 @@ impl App {
             .with_btn_text(Button::C, "OK")
             .with_btn_text(Button::B, "Bas")
             .with_btn_text(Button::A, "Haut")
+            .with_repeat(Button::A)
+            .with_repeat(Button::B)
             .on(Button::A, |_, pushed, boxes, state| {
                 if state.main.selected > 0 && pushed == false {
                     boxes
@@ -481,8 +1120,13 @@ impl App {
             })
             .on(Button::C, |_, pushed, boxes, state| {
                 if pushed == false {
-                    boxes.into_iter().for_each(|box_| box_.must_draw = true);
-                    state.current_screen = ScreenId::from(state.main.selected + 1);
+                    state.current_screen = match state.main.selected {
+                        3 => ScreenId::Upload,
+                        4 => ScreenId::Map,
+                        5 => ScreenId::Trip,
+                        6 => ScreenId::Waypoints,
+                        selected => ScreenId::from(selected + 1),
+                    };
                 }
             })
             .add_box(
@@ -491,73 +1135,125 @@ impl App {
                     .with_text_size(TextSize::Large),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 50), Size::new(WIDTH, 25))
+                GraphicBox::new(Point::new(0, 30), Size::new(WIDTH, 25))
                     .with_text("> Connexion Bluetooth")
                     .with_id(id!(0)),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 75), Size::new(WIDTH, 25))
+                GraphicBox::new(Point::new(0, 55), Size::new(WIDTH, 25))
                     .with_text("Excursion info")
                     .with_id(id!(1)),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 100), Size::new(WIDTH, 25))
+                GraphicBox::new(Point::new(0, 80), Size::new(WIDTH, 25))
                     .with_text("Options")
                     .with_id(id!(2)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 105), Size::new(WIDTH, 25))
+                    .with_text("WiFi")
+                    .with_id(id!(3)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 130), Size::new(WIDTH, 25))
+                    .with_text("Carte")
+                    .with_id(id!(4)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 155), Size::new(WIDTH, 25))
+                    .with_text("Trajet")
+                    .with_id(id!(5)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 180), Size::new(WIDTH, 25))
+                    .with_text("Etapes")
+                    .with_id(id!(6)),
             );
 
         let qr_code_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
             .with_btn_text(Button::C, "Retour")
             .with_btn_text(Button::B, "Redemander QR Code")
             .with_btn_text(Button::A, "Relancer BLE")
-            .on_update(|_, command, boxes, state, _| {
+            .on_update(|bus, command, boxes, state, _, _| {
                 if state.qr.must_get_mac() {
-                    critical_section::with(|cs| {
-                        send_i2c(cs, Commands::GetMac).and_then(|_| {
-                            state.qr.mac_requested();
-                            Some(())
-                        })
+                    bus.send_i2c(Commands::GetMac).and_then(|_| {
+                        state.qr.mac_requested();
+                        Some(())
                     });
                 }
                 match command {
                     Commands::Mac(mac) => {
                         state.qr.set_mac(mac);
-                        boxes.get_id_mut(id!("qr")).unwrap().must_draw = true
+                        boxes.get_id_mut(id!("qr")).unwrap().invalidate()
                     }
                     _ => {}
                 };
 
-                boxes
-                    .get_id_mut(BoxId::ButtonA)
-                    .unwrap()
-                    .set_visible(match state.connection.ble {
-                        BleState::Disconnected => true,
-                        _ => false,
+                if state.connection.must_get_rssi() {
+                    bus.send_i2c(Commands::GetRssi).and_then(|_| {
+                        state.connection.rssi_requested();
+                        Some(())
+                    });
+                }
+
+                boxes.get_id_mut(id!("rssi")).and_then(|box_| {
+                    box_.set_visible(state.connection.ble == BleState::Connected);
+                    box_.replace_text(|text| match state.connection.rssi {
+                        Some(rssi) => format!("{} dBm", rssi),
+                        None => text.to_string(),
                     });
+                    Some(())
+                });
+
+                boxes.get_id_mut(BoxId::ButtonA).and_then(|box_| {
+                    match state.connection.ble {
+                        BleState::Disconnected => {
+                            box_.set_visible(true);
+                            box_.set_text("Relancer BLE");
+                        }
+                        BleState::Connected | BleState::Advertising => {
+                            box_.set_visible(true);
+                            box_.set_text("Couper BLE");
+                        }
+                        _ => box_.set_visible(false),
+                    }
+                    Some(())
+                });
             })
             .on(Button::C, |_, pushed, boxes, state| {
                 if pushed == false {
-                    boxes.into_iter().for_each(|box_| box_.must_draw = true);
                     state.qr.qr_code_drawn = false;
                     state.current_screen = ScreenId::Main;
                 }
             })
-            .on(Button::A, |_, pushed, _, state| {
-                if pushed == false && state.connection.ble == BleState::Disconnected {
-                    critical_section::with(|cs| send_i2c(cs, Commands::StartBle)).or_else(|| {
-                        esp_println::println!("Error sending StartBle command");
-                        None
-                    });
+            .on(Button::A, |bus, pushed, _, state| {
+                if pushed == false {
+                    match state.connection.ble {
+                        BleState::Disconnected => {
+                            bus.send_i2c(Commands::StartBle).or_else(|| {
+                                esp_println::println!("Error sending StartBle command");
+                                None
+                            });
+                        }
+                        BleState::Connected | BleState::Advertising => {
+                            bus.send_i2c(Commands::StopBle).or_else(|| {
+                                esp_println::println!("Error sending StopBle command");
+                                None
+                            });
+                        }
+                        _ => {}
+                    }
                 }
             })
-            .on(Button::B, |cs, pushed, boxes, state| {
+            .on(Button::B, |bus, pushed, boxes, state| {
                 if pushed == false {
                     boxes.get_id_mut(id!("qr")).and_then(|box_| {
                         state.qr.reset();
-                        box_.must_draw = true;
+                        box_.invalidate();
                         Some(())
                     });
-                    send_i2c(cs, Commands::GetMac)
+                    bus.send_i2c(Commands::GetMac)
                         .and_then(|_| {
                             state.qr.mac_requested();
                             Some(())
@@ -573,20 +1269,27 @@ impl App {
                     .with_text("En attente du QR Code")
                     .with_qr_code()
                     .with_id(id!("qr")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(200, 0), Size::new(WIDTH - 200, 40))
+                    .with_text("Signal...")
+                    .with_id(id!("rssi")),
             );
 
         let infos_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .on_gesture(Button::B, Gesture::Long, toggle_recording)
             .with_btn_text(Button::C, "Retour")
             .with_btn_text(Button::B, "Nouvelle etape")
             .with_btn_text(Button::A, "Check connection")
-            .on(Button::A, |cs, pushed, _, state| {
+            .on(Button::A, |bus, pushed, _, state| {
                 if pushed == false {
                     match state.connection.ble {
                         BleState::Connected | BleState::Advertising | BleState::Disconnected => {
-                            send_i2c(cs, Commands::StartBle);
+                            bus.send_i2c(Commands::StartBle);
                         }
                         BleState::NONE => {
-                            send_i2c(cs, Commands::GetBleState);
+                            bus.send_i2c(Commands::GetBleState);
                         }
                         _ => {}
                     }
@@ -594,28 +1297,28 @@ impl App {
             })
             .on(Button::C, |_, pushed, boxes, state| {
                 if pushed == false {
-                    boxes.into_iter().for_each(|box_| box_.must_draw = true);
                     state.current_screen = ScreenId::Main;
                 }
             })
-            .on(Button::B, |cs, pushed, _, state| {
+            .on(Button::B, |bus, pushed, _, state| {
                 if pushed == false {
                     state.infos.coords.as_ref().and_then(|coords| {
                         if coords.is_valid() {
-                            send_i2c(
-                                cs,
-                                Commands::NewStep(Coordinates::new(coords.lat, coords.long)),
-                            );
+                            bus.send_i2c(Commands::NewStep(Coordinates::new(
+                                coords.lat,
+                                coords.long,
+                            )));
                         }
                         Some(())
                     });
                 }
             })
-            .on_update(|cs, command, boxes, state, c_h| {
+            .on_update(|bus, command, boxes, state, c_h, gps| {
                 match command {
+                    // Found on the M5Go when there is a route
                     Commands::ClosestStep(coords) => {
-                        if coords.is_valid() {
-                            state.infos.closest_step = Some(coords);
+                        if coords.is_valid() && state.infos.route.is_empty() {
+                            state.infos.set_closest_step(coords);
                         }
                     }
                     Commands::BleState(ble_state) => {
@@ -640,7 +1343,7 @@ impl App {
                 }
                 if state.connection.ble == BleState::NONE && state.connection.request_sent == false
                 {
-                    send_i2c(cs, Commands::GetBleState);
+                    bus.send_i2c(Commands::GetBleState);
                     state.connection.request_sent = true;
                 } else if state.connection.ble != BleState::Connected {
                     let connection_box = boxes.get_id_mut(id!("connectionState")).unwrap();
@@ -650,6 +1353,7 @@ impl App {
                             BleState::Disconnected => "L'appareil BLE n'est pas connecte!",
                             BleState::Advertising => "En attente de connexion GPS...",
                             BleState::NONE => "L'appareil BLE est-il allume?",
+                            BleState::Error => "Redemarrage du BLE...",
                             _ => text,
                         }
                         .to_string()
@@ -683,19 +1387,67 @@ impl App {
                     });
                 }
 
-                match read_gps_line(cs) {
-                    Some(message) => {
+                boxes.get_id_mut(id!("heartRate")).and_then(|box_| {
+                    match state.infos.heart_rate {
+                        Some(bpm) => box_.set_text(format!("Cardio: {} bpm", bpm).as_str()),
+                        None => box_.set_text("Pas de cardio"),
+                    }
+                    Some(())
+                });
+
+                boxes.get_id_mut(id!("cadence")).and_then(|box_| {
+                    match (state.infos.cadence, state.infos.wheel_speed) {
+                        (Some(cadence), Some(speed)) => {
+                            box_.set_text(format!("{} rpm {:.1}km/h", cadence, speed).as_str())
+                        }
+                        (Some(cadence), None) => {
+                            box_.set_text(format!("Cadence: {} rpm", cadence).as_str())
+                        }
+                        (None, Some(speed)) => {
+                            box_.set_text(format!("Roue: {:.1}km/h", speed).as_str())
+                        }
+                        (None, None) => box_.set_text("Pas de cadence"),
+                    }
+                    Some(())
+                });
+
+                boxes.get_id_mut(id!("speed")).and_then(|box_| {
+                    box_.set_filled(state.speed_alert.is_flashing());
+                    Some(())
+                });
+
+                // Rounded so that the box is drawn again only when what it shows changes
+                boxes.get_id_mut(id!("step")).and_then(|box_| {
+                    box_.set_text(&get_step_text(state));
+                    Some(())
+                });
+
+                boxes.get_id_mut(id!("peer")).and_then(|box_| {
+                    box_.set_text(
+                        get_peer_text(state.infos.coords.as_ref(), state.infos.peer.as_ref())
+                            .as_str(),
+                    );
+                    Some(())
+                });
+
+                if let Some(weather) = &state.infos.weather {
+                    boxes.get_id_mut(id!("weather")).and_then(|box_| {
+                        box_.set_text(
+                            format!(
+                                "{:.0}C {} {:.0}km/h",
+                                weather.temp, weather.condition, weather.wind
+                            )
+                            .as_str(),
+                        );
+                        Some(())
+                    });
+                }
+
+                match gps {
+                    Some(GpsEvent::Sentence(message)) => {
                         match message {
                             ParsedMessage::Incomplete => {}
                             ParsedMessage::Gga(infos) => {
-                                if infos.quality != GgaQualityIndicator::Invalid {
-                                    state.infos.time = infos.timestamp;
-                                    state.infos.coords = infos.longitude.and_then(|lon| {
-                                        infos
-                                            .latitude
-                                            .and_then(|lat| Some(Coordinates::new(lat, lon)))
-                                    });
-                                }
                                 boxes.get_id_mut(id!("time")).unwrap().replace_text(|text| {
                                     match state.infos.time {
                                         Some(timestamp) => {
@@ -774,12 +1526,13 @@ impl App {
                             _ => {}
                         };
                     }
-                    None => {
+                    Some(GpsEvent::Silent) => {
                         boxes
                             .get_id_mut(id!("time"))
                             .unwrap()
                             .set_text("Connexion...");
                     }
+                    None => {}
                 };
             })
             .add_box(
@@ -795,40 +1548,107 @@ impl App {
                     .with_id(id!("temperature")),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 40), Size::new(WIDTH / 2, 40))
+                GraphicBox::new(Point::new(0, 40), Size::new(WIDTH / 2, 27))
                     .with_text("Connexion...")
                     .with_id(id!("longitude")),
             )
             .add_box(
-                GraphicBox::new(Point::new(WIDTH as i32 / 2, 40), Size::new(WIDTH / 2, 40))
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 40), Size::new(WIDTH / 2, 27))
                     .with_text("Connexion...")
                     .with_id(id!("latitude")),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 80), Size::new(WIDTH / 2, 40))
+                GraphicBox::new(Point::new(0, 67), Size::new(WIDTH / 2, 27))
                     .with_text("Connexion...")
                     .with_id(id!("altitude")),
             )
             .add_box(
-                GraphicBox::new(Point::new(WIDTH as i32 / 2, 80), Size::new(WIDTH / 2, 40))
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 67), Size::new(WIDTH / 2, 27))
                     .with_text("Connexion...")
                     .with_id(id!("speed")),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 120), Size::new(WIDTH, 40))
+                GraphicBox::new(Point::new(0, 94), Size::new(WIDTH / 2, 27))
                     .with_text("Connexion...")
                     .with_id(id!("humidity")),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 160), Size::new(WIDTH, 40))
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 94), Size::new(WIDTH / 2, 27))
+                    .with_text("Pas de meteo")
+                    .with_id(id!("weather")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 121), Size::new(WIDTH / 2, 27))
+                    .with_text("Pas de cardio")
+                    .with_id(id!("heartRate")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 121), Size::new(WIDTH / 2, 27))
+                    .with_text("Pas de cadence")
+                    .with_id(id!("cadence")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 148), Size::new(WIDTH, 21))
+                    .with_text("Pas d'autre Byke")
+                    .with_id(id!("peer")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 169), Size::new(WIDTH, 21))
+                    .with_text("Pas d'etape")
+                    .with_id(id!("step")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 190), Size::new(WIDTH, 25))
                     .with_id(id!("connectionState"))
                     .with_color(Rgb565::RED),
             );
 
         let options_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
             .with_btn_text(Button::A, "Haut")
+            .with_repeat(Button::A)
+            .with_repeat(Button::B)
             .with_btn_text(Button::B, "Bas")
-            .on_update(|_, _, boxes, state, _| {
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!("nmea")).unwrap().replace_text(|_| {
+                    if state.options.nmea_log {
+                        "Actif"
+                    } else {
+                        "Inactif"
+                    }
+                    .to_string()
+                });
+                boxes
+                    .get_id_mut(id!("brightness"))
+                    .unwrap()
+                    .replace_text(|_| {
+                        format!(
+                            "{}%",
+                            state.options.brightness as u32 * 100 / u8::MAX as u32
+                        )
+                    });
+                boxes.get_id_mut(id!("timeout")).unwrap().replace_text(|_| {
+                    match state.options.screen_timeout {
+                        0 => String::from("Jamais"),
+                        seconds => format!("{} s", seconds),
+                    }
+                });
+                boxes.get_id_mut(id!("limit")).unwrap().replace_text(|_| {
+                    match state.options.speed_limit {
+                        0 => String::from("Aucune"),
+                        limit => format!("{} km/h", limit),
+                    }
+                });
+                boxes.get_id_mut(id!("sunset")).unwrap().replace_text(|_| {
+                    match state.options.sunset_lead {
+                        0 => String::from("Jamais"),
+                        minutes => format!("{} min avant", minutes),
+                    }
+                });
+                boxes
+                    .get_id_mut(id!("weight"))
+                    .unwrap()
+                    .replace_text(|_| format!("{} kg", state.options.rider_weight));
                 match state.options.selected {
                     0 => {
                         boxes.get_id_mut(BoxId::ButtonC).unwrap().set_text("OK");
@@ -858,14 +1678,103 @@ impl App {
                             "Remplissage des boutons en bas de l'ecran".to_string()
                         });
                     }
-                    _ => {}
-                };
-            })
-            .on(Button::A, |_, pushed, boxes, state| {
-                if state.options.selected > 0 && pushed == false {
-                    boxes
-                        .get_id_mut(id!(state.options.selected))
-                        .and_then(|el| Some(el.replace_text(|txt| txt.replace("> ", ""))));
+                    2 => {
+                        boxes.get_id_mut(BoxId::ButtonC).unwrap().set_text("Veille");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box
+                            .replace_text(|_| "Le boitier se reveille avec son bouton".to_string());
+                    }
+                    3 => {
+                        boxes.get_id_mut(BoxId::ButtonC).unwrap().set_text("OK");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Cardio et cadence".to_string());
+                    }
+                    4 => {
+                        boxes.get_id_mut(BoxId::ButtonC).unwrap().set_text("OK");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Dernier incident".to_string());
+                    }
+                    5 => {
+                        boxes.get_id_mut(BoxId::ButtonC).unwrap().replace_text(|_| {
+                            if state.options.nmea_log {
+                                "Desactiver"
+                            } else {
+                                "Activer"
+                            }
+                            .to_string()
+                        });
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Phrases GPS brutes sur la carte SD".to_string());
+                    }
+                    6 => {
+                        boxes
+                            .get_id_mut(BoxId::ButtonC)
+                            .unwrap()
+                            .set_text("Changer");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box
+                            .replace_text(|_| "Reduite quand l'ecran est en veille".to_string());
+                    }
+                    7 => {
+                        boxes
+                            .get_id_mut(BoxId::ButtonC)
+                            .unwrap()
+                            .set_text("Changer");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| {
+                            "Ecran eteint a l'arret, un bouton le rallume".to_string()
+                        });
+                    }
+                    8 => {
+                        boxes
+                            .get_id_mut(BoxId::ButtonC)
+                            .unwrap()
+                            .set_text("Changer");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Bip et vitesse clignotante au-dela".to_string());
+                    }
+                    9 => {
+                        boxes
+                            .get_id_mut(BoxId::ButtonC)
+                            .unwrap()
+                            .set_text("Changer");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Rappel d'allumer les feux et bip".to_string());
+                    }
+                    10 => {
+                        boxes
+                            .get_id_mut(BoxId::ButtonC)
+                            .unwrap()
+                            .set_text("Changer");
+
+                        let info_box = boxes.get_id_mut(id!("info")).unwrap();
+                        info_box.set_visible(true);
+                        info_box.replace_text(|_| "Pour les calories du trajet".to_string());
+                    }
+                    _ => {}
+                };
+            })
+            .on(Button::A, |_, pushed, boxes, state| {
+                if state.options.selected > 0 && pushed == false {
+                    boxes
+                        .get_id_mut(id!(state.options.selected))
+                        .and_then(|el| Some(el.replace_text(|txt| txt.replace("> ", ""))));
                     state.options.selected -= 1;
                     boxes
                         .get_id_mut(id!(state.options.selected))
@@ -883,37 +1792,143 @@ impl App {
                         .and_then(|el| Some(el.replace_text(|txt| format!("> {}", txt))));
                 }
             })
-            .on(Button::C, |_, pushed, boxes, state| {
+            .on(Button::C, |bus, pushed, boxes, state| {
                 if pushed == false {
                     match state.options.selected {
                         0 => {
-                            boxes.into_iter().for_each(|box_| box_.must_draw = true);
                             state.current_screen = ScreenId::Main;
                         }
                         1 => {
                             state.options.fill_on_click = state.options.fill_on_click == false;
                         }
+                        2 => {
+                            // For parking over several days, the stick only wakes up on its button
+                            bus.send_i2c(Commands::Sleep(0)).or_else(|| {
+                                esp_println::println!("Error sending Sleep command");
+                                None
+                            });
+                        }
+                        3 => {
+                            state.current_screen = ScreenId::Sensors;
+                            start_scan(bus, state);
+                        }
+                        4 => {
+                            state.current_screen = ScreenId::Diagnostics;
+                        }
+                        5 => {
+                            state.options.nmea_log = state.options.nmea_log == false;
+                        }
+                        6 => {
+                            state.options.brightness =
+                                next_level(&BRIGHTNESS_LEVELS, state.options.brightness);
+                        }
+                        7 => {
+                            state.options.screen_timeout =
+                                next_level(&SCREEN_TIMEOUTS, state.options.screen_timeout);
+                        }
+                        8 => {
+                            state.options.speed_limit =
+                                next_level(&SPEED_LIMITS, state.options.speed_limit);
+                        }
+                        9 => {
+                            state.options.sunset_lead =
+                                next_level(&SUNSET_LEADS, state.options.sunset_lead);
+                        }
+                        10 => {
+                            state.options.rider_weight =
+                                next_level(&RIDER_WEIGHTS, state.options.rider_weight);
+                        }
                         _ => {}
                     }
                 }
             })
             .add_box(
-                GraphicBox::new(Point::new(0, 50), Size::new(WIDTH / 2, 25))
+                GraphicBox::new(Point::new(0, 26), Size::new(WIDTH / 2, 14))
                     .with_text("> Retour")
                     .with_id(id!(0)),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, 80), Size::new(WIDTH / 2, 25))
+                GraphicBox::new(Point::new(0, 41), Size::new(WIDTH / 2, 14))
                     .with_text("Remplissage des boutons")
                     .with_id(id!(1)),
             )
             .add_box(
-                GraphicBox::new(Point::new(WIDTH as i32 / 2, 80), Size::new(WIDTH / 2, 25))
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 41), Size::new(WIDTH / 2, 14))
                     .with_id(id!("fill"))
                     .with_text("Inactif"),
             )
             .add_box(
-                GraphicBox::new(Point::new(0, HEIGHT as i32 - 60), Size::new(WIDTH, 25))
+                GraphicBox::new(Point::new(0, 56), Size::new(WIDTH / 2, 14))
+                    .with_text("Veille BLE")
+                    .with_id(id!(2)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 71), Size::new(WIDTH / 2, 14))
+                    .with_text("Capteurs")
+                    .with_id(id!(3)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 86), Size::new(WIDTH / 2, 14))
+                    .with_text("Diagnostic")
+                    .with_id(id!(4)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 101), Size::new(WIDTH / 2, 14))
+                    .with_text("Journal NMEA")
+                    .with_id(id!(5)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 101), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("nmea"))
+                    .with_text("Inactif"),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 116), Size::new(WIDTH / 2, 14))
+                    .with_text("Luminosite")
+                    .with_id(id!(6)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 116), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("brightness")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 131), Size::new(WIDTH / 2, 14))
+                    .with_text("Veille ecran")
+                    .with_id(id!(7)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 131), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("timeout")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 146), Size::new(WIDTH / 2, 14))
+                    .with_text("Limite de vitesse")
+                    .with_id(id!(8)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 146), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("limit")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 161), Size::new(WIDTH / 2, 14))
+                    .with_text("Coucher du soleil")
+                    .with_id(id!(9)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 161), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("sunset")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 176), Size::new(WIDTH / 2, 14))
+                    .with_text("Poids")
+                    .with_id(id!(10)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 176), Size::new(WIDTH / 2, 14))
+                    .with_id(id!("weight")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, HEIGHT as i32 - 48), Size::new(WIDTH, 22))
                     .with_id(id!("info")),
             )
             .add_box(
@@ -925,7 +1940,985 @@ impl App {
         self.screens.push(main_screen);
         self.screens.push(qr_code_screen);
         self.screens.push(infos_screen);
+        let sensors_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::A, "Haut")
+            .with_repeat(Button::A)
+            .with_repeat(Button::B)
+            .with_btn_text(Button::B, "Bas")
+            .with_btn_text(Button::C, "OK")
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!(1)).unwrap().replace_text(|_| {
+                    let text = if state.sensors.scanning {
+                        "Recherche..."
+                    } else {
+                        "Chercher"
+                    };
+                    get_entry_text(text, state.sensors.selected == 1)
+                });
+                for i in 0..MAX_SENSORS {
+                    let entry = i + 2;
+                    let sensor_box = boxes.get_id_mut(id!(entry)).unwrap();
+                    match state.sensors.found.get(i) {
+                        Some(sensor) => {
+                            sensor_box.set_visible(true);
+                            sensor_box.replace_text(|_| {
+                                get_entry_text(
+                                    &get_sensor_text(sensor),
+                                    state.sensors.selected == entry,
+                                )
+                            });
+                        }
+                        None => sensor_box.set_visible(false),
+                    }
+                }
+            })
+            .on(Button::A, |_, pushed, boxes, state| {
+                if state.sensors.selected > 0 && pushed == false {
+                    boxes
+                        .get_id_mut(id!(state.sensors.selected))
+                        .and_then(|el| Some(el.replace_text(|txt| txt.replace("> ", ""))));
+                    state.sensors.selected -= 1;
+                    boxes
+                        .get_id_mut(id!(state.sensors.selected))
+                        .and_then(|el| Some(el.replace_text(|txt| format!("> {}", txt))));
+                }
+            })
+            .on(Button::B, |_, pushed, boxes, state| {
+                if state.sensors.selected < state.sensors.get_max_selected() && pushed == false {
+                    boxes
+                        .get_id_mut(id!(state.sensors.selected))
+                        .and_then(|el| Some(el.replace_text(|txt| txt.replace("> ", ""))));
+                    state.sensors.selected += 1;
+                    boxes
+                        .get_id_mut(id!(state.sensors.selected))
+                        .and_then(|el| Some(el.replace_text(|txt| format!("> {}", txt))));
+                }
+            })
+            .on(Button::C, |bus, pushed, boxes, state| {
+                if pushed == false {
+                    match state.sensors.selected {
+                        0 => {
+                            if state.sensors.scanning {
+                                bus.send_i2c(Commands::ScanSensors(false));
+                            }
+                            state.current_screen = ScreenId::Options;
+                        }
+                        1 => {
+                            if state.sensors.scanning == false {
+                                start_scan(bus, state);
+                            }
+                        }
+                        selected => {
+                            if let Some(sensor) = state.sensors.found.get(selected - 2) {
+                                bus.send_i2c(Commands::PairSensor(sensor.clone()))
+                                    .and_then(|_| {
+                                        state.notification.show(
+                                            String::from("Capteur associe"),
+                                            get_sensor_text(sensor),
+                                        );
+                                        Some(())
+                                    })
+                                    .or_else(|| {
+                                        esp_println::println!("Error sending PairSensor command");
+                                        None
+                                    });
+                            }
+                        }
+                    }
+                }
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Capteurs")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 35), Size::new(WIDTH / 2, 25))
+                    .with_text("> Retour")
+                    .with_id(id!(0)),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 35), Size::new(WIDTH / 2, 25))
+                    .with_text("Chercher")
+                    .with_id(id!(1)),
+            );
+        let sensors_screen = (0..MAX_SENSORS).fold(sensors_screen, |screen, i| {
+            screen.add_box(
+                GraphicBox::new(Point::new(0, 65 + i as i32 * 30), Size::new(WIDTH, 25))
+                    .with_id(id!(i + 2)),
+            )
+        });
+
+        let diagnostics_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::C, "Retour")
+            .display_button(Button::B, false)
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Options;
+                }
+            })
+            .on(Button::A, |_, pushed, _, state| {
+                if pushed == false {
+                    state.storage.wanted = state.storage.wanted == false;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!("crash")).and_then(|box_| {
+                    box_.set_text(&wrap(
+                        state
+                            .last_crash
+                            .as_deref()
+                            .unwrap_or("Aucun incident enregistre"),
+                    ));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("stick")).and_then(|box_| {
+                    box_.set_text(&get_presence_text("Clef BLE", state.devices.stick));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("climate")).and_then(|box_| {
+                    box_.set_text(&get_presence_text("Capteur", state.devices.climate));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("imu")).and_then(|box_| {
+                    box_.set_text(&get_presence_text("IMU", state.devices.imu));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("recoveries")).and_then(|box_| {
+                    box_.set_text(&format!("Reprises du bus: {}", state.devices.recoveries));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("card")).and_then(|box_| {
+                    box_.set_text(&get_card_text(state.storage.status));
+                    Some(())
+                });
+                boxes.get_id_mut(BoxId::ButtonA).and_then(|box_| {
+                    box_.set_text(if state.storage.wanted {
+                        "Ejecter SD"
+                    } else {
+                        "Monter SD"
+                    });
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Diagnostic")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 30), Size::new(WIDTH, 25))
+                    .with_text("Dernier incident"),
+            )
+            .add_box(GraphicBox::new(Point::new(0, 58), Size::new(WIDTH, 50)).with_id(id!("crash")))
+            .add_box(
+                GraphicBox::new(Point::new(0, 113), Size::new(WIDTH / 2, 25)).with_id(id!("stick")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 113), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("climate")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 143), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("recoveries")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 143), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("imu")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 173), Size::new(WIDTH, 25)).with_id(id!("card")),
+            );
+
+        let upload_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::A, "Envoyer")
+            .with_btn_text(Button::B, "Mise a jour")
+            .with_btn_text(Button::C, "Retour")
+            .on(Button::A, |bus, pushed, _, state| {
+                if pushed || state.upload.is_busy() || state.firmware.is_busy() {
+                    return;
+                }
+                if state.recording.wanted {
+                    state.notification.show(
+                        String::from("Envoi impossible"),
+                        String::from("Enregistrement en cours"),
+                    );
+                } else {
+                    wifi::request_upload(bus, state);
+                }
+            })
+            .on(Button::B, |bus, pushed, _, state| {
+                if pushed || state.firmware.is_busy() || state.upload.is_busy() {
+                    return;
+                }
+                if state.recording.wanted {
+                    state.notification.show(
+                        String::from("Mise a jour impossible"),
+                        String::from("Enregistrement en cours"),
+                    );
+                } else {
+                    wifi::request_update(bus, state);
+                }
+            })
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Main;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!("status")).and_then(|box_| {
+                    box_.set_text(&wrap(&get_upload_text(&state.upload.status)));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("firmware")).and_then(|box_| {
+                    box_.set_text(&wrap(&get_update_text(&state.firmware)));
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("WiFi")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 30), Size::new(WIDTH, 25))
+                    .with_text("Sorties envoyees a l'arret, a la maison"),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 55), Size::new(WIDTH, 50)).with_id(id!("status")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 120), Size::new(WIDTH, 25))
+                    .with_text(&format!("Version {}", env!("CARGO_PKG_VERSION"))),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 145), Size::new(WIDTH, 50)).with_id(id!("firmware")),
+            );
+
         self.screens.push(options_screen);
+        self.screens.push(sensors_screen);
+        self.screens.push(diagnostics_screen);
+        self.screens.push(upload_screen);
+
+        let sos_screen = Screen::new(Arc::clone(&self.state))
+            .with_btn_text(Button::A, "Annuler")
+            .with_btn_text(Button::B, "Annuler")
+            .with_btn_text(Button::C, "Annuler")
+            .on(Button::A, cancel_sos)
+            .on(Button::B, cancel_sos)
+            .on(Button::C, cancel_sos)
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!("countdown")).and_then(|box_| {
+                    box_.set_text(&format!(
+                        "SOS dans {} s",
+                        state.sos.get_remaining().unwrap_or(0)
+                    ));
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 60))
+                    .with_text("Chute detectee")
+                    .with_text_size(TextSize::Large)
+                    .with_color(Rgb565::RED)
+                    .with_filled(true),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 75), Size::new(WIDTH, 60))
+                    .with_text_size(TextSize::Large)
+                    .with_color(Rgb565::RED)
+                    .with_id(id!("countdown")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 150), Size::new(WIDTH, 50))
+                    .with_text("Appuyer pour annuler"),
+            );
+        self.screens.push(sos_screen);
+
+        let map_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::C, "Retour")
+            .with_btn_text(Button::A, "Demi-tour")
+            .with_btn_text(Button::B, "Boussole")
+            .on(Button::A, |_, pushed, _, state| {
+                if pushed {
+                    return;
+                }
+                let steps = state.trail.get_backtrack();
+                if steps.is_empty() {
+                    state.notification.show(
+                        String::from("Demi-tour impossible"),
+                        String::from("Pas encore de trace"),
+                    );
+                } else {
+                    state.notification.show(
+                        String::from("Demi-tour"),
+                        format!("{} etapes jusqu'au depart", steps.len()),
+                    );
+                    state.infos.set_route(steps);
+                    state.routes.active = None;
+                }
+            })
+            .on(Button::B, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Compass;
+                }
+            })
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Main;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                boxes.get_id_mut(id!("map")).and_then(|box_| {
+                    box_.set_shapes(map::get_shapes(
+                        &state.infos,
+                        &state.trail.points,
+                        box_.drawable,
+                    ));
+                    box_.set_text(
+                        if state.infos.route.is_empty() && state.trail.points.is_empty() {
+                            "Pas d'itineraire"
+                        } else {
+                            ""
+                        },
+                    );
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, HEIGHT - 25))
+                    .with_id(id!("map")),
+            );
+        self.screens.push(map_screen);
+
+        let trip_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .on_gesture(Button::A, Gesture::Long, |_, _, state| state.trip.reset())
+            .with_btn_text(Button::A, "RAZ (long)")
+            .on_gesture(Button::B, Gesture::Double, mark_lap)
+            .with_btn_text(Button::B, "Tour (double)")
+            .with_btn_text(Button::C, "Retour")
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Main;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                let trip = &state.trip;
+                boxes.get_id_mut(id!("distance")).and_then(|box_| {
+                    box_.set_text(&format!("{:.1} km", trip.distance));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("time")).and_then(|box_| {
+                    let minutes = trip.moving_time.as_secs() / 60;
+                    box_.set_text(&format!("{}h{:02}", minutes / 60, minutes % 60));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("average")).and_then(|box_| {
+                    box_.set_text(&match trip.get_average_speed() {
+                        Some(speed) => format!("Moyenne: {:.1} km/h", speed),
+                        None => String::from("Moyenne: -"),
+                    });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("max")).and_then(|box_| {
+                    box_.set_text(&format!("Max: {:.1} km/h", trip.max_speed));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("odometer")).and_then(|box_| {
+                    box_.set_text(&format!("Compteur: {:.0} km", trip.odometer));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("elevation")).and_then(|box_| {
+                    box_.set_text(&format!("D+ {:.0} m  D- {:.0} m", trip.climb, trip.descent));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("lap")).and_then(|box_| {
+                    let lap = trip.get_lap();
+                    let mut text = format!(
+                        "Tour {}: {:.1} km {}",
+                        trip.get_lap_number(),
+                        lap.distance,
+                        get_lap_time(lap.moving_time)
+                    );
+                    if let Some(last) = trip.laps.last() {
+                        text += &format!(
+                            " | Tour {}: {:.1} km {}",
+                            trip.laps.len(),
+                            last.distance,
+                            get_lap_time(last.moving_time)
+                        );
+                    }
+                    box_.set_text(&text);
+                    Some(())
+                });
+                boxes.get_id_mut(id!("calories")).and_then(|box_| {
+                    box_.set_text(&format!("{:.0} kcal", trip.calories));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("gradient")).and_then(|box_| {
+                    box_.set_text(&match trip.gradient {
+                        Some(gradient) => format!("Pente {:+.0}%", gradient),
+                        None => String::from("Pente -"),
+                    });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("profile")).and_then(|box_| {
+                    box_.set_shapes(map::get_profile_shapes(&trip.profile, box_.drawable));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("paused")).and_then(|box_| {
+                    box_.set_visible(trip.paused);
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH / 2, 25))
+                    .with_text("Trajet")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 0), Size::new(WIDTH / 2, 25))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("calories")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 35), Size::new(WIDTH / 2, 40))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("distance")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 35), Size::new(WIDTH / 2, 40))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("time")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 80), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("average")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 80), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("max")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 108), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("odometer")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 108), Size::new(WIDTH / 2, 25))
+                    .with_id(id!("elevation")),
+            )
+            .add_box(GraphicBox::new(Point::new(0, 136), Size::new(WIDTH, 25)).with_id(id!("lap")))
+            .add_box(
+                GraphicBox::new(Point::new(0, 164), Size::new(WIDTH - 95, 46))
+                    .with_id(id!("profile")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 - 90, 164), Size::new(90, 21))
+                    .with_id(id!("gradient")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 - 90, 185), Size::new(90, 25))
+                    .with_text("En pause")
+                    .with_color(Rgb565::YELLOW)
+                    .with_id(id!("paused")),
+            );
+        self.screens.push(trip_screen);
+
+        let compass_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::C, "Retour")
+            .display_button(Button::A, false)
+            .display_button(Button::B, false)
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.current_screen = ScreenId::Map;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                let heading = state.heading.heading;
+                // Rounded so that the dial is not drawn again for every tenth of a degree
+                let heading = heading.map(|heading| heading.round());
+                let step_bearing = match (&state.infos.coords, &state.infos.closest_step) {
+                    (Some(coords), Some(step)) => Some(coords.bearing(step).round() as f32),
+                    _ => None,
+                };
+                boxes.get_id_mut(id!("dial")).and_then(|box_| {
+                    box_.set_shapes(compass::get_shapes(heading, step_bearing, box_.drawable));
+                    box_.set_text(if heading.is_none() { "Pas de cap" } else { "" });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("heading")).and_then(|box_| {
+                    box_.set_text(&match heading {
+                        Some(heading) => format!("{:.0}", heading % 360.0),
+                        None => String::from("-"),
+                    });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("cardinal")).and_then(|box_| {
+                    box_.set_text(heading.map_or("", compass::get_cardinal));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("source")).and_then(|box_| {
+                    box_.set_text(match state.heading.source {
+                        HeadingSource::Gps => "GPS",
+                        HeadingSource::Imu => "IMU",
+                    });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("bearing")).and_then(|box_| {
+                    box_.set_text(&match step_bearing {
+                        Some(bearing) => format!("Etape: {:.0}", bearing),
+                        None => String::from("Pas d'etape"),
+                    });
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(HEIGHT - 25, HEIGHT - 25))
+                    .with_id(id!("dial")),
+            )
+            .add_box(
+                GraphicBox::new(
+                    Point::new(HEIGHT as i32 - 20, 20),
+                    Size::new(WIDTH - HEIGHT + 20, 40),
+                )
+                .with_text_size(TextSize::Large)
+                .with_id(id!("heading")),
+            )
+            .add_box(
+                GraphicBox::new(
+                    Point::new(HEIGHT as i32 - 20, 65),
+                    Size::new(WIDTH - HEIGHT + 20, 40),
+                )
+                .with_text_size(TextSize::Medium)
+                .with_id(id!("cardinal")),
+            )
+            .add_box(
+                GraphicBox::new(
+                    Point::new(HEIGHT as i32 - 20, 110),
+                    Size::new(WIDTH - HEIGHT + 20, 25),
+                )
+                .with_id(id!("source")),
+            )
+            .add_box(
+                GraphicBox::new(
+                    Point::new(HEIGHT as i32 - 20, 140),
+                    Size::new(WIDTH - HEIGHT + 20, 25),
+                )
+                .with_color(Rgb565::CYAN)
+                .with_id(id!("bearing")),
+            );
+        self.screens.push(compass_screen);
+
+        let waypoints_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::A, "Haut")
+            .with_repeat(Button::A)
+            .with_repeat(Button::B)
+            .with_btn_text(Button::B, "Bas")
+            .with_btn_text(Button::C, "OK")
+            .on(Button::A, |_, pushed, _, state| {
+                if state.waypoints.list.selected > 0 && pushed == false {
+                    state
+                        .waypoints
+                        .list
+                        .select(state.waypoints.list.selected - 1);
+                }
+            })
+            .on(Button::B, |_, pushed, _, state| {
+                let max_selected = state.waypoints.get_max_selected(state.infos.route.len());
+                if state.waypoints.list.selected < max_selected && pushed == false {
+                    state
+                        .waypoints
+                        .list
+                        .select(state.waypoints.list.selected + 1);
+                }
+            })
+            .on(Button::C, |bus, pushed, _, state| {
+                if pushed {
+                    return;
+                }
+                match state.waypoints.list.selected {
+                    0 => state.current_screen = ScreenId::Main,
+                    1 => state.waypoints.removing = state.waypoints.removing == false,
+                    2 => {
+                        if state.infos.route.is_empty() == false {
+                            state.infos.set_route(vec![]);
+                            state.routes.active = None;
+                            state
+                                .notification
+                                .show(String::from("Itineraire efface"), String::new());
+                            route::send_progress(bus, 0, 0);
+                        }
+                    }
+                    3 => open_routes(state),
+                    4 => {
+                        if state.infos.route.is_empty() == false {
+                            state.infos.reverse_route();
+                            state.routes.active = None;
+                            state.notification.show(
+                                String::from("Itineraire inverse"),
+                                format!("{} etapes pour le retour", state.infos.route.len()),
+                            );
+                            route::send_progress(bus, 0, state.infos.route.len());
+                        }
+                    }
+                    selected => {
+                        let index = selected - WAYPOINT_ACTIONS;
+                        if state.waypoints.removing {
+                            state.infos.remove_step(index);
+                            state.routes.active = None;
+                            state.notification.show(
+                                String::from("Etape supprimee"),
+                                format!("{} restantes", state.infos.route.len()),
+                            );
+                        } else {
+                            state.infos.set_target(index);
+                            state.notification.show(
+                                String::from("Etape visee"),
+                                format!("{} sur {}", index + 1, state.infos.route.len()),
+                            );
+                        }
+                        route::send_progress(bus, state.infos.reached, state.infos.route.len());
+                    }
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                // The list may have shrunk since the entry was selected
+                let max_selected = state.waypoints.get_max_selected(state.infos.route.len());
+                if state.waypoints.list.selected > max_selected {
+                    state.waypoints.list.select(max_selected);
+                }
+                for row in 0..LIST_ROWS {
+                    let entry = state.waypoints.list.first + row;
+                    let text = match entry {
+                        0 => Some(String::from("Retour")),
+                        1 if state.waypoints.removing => Some(String::from("Appui: supprimer")),
+                        1 => Some(String::from("Appui: viser")),
+                        2 => Some(String::from("Effacer l'itineraire")),
+                        3 => Some(String::from("Itineraires de la carte")),
+                        4 => Some(String::from("Inverser pour le retour")),
+                        entry => state
+                            .infos
+                            .route
+                            .get(entry - WAYPOINT_ACTIONS)
+                            .map(|_| get_waypoint_text(state, entry - WAYPOINT_ACTIONS)),
+                    };
+                    boxes.get_id_mut(id!(row)).and_then(|box_| {
+                        match &text {
+                            Some(text) => {
+                                box_.set_visible(true);
+                                box_.set_text(&get_entry_text(
+                                    text,
+                                    state.waypoints.list.selected == entry,
+                                ));
+                            }
+                            None => box_.set_visible(false),
+                        }
+                        Some(())
+                    });
+                }
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Etapes")
+                    .with_text_size(TextSize::Large),
+            );
+        let waypoints_screen = (0..LIST_ROWS).fold(waypoints_screen, |screen, row| {
+            screen.add_box(
+                GraphicBox::new(Point::new(0, 30 + row as i32 * 25), Size::new(WIDTH, 25))
+                    .with_id(id!(row)),
+            )
+        });
+        self.screens.push(waypoints_screen);
+
+        let routes_screen = Screen::new(Arc::clone(&self.state))
+            .on_gesture(Button::C, Gesture::Long, go_home)
+            .with_btn_text(Button::A, "Haut")
+            .with_repeat(Button::A)
+            .with_repeat(Button::B)
+            .with_btn_text(Button::B, "Bas")
+            .with_btn_text(Button::C, "OK")
+            .on(Button::A, |_, pushed, _, state| {
+                if state.routes.list.selected > 0 && pushed == false {
+                    state.routes.list.select(state.routes.list.selected - 1);
+                }
+            })
+            .on(Button::B, |_, pushed, _, state| {
+                if state.routes.list.selected < state.routes.names.len() && pushed == false {
+                    state.routes.list.select(state.routes.list.selected + 1);
+                }
+            })
+            .on(Button::C, |bus, pushed, _, state| {
+                if pushed {
+                    return;
+                }
+                let name = match state.routes.list.selected {
+                    0 => {
+                        state.current_screen = ScreenId::Waypoints;
+                        return;
+                    }
+                    selected => state.routes.names[selected - 1].clone(),
+                };
+                match routes::load(&name) {
+                    Ok(route) => {
+                        state.notification.show(
+                            String::from("Itineraire choisi"),
+                            format!("{}, {} etapes", name, route.len()),
+                        );
+                        state.infos.set_route(route);
+                        bus.send_i2c(Commands::RouteSelected(name.clone()))
+                            .or_else(|| {
+                                println!("Error sending RouteSelected command");
+                                None
+                            });
+                        state.routes.active = Some(name);
+                    }
+                    Err(err) => {
+                        println!("Loading route {} failed: {}", name, err);
+                        state
+                            .notification
+                            .show(String::from("Itineraire illisible"), name);
+                    }
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                for row in 0..LIST_ROWS {
+                    let entry = state.routes.list.first + row;
+                    let text = match entry {
+                        0 => Some(String::from("Retour")),
+                        entry => state.routes.names.get(entry - 1).map(|name| {
+                            if state.routes.active.as_ref() == Some(name) {
+                                format!("{} (actif)", name)
+                            } else {
+                                name.clone()
+                            }
+                        }),
+                    };
+                    boxes.get_id_mut(id!(row)).and_then(|box_| {
+                        match &text {
+                            Some(text) => {
+                                box_.set_visible(true);
+                                box_.set_text(&get_entry_text(
+                                    text,
+                                    state.routes.list.selected == entry,
+                                ));
+                            }
+                            None => box_.set_visible(false),
+                        }
+                        Some(())
+                    });
+                }
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Itineraires")
+                    .with_text_size(TextSize::Large),
+            );
+        let routes_screen = (0..LIST_ROWS).fold(routes_screen, |screen, row| {
+            screen.add_box(
+                GraphicBox::new(Point::new(0, 30 + row as i32 * 25), Size::new(WIDTH, 25))
+                    .with_id(id!(row)),
+            )
+        });
+        self.screens.push(routes_screen);
+
+        let summary_screen = Screen::new(Arc::clone(&self.state))
+            .with_btn_text(Button::A, "Supprimer")
+            .with_btn_text(Button::B, "Envoyer")
+            .with_btn_text(Button::C, "Garder")
+            .on(Button::A, |_, pushed, _, state| {
+                if pushed {
+                    return;
+                }
+                if let Err(err) = recorder::discard(state) {
+                    println!("Removing the ride failed: {}", err);
+                    state.notification.show(
+                        String::from("Suppression impossible"),
+                        String::from("Carte SD absente"),
+                    );
+                }
+                state.current_screen = ScreenId::Main;
+            })
+            .on(Button::B, |bus, pushed, _, state| {
+                let stats = match &state.recording.summary {
+                    Some((stats, _)) if pushed == false => stats.clone(),
+                    _ => return,
+                };
+                let sent = bus
+                    .send_i2c(Commands::TripStats(stats))
+                    .or_else(|| {
+                        println!("Error sending TripStats command");
+                        None
+                    })
+                    .is_some();
+                if sent {
+                    state
+                        .notification
+                        .show(String::from("Resume envoye"), String::from("Au telephone"));
+                }
+            })
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.recording.summary = None;
+                    state.current_screen = ScreenId::Main;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                let (stats, name) = match &state.recording.summary {
+                    Some(summary) => summary,
+                    None => return,
+                };
+                boxes.get_id_mut(id!("file")).and_then(|box_| {
+                    box_.set_text(name);
+                    Some(())
+                });
+                boxes.get_id_mut(id!("distance")).and_then(|box_| {
+                    box_.set_text(&format!("{:.1} km", stats.distance));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("time")).and_then(|box_| {
+                    let minutes = stats.moving_time / 60;
+                    box_.set_text(&format!("{}h{:02}", minutes / 60, minutes % 60));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("average")).and_then(|box_| {
+                    box_.set_text(&match stats.moving_time {
+                        0 => String::from("Moyenne: -"),
+                        time => {
+                            format!("Moyenne: {:.1} km/h", stats.distance * 3600.0 / time as f64)
+                        }
+                    });
+                    Some(())
+                });
+                boxes.get_id_mut(id!("max")).and_then(|box_| {
+                    box_.set_text(&format!("Max: {:.1} km/h", stats.max_speed));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("climb")).and_then(|box_| {
+                    box_.set_text(&format!(
+                        "D+ {:.0} m  {:.0} kcal",
+                        stats.climb, stats.calories
+                    ));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("temperature")).and_then(|box_| {
+                    box_.set_text(&match (stats.min_temperature, stats.max_temperature) {
+                        (Some(min), Some(max)) => format!("Temp: {:.0} a {:.0} C", min, max),
+                        _ => String::from("Temp: -"),
+                    });
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Resume")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(GraphicBox::new(Point::new(0, 28), Size::new(WIDTH, 20)).with_id(id!("file")))
+            .add_box(
+                GraphicBox::new(Point::new(0, 55), Size::new(WIDTH / 2, 40))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("distance")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 55), Size::new(WIDTH / 2, 40))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("time")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 105), Size::new(WIDTH / 2, 30))
+                    .with_id(id!("average")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 105), Size::new(WIDTH / 2, 30))
+                    .with_id(id!("max")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 145), Size::new(WIDTH / 2, 30)).with_id(id!("climb")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(WIDTH as i32 / 2, 145), Size::new(WIDTH / 2, 30))
+                    .with_id(id!("temperature")),
+            );
+        self.screens.push(summary_screen);
+
+        let resume_screen = Screen::new(Arc::clone(&self.state))
+            .with_btn_text(Button::A, "Reprendre")
+            .display_button(Button::B, false)
+            .with_btn_text(Button::C, "Nouvelle")
+            .on(Button::A, |bus, pushed, _, state| {
+                if pushed == false {
+                    checkpoint::resume(bus, state);
+                }
+            })
+            .on(Button::C, |_, pushed, _, state| {
+                if pushed == false {
+                    state.checkpoint.offered = None;
+                    state.current_screen = ScreenId::Main;
+                }
+            })
+            .on_update(|_, _, boxes, state, _, _| {
+                let checkpoint = match &state.checkpoint.offered {
+                    Some(checkpoint) => checkpoint,
+                    None => return,
+                };
+                boxes.get_id_mut(id!("trip")).and_then(|box_| {
+                    let minutes = checkpoint.moving_time / 60;
+                    box_.set_text(&format!(
+                        "{:.1} km en {}h{:02}",
+                        checkpoint.distance,
+                        minutes / 60,
+                        minutes % 60
+                    ));
+                    Some(())
+                });
+                boxes.get_id_mut(id!("route")).and_then(|box_| {
+                    box_.set_text(&match &checkpoint.route {
+                        Some(route) => format!("{}, etape {}", route, checkpoint.reached + 1),
+                        None => String::from("Sans itineraire"),
+                    });
+                    Some(())
+                });
+            })
+            .add_box(
+                GraphicBox::new(Point::new(0, 0), Size::new(WIDTH, 25))
+                    .with_text("Reprendre la sortie ?")
+                    .with_text_size(TextSize::Large),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 40), Size::new(WIDTH, 25))
+                    .with_text("Sortie interrompue par une coupure"),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 80), Size::new(WIDTH, 40))
+                    .with_text_size(TextSize::Medium)
+                    .with_id(id!("trip")),
+            )
+            .add_box(
+                GraphicBox::new(Point::new(0, 130), Size::new(WIDTH, 25)).with_id(id!("route")),
+            );
+        self.screens.push(resume_screen);
+    }
+
+    /// Draws the current screen, all of it when it was just switched to
+    pub fn draw(&mut self, display: &mut Display) {
+        let current_screen = self.state.lock().unwrap().borrow().current_screen;
+        let switched = current_screen != self.on_screen;
+        self.on_screen = current_screen;
+        let screen = self
+            .screens
+            .get_mut(Into::<usize>::into(current_screen))
+            .unwrap();
+        if switched {
+            screen.repaint();
+        }
+        screen.draw(display, &mut self.buffer);
     }
 
     pub fn get_screen(&mut self) -> &mut Screen {
@@ -935,3 +2928,283 @@ impl App {
             .unwrap()
     }
 }
+
+/// Status of the TF card, the space in megabytes
+fn get_card_text(status: CardStatus) -> String {
+    match status {
+        CardStatus::Mounted { free, total } => format!(
+            "Carte SD: {} Mo libres sur {}",
+            free / 1_000_000,
+            total / 1_000_000
+        ),
+        CardStatus::Missing => String::from("Carte SD absente"),
+        CardStatus::Unmounted => String::from("Carte SD ejectee"),
+    }
+}
+
+/// Last upload of the rides
+fn get_upload_text(status: &UploadStatus) -> String {
+    match status {
+        UploadStatus::Idle => String::from("Pas encore d'envoi"),
+        UploadStatus::NotConfigured => String::from("WiFi a configurer depuis le telephone"),
+        UploadStatus::Connecting => String::from("Connexion au WiFi..."),
+        UploadStatus::Away => String::from("WiFi de la maison hors de portee"),
+        UploadStatus::Uploading { ride, percent } => format!("Envoi de {}: {}%", ride, percent),
+        UploadStatus::Done(0) => String::from("Aucune sortie a envoyer"),
+        UploadStatus::Done(rides) => format!("{} sorties envoyees", rides),
+        UploadStatus::Failed(reason) => format!("Echec: {}", reason),
+    }
+}
+
+/// Level of the battery, marked while it charges
+fn get_battery_text(battery: Option<Battery>) -> String {
+    match battery {
+        Some(Battery {
+            level,
+            charging: true,
+        }) => format!("+{}%", level),
+        Some(Battery { level, .. }) => format!("{}%", level),
+        None => String::new(),
+    }
+}
+
+/// Low enough to warn the rider, the power IC telling the level by steps of 25
+fn is_battery_low(battery: Battery) -> bool {
+    battery.level <= LOW_BATTERY_LEVEL && battery.charging == false
+}
+
+/// Last update of the firmware
+fn get_update_text(status: &UpdateStatus) -> String {
+    match status {
+        UpdateStatus::Idle => String::from("Mise a jour non verifiee"),
+        UpdateStatus::NotConfigured => String::from("Mise a jour a configurer depuis le telephone"),
+        UpdateStatus::Checking => String::from("Recherche d'une mise a jour..."),
+        UpdateStatus::Away => String::from("WiFi de la maison hors de portee"),
+        UpdateStatus::UpToDate => String::from("Micrologiciel a jour"),
+        UpdateStatus::Downloading(percent) => format!("Telechargement: {}%", percent),
+        UpdateStatus::Restarting => String::from("Mise a jour installee, redemarrage"),
+        UpdateStatus::Failed(reason) => format!("Echec: {}", reason),
+    }
+}
+
+/// Long press of B on the infos screen, starting or stopping the recording of the ride
+fn toggle_recording(_: &Bus, _: &mut Vec<GraphicBox>, state: &mut State) {
+    state.recording.wanted = state.recording.wanted == false;
+}
+
+/// Presence of a device of Port A
+fn get_presence_text(name: &str, present: Option<bool>) -> String {
+    match present {
+        Some(true) => format!("{}: present", name),
+        Some(false) => format!("{}: absent", name),
+        None => format!("{}: ?", name),
+    }
+}
+
+/// Any button of the countdown screen, the rider being fine
+fn cancel_sos(_: &Bus, pushed: bool, _: &mut Vec<GraphicBox>, state: &mut State) {
+    if pushed == false {
+        sos::cancel(state);
+    }
+}
+
+/// Long press of C, back to the main screen from anywhere
+fn go_home(_: &Bus, _: &mut Vec<GraphicBox>, state: &mut State) {
+    state.current_screen = ScreenId::Main;
+}
+
+/// Reads the routes of the card, before they are listed
+fn open_routes(state: &mut State) {
+    match routes::list() {
+        Ok(names) => {
+            state.routes.names = names;
+            state.routes.list = ScrollList::new();
+            state.current_screen = ScreenId::Routes;
+        }
+        Err(err) => {
+            println!("Listing the routes failed: {}", err);
+            state.notification.show(
+                String::from("Pas d'itineraire"),
+                String::from("Carte SD absente ou vide"),
+            );
+        }
+    }
+}
+
+/// Ends the lap of the trip, told on the recording from its next point
+fn mark_lap(_: &Bus, _: &mut Vec<GraphicBox>, state: &mut State) {
+    let lap = state.trip.mark_lap();
+    state.notification.show(
+        format!("Tour {}", state.trip.laps.len()),
+        format!(
+            "{:.1} km en {}",
+            lap.distance,
+            get_lap_time(lap.moving_time)
+        ),
+    );
+}
+
+/// Forgets the sensors found before and looks for them again, through the stick
+fn start_scan(bus: &Bus, state: &mut State) {
+    state.sensors.found.clear();
+    state.sensors.selected = state.sensors.selected.min(1);
+    state.sensors.scanning = bus
+        .send_i2c(Commands::ScanSensors(true))
+        .or_else(|| {
+            esp_println::println!("Error sending ScanSensors command");
+            None
+        })
+        .is_some();
+}
+
+fn get_sensor_text(sensor: &Sensor) -> String {
+    let kind = match sensor.kind {
+        SensorKind::HeartRate => "Cardio",
+        SensorKind::Cadence => "Cadence",
+        SensorKind::Unit => "Byke",
+    };
+    format!("{} {} ({} dBm)", kind, sensor.name, sensor.rssi)
+}
+
+/// Distance and direction of the other bike of a group ride, from the position of this one
+fn get_peer_text(coords: Option<&Coordinates>, peer: Option<&Coordinates>) -> String {
+    const DIRECTIONS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SO", "O", "NO"];
+    match (coords.filter(|coords| coords.is_valid()), peer) {
+        (Some(coords), Some(peer)) => {
+            let distance = coords.distance(peer);
+            let bearing = coords.bearing(peer);
+            let direction = DIRECTIONS[((bearing + 22.5) / 45.0) as usize % 8];
+            if distance < 1.0 {
+                format!("Autre Byke: {:.0}m, cap {}", distance * 1000.0, direction)
+            } else {
+                format!("Autre Byke: {:.1}km, cap {}", distance, direction)
+            }
+        }
+        (None, Some(_)) => String::from("Autre Byke en vue, position inconnue"),
+        (_, None) => String::from("Pas d'autre Byke"),
+    }
+}
+
+/// Position, time and speed of the GPS sentence
+fn update_position(bus: &Bus, state: &mut State, message: &ParsedMessage) {
+    match message {
+        ParsedMessage::Gga(infos) if infos.quality != GgaQualityIndicator::Invalid => {
+            state.infos.time = infos.timestamp;
+            state.infos.altitude = infos.altitude;
+            if let Some(altitude) = infos.altitude {
+                state.trip.update_altitude(altitude);
+            }
+            state.infos.coords = infos
+                .longitude
+                .and_then(|lon| infos.latitude.map(|lat| Coordinates::new(lat, lon)));
+            if let Some(coords) = &state.infos.coords {
+                state.trail.push(coords);
+                // The stick relays it to the phone at the rate it asked for
+                if state.connection.ble == BleState::Connected {
+                    bus.send_i2c(Commands::Position(Coordinates::new(
+                        coords.lat,
+                        coords.long,
+                    )));
+                }
+            }
+        }
+        ParsedMessage::Rmc(infos) => {
+            state.infos.speed = match infos.status_active {
+                Some(true) => infos.sog_knots.map(|sog| sog * 0.5144 * 3.6),
+                _ => None,
+            };
+            let coords = infos
+                .longitude
+                .and_then(|lon| infos.latitude.map(|lat| Coordinates::new(lat, lon)));
+            match (coords, state.infos.speed) {
+                (Some(coords), Some(speed)) => {
+                    state.trip.update(coords, speed, state.options.rider_weight)
+                }
+                _ => state.trip.lost(),
+            }
+            state.heading.set_course(infos.bearing, state.infos.speed);
+        }
+        _ => {}
+    }
+}
+
+/// Moving time of a lap, in minutes and seconds
+fn get_lap_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Step of the waypoints screen, with its distance when there is a fix
+fn get_waypoint_text(state: &State, index: usize) -> String {
+    let step = &state.infos.route[index];
+    let place = match &state.infos.coords {
+        Some(coords) => format!("{:.1} km", coords.distance(step)),
+        None => format!("{:.4} {:.4}", step.lat, step.long),
+    };
+    let mark = if state.infos.step == Some(index) {
+        " cible"
+    } else if index < state.infos.reached {
+        " ok"
+    } else {
+        ""
+    };
+    format!(
+        "{}/{}  {}{}",
+        index + 1,
+        state.infos.route.len(),
+        place,
+        mark
+    )
+}
+
+/// Distance to the closest step, and the time to reach it at the current speed
+fn get_step_text(state: &State) -> String {
+    let (coords, step) = match (
+        state
+            .infos
+            .coords
+            .as_ref()
+            .filter(|coords| coords.is_valid()),
+        state.infos.closest_step.as_ref(),
+    ) {
+        (Some(coords), Some(step)) => (coords, step),
+        (None, Some(_)) => return String::from("Etape: position inconnue"),
+        (_, None) => return String::from("Pas d'etape"),
+    };
+    let name = match state.infos.step {
+        Some(index) => format!("Etape {}/{}", index + 1, state.infos.route.len()),
+        None => String::from("Etape"),
+    };
+    let distance = coords.distance(step);
+    let distance_text = if distance < 1.0 {
+        // By 10 m
+        format!("{:.0}m", (distance * 100.0).round() * 10.0)
+    } else {
+        format!("{:.1}km", distance)
+    };
+    let eta_minutes = state
+        .infos
+        .speed
+        .filter(|speed| *speed >= MOVING_SPEED_KMH)
+        .map(|speed| (distance / speed * 60.0).ceil() as u32);
+    match eta_minutes {
+        Some(minutes) if minutes >= 60 => format!(
+            "{}: {}, {}h{:02}",
+            name,
+            distance_text,
+            minutes / 60,
+            minutes % 60
+        ),
+        Some(minutes) => format!("{}: {}, {} min", name, distance_text, minutes),
+        None => format!("{}: {}", name, distance_text),
+    }
+}
+
+/// Text of a list entry, marked when selected
+fn get_entry_text(text: &str, selected: bool) -> String {
+    if selected {
+        format!("> {}", text)
+    } else {
+        String::from(text)
+    }
+}
@@ -0,0 +1,222 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use shared::Coordinates;
+
+use crate::checkpoint::{Checkpoint, MAX_CHECKPOINT_LEN};
+
+const NVS_NAMESPACE: &str = "byke";
+const BRIGHTNESS_KEY: &str = "brightness";
+const FILL_ON_CLICK_KEY: &str = "fill_click";
+const NMEA_LOG_KEY: &str = "nmea_log";
+const SCREEN_TIMEOUT_KEY: &str = "screen_off";
+const STEP_RADIUS_KEY: &str = "step_radius";
+const SPEED_LIMIT_KEY: &str = "speed_limit";
+const SUNSET_LEAD_KEY: &str = "sunset_lead";
+const RIDER_WEIGHT_KEY: &str = "rider_weight";
+/// Odometer in hectometers, written each time it goes past one
+const ODOMETER_KEY: &str = "odometer";
+/// Latitude and longitude of home, removed when the phone forgets it
+const HOME_KEY: &str = "home";
+/// Trip and route progress of the ride, removed once there is nothing to resume
+const CHECKPOINT_KEY: &str = "checkpoint";
+/// Seconds the screen stays lit without a button pushed, until the rider picks another
+const DEFAULT_SCREEN_TIMEOUT: u8 = 30;
+/// Meters from a step under which it is reached, until the phone sets another
+const DEFAULT_STEP_RADIUS: u8 = 30;
+/// Minutes of warning before the sunset, until the rider picks another
+const DEFAULT_SUNSET_LEAD: u8 = 30;
+/// Kilograms of the rider, until they pick their own
+const DEFAULT_RIDER_WEIGHT: u8 = 70;
+
+/// Options of the rider, kept across the boots
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub fill_on_click: bool,
+    pub brightness: u8,
+    pub nmea_log: bool,
+    pub screen_timeout: u8,
+    pub step_radius: u8,
+    pub speed_limit: u8,
+    pub sunset_lead: u8,
+    pub rider_weight: u8,
+}
+
+/// Settings kept in the NVS, written as they change
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+    saved: Settings,
+    odometer: u32,
+    home: Option<Coordinates>,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl SettingsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let saved = Settings {
+            fill_on_click: get_u8(&nvs, FILL_ON_CLICK_KEY).map_or(false, |value| value == 1),
+            brightness: get_u8(&nvs, BRIGHTNESS_KEY).unwrap_or(u8::MAX),
+            nmea_log: get_u8(&nvs, NMEA_LOG_KEY).map_or(false, |value| value == 1),
+            screen_timeout: get_u8(&nvs, SCREEN_TIMEOUT_KEY).unwrap_or(DEFAULT_SCREEN_TIMEOUT),
+            step_radius: get_u8(&nvs, STEP_RADIUS_KEY).unwrap_or(DEFAULT_STEP_RADIUS),
+            speed_limit: get_u8(&nvs, SPEED_LIMIT_KEY).unwrap_or(0),
+            sunset_lead: get_u8(&nvs, SUNSET_LEAD_KEY).unwrap_or(DEFAULT_SUNSET_LEAD),
+            rider_weight: get_u8(&nvs, RIDER_WEIGHT_KEY).unwrap_or(DEFAULT_RIDER_WEIGHT),
+        };
+        let mut odometer = [0u8; 4];
+        let odometer = match nvs.get_raw(ODOMETER_KEY, &mut odometer).ok().flatten() {
+            Some([a, b, c, d]) => u32::from_be_bytes([*a, *b, *c, *d]),
+            _ => 0,
+        };
+        let mut home = [0u8; 16];
+        let home = match nvs.get_raw(HOME_KEY, &mut home).ok().flatten() {
+            Some(home) if home.len() == 16 => {
+                let lat = f64::from_be_bytes(home[..8].try_into().unwrap());
+                let long = f64::from_be_bytes(home[8..].try_into().unwrap());
+                Some(Coordinates::new(lat, long))
+            }
+            _ => None,
+        };
+        let mut checkpoint = [0u8; MAX_CHECKPOINT_LEN];
+        let checkpoint = nvs
+            .get_raw(CHECKPOINT_KEY, &mut checkpoint)
+            .ok()
+            .flatten()
+            .and_then(Checkpoint::from_raw);
+        Ok(Self {
+            nvs,
+            saved,
+            odometer,
+            home,
+            checkpoint,
+        })
+    }
+
+    pub fn get(&self) -> Settings {
+        self.saved
+    }
+
+    /// Kilometers ridden since the first boot
+    pub fn get_odometer(&self) -> f64 {
+        self.odometer as f64 / 10.0
+    }
+
+    /// Writes the odometer once it went past another hectometer
+    pub fn save_odometer(&mut self, kilometers: f64) {
+        let hectometers = (kilometers * 10.0) as u32;
+        if hectometers == self.odometer {
+            return;
+        }
+        self.odometer = hectometers;
+        self.nvs
+            .set_raw(ODOMETER_KEY, &hectometers.to_be_bytes())
+            .ok()
+            .or_else(|| {
+                println!("Failed to save {}", ODOMETER_KEY);
+                None
+            });
+    }
+
+    pub fn get_home(&self) -> Option<Coordinates> {
+        self.home
+            .as_ref()
+            .map(|home| Coordinates::new(home.lat, home.long))
+    }
+
+    /// Writes the home once the phone changed it
+    pub fn save_home(&mut self, home: Option<&Coordinates>) {
+        let same = match (self.home.as_ref(), home) {
+            (Some(saved), Some(home)) => saved.lat == home.lat && saved.long == home.long,
+            (saved, home) => saved.is_none() && home.is_none(),
+        };
+        if same {
+            return;
+        }
+        let saved = match home {
+            Some(home) => {
+                let mut raw = home.lat.to_be_bytes().to_vec();
+                raw.extend_from_slice(&home.long.to_be_bytes());
+                self.nvs.set_raw(HOME_KEY, &raw).map(|_| ())
+            }
+            None => self.nvs.remove(HOME_KEY).map(|_| ()),
+        };
+        saved.ok().or_else(|| {
+            println!("Failed to save {}", HOME_KEY);
+            None
+        });
+        self.home = home.map(|home| Coordinates::new(home.lat, home.long));
+    }
+
+    /// Ride cut short by the last power cut, to be offered to the rider
+    pub fn get_checkpoint(&self) -> Option<Checkpoint> {
+        self.checkpoint.clone()
+    }
+
+    /// Writes the checkpoint when the ride went on since the last one, and removes it
+    /// once the trip is too short to be resumed
+    pub fn save_checkpoint(&mut self, checkpoint: Checkpoint) {
+        let checkpoint = Some(checkpoint).filter(|checkpoint| checkpoint.is_empty() == false);
+        if checkpoint == self.checkpoint {
+            return;
+        }
+        let saved = match &checkpoint {
+            Some(checkpoint) => self
+                .nvs
+                .set_raw(CHECKPOINT_KEY, &checkpoint.to_raw())
+                .map(|_| ()),
+            None => self.nvs.remove(CHECKPOINT_KEY).map(|_| ()),
+        };
+        saved.ok().or_else(|| {
+            println!("Failed to save {}", CHECKPOINT_KEY);
+            None
+        });
+        self.checkpoint = checkpoint;
+    }
+
+    /// Writes the settings that changed since they were last saved
+    pub fn save(&mut self, settings: Settings) {
+        if settings.fill_on_click != self.saved.fill_on_click {
+            set_u8(
+                &mut self.nvs,
+                FILL_ON_CLICK_KEY,
+                settings.fill_on_click as u8,
+            );
+        }
+        if settings.brightness != self.saved.brightness {
+            set_u8(&mut self.nvs, BRIGHTNESS_KEY, settings.brightness);
+        }
+        if settings.nmea_log != self.saved.nmea_log {
+            set_u8(&mut self.nvs, NMEA_LOG_KEY, settings.nmea_log as u8);
+        }
+        if settings.screen_timeout != self.saved.screen_timeout {
+            set_u8(&mut self.nvs, SCREEN_TIMEOUT_KEY, settings.screen_timeout);
+        }
+        if settings.step_radius != self.saved.step_radius {
+            set_u8(&mut self.nvs, STEP_RADIUS_KEY, settings.step_radius);
+        }
+        if settings.speed_limit != self.saved.speed_limit {
+            set_u8(&mut self.nvs, SPEED_LIMIT_KEY, settings.speed_limit);
+        }
+        if settings.sunset_lead != self.saved.sunset_lead {
+            set_u8(&mut self.nvs, SUNSET_LEAD_KEY, settings.sunset_lead);
+        }
+        if settings.rider_weight != self.saved.rider_weight {
+            set_u8(&mut self.nvs, RIDER_WEIGHT_KEY, settings.rider_weight);
+        }
+        self.saved = settings;
+    }
+}
+
+fn get_u8(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<u8> {
+    let mut buffer = [0u8];
+    nvs.get_raw(key, &mut buffer)
+        .ok()
+        .flatten()
+        .and_then(|value| value.first().copied())
+}
+
+fn set_u8(nvs: &mut EspNvs<NvsDefault>, key: &str, value: u8) {
+    nvs.set_raw(key, &[value]).ok().or_else(|| {
+        println!("Failed to save {}", key);
+        None
+    });
+}
@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use shared::{BleState, Commands, Coordinates, LogLevel};
+
+use crate::{bus::Bus, screen::ScreenId, state::State};
+
+/// Acceleration of a shock told a crash, once the bike stays still after it
+const CRASH_G: f32 = 4.0;
+/// Time after the shock within which the bike has to be still, the rider having gone on
+/// riding otherwise
+const STILL_WINDOW: Duration = Duration::from_secs(30);
+/// Time left to the rider to cancel the SOS
+pub const SOS_COUNTDOWN: Duration = Duration::from_secs(30);
+/// Time the phone has to send the SOS back, past which it is sent again
+const SOS_RESEND: Duration = Duration::from_secs(10);
+
+pub struct SosState {
+    /// Last shock looked at, not told again
+    handled_shock: Option<Instant>,
+    countdown_from: Option<Instant>,
+    /// Screen shown before the countdown, switched back to once it ends
+    return_to: ScreenId,
+    /// SOS the phone did not send back yet, with the fix it carries
+    pending: Option<Option<Coordinates>>,
+    /// Last time the pending SOS went to the phone, none while there is no phone
+    sent_at: Option<Instant>,
+}
+
+impl SosState {
+    pub fn new() -> Self {
+        Self {
+            handled_shock: None,
+            countdown_from: None,
+            return_to: ScreenId::Main,
+            pending: None,
+            sent_at: None,
+        }
+    }
+
+    pub fn is_counting(&self) -> bool {
+        self.countdown_from.is_some()
+    }
+
+    /// Seconds left before the SOS is sent, rounded up
+    pub fn get_remaining(&self) -> Option<u64> {
+        self.countdown_from.map(|countdown_from| {
+            let remaining = SOS_COUNTDOWN.saturating_sub(countdown_from.elapsed());
+            (remaining.as_millis() as u64 + 999) / 1000
+        })
+    }
+}
+
+/// Pressed by the rider on the countdown screen
+pub fn cancel(state: &mut State) {
+    if state.sos.countdown_from.take().is_some() {
+        state.current_screen = state.sos.return_to;
+    }
+}
+
+/// Sent back by the phone once it took over the SOS
+pub fn acknowledged(state: &mut State) {
+    if state.sos.pending.take().is_some() {
+        state.sos.sent_at = None;
+        state.notification.show(
+            String::from("SOS recu"),
+            String::from("Le telephone a pris le relais"),
+        );
+    }
+}
+
+/// Starts the countdown when the bike stayed still after a hard shock, and sends the SOS
+/// to the phone through the stick once it ran out, again until the phone sends it back
+pub fn sync(bus: &Bus, state: &mut State) {
+    if let Some(countdown_from) = state.sos.countdown_from {
+        if countdown_from.elapsed() >= SOS_COUNTDOWN {
+            start(state);
+        }
+        return;
+    }
+    if state.sos.pending.is_some() {
+        resend(bus, state);
+    }
+
+    let crash = state.motion.shock.filter(|(accel, shock_at)| {
+        *accel >= CRASH_G
+            && Some(*shock_at) != state.sos.handled_shock
+            && shock_at.elapsed() < STILL_WINDOW
+    });
+    if let Some((accel, shock_at)) = crash {
+        if state.motion.moving == false {
+            println!("Crash detected ({:.1} g)", accel);
+            state.sos.handled_shock = Some(shock_at);
+            state.sos.countdown_from = Some(Instant::now());
+            state.sos.return_to = state.current_screen;
+            state.current_screen = ScreenId::Sos;
+        }
+    }
+}
+
+/// Ends the countdown, the SOS waiting for the phone when there is none
+fn start(state: &mut State) {
+    let coords = state
+        .infos
+        .coords
+        .as_ref()
+        .filter(|coords| coords.is_valid())
+        .map(|coords| Coordinates::new(coords.lat, coords.long));
+    state.sos.pending = Some(coords);
+    state.sos.sent_at = None;
+    state.sos.countdown_from = None;
+    state.current_screen = state.sos.return_to;
+    if state.connection.ble != BleState::Connected {
+        state.notification.show(
+            String::from("Pas de telephone"),
+            String::from("SOS envoye a son retour"),
+        );
+    }
+}
+
+/// Sends the pending SOS when a phone is connected, again while it does not send it back
+fn resend(bus: &Bus, state: &mut State) {
+    if state.connection.ble != BleState::Connected
+        || state
+            .sos
+            .sent_at
+            .is_some_and(|sent_at| sent_at.elapsed() < SOS_RESEND)
+    {
+        return;
+    }
+    let coords = match &state.sos.pending {
+        Some(coords) => coords
+            .as_ref()
+            .map(|coords| Coordinates::new(coords.lat, coords.long)),
+        None => return,
+    };
+    let body = if coords.is_some() {
+        "Position envoyee au telephone"
+    } else {
+        "Envoye sans position"
+    };
+    bus.send_i2c(Commands::Sos(coords)).or_else(|| {
+        println!("Error sending SOS command");
+        None
+    });
+    if state.sos.sent_at.is_none() {
+        bus.send_log(LogLevel::Error, "Crash detected, SOS sent");
+        state
+            .notification
+            .show(String::from("SOS envoye"), String::from(body));
+    }
+    state.sos.sent_at = Some(Instant::now());
+}
@@ -0,0 +1,124 @@
+use std::time::Instant;
+
+use esp_idf_sys::{
+    esp, ledc_channel_config, ledc_channel_config_t, ledc_channel_t_LEDC_CHANNEL_1,
+    ledc_clk_cfg_t_LEDC_AUTO_CLK, ledc_mode_t_LEDC_HIGH_SPEED_MODE, ledc_set_duty,
+    ledc_timer_bit_t_LEDC_TIMER_8_BIT, ledc_timer_config, ledc_timer_config_t,
+    ledc_timer_config_t__bindgen_ty_1, ledc_timer_t_LEDC_TIMER_1, ledc_update_duty,
+};
+
+const SPEAKER_PIN: i32 = 25;
+const TONE_HZ: u32 = 2000;
+/// Duty of the square wave, the speaker being loud enough far from half of it
+const VOLUME_DUTY: u32 = 16;
+
+/// Milliseconds of sound and of silence in turn, played by the UI task. They are rounded
+/// to its frame period.
+pub type Pattern = &'static [u32];
+
+/// Two short beeps, a step of the route being reached
+pub const STEP_BEEP: Pattern = &[100, 100, 100];
+/// One long beep, at the end of the route
+pub const ARRIVAL_BEEP: Pattern = &[600];
+/// Three quick beeps, over the speed limit of the options
+pub const SPEED_BEEP: Pattern = &[80, 80, 80, 80, 80];
+/// Two long beeps, the night coming
+pub const SUNSET_BEEP: Pattern = &[400, 200, 400];
+
+/// Sound asked for by the screens
+pub struct SoundState {
+    pattern: Pattern,
+    started_at: Option<Instant>,
+}
+
+impl SoundState {
+    pub fn new() -> Self {
+        Self {
+            pattern: &[],
+            started_at: None,
+        }
+    }
+
+    /// Plays the pattern from its start, over the one being played
+    pub fn play(&mut self, pattern: Pattern) {
+        self.pattern = pattern;
+        self.started_at = Some(Instant::now());
+    }
+
+    /// Whether the pattern sounds now, forgetting it once it ended
+    fn is_sounding(&mut self) -> bool {
+        let elapsed = match self.started_at {
+            Some(started_at) => started_at.elapsed().as_millis() as u32,
+            None => return false,
+        };
+        let mut end = 0;
+        for (i, duration) in self.pattern.iter().enumerate() {
+            end += duration;
+            if elapsed < end {
+                return i % 2 == 0;
+            }
+        }
+        self.started_at = None;
+        false
+    }
+}
+
+/// Speaker of the M5Go, driven by the LEDC as a square wave
+pub struct Speaker {
+    sounding: bool,
+}
+
+impl Speaker {
+    pub fn new() -> anyhow::Result<Self> {
+        let timer = ledc_timer_config_t {
+            speed_mode: ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+            __bindgen_anon_1: ledc_timer_config_t__bindgen_ty_1 {
+                duty_resolution: ledc_timer_bit_t_LEDC_TIMER_8_BIT,
+            },
+            timer_num: ledc_timer_t_LEDC_TIMER_1,
+            freq_hz: TONE_HZ,
+            clk_cfg: ledc_clk_cfg_t_LEDC_AUTO_CLK,
+        };
+        let channel = ledc_channel_config_t {
+            gpio_num: SPEAKER_PIN,
+            speed_mode: ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+            channel: ledc_channel_t_LEDC_CHANNEL_1,
+            timer_sel: ledc_timer_t_LEDC_TIMER_1,
+            duty: 0,
+            ..Default::default()
+        };
+
+        unsafe {
+            esp!(ledc_timer_config(&timer))?;
+            esp!(ledc_channel_config(&channel))?;
+        }
+        Ok(Self { sounding: false })
+    }
+
+    pub fn sync(&mut self, state: &mut SoundState) {
+        let sounding = state.is_sounding();
+        if sounding == self.sounding {
+            return;
+        }
+        self.sounding = sounding;
+        let duty = if sounding { VOLUME_DUTY } else { 0 };
+        unsafe {
+            esp!(ledc_set_duty(
+                ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+                ledc_channel_t_LEDC_CHANNEL_1,
+                duty,
+            ))
+            .and_then(|_| {
+                esp!(ledc_update_duty(
+                    ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+                    ledc_channel_t_LEDC_CHANNEL_1,
+                ))
+            })
+            .ok()
+            .or_else(|| {
+                println!("Failed to drive the speaker");
+                None
+            });
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use crate::{speaker::SPEED_BEEP, state::State};
+
+/// Speed limits of the options screen in km/h, 0 never warning
+pub const SPEED_LIMITS: [u8; 5] = [0, 20, 25, 30, 45];
+/// Speed under the limit at which the alert ends, so that it does not beep again around it
+const HYSTERESIS_KMH: f64 = 1.0;
+/// Time between the beeps while the rider stays over the limit
+const REPEAT_DELAY: Duration = Duration::from_secs(15);
+/// Half period of the flashing speed box
+const FLASH_PERIOD: Duration = Duration::from_millis(500);
+
+pub struct SpeedAlertState {
+    /// Since the speed went over the limit
+    over_since: Option<Instant>,
+    beeped_at: Option<Instant>,
+}
+
+impl SpeedAlertState {
+    pub fn new() -> Self {
+        Self {
+            over_since: None,
+            beeped_at: None,
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.over_since.is_some()
+    }
+
+    /// Whether the speed box is filled now, flashing while over the limit
+    pub fn is_flashing(&self) -> bool {
+        self.over_since.map_or(false, |over_since| {
+            (over_since.elapsed().as_millis() / FLASH_PERIOD.as_millis()) % 2 == 0
+        })
+    }
+}
+
+/// Beeps once the GPS speed goes over the limit of the options, and again every so often
+/// while it stays over
+pub fn sync(state: &mut State) {
+    let limit = state.options.speed_limit as f64;
+    let speed = match state.infos.speed {
+        Some(speed) if limit > 0.0 => speed,
+        _ => {
+            state.speed_alert = SpeedAlertState::new();
+            return;
+        }
+    };
+
+    if state.speed_alert.is_over() {
+        if speed < limit - HYSTERESIS_KMH {
+            state.speed_alert = SpeedAlertState::new();
+            return;
+        }
+    } else if speed > limit {
+        state.speed_alert.over_since = Some(Instant::now());
+    } else {
+        return;
+    }
+
+    let due = state
+        .speed_alert
+        .beeped_at
+        .map_or(true, |beeped_at| beeped_at.elapsed() >= REPEAT_DELAY);
+    if due {
+        state.speed_alert.beeped_at = Some(Instant::now());
+        state.sound.play(SPEED_BEEP);
+    }
+}
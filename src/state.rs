@@ -1,7 +1,35 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
 use nmea_parser::chrono::{DateTime, Utc};
-use shared::{BleState, Coordinates};
+use shared::{
+    Battery, BleState, BulkAssembler, Coordinates, Diagnostics, LogLevel, Sensor, Telemetry,
+};
 
-use crate::screen::ScreenId;
+use crate::{
+    backlight::BacklightState,
+    bus::Device,
+    checkpoint::CheckpointState,
+    compass::HeadingState,
+    gesture::Gestures,
+    home::HomeState,
+    imu::MotionEvent,
+    ota::UpdateStatus,
+    recorder::RecordingState,
+    route::find_step,
+    routes::RoutesState,
+    screen::ScreenId,
+    settings::Settings,
+    sos::SosState,
+    speaker::SoundState,
+    speed_alert::SpeedAlertState,
+    storage::{CardStatus, StorageState},
+    sunset::SunsetState,
+    trail::TrailState,
+    wifi::{UploadState, UploadStatus},
+};
 
 pub struct MainState {
     pub selected: usize,
@@ -39,10 +67,36 @@ impl QrState {
     }
 }
 
+pub struct WeatherState {
+    pub temp: f32,
+    pub condition: String,
+    pub wind: f32,
+}
+
 pub struct InfoState {
     pub coords: Option<Coordinates>,
     pub closest_step: Option<Coordinates>,
+    /// Last time the closest step was looked for in the route
+    pub closest_at: Option<Instant>,
+    /// Steps of the route sent by the phone
+    pub route: Vec<Coordinates>,
+    /// Index in the route of the closest step, when it is one of its steps
+    pub step: Option<usize>,
+    /// Steps of the route behind the rider
+    pub reached: usize,
     pub time: Option<DateTime<Utc>>,
+    pub weather: Option<WeatherState>,
+    pub altitude: Option<f64>,
+    pub speed: Option<f64>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    /// Beats per minute from the paired heart rate sensor
+    pub heart_rate: Option<u8>,
+    /// From the paired speed and cadence sensor, in rpm and km/h
+    pub cadence: Option<u16>,
+    pub wheel_speed: Option<f32>,
+    /// Position of the other bike of a group ride, relayed by the stick
+    pub peer: Option<Coordinates>,
 }
 
 impl InfoState {
@@ -50,8 +104,402 @@ impl InfoState {
         Self {
             coords: None,
             closest_step: None,
+            closest_at: None,
+            route: vec![],
+            step: None,
+            reached: 0,
             time: None,
+            weather: None,
+            altitude: None,
+            speed: None,
+            temperature: None,
+            humidity: None,
+            heart_rate: None,
+            cadence: None,
+            wheel_speed: None,
+            peer: None,
+        }
+    }
+
+    /// Step told by the phone, or the next one of the route
+    pub fn set_closest_step(&mut self, step: Coordinates) {
+        let index = find_step(&self.route, &step);
+        // The phone may still find closest a step of the route the rider just reached
+        if index.map_or(false, |index| index < self.reached) {
+            return;
+        }
+        self.step = index;
+        self.closest_step = Some(step);
+    }
+
+    /// Route sent by the phone, its first step being the closest one
+    pub fn set_route(&mut self, route: Vec<Coordinates>) {
+        self.route = route;
+        self.step = None;
+        self.reached = 0;
+        self.closest_step = None;
+        if let Some(first) = self.route.first() {
+            self.set_closest_step(Coordinates::new(first.lat, first.long));
+        }
+    }
+
+    /// Route ridden the other way, for the way back, from its first step again
+    pub fn reverse_route(&mut self) {
+        let mut route = std::mem::take(&mut self.route);
+        route.reverse();
+        self.set_route(route);
+    }
+
+    /// Step of the route picked by the rider, the ones before it being told reached
+    pub fn set_target(&mut self, index: usize) {
+        if let Some(step) = self.route.get(index) {
+            self.closest_step = Some(Coordinates::new(step.lat, step.long));
+            self.step = Some(index);
+            self.reached = index;
+        }
+    }
+
+    /// Removes a step of the route, the next one being targeted when it was the target
+    pub fn remove_step(&mut self, index: usize) {
+        if index >= self.route.len() {
+            return;
+        }
+        self.route.remove(index);
+        if index < self.reached {
+            self.reached -= 1;
+        }
+        let removed_target = self.step == Some(index);
+        self.step = match self.step {
+            Some(step) if step > index => Some(step - 1),
+            Some(step) if step == index && step < self.route.len() => Some(step),
+            Some(step) if step == index => None,
+            step => step,
+        };
+        if let Some(step) = self.step {
+            let step = &self.route[step];
+            self.closest_step = Some(Coordinates::new(step.lat, step.long));
+        } else if removed_target {
+            self.closest_step = None;
+        }
+    }
+
+    pub fn get_telemetry(&self) -> Telemetry {
+        Telemetry {
+            coords: self
+                .coords
+                .as_ref()
+                .map(|coords| Coordinates::new(coords.lat, coords.long)),
+            altitude: self.altitude,
+            speed: self.speed,
+            temperature: self.temperature,
+            humidity: self.humidity,
+        }
+    }
+}
+
+/// Speed under which the bike is told stopped, the fixes wandering around it
+const TRIP_MOVING_SPEED_KMH: f64 = 3.0;
+/// Time spent under that speed before the trip is paused, a slow turn not pausing it
+const AUTO_PAUSE_DELAY: Duration = Duration::from_secs(5);
+/// Weight of a new altitude in the smoothed one, the GPS altitude jumping by meters
+const ALTITUDE_SMOOTHING: f64 = 0.2;
+/// Change of the smoothed altitude counted as a climb or a descent, in meters
+const ELEVATION_STEP_M: f64 = 3.0;
+/// Distance between two points of the elevation profile at first, in kilometers
+const PROFILE_SPACING_KM: f64 = 0.1;
+/// Points of the elevation profile, every other one being dropped beyond
+const MAX_PROFILE_POINTS: usize = 200;
+/// Distance between two altitudes the gradient is computed from, in kilometers
+const GRADIENT_SPACING_KM: f64 = 0.02;
+/// Distance the gradient is measured over, shorter ones making it jump with the GPS
+const GRADIENT_WINDOW_KM: f64 = 0.2;
+/// Rider weights of the options screen in kg, the energy spent growing with them
+pub const RIDER_WEIGHTS: [u8; 8] = [50, 60, 70, 80, 90, 100, 110, 120];
+/// Weight of the bike and the bags, added to the one of the rider
+const BIKE_WEIGHT_KG: f64 = 15.0;
+/// Rolling resistance of road tyres
+const ROLLING_COEFFICIENT: f64 = 0.006;
+/// Drag area of a rider sitting upright in square meters, and the air density at sea level
+const DRAG_AREA_M2: f64 = 0.5;
+const AIR_DENSITY: f64 = 1.2;
+/// Part of the energy burnt by the body that turns the pedals
+const MUSCLE_EFFICIENCY: f64 = 0.24;
+const JOULES_PER_KCAL: f64 = 4184.0;
+/// Time between two fixes beyond which the GPS is told lost, the distance in between
+/// being left out
+const TRIP_MAX_FIX_GAP: Duration = Duration::from_secs(10);
+
+/// Part of the trip between two laps marked by the rider
+#[derive(Clone, Copy)]
+pub struct Lap {
+    /// In kilometers
+    pub distance: f64,
+    pub moving_time: Duration,
+}
+
+/// Trip computer, fed by the RMC sentences since the boot or the last reset
+pub struct TripState {
+    /// In kilometers
+    pub distance: f64,
+    pub moving_time: Duration,
+    /// In km/h
+    pub max_speed: f64,
+    /// Kilometers ridden since the first boot, kept in the NVS
+    pub odometer: f64,
+    /// Stopped for a while, the distance and the time not adding up until the bike moves
+    /// again
+    pub paused: bool,
+    /// In meters
+    pub climb: f64,
+    pub descent: f64,
+    /// Smoothed altitudes along the trip, in meters
+    pub profile: Vec<f32>,
+    /// Slope of the last meters ridden, in percent, unknown until they were
+    pub gradient: Option<f64>,
+    /// Energy spent by the rider, in kcal
+    pub calories: f64,
+    /// Distance of the trip and smoothed altitude over the window of the gradient
+    gradient_samples: VecDeque<(f64, f64)>,
+    profile_spacing: f64,
+    /// Distance of the trip at the last point of the profile
+    profiled_at: Option<f64>,
+    smoothed_altitude: Option<f64>,
+    /// Smoothed altitude the climb and the descent were last counted at
+    counted_altitude: Option<f64>,
+    /// Laps marked, the current one not being in it
+    pub laps: Vec<Lap>,
+    /// Distance and moving time of the trip when the current lap started
+    lap_start: Lap,
+    slow_since: Option<Instant>,
+    last_fix: Option<(Coordinates, Instant)>,
+}
+
+impl TripState {
+    pub fn new(odometer: f64) -> Self {
+        Self {
+            distance: 0.0,
+            moving_time: Duration::ZERO,
+            max_speed: 0.0,
+            odometer,
+            paused: false,
+            climb: 0.0,
+            descent: 0.0,
+            profile: vec![],
+            gradient: None,
+            calories: 0.0,
+            gradient_samples: VecDeque::new(),
+            profile_spacing: PROFILE_SPACING_KM,
+            profiled_at: None,
+            smoothed_altitude: None,
+            counted_altitude: None,
+            laps: vec![],
+            lap_start: Lap {
+                distance: 0.0,
+                moving_time: Duration::ZERO,
+            },
+            slow_since: None,
+            last_fix: None,
+        }
+    }
+
+    /// Adds the way from the previous fix, unless the trip is paused. The weight of the
+    /// rider is in kg.
+    pub fn update(&mut self, coords: Coordinates, speed: f64, weight: u8) {
+        let now = Instant::now();
+        if speed >= TRIP_MOVING_SPEED_KMH {
+            self.slow_since = None;
+            self.paused = false;
+        } else {
+            let slow_since = *self.slow_since.get_or_insert(now);
+            self.paused = now.duration_since(slow_since) >= AUTO_PAUSE_DELAY;
+        }
+        if let Some((last, fixed_at)) = &self.last_fix {
+            let elapsed = now.duration_since(*fixed_at);
+            if self.paused == false && elapsed <= TRIP_MAX_FIX_GAP {
+                let distance = last.distance(&coords);
+                self.distance += distance;
+                self.odometer += distance;
+                self.moving_time += elapsed;
+                self.max_speed = self.max_speed.max(speed);
+                self.calories += self.get_power(speed, weight) * elapsed.as_secs_f64()
+                    / MUSCLE_EFFICIENCY
+                    / JOULES_PER_KCAL;
+            }
         }
+        self.last_fix = Some((coords, now));
+    }
+
+    /// Power at the pedals in W against the slope, the rolling and the air, none going
+    /// down a hill steep enough for the bike to roll alone
+    fn get_power(&self, speed: f64, weight: u8) -> f64 {
+        let speed = speed / 3.6;
+        let slope = self.gradient.unwrap_or(0.0) / 100.0;
+        let mass = weight as f64 + BIKE_WEIGHT_KG;
+        let climbing = mass * 9.81 * (slope + ROLLING_COEFFICIENT) * speed;
+        let drag = 0.5 * AIR_DENSITY * DRAG_AREA_M2 * speed.powi(3);
+        (climbing + drag).max(0.0)
+    }
+
+    /// Lap being ridden, from the last one marked
+    pub fn get_lap(&self) -> Lap {
+        Lap {
+            distance: self.distance - self.lap_start.distance,
+            moving_time: self.moving_time.saturating_sub(self.lap_start.moving_time),
+        }
+    }
+
+    /// Number of the lap being ridden, from 1
+    pub fn get_lap_number(&self) -> usize {
+        self.laps.len() + 1
+    }
+
+    /// Ends the current lap, returning it
+    pub fn mark_lap(&mut self) -> Lap {
+        let lap = self.get_lap();
+        self.laps.push(lap);
+        self.lap_start = Lap {
+            distance: self.distance,
+            moving_time: self.moving_time,
+        };
+        lap
+    }
+
+    /// Counts the climb and the descent from the altitude of a GGA sentence, and samples
+    /// the profile as the trip goes on
+    pub fn update_altitude(&mut self, altitude: f64) {
+        let smoothed = self.smoothed_altitude.map_or(altitude, |smoothed| {
+            smoothed + (altitude - smoothed) * ALTITUDE_SMOOTHING
+        });
+        self.smoothed_altitude = Some(smoothed);
+        let counted = *self.counted_altitude.get_or_insert(smoothed);
+        // Not while paused, the altitude wandering as much as the position
+        if self.paused == false && (smoothed - counted).abs() >= ELEVATION_STEP_M {
+            if smoothed > counted {
+                self.climb += smoothed - counted;
+            } else {
+                self.descent += counted - smoothed;
+            }
+            self.counted_altitude = Some(smoothed);
+        }
+
+        self.update_gradient(smoothed);
+
+        let due = self.profiled_at.map_or(true, |profiled_at| {
+            self.distance - profiled_at >= self.profile_spacing
+        });
+        if due {
+            self.profiled_at = Some(self.distance);
+            self.profile.push(smoothed as f32);
+            if self.profile.len() > MAX_PROFILE_POINTS {
+                let mut index = 0;
+                self.profile.retain(|_| {
+                    index += 1;
+                    (index - 1) % 2 == 0
+                });
+                self.profile_spacing *= 2.0;
+            }
+        }
+    }
+
+    /// Slope from the oldest altitude of the window, the bike standing still keeping it
+    fn update_gradient(&mut self, smoothed: f64) {
+        let due = self
+            .gradient_samples
+            .back()
+            .is_none_or(|(distance, _)| self.distance - distance >= GRADIENT_SPACING_KM);
+        if due == false {
+            return;
+        }
+        self.gradient_samples.push_back((self.distance, smoothed));
+        while self
+            .gradient_samples
+            .get(1)
+            .is_some_and(|(distance, _)| self.distance - distance >= GRADIENT_WINDOW_KM)
+        {
+            self.gradient_samples.pop_front();
+        }
+        if let Some((distance, altitude)) = self.gradient_samples.front() {
+            let run = (self.distance - distance) * 1000.0;
+            if run >= GRADIENT_WINDOW_KM * 1000.0 {
+                self.gradient = Some((smoothed - altitude) / run * 100.0);
+            }
+        }
+    }
+
+    /// Lost fix, the way until the next one not being known
+    pub fn lost(&mut self) {
+        self.last_fix = None;
+    }
+
+    /// Average speed while moving, in km/h
+    pub fn get_average_speed(&self) -> Option<f64> {
+        Some(self.moving_time.as_secs_f64())
+            .filter(|seconds| *seconds > 0.0)
+            .map(|seconds| self.distance / seconds * 3600.0)
+    }
+
+    /// Starts a new trip, the odometer going on
+    pub fn reset(&mut self) {
+        *self = Self::new(self.odometer);
+    }
+}
+
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(5);
+/// Time left to the user to type the pairing passkey on the phone
+pub const PASSKEY_DURATION: Duration = Duration::from_secs(30);
+
+pub struct NotificationState {
+    title: String,
+    body: String,
+    received_at: Option<Instant>,
+    duration: Duration,
+}
+
+impl NotificationState {
+    pub fn new() -> Self {
+        Self {
+            title: String::new(),
+            body: String::new(),
+            received_at: None,
+            duration: NOTIFICATION_DURATION,
+        }
+    }
+
+    pub fn show(&mut self, title: String, body: String) {
+        self.show_for(title, body, NOTIFICATION_DURATION);
+    }
+
+    pub fn show_for(&mut self, title: String, body: String, duration: Duration) {
+        self.title = title;
+        self.body = body;
+        self.received_at = Some(Instant::now());
+        self.duration = duration;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.received_at
+            .map_or(false, |received_at| received_at.elapsed() < self.duration)
+    }
+
+    pub fn get_text(&self) -> String {
+        let body: String = self.body.chars().take(48).collect();
+        format!("{}\n{}", self.title, body)
+    }
+}
+
+const MAX_LOGS: usize = 10;
+const MAX_BULK_LEN: usize = 16 * 1024;
+
+pub struct LogState {
+    pub lines: VecDeque<(LogLevel, String)>,
+}
+
+impl LogState {
+    pub fn push(&mut self, level: LogLevel, text: String) {
+        if self.lines.len() == MAX_LOGS {
+            self.lines.pop_front();
+        }
+        self.lines.push_back((level, text));
     }
 }
 
@@ -59,11 +507,170 @@ pub struct OptionsState {
     pub selected: usize,
     pub max_selected: usize,
     pub fill_on_click: bool,
+    /// Level of the backlight, set by the phone
+    pub brightness: u8,
+    /// Raw GPS sentences written to the card by the GPS task
+    pub nmea_log: bool,
+    /// Seconds without a button pushed before the screen is dimmed, 0 for never
+    pub screen_timeout: u8,
+    /// Distance to a step in meters under which it is reached, set by the phone
+    pub step_radius: u8,
+    /// GPS speed in km/h over which the rider is warned, 0 for never
+    pub speed_limit: u8,
+    /// Minutes before the sunset the rider is warned at, 0 for never
+    pub sunset_lead: u8,
+    /// In kg, for the energy spent
+    pub rider_weight: u8,
 }
 
+impl OptionsState {
+    pub fn get_settings(&self) -> Settings {
+        Settings {
+            fill_on_click: self.fill_on_click,
+            brightness: self.brightness,
+            nmea_log: self.nmea_log,
+            screen_timeout: self.screen_timeout,
+            step_radius: self.step_radius,
+            speed_limit: self.speed_limit,
+            sunset_lead: self.sunset_lead,
+            rider_weight: self.rider_weight,
+        }
+    }
+}
+
+/// Rows of the lists longer than the screen, scrolling under them
+pub const LIST_ROWS: usize = 7;
+/// Entries of the waypoints screen before the steps of the route
+pub const WAYPOINT_ACTIONS: usize = 5;
+
+/// Entry of a list longer than the screen, and the first one shown
+pub struct ScrollList {
+    pub selected: usize,
+    pub first: usize,
+}
+
+impl ScrollList {
+    pub fn new() -> Self {
+        Self {
+            selected: 0,
+            first: 0,
+        }
+    }
+
+    /// Selects the entry, scrolling the list so that it stays shown
+    pub fn select(&mut self, selected: usize) {
+        self.selected = selected;
+        if selected < self.first {
+            self.first = selected;
+        } else if selected >= self.first + LIST_ROWS {
+            self.first = selected + 1 - LIST_ROWS;
+        }
+    }
+}
+
+pub struct WaypointsState {
+    /// The steps come after "Retour", the action, "Effacer", "Itineraires" and "Inverser"
+    pub list: ScrollList,
+    /// A step pressed is removed rather than targeted
+    pub removing: bool,
+}
+
+impl WaypointsState {
+    pub fn get_max_selected(&self, steps: usize) -> usize {
+        WAYPOINT_ACTIONS - 1 + steps
+    }
+}
+
+/// Sensors listed on the sensors screen, the ones found later are ignored
+pub const MAX_SENSORS: usize = 4;
+
+pub struct SensorsState {
+    /// Entry of the list, after "Retour" and "Chercher"
+    pub selected: usize,
+    pub found: Vec<Sensor>,
+    pub scanning: bool,
+}
+
+impl SensorsState {
+    pub fn add(&mut self, sensor: Sensor) {
+        let known = self
+            .found
+            .iter()
+            .any(|found| found.address == sensor.address);
+        if known == false && self.found.len() < MAX_SENSORS {
+            self.found.push(sensor);
+        }
+    }
+
+    pub fn get_max_selected(&self) -> usize {
+        1 + self.found.len()
+    }
+}
+
+const RSSI_PERIOD: Duration = Duration::from_secs(5);
+
 pub struct ConnectionState {
     pub ble: BleState,
     pub request_sent: bool,
+    pub rssi: Option<i8>,
+    rssi_requested_at: Option<Instant>,
+}
+
+impl ConnectionState {
+    pub fn must_get_rssi(&self) -> bool {
+        self.ble == BleState::Connected
+            && self
+                .rssi_requested_at
+                .map_or(true, |requested_at| requested_at.elapsed() > RSSI_PERIOD)
+    }
+
+    pub fn rssi_requested(&mut self) {
+        self.rssi_requested_at = Some(Instant::now());
+    }
+}
+
+/// Motion of the bike, told by the IMU
+pub struct MotionState {
+    pub moving: bool,
+    /// Angle from the position at boot, in degrees
+    pub tilt: Option<f32>,
+    /// Acceleration of the last shock, in g, and when it happened
+    pub shock: Option<(f32, Instant)>,
+}
+
+impl MotionState {
+    pub fn set(&mut self, event: MotionEvent) {
+        match event {
+            MotionEvent::Moving(moving) => self.moving = moving,
+            MotionEvent::Shock(accel) => {
+                // Told before the IMU tells the motion that comes with it
+                self.moving = true;
+                self.shock = Some((accel, Instant::now()));
+            }
+            MotionEvent::Tilt(tilt) => self.tilt = Some(tilt),
+            // Followed by the heading
+            MotionEvent::Turn(_) => {}
+        }
+    }
+}
+
+/// Devices of Port A, unknown until their task tried them
+pub struct DevicesState {
+    pub stick: Option<bool>,
+    pub climate: Option<bool>,
+    pub imu: Option<bool>,
+    /// Times the bus was freed since the boot
+    pub recoveries: u32,
+}
+
+impl DevicesState {
+    pub fn set(&mut self, device: Device, present: bool) {
+        match device {
+            Device::Stick => self.stick = Some(present),
+            Device::Climate => self.climate = Some(present),
+            Device::Imu => self.imu = Some(present),
+        }
+    }
 }
 
 pub struct State {
@@ -72,15 +679,46 @@ pub struct State {
     pub current_screen: ScreenId,
     pub infos: InfoState,
     pub options: OptionsState,
+    pub sensors: SensorsState,
+    pub waypoints: WaypointsState,
+    pub routes: RoutesState,
     pub connection: ConnectionState,
+    pub notification: NotificationState,
+    pub logs: LogState,
+    pub bulk: BulkAssembler,
+    pub diagnostics: Diagnostics,
+    pub gestures: Gestures,
+    pub devices: DevicesState,
+    pub motion: MotionState,
+    pub heading: HeadingState,
+    /// Countdown after a crash
+    pub sos: SosState,
+    pub storage: StorageState,
+    pub recording: RecordingState,
+    pub upload: UploadState,
+    pub firmware: UpdateStatus,
+    /// Unknown until the power IC is read
+    pub battery: Option<Battery>,
+    pub backlight: BacklightState,
+    pub sound: SoundState,
+    pub speed_alert: SpeedAlertState,
+    pub sunset: SunsetState,
+    pub trail: TrailState,
+    pub trip: TripState,
+    pub home: HomeState,
+    pub checkpoint: CheckpointState,
+    /// Why the last run that did not end well ended
+    pub last_crash: Option<String>,
 }
 
 impl State {
-    pub fn new() -> Self {
+    /// State of a boot, with the settings, the odometer and the home kept from the previous
+    /// ones
+    pub fn new(settings: Settings, odometer: f64, home: Option<Coordinates>) -> Self {
         Self {
             main: MainState {
                 selected: 0,
-                max_selected: 2,
+                max_selected: 6,
             },
             qr: QrState {
                 mac: String::new(),
@@ -91,13 +729,73 @@ impl State {
             infos: InfoState::new(),
             options: OptionsState {
                 selected: 0,
-                max_selected: 1,
-                fill_on_click: false,
+                max_selected: 10,
+                fill_on_click: settings.fill_on_click,
+                brightness: settings.brightness,
+                nmea_log: settings.nmea_log,
+                screen_timeout: settings.screen_timeout,
+                step_radius: settings.step_radius,
+                speed_limit: settings.speed_limit,
+                sunset_lead: settings.sunset_lead,
+                rider_weight: settings.rider_weight,
+            },
+            sensors: SensorsState {
+                selected: 0,
+                found: vec![],
+                scanning: false,
             },
+            waypoints: WaypointsState {
+                list: ScrollList::new(),
+                removing: false,
+            },
+            routes: RoutesState::new(),
             connection: ConnectionState {
                 ble: BleState::NONE,
                 request_sent: false,
+                rssi: None,
+                rssi_requested_at: None,
+            },
+            notification: NotificationState::new(),
+            logs: LogState {
+                lines: VecDeque::new(),
+            },
+            bulk: BulkAssembler::new(MAX_BULK_LEN),
+            diagnostics: Diagnostics::default(),
+            gestures: Gestures::default(),
+            devices: DevicesState {
+                stick: None,
+                climate: None,
+                imu: None,
+                recoveries: 0,
+            },
+            motion: MotionState {
+                moving: false,
+                tilt: None,
+                shock: None,
+            },
+            heading: HeadingState::new(),
+            sos: SosState::new(),
+            storage: StorageState {
+                status: CardStatus::Missing,
+                wanted: true,
+            },
+            recording: RecordingState::new(),
+            upload: UploadState {
+                status: UploadStatus::Idle,
+                asked_at: None,
+                tracked_at: None,
             },
+            firmware: UpdateStatus::Idle,
+            battery: None,
+            backlight: BacklightState::new(),
+            sound: SoundState::new(),
+            speed_alert: SpeedAlertState::new(),
+            sunset: SunsetState::new(),
+            trail: TrailState::new(),
+            trip: TripState::new(odometer),
+            home: HomeState::new(home),
+            checkpoint: CheckpointState::new(),
+            last_crash: None,
         }
     }
 }
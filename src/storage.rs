@@ -0,0 +1,175 @@
+use std::{
+    ffi::{c_char, CString},
+    fs::{File, OpenOptions},
+    io, ptr,
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::{
+    esp, esp_vfs_fat_mount_config_t, esp_vfs_fat_sdcard_unmount, esp_vfs_fat_sdspi_mount,
+    f_getfree, sdmmc_card_t, sdmmc_host_t, sdmmc_host_t__bindgen_ty_1, sdspi_device_config_t,
+    sdspi_host_do_transaction, sdspi_host_init, sdspi_host_io_int_enable, sdspi_host_io_int_wait,
+    sdspi_host_remove_device, sdspi_host_set_card_clk, spi_host_device_t_SPI3_HOST, FATFS,
+    SDMMC_FREQ_DEFAULT, SDMMC_HOST_FLAG_DEINIT_ARG, SDMMC_HOST_FLAG_SPI,
+};
+
+pub const MOUNT_POINT: &str = "/sd";
+/// Bus of the TF slot, the one of the screen set up by the m5-go crate
+const SD_SPI_HOST: u32 = spi_host_device_t_SPI3_HOST;
+const SD_CS_PIN: i32 = 4;
+const MAX_OPEN_FILES: i32 = 4;
+/// Size of the sectors of the cards, FatFS using no other
+const SECTOR_SIZE: u64 = 512;
+/// Delay between two checks of the card, and two attempts at mounting it when it is missing
+const CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardStatus {
+    /// Ejected from the diagnostics screen
+    Unmounted,
+    Missing,
+    /// Bytes free, and in all
+    Mounted {
+        free: u64,
+        total: u64,
+    },
+}
+
+/// TF card seen by the UI
+pub struct StorageState {
+    pub status: CardStatus,
+    /// Mounted, or ejected from the diagnostics screen so that it can be removed
+    pub wanted: bool,
+}
+
+/// TF card, mounted at `MOUNT_POINT` where the other modules use it through `std::fs`
+pub struct Storage {
+    mount_point: CString,
+    card: Option<*mut sdmmc_card_t>,
+    /// Asked by the UI at the last sync
+    wanted: bool,
+    checked_at: Option<Instant>,
+}
+
+impl Storage {
+    pub fn new() -> Self {
+        Self {
+            mount_point: CString::new(MOUNT_POINT).unwrap(),
+            card: None,
+            wanted: false,
+            checked_at: None,
+        }
+    }
+
+    fn mount(&mut self) -> anyhow::Result<()> {
+        let host = sdmmc_host_t {
+            flags: SDMMC_HOST_FLAG_SPI | SDMMC_HOST_FLAG_DEINIT_ARG,
+            slot: SD_SPI_HOST as i32,
+            max_freq_khz: SDMMC_FREQ_DEFAULT as i32,
+            io_voltage: 3.3,
+            init: Some(sdspi_host_init),
+            set_card_clk: Some(sdspi_host_set_card_clk),
+            do_transaction: Some(sdspi_host_do_transaction),
+            __bindgen_anon_1: sdmmc_host_t__bindgen_ty_1 {
+                deinit_p: Some(sdspi_host_remove_device),
+            },
+            io_int_enable: Some(sdspi_host_io_int_enable),
+            io_int_wait: Some(sdspi_host_io_int_wait),
+            ..Default::default()
+        };
+        let device = sdspi_device_config_t {
+            host_id: SD_SPI_HOST,
+            gpio_cs: SD_CS_PIN,
+            gpio_cd: -1,
+            gpio_wp: -1,
+            gpio_int: -1,
+        };
+        let mount = esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: false,
+            max_files: MAX_OPEN_FILES,
+            allocation_unit_size: 16 * 1024,
+            ..Default::default()
+        };
+        let mut card = ptr::null_mut();
+        esp!(unsafe {
+            esp_vfs_fat_sdspi_mount(self.mount_point.as_ptr(), &host, &device, &mount, &mut card)
+        })?;
+        self.card = Some(card);
+        Ok(())
+    }
+
+    fn unmount(&mut self) {
+        if let Some(card) = self.card.take() {
+            esp!(unsafe { esp_vfs_fat_sdcard_unmount(self.mount_point.as_ptr(), card) })
+                .ok()
+                .or_else(|| {
+                    println!("Unmounting the card failed");
+                    None
+                });
+        }
+    }
+
+    /// Free and total bytes of the mounted card, none when it cannot be read
+    fn get_space(&self) -> Option<(u64, u64)> {
+        // First drive of FatFS, the card being the only one mounted
+        let drive = b"0:\0".as_ptr() as *const c_char;
+        let mut free_clusters = 0;
+        let mut fs: *mut FATFS = ptr::null_mut();
+        if unsafe { f_getfree(drive, &mut free_clusters, &mut fs) } != 0 || fs.is_null() {
+            return None;
+        }
+        let fs = unsafe { &*fs };
+        let cluster_size = fs.csize as u64 * SECTOR_SIZE;
+        Some((
+            free_clusters as u64 * cluster_size,
+            (fs.n_fatent as u64 - 2) * cluster_size,
+        ))
+    }
+
+    /// Mounts or ejects the card as soon as asked, and checks it now and then: a card
+    /// pulled out is unmounted, and mounted again once it is back
+    pub fn sync(&mut self, storage: &mut StorageState) {
+        let asked = storage.wanted != self.wanted;
+        let due = self
+            .checked_at
+            .map_or(true, |at| at.elapsed() >= CHECK_PERIOD);
+        if asked == false && due == false {
+            return;
+        }
+        self.wanted = storage.wanted;
+        self.checked_at = Some(Instant::now());
+
+        if storage.wanted == false {
+            self.unmount();
+        } else if self.card.is_none() {
+            self.mount().ok().or_else(|| {
+                println!("No card to mount");
+                None
+            });
+        }
+
+        let space = self.card.and_then(|_| self.get_space());
+        if self.card.is_some() && space.is_none() {
+            self.unmount();
+        }
+        storage.status = match space {
+            Some((free, total)) => CardStatus::Mounted { free, total },
+            None if storage.wanted => CardStatus::Missing,
+            None => CardStatus::Unmounted,
+        };
+    }
+}
+
+/// Path of a file of the card
+pub fn get_path(name: &str) -> String {
+    format!("{}/{}", MOUNT_POINT, name)
+}
+
+/// Opens a file of the card to write at its end, creating it. Fails when no card is
+/// mounted.
+pub fn open_append(name: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_path(name))
+}
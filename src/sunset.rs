@@ -0,0 +1,92 @@
+use std::f64::consts::PI;
+
+use nmea_parser::chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use shared::Coordinates;
+
+use crate::{speaker::SUNSET_BEEP, state::State};
+
+/// Minutes of warning before the sunset of the options screen, 0 never warning
+pub const SUNSET_LEADS: [u8; 5] = [0, 15, 30, 45, 60];
+/// Zenith of the sun at the sunset, its upper edge touching the horizon through the
+/// refraction of the air
+const SUNSET_ZENITH_DEG: f64 = 90.833;
+
+/// Day of the sunset the rider was warned of, not warned again until the next one
+pub struct SunsetState {
+    warned_for: Option<NaiveDate>,
+}
+
+impl SunsetState {
+    pub fn new() -> Self {
+        Self { warned_for: None }
+    }
+}
+
+/// Sunset of the day at the position, from the equations of the NOAA, none during the
+/// polar day or night
+pub fn get_sunset(date: NaiveDate, coords: &Coordinates) -> Option<DateTime<Utc>> {
+    let day = date.ordinal0() as f64;
+    // Fractional year at noon, in radians
+    let year = 2.0 * PI / 365.0 * day;
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * year.cos()
+            - 0.032077 * year.sin()
+            - 0.014615 * (2.0 * year).cos()
+            - 0.040849 * (2.0 * year).sin());
+    let declination = 0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin()
+        - 0.006758 * (2.0 * year).cos()
+        + 0.000907 * (2.0 * year).sin()
+        - 0.002697 * (3.0 * year).cos()
+        + 0.00148 * (3.0 * year).sin();
+    let latitude = coords.lat.to_radians();
+    let cos_hour_angle = SUNSET_ZENITH_DEG.to_radians().cos()
+        / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if cos_hour_angle.abs() > 1.0 {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let minutes = 720.0 - 4.0 * (coords.long - hour_angle) - equation_of_time;
+    let midnight = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    Some(midnight + Duration::seconds((minutes * 60.0) as i64))
+}
+
+/// Sunset closest to the time, the one of the UTC day being past midnight in the far west
+fn get_closest_sunset(time: DateTime<Utc>, coords: &Coordinates) -> Option<DateTime<Utc>> {
+    let today = time.date_naive();
+    [today.pred_opt(), Some(today), today.succ_opt()]
+        .into_iter()
+        .flatten()
+        .filter_map(|date| get_sunset(date, coords))
+        .min_by_key(|sunset| (*sunset - time).num_seconds().abs())
+}
+
+/// Warns the rider once a day when the sunset comes within the lead of the options
+pub fn sync(state: &mut State) {
+    let lead = Duration::minutes(state.options.sunset_lead as i64);
+    let (time, coords) = match (state.infos.time, &state.infos.coords) {
+        (Some(time), Some(coords)) if lead > Duration::zero() && coords.is_valid() => {
+            (time, coords)
+        }
+        _ => return,
+    };
+    let sunset = match get_closest_sunset(time, coords) {
+        Some(sunset) => sunset,
+        None => return,
+    };
+    // The sunset moves a little with the position, the rider is warned once for its day
+    let day = sunset.date_naive();
+    if time < sunset - lead || state.sunset.warned_for == Some(day) {
+        return;
+    }
+
+    state.sunset.warned_for = Some(day);
+    let body = match (sunset - time).num_minutes() {
+        minutes if minutes > 0 => format!("Coucher du soleil dans {} min", minutes),
+        _ => String::from("Le soleil est couche"),
+    };
+    state
+        .notification
+        .show(String::from("Allumez vos feux"), body);
+    state.sound.play(SUNSET_BEEP);
+}
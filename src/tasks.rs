@@ -0,0 +1,390 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_graphics::{pixelcolor::Rgb565, prelude::DrawTarget};
+use esp_idf_hal::{delay::FreeRtos, i2c::I2cDriver, uart::UartDriver};
+use shared::{
+    registers::{DEFAULT_ADDRESS, UNIT_BLE},
+    Battery, Commands, LogLevel, Transport,
+};
+
+use crate::{
+    bus::{Bus, Device, Event},
+    crash,
+    framebuffer::{Frame, SharedScreen},
+    gps::{GpsEvent, GpsReader, NmeaLog},
+    imu::{self, MotionDetector},
+    link::{find_unit, recover_bus, I2cLink, REPLY_POLL_MS},
+    set_time,
+    wifi::{Uploader, WifiRequest},
+};
+
+/// Port A bus, shared by the stick, the temperature sensor, and the internal devices
+pub type SharedI2c = Arc<Mutex<I2cDriver<'static>>>;
+
+const SENSOR: u8 = 0x44;
+/// IP5306 power IC, on the internal bus wired to Port A
+const POWER_IC: u8 = 0x75;
+/// Registers of the IP5306 telling whether the charger is plugged in, and the battery level
+const POWER_IC_READ0: u8 = 0x70;
+const POWER_IC_READ4: u8 = 0x78;
+
+/// Failed reads after which the bus is recovered and the stick looked for again, it may
+/// have changed address. Until it answers, it is looked for again every as many failed
+/// reads, the bus being recovered the first time only.
+const MAX_STICK_FAILURES: u32 = 20;
+/// Failed reads of the temperature sensor after which the bus is recovered and the sensor
+/// started again
+const MAX_SENSOR_FAILURES: u32 = 3;
+/// Failed reads of the IMU after which it is started again
+const MAX_IMU_FAILURES: u32 = 10;
+/// Commands waiting for the stick, the oldest ones being dropped beyond
+const MAX_PENDING_COMMANDS: usize = 20;
+
+const TASK_STACK_SIZE: usize = 8 * 1024;
+/// Delay between two polls of the stick
+const BRIDGE_PERIOD_MS: u32 = 100;
+/// Delay between two polls of the stick once it is told gone
+const STICK_GONE_PERIOD_MS: u32 = 1000;
+/// Time without a GPS sentence after which the GPS is told silent
+const GPS_SILENCE: Duration = Duration::from_secs(1);
+/// Longest wait of the display task for a frame, before it feeds the watchdog
+const DISPLAY_IDLE: Duration = Duration::from_secs(1);
+/// Longest wait of the WiFi task for a request, before it feeds the watchdog
+const WIFI_IDLE: Duration = Duration::from_secs(1);
+/// Delay between two reads of the temperature sensor
+const SENSOR_PERIOD_MS: u32 = 2000;
+/// Delay between two reads of the IMU, a shock lasting a few of them
+const IMU_PERIOD_MS: u32 = 20;
+
+fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name(name.to_string())
+        .stack_size(TASK_STACK_SIZE)
+        .spawn(move || {
+            // Each task feeds the watchdog in its loop
+            crash::watch_task();
+            f()
+        })?;
+    Ok(())
+}
+
+/// Reads the GPS sentences as they come, telling when none did for `GPS_SILENCE`. They are
+/// also written to the card as they are while `nmea_log` is set.
+pub fn spawn_gps(
+    uart: UartDriver<'static>,
+    bus: Bus,
+    nmea_log: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    spawn("gps", move || {
+        let mut reader = GpsReader::new(uart);
+        let mut log = NmeaLog::new();
+        let mut line_read = Instant::now();
+        loop {
+            crash::feed_watchdog();
+            reader.fill();
+            let mut events = vec![];
+            while let Some(line) = reader.next_line() {
+                line_read = Instant::now();
+                log.write(&line, nmea_log.load(Ordering::Relaxed));
+                if let Some(message) = reader.parse(&line) {
+                    events.push(GpsEvent::Sentence(message));
+                }
+            }
+            if line_read.elapsed() > GPS_SILENCE {
+                line_read = Instant::now();
+                events.push(GpsEvent::Silent);
+            }
+            for event in events {
+                if bus.publish(Event::Gps(event)).is_none() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Polls the stick for its commands and sends it the ones of the bus
+pub fn spawn_bridge(i2c: SharedI2c, commands: Receiver<Commands>, bus: Bus) -> anyhow::Result<()> {
+    spawn("bridge", move || {
+        // An older stick does not answer the scan, it is on the default address
+        let mut stick = find_stick(&i2c);
+        let mut stick_failures = 0;
+        let mut present = None;
+        let mut pending = VecDeque::new();
+
+        loop {
+            crash::feed_watchdog();
+            let received = i2c
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Port A poisoned"))
+                .and_then(|mut driver| I2cLink::new(&mut driver, stick).receive());
+            stick_failures = if received.is_err() {
+                stick_failures + 1
+            } else {
+                0
+            };
+            if received.is_ok() && present != Some(true) {
+                present = Some(true);
+                bus.publish(Event::Presence(Device::Stick, true));
+            }
+            if stick_failures == MAX_STICK_FAILURES {
+                // Told gone once it failed for a while only, a read fails now and then
+                if present != Some(false) {
+                    present = Some(false);
+                    bus.publish(Event::Presence(Device::Stick, false));
+                }
+                // The bus is recovered once, the stick being unplugged when it still fails
+                // after
+                recover(&i2c, &bus);
+            }
+            if stick_failures >= MAX_STICK_FAILURES && stick_failures % MAX_STICK_FAILURES == 0 {
+                stick = find_stick(&i2c);
+            }
+
+            if let Ok(Some((command, timestamp))) = received {
+                match command {
+                    Commands::NONE => {}
+                    Commands::Log { level, ref text } => {
+                        println!("[stick] {:?}: {}", level, text)
+                    }
+                    Commands::SetTime(unix_ms) => {
+                        // The stick stamped it when loading the reply, read at the next poll
+                        set_time(unix_ms + REPLY_POLL_MS as u64);
+                    }
+                    _ => println!("received command : {:?}", command),
+                };
+                if bus.publish(Event::Command(command, timestamp)).is_none() {
+                    return;
+                }
+            }
+
+            pending.extend(commands.try_iter());
+            while pending.len() > MAX_PENDING_COMMANDS {
+                if let Some(command) = pending.pop_front() {
+                    println!("Dropping command: {:?}", command);
+                }
+            }
+            if let Some(command) = pending.pop_front() {
+                println!("sending command: {:?}", command);
+                let sent = i2c
+                    .lock()
+                    .ok()
+                    .and_then(|mut driver| I2cLink::new(&mut driver, stick).send(&command).ok());
+                if sent.is_none() {
+                    // Sent again after the ones queued since
+                    println!("Failed to send command");
+                    pending.push_back(command);
+                }
+            }
+            // Polled less often while it is gone, each failed read holding the shared bus
+            let period = if stick_failures >= MAX_STICK_FAILURES {
+                STICK_GONE_PERIOD_MS
+            } else {
+                BRIDGE_PERIOD_MS
+            };
+            FreeRtos::delay_ms(period);
+        }
+    })
+}
+
+/// Sends the areas composed by the UI task to the panel, giving their buffers back. The
+/// UI task goes on with the next area while this one is blocked writing to the SPI bus.
+pub fn spawn_display(
+    screen: SharedScreen,
+    frames: Receiver<Frame>,
+    spares: Sender<Vec<Rgb565>>,
+) -> anyhow::Result<()> {
+    spawn("display", move || loop {
+        crash::feed_watchdog();
+        let (area, pixels) = match frames.recv_timeout(DISPLAY_IDLE) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        screen
+            .lock()
+            .ok()
+            .and_then(|mut driver| driver.fill_contiguous(&area, pixels.iter().copied()).ok())
+            .or_else(|| {
+                println!("Flushing the buffer failed");
+                None
+            });
+        if spares.send(pixels).is_err() {
+            return;
+        }
+    })
+}
+
+/// Reads the temperature and humidity sensor of Port A, and the battery level
+pub fn spawn_sensors(i2c: SharedI2c, bus: Bus) -> anyhow::Result<()> {
+    spawn("sensors", move || {
+        if start_sensor(&i2c, &bus) == false {
+            bus.send_log(LogLevel::Warn, "Temperature sensor not found");
+        }
+        let mut failures = 0;
+        let mut battery = None;
+        loop {
+            crash::feed_watchdog();
+            if let Some(read) = read_battery(&i2c).filter(|read| Some(*read) != battery) {
+                battery = Some(read);
+                bus.publish(Event::Battery(read));
+            }
+            let mut sensor_buffer = [0u8; 6];
+            let read = i2c
+                .lock()
+                .ok()
+                .and_then(|mut driver| driver.read(SENSOR, &mut sensor_buffer, 50).ok());
+            if read.is_none() {
+                failures += 1;
+                // The bus is recovered once, the sensor being unplugged when it still
+                // fails after
+                if failures == MAX_SENSOR_FAILURES {
+                    recover(&i2c, &bus);
+                }
+                if failures % MAX_SENSOR_FAILURES == 0 {
+                    start_sensor(&i2c, &bus);
+                }
+            } else {
+                failures = 0;
+                let data = sensor_buffer
+                    .to_vec()
+                    .iter_mut()
+                    .map(|i| f32::from(*i))
+                    .collect::<heapless::Vec<f32, 6>>();
+
+                let c = ((((data[0] * 256.0) + data[1]) * 175.) / 65535.0) - 45.;
+                let h = (((data[3] * 256.0) + data[4]) * 100.) / 65535.0;
+                if bus.publish(Event::Climate(c, h)).is_none() {
+                    return;
+                }
+            }
+            FreeRtos::delay_ms(SENSOR_PERIOD_MS);
+        }
+    })
+}
+
+/// Reads the IMU, telling the motion of the bike, its shocks and its tilt
+pub fn spawn_imu(i2c: SharedI2c, bus: Bus) -> anyhow::Result<()> {
+    spawn("imu", move || {
+        if start_imu(&i2c, &bus) == false {
+            bus.send_log(LogLevel::Warn, "IMU not found");
+        }
+        let mut detector = MotionDetector::new();
+        let mut failures = 0;
+        loop {
+            crash::feed_watchdog();
+            let sample = i2c
+                .lock()
+                .ok()
+                .and_then(|mut driver| imu::read(&mut driver));
+            if let Some(sample) = sample {
+                failures = 0;
+                for event in detector.update(&sample) {
+                    if bus.publish(Event::Motion(event)).is_none() {
+                        return;
+                    }
+                }
+            } else {
+                failures += 1;
+                // Port A is recovered by the tasks of its devices, the IMU being on it too
+                if failures % MAX_IMU_FAILURES == 0 {
+                    start_imu(&i2c, &bus);
+                }
+            }
+            FreeRtos::delay_ms(IMU_PERIOD_MS);
+        }
+    })
+}
+
+/// Handles the WiFi requests of the other tasks, an upload taking as long as it takes
+pub fn spawn_wifi(
+    mut uploader: Uploader,
+    requests: Receiver<WifiRequest>,
+    bus: Bus,
+) -> anyhow::Result<()> {
+    spawn("wifi", move || loop {
+        crash::feed_watchdog();
+        match requests.recv_timeout(WIFI_IDLE) {
+            Ok(request) => uploader.handle(request, &bus),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    })
+}
+
+/// Starts the periodic measures of the temperature sensor, telling whether it answered
+fn start_sensor(i2c: &SharedI2c, bus: &Bus) -> bool {
+    let started = i2c
+        .lock()
+        .ok()
+        .and_then(|mut driver| driver.write(SENSOR, &[0x20, 0x32], 100).ok())
+        .is_some();
+    bus.publish(Event::Presence(Device::Climate, started));
+    started
+}
+
+fn start_imu(i2c: &SharedI2c, bus: &Bus) -> bool {
+    let started = i2c
+        .lock()
+        .ok()
+        .map_or(false, |mut driver| imu::start(&mut driver));
+    bus.publish(Event::Presence(Device::Imu, started));
+    started
+}
+
+/// Battery level and charger of the IP5306, the level being told by steps of 25 percent
+fn read_battery(i2c: &SharedI2c) -> Option<Battery> {
+    i2c.lock().ok().and_then(|mut driver| {
+        let mut status = [0u8];
+        driver
+            .write_read(POWER_IC, &[POWER_IC_READ0], &mut status, 50)
+            .ok()?;
+        let mut level = [0u8];
+        driver
+            .write_read(POWER_IC, &[POWER_IC_READ4], &mut level, 50)
+            .ok()?;
+        Some(Battery {
+            // The bits of the lit LEDs, cleared from the top as the battery empties
+            level: match level[0] & 0xf0 {
+                0x00 => 100,
+                0x80 => 75,
+                0xc0 => 50,
+                0xe0 => 25,
+                _ => 0,
+            },
+            charging: status[0] & 0x08 != 0,
+        })
+    })
+}
+
+/// Frees Port A, a device having failed too many times
+fn recover(i2c: &SharedI2c, bus: &Bus) {
+    println!("Recovering Port A");
+    i2c.lock()
+        .ok()
+        .and_then(|mut driver| recover_bus(&mut driver).ok())
+        .and_then(|_| bus.publish(Event::BusRecovered))
+        .or_else(|| {
+            println!("Port A recovery failed");
+            None
+        });
+}
+
+fn find_stick(i2c: &SharedI2c) -> u8 {
+    let stick = i2c
+        .lock()
+        .ok()
+        .and_then(|mut driver| find_unit(&mut driver, UNIT_BLE))
+        .unwrap_or(DEFAULT_ADDRESS);
+    println!("Stick on {:#04x}", stick);
+    stick
+}
@@ -0,0 +1,68 @@
+use shared::Coordinates;
+
+/// Distance between two points of the trail at first, in kilometers
+const TRAIL_SPACING_KM: f64 = 0.02;
+/// Points kept in RAM, every other one being dropped beyond so that the whole ride stays
+/// on the map
+const MAX_TRAIL_POINTS: usize = 500;
+/// Distance between the steps of the way back, in kilometers, so that they are not all
+/// within the radius of the next one
+const BACKTRACK_SPACING_KM: f64 = 0.2;
+
+/// Positions of the ride since the boot, thinned by distance
+pub struct TrailState {
+    pub points: Vec<Coordinates>,
+    /// Distance between two points, doubled each time the trail is thinned
+    spacing: f64,
+}
+
+impl TrailState {
+    pub fn new() -> Self {
+        Self {
+            points: vec![],
+            spacing: TRAIL_SPACING_KM,
+        }
+    }
+
+    /// Adds the position once it is far enough from the last point
+    pub fn push(&mut self, coords: &Coordinates) {
+        let far = self
+            .points
+            .last()
+            .map_or(true, |last| last.distance(coords) >= self.spacing);
+        if coords.is_valid() == false || far == false {
+            return;
+        }
+        self.points.push(Coordinates::new(coords.lat, coords.long));
+        if self.points.len() > MAX_TRAIL_POINTS {
+            // The last point is kept, the trail reaching the rider
+            let parity = (self.points.len() - 1) % 2;
+            let mut index = 0;
+            self.points.retain(|_| {
+                index += 1;
+                (index - 1) % 2 == parity
+            });
+            self.spacing *= 2.0;
+        }
+    }
+
+    /// Steps leading back to the start of the trail, from the last point
+    pub fn get_backtrack(&self) -> Vec<Coordinates> {
+        let mut steps = vec![];
+        let mut from = match self.points.len() {
+            0 => return steps,
+            len => len - 1,
+        };
+        for (index, point) in self.points.iter().enumerate().rev() {
+            if point.distance(&self.points[from]) >= BACKTRACK_SPACING_KM {
+                steps.push(Coordinates::new(point.lat, point.long));
+                from = index;
+            }
+        }
+        // The start of the trail ends the way back
+        if from != 0 {
+            steps.push(Coordinates::new(self.points[0].lat, self.points[0].long));
+        }
+        steps
+    }
+}
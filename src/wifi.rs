@@ -0,0 +1,530 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use embedded_svc::{
+    http::{client::Client, Status},
+    io::Write,
+    mqtt::client::QoS,
+    wifi::{ClientConfiguration, Configuration},
+};
+use esp_idf_hal::{delay::FreeRtos, modem::Modem};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::client::{Configuration as HttpConfiguration, EspHttpConnection},
+    mqtt::client::{EspMqttClient, MqttClientConfiguration},
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    wifi::EspWifi,
+};
+use esp_idf_sys::esp_restart;
+use shared::{Telemetry, Tracking};
+
+use crate::{
+    bus::{Bus, Event},
+    crash,
+    ota::{self, UpdateStatus},
+    recorder::{RIDES_DIR, SENT_DIR},
+    state::State,
+    storage,
+};
+
+const WIFI_NAMESPACE: &str = "wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+const URL_KEY: &str = "url";
+const FIRMWARE_URL_KEY: &str = "fw_url";
+const BROKER_KEY: &str = "mqtt_broker";
+const TOPIC_KEY: &str = "mqtt_topic";
+const MQTT_USERNAME_KEY: &str = "mqtt_user";
+const MQTT_PASSWORD_KEY: &str = "mqtt_pass";
+const TRACKING_RATE_KEY: &str = "mqtt_rate";
+/// Longest values of the network, the ones of the WiFi driver
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+const MAX_URL_LEN: usize = 256;
+const MAX_MQTT_LEN: usize = 64;
+
+/// Delay between two uploads asked while parked, the home network being in reach or not
+const UPLOAD_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// Speed under which the bike is told parked
+const PARKED_SPEED_KMH: f64 = 2.0;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const CONNECT_POLL_MS: u32 = 200;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of a ride read and posted at once
+const CHUNK_LEN: usize = 1024;
+/// Time the end of the update stays on the screen before the new firmware boots
+const RESTART_DELAY_MS: u32 = 3000;
+/// Delay between two positions handed to the WiFi task, which publishes them at the rate
+/// of the tracking
+const TRACK_PERIOD: Duration = Duration::from_secs(1);
+/// Delay between two attempts at joining the network for the tracking, a scan holding the
+/// WiFi task for seconds
+const TRACK_CONNECT_RETRY: Duration = Duration::from_secs(60);
+const MQTT_CLIENT_ID: &str = "byke-m5go";
+
+/// Home network, endpoint of the rides, URL of the firmware and broker of the tracking,
+/// given by the phone
+#[derive(Clone, Default)]
+pub struct WifiConfig {
+    pub ssid: String,
+    pub password: String,
+    pub url: String,
+    pub firmware_url: String,
+    pub tracking: Tracking,
+}
+
+impl WifiConfig {
+    fn is_tracking(&self) -> bool {
+        self.tracking.rate > 0
+            && self.tracking.broker.is_empty() == false
+            && self.ssid.is_empty() == false
+    }
+}
+
+/// What the other tasks ask the WiFi task
+pub enum WifiRequest {
+    Network {
+        ssid: String,
+        password: String,
+    },
+    Url(String),
+    FirmwareUrl(String),
+    Tracking(Tracking),
+    /// Latest telemetry, published when the tracking is due
+    Track(Telemetry),
+    /// Uploads the rides of the card when the home network is in reach
+    Upload,
+    /// Installs the firmware of the URL when it is not the running one, and restarts
+    Update,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadStatus {
+    Idle,
+    /// The phone gave no network or no endpoint yet
+    NotConfigured,
+    Connecting,
+    /// Home network out of reach
+    Away,
+    Uploading {
+        ride: String,
+        percent: u8,
+    },
+    /// Rides uploaded
+    Done(u32),
+    Failed(String),
+}
+
+/// Uploads seen by the UI
+pub struct UploadState {
+    pub status: UploadStatus,
+    pub asked_at: Option<Instant>,
+    /// Last telemetry handed to the WiFi task for the live tracking
+    pub tracked_at: Option<Instant>,
+}
+
+impl UploadState {
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self.status,
+            UploadStatus::Connecting | UploadStatus::Uploading { .. }
+        )
+    }
+}
+
+/// Asks for an upload, told through the upload events
+pub fn request_upload(bus: &Bus, state: &mut State) {
+    state.upload.asked_at = Some(Instant::now());
+    bus.send_wifi(WifiRequest::Upload).or_else(|| {
+        println!("WiFi task gone");
+        None
+    });
+}
+
+/// Asks for the firmware to be checked, told through the update events
+pub fn request_update(bus: &Bus, state: &mut State) {
+    state.firmware = UpdateStatus::Checking;
+    bus.send_wifi(WifiRequest::Update).or_else(|| {
+        println!("WiFi task gone");
+        None
+    });
+}
+
+/// Asks for an upload now and then while the bike is parked and no ride is being recorded,
+/// and hands the telemetry to the live tracking while the position is known
+pub fn sync(bus: &Bus, state: &mut State) {
+    let located = state.infos.coords.as_ref().map_or(false, |c| c.is_valid());
+    let track = state
+        .upload
+        .tracked_at
+        .map_or(true, |at| at.elapsed() >= TRACK_PERIOD);
+    if located && track {
+        state.upload.tracked_at = Some(Instant::now());
+        bus.send_wifi(WifiRequest::Track(state.infos.get_telemetry()));
+    }
+
+    // Not while the rider may still discard the ride just stopped
+    let parked = state.recording.wanted == false
+        && state.recording.summary.is_none()
+        && state.firmware.is_busy() == false
+        && state
+            .infos
+            .speed
+            .map_or(true, |speed| speed < PARKED_SPEED_KMH);
+    let due = state
+        .upload
+        .asked_at
+        .map_or(true, |at| at.elapsed() >= UPLOAD_PERIOD);
+    if parked && due && state.upload.is_busy() == false {
+        request_upload(bus, state);
+    }
+}
+
+/// Rides of the card not uploaded yet, by name, with their length
+fn list_rides() -> std::io::Result<Vec<(String, u64)>> {
+    let mut rides = fs::read_dir(storage::get_path(RIDES_DIR))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map_or(false, |kind| kind.is_file()))
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let len = entry.metadata().ok()?.len();
+            Some((name, len)).filter(|(name, _)| name.ends_with(".csv"))
+        })
+        .collect::<Vec<(String, u64)>>();
+    rides.sort();
+    Ok(rides)
+}
+
+/// WiFi of the M5Go, only up while the rides are uploaded or the firmware updated, or
+/// while the rides are tracked
+pub struct Uploader {
+    wifi: EspWifi<'static>,
+    nvs: EspNvs<NvsDefault>,
+    config: WifiConfig,
+    mqtt: Option<EspMqttClient>,
+    tracked_at: Option<Instant>,
+    joined_at: Option<Instant>,
+}
+
+impl Uploader {
+    pub fn new(
+        modem: Modem,
+        sysloop: EspSystemEventLoop,
+        partition: EspDefaultNvsPartition,
+    ) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(partition.clone(), WIFI_NAMESPACE, true)?;
+        let config = WifiConfig {
+            ssid: get_string(&nvs, SSID_KEY, MAX_SSID_LEN),
+            password: get_string(&nvs, PASSWORD_KEY, MAX_PASSWORD_LEN),
+            url: get_string(&nvs, URL_KEY, MAX_URL_LEN),
+            firmware_url: get_string(&nvs, FIRMWARE_URL_KEY, MAX_URL_LEN),
+            tracking: Tracking {
+                broker: get_string(&nvs, BROKER_KEY, MAX_URL_LEN),
+                topic: get_string(&nvs, TOPIC_KEY, MAX_MQTT_LEN),
+                username: get_string(&nvs, MQTT_USERNAME_KEY, MAX_MQTT_LEN),
+                password: get_string(&nvs, MQTT_PASSWORD_KEY, MAX_MQTT_LEN),
+                rate: get_rate(&nvs),
+            },
+        };
+        let wifi = EspWifi::new(modem, sysloop, Some(partition))?;
+        Ok(Self {
+            wifi,
+            nvs,
+            config,
+            mqtt: None,
+            tracked_at: None,
+            joined_at: None,
+        })
+    }
+
+    pub fn handle(&mut self, request: WifiRequest, bus: &Bus) {
+        match request {
+            WifiRequest::Network { ssid, password } => {
+                if ssid.len() > MAX_SSID_LEN || password.len() > MAX_PASSWORD_LEN {
+                    println!("WiFi network refused, too long");
+                    return;
+                }
+                set_string(&mut self.nvs, SSID_KEY, &ssid);
+                set_string(&mut self.nvs, PASSWORD_KEY, &password);
+                self.config.ssid = ssid;
+                self.config.password = password;
+            }
+            WifiRequest::Url(url) => {
+                if url.len() > MAX_URL_LEN {
+                    println!("Upload URL refused, too long");
+                    return;
+                }
+                set_string(&mut self.nvs, URL_KEY, &url);
+                self.config.url = url;
+            }
+            WifiRequest::FirmwareUrl(url) => {
+                if url.len() > MAX_URL_LEN {
+                    println!("Firmware URL refused, too long");
+                    return;
+                }
+                set_string(&mut self.nvs, FIRMWARE_URL_KEY, &url);
+                self.config.firmware_url = url;
+            }
+            WifiRequest::Tracking(tracking) => {
+                if tracking.broker.len() > MAX_URL_LEN
+                    || [&tracking.topic, &tracking.username, &tracking.password]
+                        .iter()
+                        .any(|value| value.len() > MAX_MQTT_LEN)
+                {
+                    println!("Tracking refused, too long");
+                    return;
+                }
+                set_string(&mut self.nvs, BROKER_KEY, &tracking.broker);
+                set_string(&mut self.nvs, TOPIC_KEY, &tracking.topic);
+                set_string(&mut self.nvs, MQTT_USERNAME_KEY, &tracking.username);
+                set_string(&mut self.nvs, MQTT_PASSWORD_KEY, &tracking.password);
+                self.nvs
+                    .set_raw(TRACKING_RATE_KEY, &tracking.rate.to_be_bytes())
+                    .ok()
+                    .or_else(|| {
+                        println!("Failed to save {}", TRACKING_RATE_KEY);
+                        None
+                    });
+                self.config.tracking = tracking;
+                // Connected again to the new broker, or turned off
+                self.mqtt = None;
+                self.disconnect();
+            }
+            WifiRequest::Track(telemetry) => {
+                if self.config.is_tracking() {
+                    if let Err(err) = self.track(&telemetry) {
+                        // Connected again to the broker at the next publication
+                        println!("Tracking failed: {}", err);
+                        self.mqtt = None;
+                    }
+                }
+            }
+            WifiRequest::Upload => {
+                let status = self.upload(bus).unwrap_or_else(|err| {
+                    println!("Upload failed: {}", err);
+                    UploadStatus::Failed(err.to_string())
+                });
+                self.disconnect();
+                bus.publish(Event::Upload(status));
+            }
+            WifiRequest::Update => {
+                let status = self.update(bus).unwrap_or_else(|err| {
+                    println!("Update failed: {}", err);
+                    UpdateStatus::Failed(err.to_string())
+                });
+                self.disconnect();
+                let restart = status == UpdateStatus::Restarting;
+                bus.publish(Event::Update(status));
+                if restart {
+                    FreeRtos::delay_ms(RESTART_DELAY_MS);
+                    unsafe { esp_restart() };
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, bus: &Bus) -> anyhow::Result<UpdateStatus> {
+        if self.config.ssid.is_empty() || self.config.firmware_url.is_empty() {
+            return Ok(UpdateStatus::NotConfigured);
+        }
+        bus.publish(Event::Update(UpdateStatus::Checking));
+        if self.connect()? == false {
+            return Ok(UpdateStatus::Away);
+        }
+        let downloaded = ota::download(&self.config.firmware_url, |percent| {
+            bus.publish(Event::Update(UpdateStatus::Downloading(percent)));
+        })?;
+        Ok(if downloaded {
+            UpdateStatus::Restarting
+        } else {
+            UpdateStatus::UpToDate
+        })
+    }
+
+    /// Uploads the rides of the card, moving each one to `SENT_DIR` once the endpoint took
+    /// it
+    fn upload(&mut self, bus: &Bus) -> anyhow::Result<UploadStatus> {
+        if self.config.ssid.is_empty() || self.config.url.is_empty() {
+            return Ok(UploadStatus::NotConfigured);
+        }
+        // No card, or no ride recorded yet
+        let rides = list_rides().unwrap_or_default();
+        if rides.is_empty() {
+            return Ok(UploadStatus::Done(0));
+        }
+
+        bus.publish(Event::Upload(UploadStatus::Connecting));
+        if self.connect()? == false {
+            return Ok(UploadStatus::Away);
+        }
+        let total = rides.iter().map(|(_, len)| len).sum::<u64>().max(1);
+        let mut sent = 0;
+        let mut told = None;
+        fs::create_dir_all(storage::get_path(SENT_DIR))?;
+        for (ride, len) in &rides {
+            self.post(ride, *len, |posted| {
+                // Told as the percentage changes only, not to flood the UI task
+                let percent = ((sent + posted) * 100 / total) as u8;
+                if told != Some(percent) {
+                    told = Some(percent);
+                    bus.publish(Event::Upload(UploadStatus::Uploading {
+                        ride: ride.clone(),
+                        percent,
+                    }));
+                }
+            })?;
+            sent += len;
+            fs::rename(
+                storage::get_path(&format!("{}/{}", RIDES_DIR, ride)),
+                storage::get_path(&format!("{}/{}", SENT_DIR, ride)),
+            )?;
+        }
+        Ok(UploadStatus::Done(rides.len() as u32))
+    }
+
+    /// Publishes the telemetry when the rate of the tracking is due, joining the network
+    /// and the broker first when needed
+    fn track(&mut self, telemetry: &Telemetry) -> anyhow::Result<()> {
+        let rate = Duration::from_secs(self.config.tracking.rate as u64);
+        if self.tracked_at.map_or(false, |at| at.elapsed() < rate) {
+            return Ok(());
+        }
+        self.tracked_at = Some(Instant::now());
+
+        if self.wifi.is_connected()? == false {
+            self.mqtt = None;
+            if self
+                .joined_at
+                .map_or(false, |at| at.elapsed() < TRACK_CONNECT_RETRY)
+            {
+                return Ok(());
+            }
+            self.joined_at = Some(Instant::now());
+            if self.connect()? == false {
+                return Ok(());
+            }
+        }
+        if self.mqtt.is_none() {
+            let tracking = &self.config.tracking;
+            let conf = MqttClientConfiguration {
+                client_id: Some(MQTT_CLIENT_ID),
+                username: Some(tracking.username.as_str()).filter(|name| name.is_empty() == false),
+                password: Some(tracking.password.as_str()).filter(|pass| pass.is_empty() == false),
+                ..Default::default()
+            };
+            self.mqtt = Some(EspMqttClient::new(&tracking.broker, &conf, |_| {})?);
+        }
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            mqtt.publish(
+                &self.config.tracking.topic,
+                QoS::AtMostOnce,
+                false,
+                telemetry.to_json().as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the home network, false when it is out of reach
+    fn connect(&mut self) -> anyhow::Result<bool> {
+        if self.wifi.is_connected()? {
+            return Ok(true);
+        }
+        self.wifi
+            .set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: self.config.ssid.as_str().into(),
+                password: self.config.password.as_str().into(),
+                ..Default::default()
+            }))?;
+        self.wifi.start()?;
+        let home = self
+            .wifi
+            .scan()?
+            .iter()
+            .any(|access_point| access_point.ssid.as_str() == self.config.ssid);
+        if home == false {
+            return Ok(false);
+        }
+
+        self.wifi.connect()?;
+        let started = Instant::now();
+        while started.elapsed() < CONNECT_TIMEOUT {
+            crash::feed_watchdog();
+            if self.wifi.is_connected()? && self.wifi.sta_netif().is_up()? {
+                return Ok(true);
+            }
+            FreeRtos::delay_ms(CONNECT_POLL_MS);
+        }
+        anyhow::bail!("Connection timed out")
+    }
+
+    /// Turns the WiFi off, unless the tracking uses it
+    fn disconnect(&mut self) {
+        if self.config.is_tracking() {
+            return;
+        }
+        self.mqtt = None;
+        self.wifi.disconnect().ok();
+        self.wifi.stop().ok();
+    }
+
+    /// Posts a ride to the endpoint, telling the bytes posted so far as they go
+    fn post(&self, ride: &str, len: u64, mut progress: impl FnMut(u64)) -> anyhow::Result<()> {
+        let mut file = File::open(storage::get_path(&format!("{}/{}", RIDES_DIR, ride)))?;
+        let mut client = Client::wrap(EspHttpConnection::new(&HttpConfiguration {
+            timeout: Some(HTTP_TIMEOUT),
+            ..Default::default()
+        })?);
+        let len_header = len.to_string();
+        let headers = [
+            ("Content-Type", "text/csv"),
+            ("Content-Length", len_header.as_str()),
+            ("X-Ride", ride),
+        ];
+        let mut request = client.post(&self.config.url, &headers)?;
+        let mut chunk = [0u8; CHUNK_LEN];
+        let mut posted = 0;
+        loop {
+            crash::feed_watchdog();
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            request.write_all(&chunk[..read])?;
+            posted += read as u64;
+            progress(posted);
+        }
+        let status = request.submit()?.status();
+        if (200..300).contains(&status) == false {
+            anyhow::bail!("Endpoint answered {}", status);
+        }
+        Ok(())
+    }
+}
+
+fn get_string(nvs: &EspNvs<NvsDefault>, key: &str, max_len: usize) -> String {
+    let mut buffer = vec![0u8; max_len];
+    nvs.get_raw(key, &mut buffer)
+        .ok()
+        .flatten()
+        .map(|value| String::from_utf8_lossy(value).to_string())
+        .unwrap_or_default()
+}
+
+fn get_rate(nvs: &EspNvs<NvsDefault>) -> u16 {
+    let mut buffer = [0u8; 2];
+    match nvs.get_raw(TRACKING_RATE_KEY, &mut buffer).ok().flatten() {
+        Some([high, low]) => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    }
+}
+
+fn set_string(nvs: &mut EspNvs<NvsDefault>, key: &str, value: &str) {
+    nvs.set_raw(key, value.as_bytes()).ok().or_else(|| {
+        println!("Failed to save {}", key);
+        None
+    });
+}